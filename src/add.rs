@@ -39,12 +39,10 @@ impl ConstraintSynthesizer<Fr> for AddCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let before = self.before.clone();
         let after = self.after.clone();
 
-        println!("before {:?}", before);
-        println!("after {:?}", after);
-    
         let elen = before.expr_stack.len();
     
         let pc_hash = hash_code(&self.params, &after.pc);
@@ -59,8 +57,6 @@ impl ConstraintSynthesizer<Fr> for AddCircuit {
             AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(self.calc_hash())).unwrap(),
         );
     
-        println!("p1 {}, p2 {}", p1, p2);
-    
         let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
     
         let var_a = FpVar::Var(
@@ -86,7 +82,6 @@ impl ConstraintSynthesizer<Fr> for AddCircuit {
         inputs_pc.push(hash_pc_after_var.clone());
         let hash_pc_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_pc).unwrap();
     
-        println!("pc hash {}", hash_code(&self.params, &before.pc));
 //        println!("pc hash {}", hash_pc_gadget.value().unwrap());
     
         let mut inputs_stack_before2 = Vec::new();
@@ -96,7 +91,6 @@ impl ConstraintSynthesizer<Fr> for AddCircuit {
         ));
         let hash_stack_before2_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_stack_before2).unwrap();
     
-        println!("stack before2 {}", hash_list(&self.params, &before.expr_stack[..elen-1].iter().map(|a| Fr::from(*a)).collect::<Vec<Fr>>()));
 //        println!("stack before2 {}", hash_stack_before2_gadget.value().unwrap());
     
         let mut inputs_stack_before = Vec::new();
@@ -104,7 +98,6 @@ impl ConstraintSynthesizer<Fr> for AddCircuit {
         inputs_stack_before.push(hash_stack_before2_gadget);
         let hash_stack_before_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_stack_before).unwrap();
     
-        println!("stack before {}", hash_list(&self.params, &before.expr_stack.iter().map(|a| Fr::from(*a)).collect::<Vec<Fr>>()));
 //        println!("stack before {}", hash_stack_before_gadget.value().unwrap());
 
         let mut inputs_stack_after = Vec::new();
@@ -115,7 +108,6 @@ impl ConstraintSynthesizer<Fr> for AddCircuit {
         ));
         let hash_stack_after_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_stack_after).unwrap();
     
-        println!("stack after {}", hash_list(&self.params, &after.expr_stack.iter().map(|a| Fr::from(*a)).collect::<Vec<Fr>>()));
 //        println!("stack after {}", hash_stack_after_gadget.value().unwrap());
 
         // Compute VM hash before
@@ -140,8 +132,6 @@ impl ConstraintSynthesizer<Fr> for AddCircuit {
         let hash_transition_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_transition).unwrap();
         hash_transition_gadget.enforce_equal(&public_var)?;
     
-        println!("Made circuit");
-        println!("before {}, after {}", before.hash(&self.params), after.hash(&self.params));
 //        println!("before {}, after {}", hash_vm_before_gadget.value().unwrap(), hash_vm_after_gadget.value().unwrap());
 
         Ok(())
@@ -2,154 +2,518 @@ use ark_r1cs_std::alloc::AllocVar;
 use ark_r1cs_std::{
     fields::fp::{AllocatedFp, FpVar},
 };
-use ark_crypto_primitives::crh::poseidon::CRH;
 use ark_crypto_primitives::crh::poseidon::constraints::{CRHGadget, CRHParametersVar};
 use ark_mnt4_298::Fr;
 use ark_relations::r1cs::ConstraintSynthesizer;
-use ark_crypto_primitives::{CRHSchemeGadget, CRHScheme};
+use ark_crypto_primitives::CRHSchemeGadget;
 use ark_relations::r1cs::SynthesisError;
 use ark_relations::r1cs::ConstraintSystemRef;
 use ark_r1cs_std::eq::EqGadget;
+use ark_r1cs_std::boolean::{AllocatedBool, Boolean};
 use ark_sponge::poseidon::PoseidonParameters;
-use ark_relations::r1cs::ConstraintSystem;
+use ark_r1cs_std::R1CSVar;
+use ark_ff::PrimeField;
+
+use std::cmp::Ordering;
+
+use crate::{Transition,hash_list,hash_code,hash_pair};
+use crate::CodeTree::*;
+use crate::ControlFrame::LoopFrame;
+
+// Opcodes in the same order as get_transitions
+pub const ADD: usize = 0;
+pub const SUB: usize = 1;
+pub const GT: usize = 2;
+pub const CONST: usize = 3;
+pub const GET: usize = 4;
+pub const SET: usize = 5;
+pub const LOOP: usize = 6;
+pub const END: usize = 7;
+pub const BREAKNO: usize = 8;
+pub const BREAKYES: usize = 9;
+pub const OPS: usize = 10;
+
+// code_args tag of each opcode
+const TAGS: [u64; OPS] = [1, 2, 3, 6, 4, 5, 8, 9, 7, 7];
+
+// number of values popped from the expression stack
+const POPS: [usize; OPS] = [2, 2, 2, 0, 0, 1, 0, 0, 1, 1];
 
-use crate::{VM,Transition,hash_list,hash_code};
-use crate::InstructionCircuit;
+// deepest break the circuit proves, a break to depth d pops d+1 frames
+pub const MAX_BREAK_DEPTH: usize = 3;
+
+// Opcodes a batch key proves. Straight-line batches have no loop, end or taken break,
+// so they don't hash control frames and have fewer constraints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchKind {
+    All,
+    Straight,
+}
+
+// key order used by make_batch_circuits, the index is the selection index
+pub const KINDS: [BatchKind; 2] = [BatchKind::All, BatchKind::Straight];
+
+impl BatchKind {
+    pub fn allows(&self, op: usize) -> bool {
+        match self {
+            BatchKind::All => true,
+            BatchKind::Straight => op != LOOP && op != END && op != BREAKYES,
+        }
+    }
 
+    // cheapest kind that proves all the transitions
+    pub fn select(trs: &[Transition]) -> usize {
+        KINDS.iter().rposition(|k| trs.iter().all(|tr| k.allows(op_index(tr)))).unwrap()
+    }
+}
+
+pub fn op_index(tr: &Transition) -> usize {
+    let before = &tr.before;
+    match &before.pc[0] {
+        CAdd => ADD,
+        CSub => SUB,
+        CGt => GT,
+        CConst(_) => CONST,
+        CGetLocal(_) => GET,
+        CSetLocal(_) => SET,
+        CLoop(_) => LOOP,
+        CEnd => END,
+        CBreakIf(_) => {
+            if *before.expr_stack.last().unwrap() != 0 { BREAKYES } else { BREAKNO }
+        }
+    }
+}
+
+// Witness for one transition of any opcode
 #[derive(Debug, Clone)]
-pub struct AddManyCircuit {
-    pub steps: Vec<(VM,VM)>,
-    pub params: PoseidonParameters<Fr>,
+pub struct Step {
+    pub op: usize,
+    pub a: Fr, // top of stack
+    pub b: Fr, // second element of stack
+    pub imm: Fr, // immediate, or hash of continuation for loops
+    pub idx: bool, // local index for get and set
+    pub rest: Fr, // stack below the popped elements
+    pub next: Fr, // pc without the current instruction
+    pub ctrl: Fr, // control stack below the pushed or popped frame
+    pub cont: Fr,
+    pub start: Fr,
+    pub depth: usize, // frames popped above the target of a break
+    pub frames: Vec<Fr>, // hashes of those frames, top first
+    pub locals: (Fr, Fr),
+    pub hash: Fr,
 }
 
-impl InstructionCircuit for AddManyCircuit {
-    fn calc_hash(&self) -> Fr {
-        panic!("calc hash");
+impl Step {
+    pub fn new(params: &PoseidonParameters<Fr>, tr: &Transition) -> Result<Self, String> {
+        let before = &tr.before;
+        let after = &tr.after;
+        let op = op_index(tr);
+        let elen = before.expr_stack.len();
+        let stack = before.expr_stack.iter().map(|a| Fr::from(*a)).collect::<Vec<Fr>>();
+        let a = if elen > 0 { stack[elen - 1] } else { Fr::from(0) };
+        let b = if elen > 1 { stack[elen - 2] } else { Fr::from(0) };
+        let (imm, idx) = match &before.pc[0] {
+            CConst(x) => (Fr::from(*x), false),
+            CGetLocal(x) | CSetLocal(x) => (Fr::from(*x), *x == 1),
+            CBreakIf(x) => (Fr::from(*x), false),
+            CLoop(cont) => (hash_code(params, cont), false),
+            _ => (Fr::from(0), false),
+        };
+        let depth = match &before.pc[0] {
+            CBreakIf(x) if op == BREAKYES => *x as usize,
+            _ => 0,
+        };
+        if depth > MAX_BREAK_DEPTH {
+            return Err(format!("break depth {} is more than {}", depth, MAX_BREAK_DEPTH));
+        }
+        if elen < POPS[op] {
+            return Err(format!("{:?} needs {} stack elements, has {}", before.pc[0], POPS[op], elen));
+        }
+        let clen = before.control_stack.len();
+        if (op == END || op == BREAKYES) && clen <= depth {
+            return Err(format!("{:?} needs {} control frames, has {}", before.pc[0], depth + 1, clen));
+        }
+        let (cont, start) = match op {
+            LOOP => (imm, hash_code(params, &before.pc)),
+            END | BREAKYES => {
+                let LoopFrame(cont, start) = before.control_stack[clen - 1 - depth].clone();
+                (hash_code(params, &cont), hash_code(params, &start))
+            }
+            _ => (Fr::from(0), Fr::from(0)),
+        };
+        let mut frames = vec![Fr::from(0); MAX_BREAK_DEPTH];
+        for j in 0..depth {
+            frames[j] = before.control_stack[clen - 1 - j].hash(params);
+        }
+        let ctrl = if op == END || op == BREAKYES { after.hash_control(params) } else { before.hash_control(params) };
+        Ok(Step {
+            op,
+            a,
+            b,
+            imm,
+            idx,
+            rest: hash_list(params, &stack[..elen - POPS[op]]),
+            next: hash_code(params, &before.pc[1..]),
+            ctrl,
+            cont,
+            start,
+            depth,
+            frames,
+            locals: (Fr::from(before.locals[0]), Fr::from(before.locals[1])),
+            hash: hash_pair(params, &before.hash(params), &after.hash(params)),
+        })
     }
-    fn transition(&self) -> Transition {
-        panic!("transition")
+}
+
+fn witness(cs: &ConstraintSystemRef<Fr>, v: Fr) -> FpVar<Fr> {
+    FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(v)).unwrap())
+}
+
+fn hash(params_g: &CRHParametersVar<Fr>, inputs: Vec<FpVar<Fr>>) -> FpVar<Fr> {
+    CRHGadget::<Fr>::evaluate(params_g, &inputs).unwrap()
+}
+
+fn enforce_u32(cs: &ConstraintSystemRef<Fr>, v: &FpVar<Fr>, cond: &Boolean<Fr>) {
+    let mut packed = FpVar::Constant(Fr::from(0));
+    for i in 0..32 {
+        let bit = Boolean::from(
+            AllocatedBool::<Fr>::new_witness(cs.clone(), || v.value().map(|x| (x.into_repr().as_ref()[0] >> i) & 1 == 1)).unwrap(),
+        );
+        packed = packed + FpVar::from(bit) * Fr::from(1u64 << i);
     }
+    packed.conditional_enforce_equal(v, cond).unwrap();
 }
 
-fn generate_step(cs: ConstraintSystemRef<Fr>, params: PoseidonParameters<Fr>, params_g: &CRHParametersVar::<Fr>, before: VM, after: VM) -> Result<FpVar<Fr>, SynthesisError> {
-    let elen = before.expr_stack.len();
+// Computes the VM hashes before and after one step. The opcode is one-hot and bound to the pc hash through its tag,
+// every variant of the stack, locals, pc and control hashes is computed and the right ones are selected.
+// Opcodes the kind doesn't allow are constant false.
+pub fn generate_step(cs: ConstraintSystemRef<Fr>, params_g: &CRHParametersVar<Fr>, step: &Step, kind: BatchKind) -> (FpVar<Fr>, FpVar<Fr>) {
+    let zero = FpVar::Constant(Fr::from(0));
 
-    let pc_hash = hash_code(&params, &after.pc);
-    let stack_hash = hash_list(&params, &before.expr_stack[..elen-2].iter().map(|a| Fr::from(*a)).collect::<Vec<Fr>>());
-    let locals_hash = before.hash_locals(&params);
-    let control_hash = before.hash_control(&params);
+    let mut sel = vec![];
+    for i in 0..OPS {
+        if !kind.allows(i) {
+            sel.push(Boolean::FALSE);
+            continue;
+        }
+        sel.push(Boolean::from(
+            AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(step.op == i)).unwrap(),
+        ));
+    }
+    let mut count = zero.clone();
+    let mut tag = zero.clone();
+    for i in 0..OPS {
+        let s: FpVar<Fr> = From::from(sel[i].clone());
+        count = count + s.clone();
+        tag = tag + s * Fr::from(TAGS[i]);
+    }
+    count.enforce_equal(&FpVar::Constant(Fr::from(1))).unwrap();
 
-    let p1 = before.expr_stack[elen - 1];
-    let p2 = before.expr_stack[elen - 2];
+    let binop = Boolean::kary_or(&sel[ADD..=GT]).unwrap();
+    let push = Boolean::kary_or(&[binop.clone(), sel[CONST].clone(), sel[GET].clone()]).unwrap();
+    let pop = Boolean::kary_or(&[sel[SET].clone(), sel[BREAKNO].clone(), sel[BREAKYES].clone()]).unwrap();
+    let short = Boolean::kary_or(&[binop.clone(), sel[END].clone()]).unwrap();
+    let exit = Boolean::kary_or(&[sel[END].clone(), sel[BREAKYES].clone()]).unwrap();
 
-    let var_a = FpVar::Var(
-        AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(p1))).unwrap(),
-    );
-    let var_b = FpVar::Var(
-        AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(p2))).unwrap(),
+    let a = witness(&cs, step.a);
+    let b = witness(&cs, step.b);
+    let imm = witness(&cs, step.imm);
+    let rest = witness(&cs, step.rest);
+    let next = witness(&cs, step.next);
+    let ctrl = witness(&cs, step.ctrl);
+    let cont = witness(&cs, step.cont);
+    let start = witness(&cs, step.start);
+    let l0 = witness(&cs, step.locals.0);
+    let l1 = witness(&cs, step.locals.1);
+    let idx = Boolean::from(
+        AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(step.idx)).unwrap(),
     );
 
-    let locals_var = FpVar::Var(
-        AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(locals_hash)).unwrap(),
-    );
-    let control_var = FpVar::Var(
-        AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(control_hash)).unwrap(),
-    );
+    // get and set take a boolean local index
+    let local_op = Boolean::kary_or(&[sel[GET].clone(), sel[SET].clone()]).unwrap();
+    imm.conditional_enforce_equal(&FpVar::from(idx.clone()), &local_op).unwrap();
+    a.conditional_enforce_equal(&zero, &sel[BREAKNO]).unwrap();
+    a.is_eq(&zero).unwrap().conditional_enforce_equal(&Boolean::constant(false), &sel[BREAKYES]).unwrap();
 
-    let hash_pc_after_var = FpVar::Var(
-        AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(pc_hash)).unwrap(),
-    );
+    let read = idx.select(&l1, &l0).unwrap();
+    let gt: FpVar<Fr> = From::from(b.is_cmp(&a, Ordering::Greater, false).unwrap());
+    let mut res = a.clone() + b.clone();
+    res = sel[SUB].select(&(b.clone() - a.clone()), &res).unwrap();
+    // add and sub must not overflow, constants and locals are 32-bit already
+    enforce_u32(&cs, &res, &Boolean::kary_or(&sel[ADD..=SUB]).unwrap());
+    res = sel[GT].select(&gt, &res).unwrap();
+    res = sel[CONST].select(&imm, &res).unwrap();
+    res = sel[GET].select(&read, &res).unwrap();
+
+    // stack
+    let below = hash(params_g, vec![b.clone(), rest.clone()]);
+    let pop2 = hash(params_g, vec![a.clone(), below]);
+    let pop1 = hash(params_g, vec![a.clone(), rest.clone()]);
+    let pushed = hash(params_g, vec![res, rest.clone()]);
+    let stack_before = binop.select(&pop2, &pop.select(&pop1, &rest).unwrap()).unwrap();
+    let stack_after = push.select(&pushed, &rest).unwrap();
+
+    // locals
+    let l0_after = sel[SET].select(&idx.select(&l0, &a).unwrap(), &l0).unwrap();
+    let l1_after = sel[SET].select(&idx.select(&a, &l1).unwrap(), &l1).unwrap();
+    let locals_before = hash(params_g, vec![l0, l1]);
+    let locals_after = hash(params_g, vec![l0_after, l1_after]);
+
+    // pc
+    let pc2 = hash(params_g, vec![tag.clone(), next.clone()]);
+    let pc3 = hash(params_g, vec![tag, imm.clone(), next.clone()]);
+    let pc_before = short.select(&pc2, &pc3).unwrap();
+    cont.conditional_enforce_equal(&imm, &sel[LOOP]).unwrap();
+    start.conditional_enforce_equal(&pc_before, &sel[LOOP]).unwrap();
+    let pc_after = sel[END].select(&cont, &sel[BREAKYES].select(&start, &next).unwrap()).unwrap();
+
+    // control, a taken break to depth d also pops the d frames above its target.
+    // skip[j] is true if frame j is popped, the skipped frames are a prefix and there are imm of them.
+    let (ctrl_before, ctrl_after) = if kind == BatchKind::All {
+        let frame = hash(params_g, vec![FpVar::Constant(Fr::from(1)), cont, start]);
+        let framed = hash(params_g, vec![frame, ctrl.clone()]);
+        let mut skip = vec![];
+        let mut depth = zero.clone();
+        for j in 0..MAX_BREAK_DEPTH {
+            let b = Boolean::from(
+                AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(j < step.depth)).unwrap(),
+            );
+            b.and(&sel[BREAKYES].not()).unwrap().enforce_equal(&Boolean::FALSE).unwrap();
+            if j > 0 {
+                b.and(&skip[j-1].not()).unwrap().enforce_equal(&Boolean::FALSE).unwrap();
+            }
+            depth = depth + FpVar::from(b.clone());
+            skip.push(b);
+        }
+        imm.conditional_enforce_equal(&depth, &sel[BREAKYES]).unwrap();
+        let mut popped = framed.clone();
+        for j in (0..MAX_BREAK_DEPTH).rev() {
+            let skipped = hash(params_g, vec![witness(&cs, step.frames[j]), popped.clone()]);
+            popped = skip[j].select(&skipped, &popped).unwrap();
+        }
+        (exit.select(&popped, &ctrl).unwrap(), sel[LOOP].select(&framed, &ctrl).unwrap())
+    } else {
+        (ctrl.clone(), ctrl.clone())
+    };
+
+    let vm_before = hash(params_g, vec![pc_before, stack_before, locals_before, ctrl_before]);
+    let vm_after = hash(params_g, vec![pc_after, stack_after, locals_after, ctrl_after]);
+    (vm_before, vm_after)
+}
+
+// Proves K consecutive transitions with one key. Each step starts from the state the previous one ended in,
+// the public input is the root of the tree of transition hashes, the same subtree that merkleloop
+// and the transition aggregation build, so K has to be a power of two.
+#[derive(Debug, Clone)]
+pub struct BatchCircuit {
+    pub steps: Vec<Step>,
+    pub kind: BatchKind,
+    pub params: PoseidonParameters<Fr>,
+}
+
+impl BatchCircuit {
+    pub fn new(params: &PoseidonParameters<Fr>, trs: &[Transition], kind: BatchKind) -> Result<Self, String> {
+        if !trs.len().is_power_of_two() {
+            return Err(format!("batch of {} transitions is not a power of two", trs.len()));
+        }
+        if let Some(tr) = trs.iter().find(|tr| !kind.allows(op_index(tr))) {
+            return Err(format!("{:?} batch can't prove {:?}", kind, tr.before.pc[0]));
+        }
+        let steps = trs.iter().map(|tr| Step::new(params, tr)).collect::<Result<Vec<Step>, String>>()?;
+        Ok(BatchCircuit {
+            steps,
+            kind,
+            params: params.clone(),
+        })
+    }
 
-    let mut inputs_pc = Vec::new();
-    inputs_pc.push(FpVar::Constant(Fr::from(1)));
-    inputs_pc.push(hash_pc_after_var.clone());
-    let hash_pc_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_pc).unwrap();
-
-    let mut inputs_stack_before2 = Vec::new();
-    inputs_stack_before2.push(var_b.clone());
-    inputs_stack_before2.push(FpVar::Var(
-        AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(stack_hash)).unwrap(),
-    ));
-    let hash_stack_before2_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_stack_before2).unwrap();
-
-    let mut inputs_stack_before = Vec::new();
-    inputs_stack_before.push(var_a.clone());
-    inputs_stack_before.push(hash_stack_before2_gadget);
-    let hash_stack_before_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_stack_before).unwrap();
-
-    let mut inputs_stack_after = Vec::new();
-    // TODO: check that 32-bit
-    inputs_stack_after.push(var_a.clone() + var_b.clone());
-    inputs_stack_after.push(FpVar::Var(
-        AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(stack_hash)).unwrap(),
-    ));
-    let hash_stack_after_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_stack_after).unwrap();
-
-    // Compute VM hash before
-    let mut inputs_vm_before = Vec::new();
-    inputs_vm_before.push(hash_pc_gadget);
-    inputs_vm_before.push(hash_stack_before_gadget);
-    inputs_vm_before.push(locals_var.clone());
-    inputs_vm_before.push(control_var.clone());
-    let hash_vm_before_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_vm_before).unwrap();
-
-    // Compute VM hash after
-    let mut inputs_vm_after = Vec::new();
-    inputs_vm_after.push(hash_pc_after_var);
-    inputs_vm_after.push(hash_stack_after_gadget);
-    inputs_vm_after.push(locals_var.clone());
-    inputs_vm_after.push(control_var.clone());
-    let hash_vm_after_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_vm_after).unwrap();
-
-    let mut inputs_transition = Vec::new();
-    inputs_transition.push(hash_vm_before_gadget.clone());
-    inputs_transition.push(hash_vm_after_gadget.clone());
-    let hash_transition_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_transition).unwrap();
-
-    Ok(hash_transition_gadget)
-}
-
-impl ConstraintSynthesizer<Fr> for AddManyCircuit {
+    pub fn calc_hashes(&self) -> Vec<Fr> {
+        self.steps.iter().map(|s| s.hash).collect()
+    }
+
+    pub fn calc_hash(&self) -> Fr {
+        let mut level = self.calc_hashes();
+        while level.len() > 1 {
+            level = level.chunks(2).map(|p| hash_pair(&self.params, &p[0], &p[1])).collect();
+        }
+        level[0]
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for BatchCircuit {
     fn generate_constraints(
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        let public_var = FpVar::Var(
+            AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(self.calc_hash())).unwrap(),
+        );
         let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
-        // make each step
-        let mut vars = vec![];
-        for el in self.steps {
-            let var = generate_step(cs.clone(), self.params.clone(), &params_g, el.0, el.1);
-            vars.push(var);
-        }
-        // println!("num constraints {}, valid {}", cs.num_constraints(), cs.is_satisfied().unwrap());
-        println!("num constraints {}", cs.num_constraints());
+        let mut level = vec![];
+        let mut prev: Option<FpVar<Fr>> = None;
+        for step in self.steps.iter() {
+            let (before, after) = generate_step(cs.clone(), &params_g, step, self.kind);
+            if let Some(prev) = prev {
+                prev.enforce_equal(&before)?;
+            }
+            level.push(hash(&params_g, vec![before, after.clone()]));
+            prev = Some(after);
+        }
+        while level.len() > 1 {
+            level = level.chunks(2).map(|p| hash(&params_g, vec![p[0].clone(), p[1].clone()])).collect();
+        }
+        level[0].enforce_equal(&public_var)?;
         Ok(())
     }
 }
 
-pub fn test(params: &PoseidonParameters<Fr>, step: (VM, VM)) {
-    use ark_std::test_rng;
-    use std::time::Instant;
-    use crate::InnerSNARK;
-    use ark_crypto_primitives::CircuitSpecificSetupSNARK;
-    use ark_crypto_primitives::SNARK;
-    let mut steps = vec![];
-    for _i in 0..256 {
-        steps.push(step.clone())
-    }
-    let circuit = AddManyCircuit {
-        params: params.clone(),
-        steps,
+#[cfg(test)]
+use ark_relations::r1cs::ConstraintSystem;
+
+#[cfg(test)]
+fn run(params: &PoseidonParameters<Fr>, code: Vec<crate::CodeTree>) -> Vec<Transition> {
+    let mut vm = crate::VM::new(code);
+    let mut c = crate::Collector {
+        add: vec![], sub: vec![], gt: vec![], get: vec![], set: vec![],
+        constant: vec![], loopi: vec![], endi: vec![], breakno: vec![], breakyes: vec![],
     };
-    /* let cs_sys = ConstraintSystem::<Fr>::new();
+    for _i in 0..64 {
+        vm.step(params, &mut c);
+    }
+    // execution order
+    let mut trs = crate::get_transitions(&c);
+    trs.sort_by_key(|tr| tr.before.step_counter);
+    trs
+}
+
+// Program that uses every opcode, including both outcomes of a break
+#[cfg(test)]
+fn test_transitions(params: &PoseidonParameters<Fr>) -> Vec<Transition> {
+    let cont = vec![CGetLocal(1), CConst(2), CAdd, CSetLocal(1), CEnd];
+    let code = vec![
+        CConst(3), CSetLocal(0),
+        CLoop(cont),
+        CGetLocal(0), CConst(1), CSub, CSetLocal(0),
+        CGetLocal(0), CConst(0), CGt, CBreakIf(0),
+        CEnd,
+    ];
+    run(params, code)
+}
+
+// Break out of an inner loop to the start of the outer one
+#[cfg(test)]
+fn nested_transitions(params: &PoseidonParameters<Fr>) -> Vec<Transition> {
+    let code = vec![
+        CConst(2), CSetLocal(0),
+        CLoop(vec![CGetLocal(1), CEnd]),
+        CLoop(vec![CEnd]),
+        CGetLocal(0), CConst(1), CSub, CSetLocal(0),
+        CGetLocal(0), CConst(0), CGt, CBreakIf(1),
+        CEnd,
+    ];
+    run(params, code)
+}
+
+#[cfg(test)]
+fn check_circuit(circuit: BatchCircuit) -> bool {
+    let cs_sys = ConstraintSystem::<Fr>::new();
     let cs = ConstraintSystemRef::new(cs_sys);
-    circuit.generate_constraints(cs); */
-    let mut rng = test_rng();
-    println!("Setting up circuit");
-    let (pk, _vk) = InnerSNARK::setup(circuit.clone(), &mut rng).unwrap();
-    println!("Testing prove");
-    let start = Instant::now();
-    let _proof = InnerSNARK::prove(&pk, circuit.clone(), &mut rng).unwrap();
-    let elapsed = start.elapsed();
-    println!("proving took {} ms", elapsed.as_millis());
+    circuit.generate_constraints(cs.clone()).unwrap();
+    cs.is_satisfied().unwrap()
+}
+
+#[test]
+fn test_batch_circuit() {
+    let params = crate::generate_hash();
+    let trs = test_transitions(&params);
+    let mut seen = vec![false; OPS];
+    for tr in trs.iter() {
+        seen[op_index(tr)] = true;
+        assert_eq!(crate::code_args(&tr.before.pc[0])[0], Fr::from(TAGS[op_index(tr)]));
+    }
+    assert!(seen.iter().all(|a| *a));
+    for k in [2, 4, 8] {
+        for chunk in trs.chunks_exact(k) {
+            let circuit = BatchCircuit::new(&params, chunk, BatchKind::All).unwrap();
+            assert_eq!(circuit.calc_hashes()[0], hash_pair(&params, &chunk[0].before.hash(&params), &chunk[0].after.hash(&params)));
+            assert!(check_circuit(circuit));
+        }
+    }
+    assert!(BatchCircuit::new(&params, &trs[..3], BatchKind::All).is_err());
+}
+
+#[test]
+fn test_batch_chained() {
+    let params = crate::generate_hash();
+    let trs = test_transitions(&params);
+    // valid transitions that don't follow each other
+    let swapped = vec![trs[1].clone(), trs[0].clone()];
+    assert!(!check_circuit(BatchCircuit::new(&params, &swapped, BatchKind::All).unwrap()));
+    let skipped = vec![trs[0].clone(), trs[2].clone()];
+    assert!(!check_circuit(BatchCircuit::new(&params, &skipped, BatchKind::All).unwrap()));
+}
+
+#[test]
+fn test_batch_break_depth() {
+    let params = crate::generate_hash();
+    let trs = nested_transitions(&params);
+    let deep = trs.iter().position(|tr| op_index(tr) == BREAKYES && tr.before.pc[0] == CBreakIf(1)).unwrap();
+    assert_eq!(trs[deep].before.control_stack.len(), trs[deep].after.control_stack.len() + 2);
+    let circuit = BatchCircuit::new(&params, &trs[deep - deep % 8..deep - deep % 8 + 8], BatchKind::All).unwrap();
+    assert!(check_circuit(circuit.clone()));
+    // claim a shallower break
+    let mut bad = circuit.clone();
+    bad.steps[deep % 8].depth = 0;
+    assert!(!check_circuit(bad));
+}
+
+#[test]
+fn test_batch_kinds() {
+    let params = crate::generate_hash();
+    let trs = test_transitions(&params);
+    let straight = &trs[0..2];
+    let control = &trs[0..4];
+    assert_eq!(KINDS[BatchKind::select(straight)], BatchKind::Straight);
+    assert_eq!(KINDS[BatchKind::select(control)], BatchKind::All);
+    assert!(BatchCircuit::new(&params, control, BatchKind::Straight).is_err());
+    let count = |kind| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        BatchCircuit::new(&params, straight, kind).unwrap().generate_constraints(cs.clone()).unwrap();
+        assert!(cs.is_satisfied().unwrap());
+        cs.num_constraints()
+    };
+    assert!(count(BatchKind::Straight) < count(BatchKind::All));
+}
+
+#[test]
+fn test_batch_invalid() {
+    let params = crate::generate_hash();
+    let trs = test_transitions(&params);
+    let trs = &trs[..16];
+    let circuit = BatchCircuit::new(&params, trs, BatchKind::All).unwrap();
+    for i in 0..trs.len() {
+        // claim another opcode
+        let mut bad = circuit.clone();
+        bad.steps[i].op = (bad.steps[i].op + 1) % OPS;
+        assert!(!check_circuit(bad));
+        // wrong top of stack
+        let mut bad = circuit.clone();
+        bad.steps[i].a += Fr::from(1);
+        if bad.steps[i].op != END && bad.steps[i].op != LOOP && bad.steps[i].op != CONST && bad.steps[i].op != GET {
+            assert!(!check_circuit(bad));
+        }
+    }
+}
+
+#[test]
+fn test_step_short_stack() {
+    let params = crate::generate_hash();
+    let trs = test_transitions(&params);
+    let mut tr = trs.iter().find(|tr| op_index(tr) == ADD).unwrap().clone();
+    assert!(Step::new(&params, &tr).is_ok());
+    tr.before.expr_stack.truncate(1);
+    assert!(Step::new(&params, &tr).is_err());
+    assert!(BatchCircuit::new(&params, &[tr], BatchKind::All).is_err());
 }
@@ -72,6 +72,7 @@ impl ConstraintSynthesizer<MNT6Fr> for InnerAggregateFinal {
         self,
         cs: ConstraintSystemRef<MNT6Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
 
         let start_var = FpVar::Var(
             AllocatedFp::<MNT6Fr>::new_input(cs.clone(), || Ok(mnt6(&self.start_st))).unwrap(),
@@ -144,8 +145,6 @@ impl ConstraintSynthesizer<MNT6Fr> for InnerAggregateFinal {
 
         // println!("Working: {}", cs.is_satisfied().unwrap());
 
-        println!("recursive circuit has {} constraints", cs.num_constraints());
-
         Ok(())
     }
 }
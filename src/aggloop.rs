@@ -54,6 +54,7 @@ use crate::InnerSNARKVK;
 use crate::InnerSNARKProof;
 use crate::OuterSNARKPK;
 use crate::InnerSNARKPK;
+use crate::metrics;
 
 pub trait LoopCircuit : ConstraintSynthesizer<Fr> + Clone {
     fn get_inputs(&self) -> Vec<Fr>;
@@ -90,6 +91,7 @@ impl ConstraintSynthesizer<MNT6Fr> for InnerAggregateLoop {
         self,
         cs: ConstraintSystemRef<MNT6Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
 
         let start_var = FpVar::Var(
             AllocatedFp::<MNT6Fr>::new_input(cs.clone(), || Ok(mnt6(&self.start_st))).unwrap(),
@@ -157,8 +159,6 @@ impl ConstraintSynthesizer<MNT6Fr> for InnerAggregateLoop {
 
         // println!("Working: {}", cs.is_satisfied().unwrap());
 
-        println!("recursive circuit has {} constraints", cs.num_constraints());
-
         Ok(())
     }
 }
@@ -203,6 +203,7 @@ impl ConstraintSynthesizer<Fr> for OuterAggregateLoop {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let start_var = FpVar::Var(
             AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(self.start_st.clone())).unwrap(),
         );
@@ -273,7 +274,6 @@ impl ConstraintSynthesizer<Fr> for OuterAggregateLoop {
     
         // println!("Working: {}", cs.is_satisfied().unwrap());
     
-        println!("recursive circuit has {} constraints", cs.num_constraints());
         Ok(())
     }
 }
@@ -287,17 +287,17 @@ pub struct InnerSetup {
 fn aggregate_level1<C:LoopCircuit>(a: C, b: C, setup: &InnerSetup) -> InnerAggregateLoop {
     let mut rng = test_rng();
 
-    let proof1 = InnerSNARK::prove(&setup.pk, a.clone(), &mut rng).unwrap();
-    let proof2 = InnerSNARK::prove(&setup.pk, b.clone(), &mut rng).unwrap();
+    let proof1 = metrics::prove::<C, _, _>(|| InnerSNARK::prove(&setup.pk, a.clone(), &mut rng).unwrap());
+    let proof2 = metrics::prove::<C, _, _>(|| InnerSNARK::prove(&setup.pk, b.clone(), &mut rng).unwrap());
 
-    println!("proof1: {}", InnerSNARK::verify(&setup.vk, &a.get_inputs(), &proof1).unwrap());
-    println!("proof2: {}", InnerSNARK::verify(&setup.vk, &b.get_inputs(), &proof2).unwrap());
+    tracing::debug!(verified = InnerSNARK::verify(&setup.vk, &a.get_inputs(), &proof1).unwrap(), "proof1");
+    tracing::debug!(verified = InnerSNARK::verify(&setup.vk, &b.get_inputs(), &proof2).unwrap(), "proof2");
 
     let (start_st,mid_st,root) = a.get();
     let (mid2,end_st,root2) = b.get();
 
-    println!("1 start {} end {} root {}",start_st,mid_st,root);
-    println!("2 start {} end {} root {}",mid2,end_st,root2);
+    tracing::trace!(%start_st, %mid_st, %root, "proof1 states");
+    tracing::trace!(%mid2, %end_st, %root2, "proof2 states");
 
     InnerAggregateLoop {
         start_st,
@@ -319,17 +319,17 @@ pub struct OuterSetup {
 fn aggregate_level2<C:LoopCircuit2>(a: C, b: C, setup: &OuterSetup) -> OuterAggregateLoop {
     let mut rng = test_rng();
 
-    let proof1 = OuterSNARK::prove(&setup.pk, a.clone(), &mut rng).unwrap();
-    let proof2 = OuterSNARK::prove(&setup.pk, b.clone(), &mut rng).unwrap();
+    let proof1 = metrics::prove::<C, _, _>(|| OuterSNARK::prove(&setup.pk, a.clone(), &mut rng).unwrap());
+    let proof2 = metrics::prove::<C, _, _>(|| OuterSNARK::prove(&setup.pk, b.clone(), &mut rng).unwrap());
 
-    println!("proof1: {}", OuterSNARK::verify(&setup.vk, &a.get_inputs(), &proof1).unwrap());
-    println!("proof2: {}", OuterSNARK::verify(&setup.vk, &b.get_inputs(), &proof2).unwrap());
+    tracing::debug!(verified = OuterSNARK::verify(&setup.vk, &a.get_inputs(), &proof1).unwrap(), "proof1");
+    tracing::debug!(verified = OuterSNARK::verify(&setup.vk, &b.get_inputs(), &proof2).unwrap(), "proof2");
 
     let (start_st,mid_st,root) = a.get();
     let (mid2,end_st,root2) = b.get();
 
-    println!("1 start {} end {} root {}",start_st,mid_st,root);
-    println!("2 start {} end {} root {}",mid2,end_st,root2);
+    tracing::trace!(%start_st, %mid_st, %root, "proof1 states");
+    tracing::trace!(%mid2, %end_st, %root2, "proof2 states");
 
     OuterAggregateLoop {
         start_st,
@@ -345,7 +345,7 @@ fn aggregate_level2<C:LoopCircuit2>(a: C, b: C, setup: &OuterSetup) -> OuterAggr
 pub fn outer_to_inner<C: LoopCircuit2>(circuit: &C, setup: &OuterSetup) -> (OuterAggregateLoop, InnerSetup) {
     let mut rng = test_rng();
     let agg_circuit1 = aggregate_level2(circuit.clone(), circuit.clone(), setup);
-    let (pk, vk) = InnerSNARK::setup(agg_circuit1.clone(), &mut rng).unwrap();
+    let (pk, vk) = metrics::setup::<OuterAggregateLoop, _, _>(|| InnerSNARK::setup(agg_circuit1.clone(), &mut rng).unwrap());
 
     let setup2 = InnerSetup {
         pk,
@@ -358,7 +358,7 @@ pub fn outer_to_inner<C: LoopCircuit2>(circuit: &C, setup: &OuterSetup) -> (Oute
 pub fn inner_to_outer<C: LoopCircuit>(circuit: &C, setup: &InnerSetup) -> (InnerAggregateLoop, OuterSetup) {
     let mut rng = test_rng();
     let agg_circuit1 = aggregate_level1(circuit.clone(), circuit.clone(), setup);
-    let (pk, vk) = OuterSNARK::setup(agg_circuit1.clone(), &mut rng).unwrap();
+    let (pk, vk) = metrics::setup::<InnerAggregateLoop, _, _>(|| OuterSNARK::setup(agg_circuit1.clone(), &mut rng).unwrap());
 
     let setup2 = OuterSetup {
         pk,
@@ -382,4 +382,57 @@ pub fn aggregate_list1<C: LoopCircuit>(circuit: &[C], setup: &InnerSetup) -> Vec
         level1.push(aggregate_level1(circuit[2*i].clone(), circuit[2*i+1].clone(), setup));
     }
     level1
-}
\ No newline at end of file
+}
+// Aggregates loop circuits sharing one key into a single proof, the number of circuits has to be a power of 4.
+// Returns the proof with its key and the start and end states
+pub fn prove_loops<C: LoopCircuit>(kind: &'static str, circuits: &[C]) -> (InnerSNARKProof, InnerSNARKVK, Fr, Fr) {
+    let mut levels = 0;
+    while (1 << (2 * levels)) < circuits.len() {
+        levels += 1;
+    }
+    assert!(levels > 0 && (1 << (2 * levels)) == circuits.len(), "number of circuits has to be a power of 4");
+
+    let circuit = circuits[0].clone();
+    let mut rng = test_rng();
+    let (pk, vk) = metrics::setup::<C, _, _>(|| InnerSNARK::setup(circuit.clone(), &mut rng).unwrap());
+    let setup1 = InnerSetup { pk, vk };
+
+    // Keys for the aggregation levels
+    let (agg_circuit_out, setup_out) = inner_to_outer(&circuit, &setup1);
+    let mut setups1 = vec![setup_out];
+    let mut setups2 = vec![];
+    let mut agg_circuit_out = agg_circuit_out;
+    for i in 0..levels {
+        let (agg_circuit_in, setup_in) = outer_to_inner(&agg_circuit_out, &setups1[i]);
+        setups2.push(setup_in);
+        if i + 1 < levels {
+            let (next_out, setup_out) = inner_to_outer(&agg_circuit_in, &setups2[i]);
+            setups1.push(setup_out);
+            agg_circuit_out = next_out;
+        }
+    }
+
+    let mut prev_level = {
+        let _level = metrics::level(kind, 0, circuits.len());
+        aggregate_list1(circuits, &setup1)
+    };
+    for i in 0..levels {
+        let level2 = {
+            let _level = metrics::level(kind, 2*i + 1, prev_level.len());
+            aggregate_list2(&prev_level, &setups1[i])
+        };
+        if level2.len() == 1 {
+            let last = level2[0].clone();
+            let setup = setups2[i].clone();
+            let proof = metrics::prove::<OuterAggregateLoop, _, _>(|| InnerSNARK::prove(&setup.pk, last.clone(), &mut rng).unwrap());
+            tracing::debug!(verified = InnerSNARK::verify(&setup.vk, &last.get_inputs(), &proof).unwrap(), "last proof");
+            let (start_st, end_st, _) = last.get();
+            return (proof, setup.vk.clone(), start_st, end_st);
+        }
+        prev_level = {
+            let _level = metrics::level(kind, 2*i + 2, level2.len());
+            aggregate_list1(&level2, &setups2[i])
+        };
+    }
+    panic!("Wrong kind of last proof");
+}
@@ -61,6 +61,7 @@ use crate::convert_inputs;
 use crate::hash_pair;
 use crate::Transition;
 use crate::mnt6;
+use crate::metrics;
 
 #[derive(Debug, Clone)]
 pub struct HashCircuit {
@@ -83,6 +84,7 @@ impl ConstraintSynthesizer<Fr> for HashCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let a_var = FpVar::Var(
             AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(self.a)).unwrap(),
         );
@@ -125,6 +127,7 @@ impl ConstraintSynthesizer<MNT6Fr> for InnerAggregationCircuit {
         self,
         cs: ConstraintSystemRef<MNT6Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let public_var = <InnerSNARKGadget as SNARKGadget<
             <MNT4PairingEngine as PairingEngine>::Fr,
             <MNT4PairingEngine as PairingEngine>::Fq,
@@ -212,8 +215,6 @@ impl ConstraintSynthesizer<MNT6Fr> for InnerAggregationCircuit {
 
         // println!("Working: {}", cs.is_satisfied().unwrap());
 
-        println!("recursive circuit has {} constraints", cs.num_constraints());
-
         Ok(())
     }
 }
@@ -243,6 +244,7 @@ impl ConstraintSynthesizer<Fr> for OuterAggregationCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let public_var = <OuterSNARKGadget as SNARKGadget<
             <MNT6PairingEngine as PairingEngine>::Fr,
             <MNT6PairingEngine as PairingEngine>::Fq,
@@ -323,7 +325,6 @@ impl ConstraintSynthesizer<Fr> for OuterAggregationCircuit {
     
         // println!("Working: {}", cs.is_satisfied().unwrap());
     
-        println!("recursive circuit has {} constraints", cs.num_constraints());
         Ok(())
     }
 }
@@ -348,23 +349,17 @@ fn aggregate_level1<C:InstructionCircuit>(a: C, b: C, setup: &InnerSetup) -> Inn
         params: setup.params.clone(),
     };
 
-    let start = Instant::now();
-    let proof1 = InnerSNARK::prove(&setup.pk, a.clone(), &mut rng).unwrap();
-    let elapsed = start.elapsed();
-    println!("proving took {} ms", elapsed.as_millis());
-    let start = Instant::now();
-    let proof2 = InnerSNARK::prove(&setup.pk, b.clone(), &mut rng).unwrap();
-    let elapsed = start.elapsed();
-    println!("proving took {} ms", elapsed.as_millis());
-    let proof_hash = InnerSNARK::prove(&setup.hash_pk, hash_circuit.clone(), &mut rng).unwrap();
+    let proof1 = metrics::prove::<C, _, _>(|| InnerSNARK::prove(&setup.pk, a.clone(), &mut rng).unwrap());
+    let proof2 = metrics::prove::<C, _, _>(|| InnerSNARK::prove(&setup.pk, b.clone(), &mut rng).unwrap());
+    let proof_hash = metrics::prove::<HashCircuit, _, _>(|| InnerSNARK::prove(&setup.hash_pk, hash_circuit.clone(), &mut rng).unwrap());
 
     let hash3 = hash_circuit.calc_hash();
 
-    println!("proof1: {}", InnerSNARK::verify(&setup.vk, &vec![hash1.clone()], &proof1).unwrap());
-    println!("proof2: {}", InnerSNARK::verify(&setup.vk, &vec![hash2.clone()], &proof2).unwrap());
-    println!(
-        "proof hash: {}",
-        InnerSNARK::verify(&setup.hash_vk, &vec![hash1.clone(), hash2.clone(), hash3.clone()], &proof_hash).unwrap()
+    tracing::debug!(verified = InnerSNARK::verify(&setup.vk, &vec![hash1.clone()], &proof1).unwrap(), "proof1");
+    tracing::debug!(verified = InnerSNARK::verify(&setup.vk, &vec![hash2.clone()], &proof2).unwrap(), "proof2");
+    tracing::debug!(
+        verified = InnerSNARK::verify(&setup.hash_vk, &vec![hash1.clone(), hash2.clone(), hash3.clone()], &proof_hash).unwrap(),
+        "proof hash",
     );
 
     InnerAggregationCircuit {
@@ -386,24 +381,16 @@ pub struct OuterSetup {
     pub params: PoseidonParameters<Fr>,
 }
 
-use std::time::Instant;
-
 fn aggregate_level2<C:InstructionCircuit2>(a: C, b: C, setup: &OuterSetup) -> OuterAggregationCircuit {
     let mut rng = test_rng();
     let hash1 = a.calc_hash();
     let hash2 = b.calc_hash();
 
-    let start = Instant::now();
-    let proof1 = OuterSNARK::prove(&setup.pk, a.clone(), &mut rng).unwrap();
-    let elapsed = start.elapsed();
-    println!("proving took {} ms", elapsed.as_millis());
-    let start = Instant::now();
-    let proof2 = OuterSNARK::prove(&setup.pk, b.clone(), &mut rng).unwrap();
-    let elapsed = start.elapsed();
-    println!("proving took {} ms", elapsed.as_millis());
+    let proof1 = metrics::prove::<C, _, _>(|| OuterSNARK::prove(&setup.pk, a.clone(), &mut rng).unwrap());
+    let proof2 = metrics::prove::<C, _, _>(|| OuterSNARK::prove(&setup.pk, b.clone(), &mut rng).unwrap());
 
-    println!("proof1: {}", OuterSNARK::verify(&setup.vk, &convert_inputs(&vec![hash1.clone()]), &proof1).unwrap());
-    println!("proof2: {}", OuterSNARK::verify(&setup.vk, &convert_inputs(&vec![hash2.clone()]), &proof2).unwrap());
+    tracing::debug!(verified = OuterSNARK::verify(&setup.vk, &convert_inputs(&vec![hash1.clone()]), &proof1).unwrap(), "proof1");
+    tracing::debug!(verified = OuterSNARK::verify(&setup.vk, &convert_inputs(&vec![hash2.clone()]), &proof2).unwrap(), "proof2");
 
     OuterAggregationCircuit {
         a: hash1,
@@ -420,7 +407,7 @@ pub fn outer_to_inner<C: InstructionCircuit2>(circuit: &C, setup: &OuterSetup, h
     (OuterAggregationCircuit, InnerSetup) {
     let mut rng = test_rng();
     let agg_circuit1 = aggregate_level2(circuit.clone(), circuit.clone(), setup);
-    let (pk, vk) = InnerSNARK::setup(agg_circuit1.clone(), &mut rng).unwrap();
+    let (pk, vk) = metrics::setup::<OuterAggregationCircuit, _, _>(|| InnerSNARK::setup(agg_circuit1.clone(), &mut rng).unwrap());
 
     let setup2 = InnerSetup {
         pk,
@@ -437,7 +424,7 @@ pub fn inner_to_outer<C: InstructionCircuit>(circuit: &C, setup: &InnerSetup) ->
     (InnerAggregationCircuit, OuterSetup) {
     let mut rng = test_rng();
     let agg_circuit1 = aggregate_level1(circuit.clone(), circuit.clone(), setup);
-    let (pk, vk) = OuterSNARK::setup(agg_circuit1.clone(), &mut rng).unwrap();
+    let (pk, vk) = metrics::setup::<InnerAggregationCircuit, _, _>(|| OuterSNARK::setup(agg_circuit1.clone(), &mut rng).unwrap());
 
     let setup2 = OuterSetup {
         pk,
@@ -43,13 +43,11 @@ impl ConstraintSynthesizer<Fr> for BreakYesCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         use crate::ControlFrame::LoopFrame;
         let before = self.before.clone();
         let after = self.after.clone();
 
-        println!("before {:?}", before);
-        println!("after {:?}", after);
-
         let LoopFrame(cont, start) = before.control_stack.last().unwrap().clone();
 
         let cont_hash = hash_code(&self.params, &cont);
@@ -118,16 +116,12 @@ impl ConstraintSynthesizer<Fr> for BreakYesCircuit {
             control_after_var.clone(),
         ]).unwrap();
 
-        println!("pc before hash {}", hash_code(&self.params, &before.pc));
         // println!("pc before hash {}", hash_pc_before_var.value().unwrap());
 
-        println!("control before hash {}", before.hash_control(&self.params));
         // println!("control before hash {}", control_before_var.value().unwrap());
 
-        println!("stack before hash {}", before.hash_stack(&self.params));
         // println!("stack before hash {}", stack_before_var.value().unwrap());
 
-        println!("pc after hash {}", hash_code(&self.params, &after.pc));
         // println!("pc after hash {}", start_var.value().unwrap());
 
         // Compute VM hash before
@@ -152,8 +146,6 @@ impl ConstraintSynthesizer<Fr> for BreakYesCircuit {
         let hash_transition_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_transition).unwrap();
         hash_transition_gadget.enforce_equal(&public_var)?;
     
-        println!("Made circuit");
-        println!("before {}, after {}", before.hash(&self.params), after.hash(&self.params));
         // println!("before {}, after {}", hash_vm_before_gadget.value().unwrap(), hash_vm_after_gadget.value().unwrap());
 
         Ok(())
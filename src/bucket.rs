@@ -1,18 +1,24 @@
 use crate::as_waksman::IntegerPermutation;
-use crate::as_waksman::AsWaksmanRoute;
-use crate::as_waksman::AsWaksmanTopology;
-use crate::Transition;
+use crate::permutation::{fill_permutation, permute_list_with, PermutationArgument, WaksmanArgument};
+#[cfg(test)]
+use crate::permutation::GrandProductArgument;
+use crate::{Transition, hash_pair, hash_list};
 
 use ark_mnt4_298::Fr;
+use ark_ff::{PrimeField, Zero, One};
 use ark_crypto_primitives::crh::poseidon::constraints::CRHGadget;
-use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::fields::fp::{AllocatedFp, FpVar};
 use ark_crypto_primitives::crh::poseidon::constraints::CRHParametersVar;
 use ark_crypto_primitives::CRHSchemeGadget;
 use ark_sponge::poseidon::PoseidonParameters;
-use ark_relations::r1cs::ConstraintSystemRef;
-
-// use ark_r1cs_std::R1CSVar;
+use ark_relations::r1cs::{ConstraintSystemRef, ConstraintSynthesizer, SynthesisError};
+use ark_r1cs_std::alloc::AllocVar;
+use ark_r1cs_std::boolean::{AllocatedBool, Boolean};
+use ark_r1cs_std::eq::EqGadget;
+use ark_r1cs_std::R1CSVar;
 
+// Bucket i has the transitions with i*bucket_size <= step < (i+1)*bucket_size, sorted by step.
+// A non-empty bucket gets a slice of next_pow2(size) leaves at the bottom of the tree.
 #[derive(Debug, Clone)]
 struct Bucket {
     list: Vec<usize>,
@@ -43,7 +49,8 @@ impl Bucket {
     }
 }
 
-fn make_buckets(trs: &Vec<Transition>, bucket_size: usize, num_buckets: usize) -> Vec<Bucket> {
+// Unsatisfiable if a step is outside of the buckets
+fn make_buckets(trs: &Vec<Transition>, bucket_size: usize, num_buckets: usize) -> Result<Vec<Bucket>, SynthesisError> {
     let mut buckets = vec![];
     for i in 0..num_buckets {
         buckets.push(Bucket::new(i));
@@ -51,143 +58,107 @@ fn make_buckets(trs: &Vec<Transition>, bucket_size: usize, num_buckets: usize) -
     for (i, tr) in trs.iter().enumerate() {
         let c = tr.before.step_counter;
         let bucket_idx = c / bucket_size;
+        if bucket_idx >= num_buckets {
+            return Err(SynthesisError::Unsatisfiable);
+        }
         buckets[bucket_idx].list.push(i);
     };
-    buckets
+    for b in buckets.iter_mut() {
+        b.list.sort_by_key(|i| trs[*i].before.step_counter);
+    }
+    Ok(buckets)
 }
 
+// Slices are given from the left. When they are reserved in decreasing size, every slice
+// starts at a multiple of its size, so it is a subtree of the tree
 #[derive(Debug, Clone)]
 struct Slices {
-    slices: Vec<(usize,usize)>
+    next: usize,
+    total: usize,
 }
 
 impl Slices {
-    fn new(num: usize) -> Self {
-        let mut num = num;
-        let mut slices = vec![];
-        let mut acc = 0;
-        while num > 0 {
-            slices.push((num, acc));
-            acc += num;
-            num = num / 2;
-        };
-        Slices { slices }
+    fn new(total: usize) -> Self {
+        Slices { next: 0, total }
     }
 
     fn reserve(&mut self, num: usize) -> (usize, usize) {
-        // find first that is large enough
-        // if too large, split it first
-        for i in 0..self.slices.len() {
-            let (sz, idx) = self.slices[i].clone();
-            let mut sz = sz;
-            if num < sz {
-                let mut next_idx = idx + sz/2;
-                while num < sz/2 {
-                    sz = sz/2;
-                    self.slices.push((sz, next_idx));
-                    next_idx = next_idx - sz/2;
-                };
-                self.slices[i] = (0,0);
-                return (sz, idx)
-            }
+        let sz = num.next_power_of_two();
+        if self.next % sz != 0 || self.next + sz > self.total {
+            panic!("Cannot find slice");
         }
-        panic!("Cannot find slice");
+        let start = self.next;
+        self.next += sz;
+        (start, sz)
     }
 }
 
+// number of leaves at the bottom of the tree, the slices take less than twice the elements
+fn tree_size(elems: usize) -> usize {
+    (2*elems).next_power_of_two()
+}
 
-fn make_bucket_slices(mut buckets: Vec<Bucket>) -> Vec<Bucket> {
-    // sort buckets by size
-    buckets.sort_by(|a, b| (a.list.len()).cmp(&b.list.len()));
-    let mut slices = Slices::new(buckets.len());
+fn make_bucket_slices(mut buckets: Vec<Bucket>, size: usize) -> Vec<Bucket> {
+    // sort buckets by slice size, largest first
+    buckets.sort_by(|a, b| b.size().next_power_of_two().cmp(&a.size().next_power_of_two()).then(a.idx.cmp(&b.idx)));
+    let mut slices = Slices::new(size);
     let mut res = vec![];
     for b in buckets.iter() {
-        let (start, idx) = slices.reserve(b.size());
-        res.push(b.set_slice(start, idx));
+        if b.size() == 0 {
+            res.push(b.clone());
+            continue;
+        }
+        let (start, sz) = slices.reserve(b.size());
+        res.push(b.set_slice(start, sz));
     };
+    res.sort_by_key(|b| b.idx);
     res
 }
 
 // route buckets to the bottom of the tree
-// elems is the number of elems in the buckets
-fn route_bucket_contents(buckets: &Vec<Bucket>, elems: usize) -> IntegerPermutation {
-    let mut list : Vec<i32> = vec![-1; elems*2-1];
+// the inputs are the elems transitions followed by zeroes
+fn route_bucket_contents(buckets: &Vec<Bucket>, size: usize) -> IntegerPermutation {
+    let mut fixed = vec![];
     for bucket in buckets.iter() {
-        let start = bucket.slice_start;
         for (i, idx) in bucket.list.iter().enumerate() {
-            list[start+i] = *idx as i32;
+            fixed.push((*idx, bucket.slice_start+i));
         }
     };
-    let mut acc = elems;
-    // route zeroes
-    for i in 0..list.len() {
-        if list[i] == -1 {
-            list[i] = acc as i32;
-            acc += 1;
-        }
-    }
-    // create permutation
-    let mut perm = IntegerPermutation::new(elems*2-1);
-    for i in 0..list.len() {
-        perm.set(i, list[i] as usize);
-    }
-    perm
+    fill_permutation(size, fixed)
 }
 
 ////// route buckets from the merkle tree
 
 // compute idx of bucket in the tree
 // bottom level has lowest indices
-fn compute_idx(b: &Bucket, elems: usize) -> i32 {
+fn compute_idx(b: &Bucket, size: usize) -> usize {
     let mut sz = b.slice_size;
-    let mut level_idx = b.slice_start;
-    let mut elems = elems;
+    let mut width = size;
     let mut level_acc = 0;
     while sz > 1 {
         sz = sz/2;
-        level_acc += elems*2-1;
-        elems = elems/2;
-        level_idx = level_idx/2;
-    }
-    (level_idx + level_acc) as i32
-}
-
-fn total_size(elems: usize) -> usize {
-    let mut elems = elems;
-    let mut size = 0;
-    while elems > 0 {
-        size += elems*2-1;
-        elems = elems/2;
+        level_acc += width;
+        width = width/2;
     }
-    size
+    level_acc + b.slice_start / b.slice_size
 }
 
-fn route_buckets(buckets: &Vec<Bucket>, elems: usize) -> IntegerPermutation {
-    let size = total_size(elems);
-    let mut list : Vec<i32> = vec![-1; size];
+// the inputs are the nodes of the tree followed by a zero for each bucket, empty buckets take their zero
+fn route_buckets(buckets: &Vec<Bucket>, size: usize) -> IntegerPermutation {
+    let nodes = 2*size - 1;
+    let mut fixed = vec![];
     for bucket in buckets.iter() {
-        list[bucket.idx] = compute_idx(bucket, elems);
-    };
-    let mut acc = buckets.len();
-    // route zeroes
-    for i in 0..list.len() {
-        if list[i] == -1 {
-            list[i] = acc as i32;
-            acc += 1;
+        if bucket.size() == 0 {
+            fixed.push((nodes + bucket.idx, bucket.idx));
+        } else {
+            fixed.push((compute_idx(bucket, size), bucket.idx));
         }
-    }
-    // create permutation
-    let mut perm = IntegerPermutation::new(size);
-    for i in 0..list.len() {
-        perm.set(i, list[i] as usize);
-    }
-    perm
+    };
+    fill_permutation(nodes + buckets.len(), fixed)
 }
 
 // make merkle tree from variables
 fn hash_tree(
-    _cs: &ConstraintSystemRef<Fr>,
-    _params: &PoseidonParameters<Fr>,
     params_g: &CRHParametersVar::<Fr>,
     vars: &[FpVar<Fr>],
 ) -> Vec<FpVar<Fr>> {
@@ -205,39 +176,387 @@ fn hash_tree(
             tree.push(var.clone());
             next_level.push(var);
         }
-        // tree.push(next_level.clone());
         level = next_level;
     }
     tree
 }
 
-// zero sized buckets will also have a slice, they will get constant zero as input
+fn fr_to_u64(v: Fr) -> u64 {
+    v.into_repr().as_ref()[0]
+}
+
+// Witness the n lowest bits of v and check that they give v, so v < 2^n
+fn to_bits_n(cs: &ConstraintSystemRef<Fr>, v: &FpVar<Fr>, n: usize) -> Vec<Boolean<Fr>> {
+    let mut bits = vec![];
+    for i in 0..n {
+        let v = v.clone();
+        let bool_var = AllocatedBool::<Fr>::new_witness(cs.clone(), || {
+            let a = fr_to_u64(v.value()?);
+            Ok((a >> i) & 1 == 1)
+        }).unwrap();
+        bits.push(Boolean::from(bool_var));
+    }
+    Boolean::le_bits_to_fp_var(&bits).unwrap().enforce_equal(v).unwrap();
+    bits
+}
+
+// a < b, both have to be smaller than 2^32
+fn lt32(cs: &ConstraintSystemRef<Fr>, a: &FpVar<Fr>, b: &FpVar<Fr>) -> Boolean<Fr> {
+    let d = b - a + FpVar::Constant(Fr::from((1u64 << 32) - 1));
+    to_bits_n(cs, &d, 33)[32].clone()
+}
+
+// Node of the tree. ok means that the items are packed to the left, have the same tag and increasing steps
+#[derive(Clone)]
+struct Node {
+    hash: FpVar<Fr>,
+    ok: Boolean<Fr>,
+    tag: FpVar<Fr>,
+    count: FpVar<Fr>,
+    min: FpVar<Fr>,
+    max: FpVar<Fr>,
+    cap: usize,
+}
+
+// leaf is (hash, step, is_item)
+fn leaf_node(cs: &ConstraintSystemRef<Fr>, bucket_bits: usize, leaf: &[FpVar<Fr>]) -> Node {
+    let bits = to_bits_n(cs, &leaf[1], 32);
+    Node {
+        hash: leaf[0].clone(),
+        ok: Boolean::TRUE,
+        tag: Boolean::le_bits_to_fp_var(&bits[bucket_bits..]).unwrap(),
+        count: leaf[2].clone(),
+        min: leaf[1].clone(),
+        max: leaf[1].clone(),
+        cap: 1,
+    }
+}
+
+fn join(cs: &ConstraintSystemRef<Fr>, params_g: &CRHParametersVar::<Fr>, l: &Node, r: &Node) -> Node {
+    let zero = FpVar::Constant(Fr::zero());
+    let r_empty = r.count.is_eq(&zero).unwrap();
+    let l_full = l.count.is_eq(&FpVar::Constant(Fr::from(l.cap as u64))).unwrap();
+    let same = l.tag.is_eq(&r.tag).unwrap();
+    let ordered = lt32(cs, &l.max, &r.min);
+    let merged = Boolean::kary_and(&[l.ok.clone(), r.ok.clone(), same, l_full, ordered]).unwrap();
+    Node {
+        hash: CRHGadget::<Fr>::evaluate(&params_g, &vec![l.hash.clone(), r.hash.clone()]).unwrap(),
+        ok: r_empty.select(&l.ok, &merged).unwrap(),
+        tag: l.tag.clone(),
+        count: &l.count + &r.count,
+        min: l.min.clone(),
+        max: r_empty.select(&l.max, &r.max).unwrap(),
+        cap: l.cap*2,
+    }
+}
+
+// all nodes of the tree, bottom level first
+fn node_tree(cs: &ConstraintSystemRef<Fr>, params_g: &CRHParametersVar::<Fr>, bucket_bits: usize, bottom: &[Vec<FpVar<Fr>>]) -> Vec<Node> {
+    let mut level: Vec<Node> = bottom.iter().map(|leaf| leaf_node(cs, bucket_bits, leaf)).collect();
+    let mut tree = level.clone();
+    while level.len() > 1 {
+        let mut next_level = vec![];
+        for i in 0..level.len()/2 {
+            next_level.push(join(cs, params_g, &level[2*i], &level[2*i+1]));
+        }
+        tree.extend(next_level.iter().cloned());
+        level = next_level;
+    }
+    tree
+}
+
+// zero sized buckets don't have a slice, they will get constant zero as root
 
-fn compute_buckets(
+fn compute_buckets<P: PermutationArgument>(
+    arg: &P,
     cs: ConstraintSystemRef<Fr>,
-    params: &PoseidonParameters<Fr>,
     params_g: &CRHParametersVar::<Fr>,
-    vars: Vec<FpVar<Fr>>,
-    trs: &Vec<Transition>,
+    leaves: Vec<FpVar<Fr>>,
+    steps: Vec<FpVar<Fr>>,
+    buckets: &Vec<Bucket>,
     bucket_size: usize,
-    num_buckets: usize,
 ) -> FpVar<Fr> {
-    let elems = trs.len();
-    let buckets = make_buckets(trs, bucket_size, num_buckets);
-    let buckets = make_bucket_slices(buckets);
-    let perm1 = route_bucket_contents(&buckets, elems);
-    // use permutation
-    let mut vars = vars.clone();
-    let zero_var = FpVar::Constant(Fr::from(0));
-    while vars.len() < elems*2-1 {
-        vars.push(zero_var.clone());
-    }
-    let tree_bottom = crate::permutation::permutation(cs.clone(), vars, perm1);
-    let tree_vars = hash_tree(&cs, &params, &params_g, &tree_bottom);
+    let elems = leaves.len();
+    let num_buckets = buckets.len();
+    let size = tree_size(elems);
+    let zero = FpVar::Constant(Fr::zero());
+    let one = FpVar::Constant(Fr::one());
+    let mut vars = vec![];
+    for (leaf, step) in leaves.iter().zip(steps.iter()) {
+        vars.push(vec![leaf.clone(), step.clone(), one.clone()]);
+    }
+    while vars.len() < size {
+        vars.push(vec![zero.clone(); 3]);
+    }
+    let perm1 = route_bucket_contents(buckets, size);
+    let tree_bottom = permute_list_with(arg, cs.clone(), vars, perm1).unwrap();
+    let tree = node_tree(&cs, params_g, bucket_size.trailing_zeros() as usize, &tree_bottom);
     // use second permutation
-    let perm2 = route_buckets(&buckets, elems);
-    let bucket_vars = crate::permutation::permutation(cs.clone(), tree_vars, perm2);
-    let bucket_tree_vars = hash_tree(&cs, &params, &params_g, &bucket_vars[0..num_buckets]);
+    let mut nodes = vec![];
+    for n in tree.iter() {
+        nodes.push(vec![n.hash.clone(), FpVar::from(n.ok.clone()), n.tag.clone(), n.count.clone(), FpVar::Constant(Fr::from(n.cap as u64))]);
+    }
+    for _i in 0..num_buckets {
+        nodes.push(vec![zero.clone(); 5]);
+    }
+    let perm2 = route_buckets(buckets, size);
+    let bucket_vars = permute_list_with(arg, cs.clone(), nodes, perm2).unwrap();
+    let mut total = zero.clone();
+    let mut roots = vec![];
+    for (i, b) in bucket_vars[0..num_buckets].iter().enumerate() {
+        let empty = b[3].is_eq(&zero).unwrap();
+        b[0].conditional_enforce_equal(&zero, &empty).unwrap();
+        b[1].conditional_enforce_equal(&one, &empty.not()).unwrap();
+        b[2].conditional_enforce_equal(&FpVar::Constant(Fr::from(i as u64)), &empty.not()).unwrap();
+        // capacity has to be the smallest power of two that fits the bucket
+        lt32(&cs, &b[4], &(&b[3] + &b[3])).conditional_enforce_equal(&Boolean::TRUE, &empty.not()).unwrap();
+        total += &b[3];
+        roots.push(b[0].clone());
+    }
+    // every item is in one of the buckets
+    total.enforce_equal(&FpVar::Constant(Fr::from(elems as u64))).unwrap();
+    while roots.len() < num_buckets.next_power_of_two() {
+        roots.push(zero.clone());
+    }
+    let bucket_tree_vars = hash_tree(&params_g, &roots);
     bucket_tree_vars.last().unwrap().clone()
 }
 
+fn tree_root(params: &PoseidonParameters<Fr>, leaves: &[Fr]) -> Fr {
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = (0..level.len()/2).map(|i| hash_pair(params, &level[2*i], &level[2*i+1])).collect();
+    }
+    level[0]
+}
+
+fn pad(mut v: Vec<Fr>, size: usize) -> Vec<Fr> {
+    while v.len() < size {
+        v.push(Fr::zero());
+    }
+    v
+}
+
+pub fn transition_leaf(params: &PoseidonParameters<Fr>, tr: &Transition) -> Fr {
+    let hash = hash_pair(params, &tr.before.hash(params), &tr.after.hash(params));
+    hash_pair(params, &hash, &Fr::from(tr.before.step_counter as u64))
+}
+
+// hash of the leaves in the given order
+pub fn list_hash(params: &PoseidonParameters<Fr>, trs: &[Transition]) -> Fr {
+    hash_list(params, &trs.iter().map(|tr| transition_leaf(params, tr)).collect::<Vec<Fr>>())
+}
+
+pub fn bucket_roots(params: &PoseidonParameters<Fr>, trs: &Vec<Transition>, bucket_size: usize, num_buckets: usize) -> Result<Vec<Fr>, SynthesisError> {
+    Ok(make_buckets(trs, bucket_size, num_buckets)?.iter().map(|b| {
+        if b.size() == 0 {
+            return Fr::zero();
+        }
+        let leaves = b.list.iter().map(|i| transition_leaf(params, &trs[*i])).collect();
+        tree_root(params, &pad(leaves, b.size().next_power_of_two()))
+    }).collect())
+}
+
+pub fn bucket_root(params: &PoseidonParameters<Fr>, trs: &Vec<Transition>, bucket_size: usize, num_buckets: usize) -> Result<Fr, SynthesisError> {
+    Ok(tree_root(params, &pad(bucket_roots(params, trs, bucket_size, num_buckets)?, num_buckets.next_power_of_two())))
+}
+
+fn alloc_leaves(cs: &ConstraintSystemRef<Fr>, params: &PoseidonParameters<Fr>, params_g: &CRHParametersVar::<Fr>, trs: &[Transition]) -> (Vec<FpVar<Fr>>, Vec<FpVar<Fr>>) {
+    let mut leaves = vec![];
+    let mut steps = vec![];
+    for tr in trs.iter() {
+        let hash = hash_pair(params, &tr.before.hash(params), &tr.after.hash(params));
+        let hash_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(hash)).unwrap());
+        let step_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(tr.before.step_counter as u64))).unwrap());
+        leaves.push(CRHGadget::<Fr>::evaluate(&params_g, &vec![hash_var, step_var.clone()]).unwrap());
+        steps.push(step_var);
+    }
+    (leaves, steps)
+}
+
+// Commits to an unordered list of transitions grouped by step range.
+// Public inputs are list, the hash_list of the leaves in the given order, and root.
+// A leaf is hash_pair(transition hash, step). The root of bucket i is the merkle root of the leaves with
+// i*bucket_size <= step < (i+1)*bucket_size sorted by step and padded with zeroes to the next power of two,
+// or zero for an empty bucket. root is the merkle root of the bucket roots padded to a power of two.
+// So root only depends on the set of transitions. The circuit is unsatisfied if two of them have the same
+// step, and generate_constraints returns Unsatisfiable if a step is outside of the buckets, bucket_size is
+// not a power of two or there are no transitions or buckets.
+#[derive(Debug, Clone)]
+pub struct BucketCircuit {
+    pub params: PoseidonParameters<Fr>,
+    pub trs: Vec<Transition>,
+    pub bucket_size: usize,
+    pub num_buckets: usize,
+}
+
+impl BucketCircuit {
+    pub fn new(params: &PoseidonParameters<Fr>, trs: Vec<Transition>, bucket_size: usize, num_buckets: usize) -> Self {
+        BucketCircuit {
+            params: params.clone(),
+            trs,
+            bucket_size,
+            num_buckets,
+        }
+    }
+
+    pub fn list(&self) -> Fr {
+        list_hash(&self.params, &self.trs)
+    }
+
+    pub fn root(&self) -> Result<Fr, SynthesisError> {
+        bucket_root(&self.params, &self.trs, self.bucket_size, self.num_buckets)
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for BucketCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        if !self.bucket_size.is_power_of_two() || self.trs.len() == 0 || self.num_buckets == 0 {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+        let buckets = make_buckets(&self.trs, self.bucket_size, self.num_buckets)?;
+        let root = self.root()?;
+        let list_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(self.list())).unwrap());
+        let root_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(root)).unwrap());
+        let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
+        let buckets = make_bucket_slices(buckets, tree_size(self.trs.len()));
+        let (leaves, steps) = alloc_leaves(&cs, &self.params, &params_g, &self.trs);
+        let mut acc = FpVar::Constant(Fr::zero());
+        for leaf in leaves.iter() {
+            acc = CRHGadget::<Fr>::evaluate(&params_g, &vec![leaf.clone(), acc]).unwrap();
+        }
+        acc.enforce_equal(&list_var).unwrap();
+        let root = compute_buckets(&WaksmanArgument, cs, &params_g, leaves, steps, &buckets, self.bucket_size);
+        root.enforce_equal(&root_var).unwrap();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use ark_relations::r1cs::ConstraintSystem;
+
+// straight line program, the transitions are grouped by instruction so they are not in step order
+#[cfg(test)]
+fn test_transitions(params: &PoseidonParameters<Fr>, n: u32) -> Vec<Transition> {
+    use crate::CodeTree::*;
+    let mut code = vec![];
+    for i in 0..n {
+        code.push(CConst(i));
+        code.push(CSetLocal(0));
+    }
+    code.push(CEnd);
+    let mut vm = crate::VM::new(code);
+    let mut c = crate::Collector {
+        add: vec![], sub: vec![], gt: vec![], get: vec![], set: vec![],
+        constant: vec![], loopi: vec![], endi: vec![], breakno: vec![], breakyes: vec![],
+    };
+    for _i in 0..2*n {
+        vm.step(params, &mut c);
+    }
+    crate::get_transitions(&c)
+}
+
+#[cfg(test)]
+fn check_circuit(circuit: BucketCircuit) -> bool {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    cs.is_satisfied().unwrap()
+}
+
+// run the bucket gadget with the given layout
+#[cfg(test)]
+fn check_layout(params: &PoseidonParameters<Fr>, trs: &Vec<Transition>, buckets: &Vec<Bucket>, bucket_size: usize, root: Fr) -> bool {
+    check_layout_with(&WaksmanArgument, params, trs, buckets, bucket_size, root)
+}
+
+#[cfg(test)]
+fn check_layout_with<P: PermutationArgument>(arg: &P, params: &PoseidonParameters<Fr>, trs: &Vec<Transition>, buckets: &Vec<Bucket>, bucket_size: usize, root: Fr) -> bool {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+    let (leaves, steps) = alloc_leaves(&cs, params, &params_g, trs);
+    let res = compute_buckets(arg, cs.clone(), &params_g, leaves, steps, buckets, bucket_size);
+    res.enforce_equal(&FpVar::Constant(root)).unwrap();
+    cs.is_satisfied().unwrap()
+}
+
+#[test]
+fn test_bucket_circuit() {
+    let params = crate::generate_hash();
+    let trs = test_transitions(&params, 5);
+    assert_eq!(trs.len(), 10);
+    for (bucket_size, num_buckets) in [(1, 10), (2, 5), (4, 3), (16, 1)] {
+        let circuit = BucketCircuit::new(&params, trs.clone(), bucket_size, num_buckets);
+        assert!(check_circuit(circuit.clone()));
+        // root does not depend on the order
+        let mut rev = trs.clone();
+        rev.reverse();
+        let other = BucketCircuit::new(&params, rev, bucket_size, num_buckets);
+        assert_eq!(other.root().unwrap(), circuit.root().unwrap());
+        assert_ne!(other.list(), circuit.list());
+        assert!(check_circuit(other));
+    }
+    // buckets of different sizes, steps 0 2 3 | 5 6 | 8 9
+    let some: Vec<Transition> = trs.iter().filter(|tr| tr.before.step_counter % 3 != 1).cloned().collect();
+    let roots = bucket_roots(&params, &some, 4, 4).unwrap();
+    assert!(roots[0..3].iter().all(|r| *r != Fr::zero()));
+    assert_eq!(roots[3], Fr::zero());
+    assert!(check_circuit(BucketCircuit::new(&params, some, 4, 4)));
+}
+
+#[test]
+fn test_bucket_binding() {
+    let params = crate::generate_hash();
+    let trs = test_transitions(&params, 5);
+    let root = bucket_root(&params, &trs, 2, 5).unwrap();
+    // same step twice
+    let mut dup = trs.clone();
+    let mut tr = trs[0].clone();
+    tr.after = trs[1].after.clone();
+    dup.push(tr);
+    assert!(!check_circuit(BucketCircuit::new(&params, dup, 2, 5)));
+    let good = make_bucket_slices(make_buckets(&trs, 2, 5).unwrap(), tree_size(trs.len()));
+    assert!(check_layout(&params, &trs, &good, 2, root));
+    assert!(!check_layout(&params, &trs, &good, 2, Fr::zero()));
+    // items of a bucket have to be sorted by step
+    let mut unsorted = good.clone();
+    unsorted[1].list.reverse();
+    assert!(!check_layout(&params, &trs, &unsorted, 2, root));
+    // same with the grand product argument
+    let gp = GrandProductArgument::new(&crate::hash::generate_params());
+    assert!(check_layout_with(&gp, &params, &trs, &good, 2, root));
+    assert!(!check_layout_with(&gp, &params, &trs, &unsorted, 2, root));
+    // bucket has to be at the right index
+    let mut swapped = good.clone();
+    swapped.swap(1, 2);
+    swapped[1].idx = 1;
+    swapped[2].idx = 2;
+    assert!(!check_layout(&params, &trs, &swapped, 2, root));
+    // bucket with one item cannot use a slice of two
+    let some: Vec<Transition> = trs.iter().filter(|tr| tr.before.step_counter != 1).cloned().collect();
+    let root = bucket_root(&params, &some, 2, 5).unwrap();
+    let good = make_bucket_slices(make_buckets(&some, 2, 5).unwrap(), tree_size(some.len()));
+    assert!(check_layout(&params, &some, &good, 2, root));
+    assert_eq!((good[0].slice_start, good[0].slice_size), (8, 1));
+    let mut wide = good.clone();
+    wide[0] = good[0].set_slice(8, 2);
+    assert!(!check_layout(&params, &some, &wide, 2, root));
+}
+
+#[test]
+fn test_bucket_layout_errors() {
+    let params = crate::generate_hash();
+    let trs = test_transitions(&params, 5);
+    let synthesize = |trs: Vec<Transition>, bucket_size, num_buckets| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        BucketCircuit::new(&params, trs, bucket_size, num_buckets).generate_constraints(ConstraintSystemRef::new(cs_sys))
+    };
+    // steps 6 to 9 are not in any bucket
+    assert!(matches!(synthesize(trs.clone(), 2, 3), Err(SynthesisError::Unsatisfiable)));
+    assert!(matches!(synthesize(trs.clone(), 3, 4), Err(SynthesisError::Unsatisfiable)));
+    assert!(matches!(synthesize(trs.clone(), 2, 0), Err(SynthesisError::Unsatisfiable)));
+    assert!(matches!(synthesize(vec![], 2, 5), Err(SynthesisError::Unsatisfiable)));
+    assert!(synthesize(trs, 2, 5).is_ok());
+}
@@ -0,0 +1,269 @@
+use ark_r1cs_std::alloc::AllocVar;
+use ark_r1cs_std::fields::fp::{AllocatedFp, FpVar};
+use ark_crypto_primitives::crh::poseidon::CRH;
+use ark_crypto_primitives::crh::poseidon::constraints::{CRHGadget, CRHParametersVar};
+use ark_mnt4_298::Fr;
+use ark_ff::Zero;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use ark_crypto_primitives::{CRHSchemeGadget, CRHScheme};
+use ark_r1cs_std::eq::EqGadget;
+use ark_r1cs_std::boolean::Boolean;
+use ark_sponge::poseidon::PoseidonParameters;
+
+use crate::{CodeTree, code_args, hash_code};
+use crate::hash::fr_to_u128;
+
+// Bytecode is a flat list of (op, immediate) words. Ops 1-9 are the tags of hash_code (see code_args),
+// a loop is 8, its body and then the close marker 10. 0 is padding and is skipped.
+pub const OP_PAD: u32 = 0;
+pub const OP_LOOP: u32 = 8;
+pub const OP_CLOSE: u32 = 10;
+
+pub fn flatten_code(code: &[CodeTree]) -> Vec<(u32, u32)> {
+    let mut res = vec![];
+    for op in code.iter() {
+        let args = code_args(op);
+        let tag = fr_to_u128(args[0]) as u32;
+        let imm = args.get(1).map(|x| fr_to_u128(*x) as u32).unwrap_or(0);
+        res.push((tag, imm));
+        if let CodeTree::CLoop(cont) = op {
+            res.extend(flatten_code(cont));
+            res.push((OP_CLOSE, 0));
+        }
+    }
+    res
+}
+
+// nesting depth of loops
+pub fn code_depth(code: &[CodeTree]) -> usize {
+    code.iter().map(|op| match op {
+        CodeTree::CLoop(cont) => code_depth(cont) + 1,
+        _ => 0,
+    }).max().unwrap_or(0)
+}
+
+// commitment to the bytecode, padding is skipped
+pub fn hash_bytecode(params: &PoseidonParameters<Fr>, words: &[(u32, u32)]) -> Fr {
+    let mut res = Fr::zero();
+    for (op, imm) in words.iter() {
+        if *op == OP_PAD {
+            continue;
+        }
+        let mut inputs = vec![];
+        inputs.push(Fr::from(*op));
+        inputs.push(Fr::from(*imm));
+        inputs.push(res);
+        res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
+    }
+    res
+}
+
+pub fn alloc_bytecode(cs: &ConstraintSystemRef<Fr>, words: &[(u32, u32)], max_len: usize) -> Vec<(FpVar<Fr>, FpVar<Fr>)> {
+    assert!(words.len() <= max_len, "bytecode is too long");
+    let mut res = vec![];
+    for i in 0..max_len {
+        let (op, imm) = if i < words.len() { words[i] } else { (OP_PAD, 0) };
+        let op_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(op))).unwrap());
+        let imm_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(imm))).unwrap());
+        res.push((op_var, imm_var));
+    }
+    res
+}
+
+fn dot(sel: &[Boolean<Fr>], vals: &[FpVar<Fr>]) -> FpVar<Fr> {
+    let mut res = FpVar::Constant(Fr::zero());
+    for (b, v) in sel.iter().zip(vals.iter()) {
+        res += FpVar::from(b.clone()) * v;
+    }
+    res
+}
+
+// Returns (hash_code of the program, hash_bytecode of the words).
+// The bytecode is read backwards like in hash_code, with a stack of max_depth+1 partial hashes.
+// Close marker opens a new frame, loop pops the body and hashes it into the frame below.
+pub fn hash_code_gadget(
+    params_g: &CRHParametersVar::<Fr>,
+    words: &[(FpVar<Fr>, FpVar<Fr>)],
+    max_depth: usize,
+) -> (FpVar<Fr>, FpVar<Fr>) {
+    let zero = FpVar::Constant(Fr::zero());
+    let mut stack = vec![zero.clone(); max_depth+1];
+    let mut sel = vec![Boolean::FALSE; max_depth+1];
+    sel[0] = Boolean::TRUE;
+    let mut acc = zero.clone();
+    for (op, imm) in words.iter().rev() {
+        let is_op: Vec<Boolean<Fr>> = (0..=OP_CLOSE).map(|k| op.is_eq(&FpVar::Constant(Fr::from(k))).unwrap()).collect();
+        let mut sum = zero.clone();
+        for b in is_op.iter() {
+            sum += FpVar::from(b.clone());
+        }
+        sum.enforce_equal(&FpVar::Constant(Fr::from(1u32))).unwrap();
+        let is_pad = is_op[OP_PAD as usize].clone();
+        let is_loop = is_op[OP_LOOP as usize].clone();
+        let is_close = is_op[OP_CLOSE as usize].clone();
+        let plain2 = Boolean::kary_or(&[is_op[1].clone(), is_op[2].clone(), is_op[3].clone(), is_op[9].clone()]).unwrap();
+        let plain3 = Boolean::kary_or(&[is_op[4].clone(), is_op[5].clone(), is_op[6].clone(), is_op[7].clone()]).unwrap();
+        // only ops 4-7 have an immediate
+        imm.conditional_enforce_equal(&zero, &plain3.not()).unwrap();
+        // no overflow or underflow
+        Boolean::and(&is_close, &sel[max_depth]).unwrap().enforce_equal(&Boolean::FALSE).unwrap();
+        Boolean::and(&is_loop, &sel[0]).unwrap().enforce_equal(&Boolean::FALSE).unwrap();
+
+        let top = dot(&sel, &stack);
+        let below = dot(&sel[1..], &stack[..max_depth]);
+        let h2 = CRHGadget::<Fr>::evaluate(&params_g, &vec![op.clone(), top.clone()]).unwrap();
+        let a = is_loop.select(&top, imm).unwrap();
+        let c = is_loop.select(&below, &top).unwrap();
+        let h3 = CRHGadget::<Fr>::evaluate(&params_g, &vec![op.clone(), a, c]).unwrap();
+        let v = plain2.select(&h2, &h3).unwrap();
+        let write_here = Boolean::or(&plain2, &plain3).unwrap();
+
+        let mut next_stack = vec![];
+        let mut next_sel = vec![];
+        for i in 0..=max_depth {
+            let up = if i > 0 { sel[i-1].clone() } else { Boolean::FALSE };
+            let down = if i < max_depth { sel[i+1].clone() } else { Boolean::FALSE };
+            let write = Boolean::or(&Boolean::and(&write_here, &sel[i]).unwrap(), &Boolean::and(&is_loop, &down).unwrap()).unwrap();
+            let clear = Boolean::and(&is_close, &up).unwrap();
+            let value = write.select(&v, &stack[i]).unwrap();
+            next_stack.push(clear.select(&zero, &value).unwrap());
+            let moved = is_loop.select(&down, &sel[i]).unwrap();
+            next_sel.push(is_close.select(&up, &moved).unwrap());
+        }
+        stack = next_stack;
+        sel = next_sel;
+
+        let h = CRHGadget::<Fr>::evaluate(&params_g, &vec![op.clone(), imm.clone(), acc.clone()]).unwrap();
+        acc = is_pad.select(&acc, &h).unwrap();
+    }
+    // all loops are closed
+    sel[0].enforce_equal(&Boolean::TRUE).unwrap();
+    (stack[0].clone(), acc)
+}
+
+// Proves that the program hash is hash_code of the program committed by the bytecode hash.
+// Public inputs are the program hash and hash_bytecode of the flattened code. The circuit only
+// depends on max_len and max_depth, so one key works for all programs that fit.
+#[derive(Debug, Clone)]
+pub struct CodeHashCircuit {
+    pub params: PoseidonParameters<Fr>,
+    pub code: Vec<CodeTree>,
+    pub max_len: usize,
+    pub max_depth: usize,
+}
+
+impl CodeHashCircuit {
+    pub fn new(params: &PoseidonParameters<Fr>, code: Vec<CodeTree>, max_len: usize, max_depth: usize) -> Result<Self, String> {
+        if flatten_code(&code).len() > max_len {
+            return Err("bytecode is too long".to_string());
+        }
+        if code_depth(&code) > max_depth {
+            return Err("loops are nested too deep".to_string());
+        }
+        Ok(CodeHashCircuit {
+            params: params.clone(),
+            code,
+            max_len,
+            max_depth,
+        })
+    }
+
+    pub fn get_inputs(&self) -> Vec<Fr> {
+        vec![hash_code(&self.params, &self.code), hash_bytecode(&self.params, &flatten_code(&self.code))]
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for CodeHashCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        let words = flatten_code(&self.code);
+        if words.len() > self.max_len {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+        let inputs = self.get_inputs();
+        let hash_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(inputs[0])).unwrap());
+        let bytecode_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(inputs[1])).unwrap());
+        let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
+        let words = alloc_bytecode(&cs, &words, self.max_len);
+        let (hash, bytecode) = hash_code_gadget(&params_g, &words, self.max_depth);
+        hash.enforce_equal(&hash_var).unwrap();
+        bytecode.enforce_equal(&bytecode_var).unwrap();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use ark_relations::r1cs::ConstraintSystem;
+#[cfg(test)]
+use ark_r1cs_std::R1CSVar;
+
+#[cfg(test)]
+fn run_gadget(params: &PoseidonParameters<Fr>, words: &[(u32, u32)], max_len: usize, max_depth: usize) -> (Fr, Fr, bool, usize) {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+    let vars = alloc_bytecode(&cs, words, max_len);
+    let (hash, bytecode) = hash_code_gadget(&params_g, &vars, max_depth);
+    (hash.value().unwrap(), bytecode.value().unwrap(), cs.is_satisfied().unwrap(), cs.num_constraints())
+}
+
+#[test]
+fn test_code_hash() {
+    use crate::CodeTree::*;
+    let params = crate::generate_hash();
+    let body = vec![CGetLocal(0), CConst(1), CAdd, CSetLocal(0), CGetLocal(0), CConst(10), CGt, CBreakIf(0), CEnd];
+    let programs = vec![
+        vec![],
+        vec![CConst(5), CSetLocal(1), CGetLocal(1), CSub, CEnd],
+        vec![CConst(0), CSetLocal(0), CLoop(body.clone()), CLoop(vec![]), CGetLocal(0), CEnd],
+        vec![CLoop(vec![CLoop(body.clone()), CBreakIf(1), CEnd]), CEnd],
+    ];
+    let mut counts = vec![];
+    for code in programs {
+        let words = flatten_code(&code);
+        let (hash, bytecode, ok, count) = run_gadget(&params, &words, 32, 2);
+        assert!(ok);
+        assert_eq!(hash, hash_code(&params, &code));
+        assert_eq!(bytecode, hash_bytecode(&params, &words));
+        counts.push(count);
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        CodeHashCircuit::new(&params, code, 32, 2).unwrap().generate_constraints(cs.clone()).unwrap();
+        assert!(cs.is_satisfied().unwrap());
+    }
+    // the circuit does not depend on the program
+    assert!(counts.iter().all(|c| *c == counts[0]));
+    // programs that don't fit
+    let code = vec![CLoop(vec![CLoop(vec![CEnd])])];
+    assert!(CodeHashCircuit::new(&params, code.clone(), 32, 1).is_err());
+    assert!(CodeHashCircuit::new(&params, code.clone(), 2, 2).is_err());
+    let circuit = CodeHashCircuit { params: params.clone(), code, max_len: 2, max_depth: 2 };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    assert!(matches!(circuit.generate_constraints(ConstraintSystemRef::new(cs_sys)), Err(SynthesisError::Unsatisfiable)));
+}
+
+#[test]
+fn test_code_hash_invalid() {
+    use crate::CodeTree::*;
+    let params = crate::generate_hash();
+    // loop without close marker
+    assert!(!run_gadget(&params, &[(OP_LOOP, 0), (9, 0)], 8, 2).2);
+    // close marker without loop
+    assert!(!run_gadget(&params, &[(OP_CLOSE, 0), (9, 0)], 8, 2).2);
+    // immediate that is not hashed
+    assert!(!run_gadget(&params, &[(1, 3), (9, 0)], 8, 2).2);
+    // unknown op
+    assert!(!run_gadget(&params, &[(11, 0)], 8, 2).2);
+    // too deep
+    let code = vec![CLoop(vec![CLoop(vec![CEnd])])];
+    assert!(run_gadget(&params, &flatten_code(&code), 8, 2).2);
+    assert!(!run_gadget(&params, &flatten_code(&code), 8, 1).2);
+    // padding in the middle is skipped
+    let words = flatten_code(&code);
+    let mut padded = words.clone();
+    padded.insert(2, (OP_PAD, 0));
+    let (hash, bytecode, ok, _) = run_gadget(&params, &padded, 8, 2);
+    assert!(ok);
+    assert_eq!(hash, hash_code(&params, &code));
+    assert_eq!(bytecode, hash_bytecode(&params, &words));
+}
@@ -0,0 +1,1318 @@
+use parity_wasm::elements::Instruction::*;
+use parity_wasm::elements::*;
+
+use crate::vm::{assemble, Program};
+
+/* Compiles Wasm to the register machine in vm.rs.
+
+   Values of the Wasm operand stack are kept in r0..r2 and spilled to memory when the registers run out,
+   r3 is used for addresses and constants. i32 values are kept zero extended.
+
+   Each function has a static frame in data memory with the locals followed by the spill slots of the
+   operand stack, so recursion is not supported. Calls pass the arguments by writing them to the locals
+   of the callee and the result is returned in r0. At every branch and label all values are spilled, so
+   the code at a label does not depend on where it was reached from.
+
+   Linear memory is in data memory from MEMORY_BASE with one byte per cell, so loads and stores of any
+   width and alignment are sequences of byte accesses. The size of the memory is its initial size and
+   memory.grow always fails. Out of bounds accesses, unreachable, division by zero and signed division
+   overflow go to a trap instruction, which cannot be proven.
+
+   br_table is a chain of compare and jump, so it takes a number of steps linear in the index. clz, ctz and
+   popcnt are loops over the bits of the value.
+
+   Limits, compile returns an error for modules that hit them:
+     - frames are static, so recursive calls are not supported
+     - a function has at most FRAME_SLOTS locals and operand stack values
+     - tables and call_indirect, imported functions and memories, floats, passive data segments and
+       blocks with parameters or several results are not supported
+     - memory is at most MEMORY_BASE bytes
+*/
+
+pub const GLOBALS_BASE : u64 = 0;
+pub const FRAMES_BASE : u64 = 1 << 20;
+pub const FRAME_SLOTS : u64 = 1 << 10;
+pub const MEMORY_BASE : u64 = 1 << 31;
+pub const PAGE_SIZE : u64 = 1 << 16;
+
+#[derive(Debug, Clone, PartialEq)]
+enum FrameKind {
+    Block,
+    Loop,
+    If,
+}
+
+#[derive(Debug, Clone)]
+struct Frame {
+    kind: FrameKind,
+    // branches go here
+    label: String,
+    end_label: String,
+    else_label: String,
+    has_else: bool,
+    height: usize,
+    arity: usize,
+}
+
+struct FuncCompiler<'a> {
+    out: &'a mut Vec<String>,
+    label_count: &'a mut usize,
+    base: u64,
+    num_locals: usize,
+    // None if the value is spilled to its slot
+    stack: Vec<Option<usize>>,
+    frames: Vec<Frame>,
+    dead: bool,
+    // linear memory in bytes
+    memory_size: u64,
+}
+
+fn block_arity(ty: &BlockType) -> Result<usize, String> {
+    match ty {
+        BlockType::NoResult => Ok(0),
+        BlockType::Value(_) => Ok(1),
+        #[allow(unreachable_patterns)]
+        _ => Err("block type not supported".to_string()),
+    }
+}
+
+fn function_type(module: &Module, idx: usize) -> FunctionType {
+    let type_ref = module.function_section().unwrap().entries()[idx].type_ref();
+    match &module.type_section().unwrap().types()[type_ref as usize] {
+        Type::Function(ft) => ft.clone(),
+    }
+}
+
+fn const_value(code: &[Instruction]) -> Result<u64, String> {
+    match code[0] {
+        I32Const(v) => Ok(v as u32 as u64),
+        I64Const(v) => Ok(v as u64),
+        _ => Err("initializer not supported".to_string()),
+    }
+}
+
+fn memory_size(module: &Module) -> Result<u64, String> {
+    if module.import_count(ImportCountType::Memory) > 0 {
+        return Err("imported memory not supported".to_string());
+    }
+    let pages = match module.memory_section() {
+        Some(section) => section.entries().first().map(|m| m.limits().initial() as u64).unwrap_or(0),
+        None => 0,
+    };
+    // addresses have to stay below the control stack
+    if pages * PAGE_SIZE > MEMORY_BASE {
+        return Err(format!("memory of {} pages is too large", pages));
+    }
+    Ok(pages * PAGE_SIZE)
+}
+
+impl<'a> FuncCompiler<'a> {
+    fn emit(&mut self, s: String) {
+        self.out.push(s)
+    }
+
+    fn new_label(&mut self) -> String {
+        *self.label_count += 1;
+        format!("L{}", self.label_count)
+    }
+
+    // compile_function checks that the slots fit in the frame
+    fn slot(&self, d: usize) -> u64 {
+        self.base + (self.num_locals + d) as u64
+    }
+
+    fn check_frame(&self) -> Result<(), String> {
+        if self.num_locals + self.stack.len() >= FRAME_SLOTS as usize {
+            return Err("function frame too large".to_string());
+        }
+        Ok(())
+    }
+
+    fn load_const(&mut self, r: usize, v: u64) {
+        let hi = v >> 32;
+        let lo = v & 0xffffffff;
+        if hi == 0 {
+            self.emit(format!("const r{}, {}", r, lo));
+            return;
+        }
+        self.emit(format!("const r{}, {}", r, hi));
+        self.emit("const r3, 32".to_string());
+        self.emit(format!("shl r{}, r{}, r3", r, r));
+        if lo != 0 {
+            self.emit(format!("const r3, {}", lo));
+            self.emit(format!("or r{}, r{}, r3", r, r));
+        }
+    }
+
+    fn load(&mut self, r: usize, addr: u64) {
+        self.emit(format!("const r3, {}", addr));
+        self.emit(format!("load r{}, r3", r));
+    }
+
+    fn store(&mut self, addr: u64, r: usize) {
+        self.emit(format!("const r3, {}", addr));
+        self.emit(format!("store r3, r{}", r));
+    }
+
+    fn spill(&mut self, d: usize) {
+        if let Some(r) = self.stack[d] {
+            let addr = self.slot(d);
+            self.store(addr, r);
+            self.stack[d] = None;
+        }
+    }
+
+    fn flush(&mut self) {
+        for d in 0..self.stack.len() {
+            self.spill(d);
+        }
+    }
+
+    // Finds a register, the top "keep" values are not spilled
+    fn free_reg(&mut self, keep: usize) -> Result<usize, String> {
+        for r in 0..3 {
+            if !self.stack.contains(&Some(r)) {
+                return Ok(r);
+            }
+        }
+        for d in 0..self.stack.len().saturating_sub(keep) {
+            if let Some(r) = self.stack[d] {
+                self.spill(d);
+                return Ok(r);
+            }
+        }
+        Err("no free registers".to_string())
+    }
+
+    fn top_in_reg(&mut self, k: usize) -> Result<(), String> {
+        let len = self.stack.len();
+        if len < k {
+            return Err("operand stack underflow".to_string());
+        }
+        for d in len - k..len {
+            if self.stack[d].is_none() {
+                let r = self.free_reg(k)?;
+                let addr = self.slot(d);
+                self.load(r, addr);
+                self.stack[d] = Some(r);
+            }
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<usize, String> {
+        self.top_in_reg(1)?;
+        Ok(self.stack.pop().unwrap().unwrap())
+    }
+
+    fn pop2(&mut self) -> Result<(usize, usize), String> {
+        self.top_in_reg(2)?;
+        let b = self.stack.pop().unwrap().unwrap();
+        let a = self.stack.pop().unwrap().unwrap();
+        Ok((a, b))
+    }
+
+    fn push_new(&mut self) -> Result<usize, String> {
+        let r = self.free_reg(0)?;
+        self.stack.push(Some(r));
+        Ok(r)
+    }
+
+    fn mask32(&mut self, r: usize) {
+        self.emit("const r3, 0xffffffff".to_string());
+        self.emit(format!("and r{}, r{}, r3", r, r));
+    }
+
+    fn sext32(&mut self, r: usize) {
+        self.emit("const r3, 32".to_string());
+        self.emit(format!("shl r{}, r{}, r3", r, r));
+        self.emit(format!("shr_s r{}, r{}, r3", r, r));
+    }
+
+    fn arith(&mut self, op: &str, is32: bool) -> Result<(), String> {
+        let (a, b) = self.pop2()?;
+        self.emit(format!("{} r{}, r{}, r{}", op, a, a, b));
+        if is32 && (op == "add" || op == "sub" || op == "mul") {
+            self.mask32(a);
+        }
+        self.stack.push(Some(a));
+        Ok(())
+    }
+
+    // Continues if r is not zero. The label is only reached from the jump, so nothing needs to be spilled.
+    fn trap_if_zero(&mut self, r: usize) {
+        let ok = self.new_label();
+        self.emit(format!("jnz r{}, {}", r, ok));
+        self.emit("trap".to_string());
+        self.emit(format!("{}:", ok));
+    }
+
+    // Division by zero and signed overflow trap
+    fn div(&mut self, op: &str, is32: bool, signed: bool) -> Result<(), String> {
+        // operands stay on the stack until the checks have their temporary register
+        self.top_in_reg(2)?;
+        let len = self.stack.len();
+        let (a, b) = (self.stack[len - 2].unwrap(), self.stack[len - 1].unwrap());
+        self.trap_if_zero(b);
+        if is32 && signed {
+            self.sext32(a);
+            self.sext32(b);
+        }
+        let overflow = op == "div_s";
+        let t = if overflow { self.free_reg(2)? } else { 0 };
+        if overflow && !is32 {
+            // traps if b is -1 and a is i64::MIN, the only value where a - 1 is not smaller
+            self.emit("const r3, 1".to_string());
+            self.emit("neg r3, r3".to_string());
+            self.emit(format!("xor r{}, r{}, r3", t, b));
+            self.emit("const r3, 1".to_string());
+            self.emit(format!("sub r3, r{}, r3", a));
+            self.emit(format!("lt_s r3, r3, r{}", a));
+            self.emit(format!("or r{}, r{}, r3", t, t));
+            self.trap_if_zero(t);
+        }
+        self.emit(format!("{} r{}, r{}, r{}", op, a, a, b));
+        if overflow && is32 {
+            // operands are sign extended, only i32::MIN / -1 gives 2^31
+            self.emit(format!("const r{}, 0x80000000", t));
+            self.emit(format!("xor r{}, r{}, r{}", t, a, t));
+            self.trap_if_zero(t);
+        }
+        if is32 {
+            self.mask32(a);
+        }
+        self.stack.truncate(len - 2);
+        self.stack.push(Some(a));
+        Ok(())
+    }
+
+    // r0 = MEMORY_BASE + r0 + offset, traps unless the w bytes from there are in linear memory
+    fn effective_address(&mut self, offset: u32, w: u64) {
+        if offset != 0 {
+            self.emit(format!("const r3, {}", offset));
+            self.emit("add r0, r0, r3".to_string());
+        }
+        let bound = (self.memory_size + 1).saturating_sub(w);
+        self.emit(format!("const r3, {}", bound));
+        self.emit("lt_u r2, r0, r3".to_string());
+        self.trap_if_zero(2);
+        self.emit(format!("const r3, {}", MEMORY_BASE));
+        self.emit("add r0, r0, r3".to_string());
+    }
+
+    // Loads w bytes, little endian
+    fn load_mem(&mut self, offset: u32, w: u64, signed: bool, is32: bool) {
+        // everything is spilled so that r0..r2 are free
+        self.flush();
+        let len = self.stack.len();
+        let addr = self.slot(len - 1);
+        self.load(0, addr);
+        self.stack.pop();
+        self.effective_address(offset, w);
+        self.emit("const r1, 0".to_string());
+        for i in (0..w).rev() {
+            if i != w - 1 {
+                self.emit("const r3, 8".to_string());
+                self.emit("shl r1, r1, r3".to_string());
+            }
+            self.emit(format!("const r3, {}", i));
+            self.emit("add r3, r0, r3".to_string());
+            self.emit("load r2, r3".to_string());
+            self.emit("or r1, r1, r2".to_string());
+        }
+        if signed && w < 8 {
+            self.emit(format!("const r3, {}", 64 - 8 * w));
+            self.emit("shl r1, r1, r3".to_string());
+            self.emit("shr_s r1, r1, r3".to_string());
+            if is32 {
+                self.mask32(1);
+            }
+        }
+        self.stack.push(Some(1));
+    }
+
+    // Stores the low w bytes, little endian
+    fn store_mem(&mut self, offset: u32, w: u64) {
+        self.flush();
+        let len = self.stack.len();
+        let value = self.slot(len - 1);
+        let addr = self.slot(len - 2);
+        self.load(1, value);
+        self.load(0, addr);
+        self.stack.truncate(len - 2);
+        self.effective_address(offset, w);
+        for i in 0..w {
+            self.emit("const r3, 255".to_string());
+            self.emit("and r2, r1, r3".to_string());
+            self.emit(format!("const r3, {}", i));
+            self.emit("add r3, r0, r3".to_string());
+            self.emit("store r3, r2".to_string());
+            self.emit("const r3, 8".to_string());
+            self.emit("shr_u r1, r1, r3".to_string());
+        }
+    }
+
+    fn shift(&mut self, op: &str, is32: bool) -> Result<(), String> {
+        let (a, b) = self.pop2()?;
+        if is32 {
+            self.emit("const r3, 31".to_string());
+            self.emit(format!("and r{}, r{}, r3", b, b));
+            if op == "shr_s" {
+                self.sext32(a);
+            }
+        }
+        self.emit(format!("{} r{}, r{}, r{}", op, a, a, b));
+        if is32 {
+            self.mask32(a);
+        }
+        self.stack.push(Some(a));
+        Ok(())
+    }
+
+    fn compare(&mut self, op: &str, is32: bool, swap: bool, negate: bool) -> Result<(), String> {
+        let (a, b) = self.pop2()?;
+        if is32 && op == "lt_s" {
+            self.sext32(a);
+            self.sext32(b);
+        }
+        if swap {
+            self.emit(format!("{} r{}, r{}, r{}", op, a, b, a));
+        } else {
+            self.emit(format!("{} r{}, r{}, r{}", op, a, a, b));
+        }
+        if negate {
+            self.emit("const r3, 1".to_string());
+            self.emit(format!("xor r{}, r{}, r3", a, a));
+        }
+        self.stack.push(Some(a));
+        Ok(())
+    }
+
+    // Shifts one way by the amount and the other way by the width minus the amount. For a zero amount the
+    // second shift gives 0 for i32 and the value itself for i64, either way the result is the value.
+    fn rotate(&mut self, left: bool, is32: bool) -> Result<(), String> {
+        let (a, b) = self.pop2()?;
+        let (there, back) = if left { ("shl", "shr_u") } else { ("shr_u", "shl") };
+        if is32 {
+            self.emit("const r3, 31".to_string());
+            self.emit(format!("and r{}, r{}, r3", b, b));
+            self.emit("const r3, 32".to_string());
+            self.emit(format!("sub r3, r3, r{}", b));
+        } else {
+            self.emit(format!("neg r3, r{}", b));
+        }
+        self.emit(format!("{} r3, r{}, r3", back, a));
+        self.emit(format!("{} r{}, r{}, r{}", there, a, a, b));
+        self.emit(format!("or r{}, r{}, r3", a, a));
+        if is32 {
+            self.mask32(a);
+        }
+        self.stack.push(Some(a));
+        Ok(())
+    }
+
+    // clz, ctz and popcnt shift the value out one bit at a time. Counting trailing zeros is counting
+    // the ones of (a & -a) - 1, which is all ones for zero.
+    fn count_bits(&mut self, op: &str, is32: bool) -> Result<(), String> {
+        self.top_in_reg(1)?;
+        let t = self.free_reg(1)?;
+        let a = self.pop()?;
+        if op == "ctz" {
+            self.emit(format!("neg r3, r{}", a));
+            self.emit(format!("and r{}, r{}, r3", a, a));
+            self.emit("const r3, 1".to_string());
+            self.emit(format!("sub r{}, r{}, r3", a, a));
+            if is32 {
+                self.mask32(a);
+            }
+        }
+        let width = if is32 { 32 } else { 64 };
+        self.emit(format!("const r{}, {}", t, if op == "clz" { width } else { 0 }));
+        let start = self.new_label();
+        let body = self.new_label();
+        let done = self.new_label();
+        self.emit(format!("{}:", start));
+        self.emit(format!("jnz r{}, {}", a, body));
+        self.emit(format!("jmp {}", done));
+        self.emit(format!("{}:", body));
+        self.emit("const r3, 1".to_string());
+        if op == "clz" {
+            // one less leading zero for each bit of the value
+            self.emit(format!("sub r{}, r{}, r3", t, t));
+        } else {
+            self.emit(format!("and r3, r{}, r3", a));
+            self.emit(format!("add r{}, r{}, r3", t, t));
+            self.emit("const r3, 1".to_string());
+        }
+        self.emit(format!("shr_u r{}, r{}, r3", a, a));
+        self.emit(format!("jmp {}", start));
+        self.emit(format!("{}:", done));
+        self.stack.push(Some(t));
+        Ok(())
+    }
+
+    // Value for the branch target goes to the slot at the height of the block
+    fn move_result(&mut self, frame: &Frame) {
+        let arity = if frame.kind == FrameKind::Loop { 0 } else { frame.arity };
+        if arity == 1 && self.stack.len() - 1 != frame.height {
+            let from = self.slot(self.stack.len() - 1);
+            let to = self.slot(frame.height);
+            self.load(0, from);
+            self.store(to, 0);
+        }
+    }
+
+    fn branch(&mut self, depth: u32) {
+        let frame = self.frames[self.frames.len() - 1 - depth as usize].clone();
+        self.flush();
+        self.move_result(&frame);
+        self.emit(format!("jmp {}", frame.label));
+    }
+
+    // Compare and jump for each entry of the table, indices out of range fall through to the default
+    fn branch_table(&mut self, table: &[u32], default: u32) -> Result<(), String> {
+        let mut c = self.pop()?;
+        self.flush();
+        // move_result uses r0
+        if c == 0 {
+            self.emit("const r3, 0".to_string());
+            self.emit("add r1, r0, r3".to_string());
+            c = 1;
+        }
+        for (i, depth) in table.iter().enumerate() {
+            let frame = self.frames[self.frames.len() - 1 - *depth as usize].clone();
+            let next = self.new_label();
+            self.emit(format!("const r3, {}", i));
+            self.emit(format!("sub r3, r{}, r3", c));
+            self.emit(format!("jnz r3, {}", next));
+            self.move_result(&frame);
+            self.emit(format!("jmp {}", frame.label));
+            self.emit(format!("{}:", next));
+        }
+        self.branch(default);
+        Ok(())
+    }
+
+    fn branch_if(&mut self, depth: u32) -> Result<(), String> {
+        let frame = self.frames[self.frames.len() - 1 - depth as usize].clone();
+        let c = self.pop()?;
+        self.flush();
+        let arity = if frame.kind == FrameKind::Loop { 0 } else { frame.arity };
+        if arity == 0 || self.stack.len() - 1 == frame.height {
+            self.emit(format!("jnz r{}, {}", c, frame.label));
+            return Ok(());
+        }
+        let taken = self.new_label();
+        let cont = self.new_label();
+        self.emit(format!("jnz r{}, {}", c, taken));
+        self.emit(format!("jmp {}", cont));
+        self.emit(format!("{}:", taken));
+        self.move_result(&frame);
+        self.emit(format!("jmp {}", frame.label));
+        self.emit(format!("{}:", cont));
+        Ok(())
+    }
+
+    // End of the code reachable in the frame, the values are left in the slots
+    fn finish_frame(&mut self, frame: &Frame) {
+        if !self.dead {
+            self.flush();
+            self.move_result(frame);
+        }
+        self.stack.truncate(frame.height);
+    }
+
+    fn start_frame(&mut self, kind: FrameKind, ty: &BlockType) -> Result<(), String> {
+        let start = self.new_label();
+        let end_label = self.new_label();
+        let else_label = self.new_label();
+        let label = if kind == FrameKind::Loop { start.clone() } else { end_label.clone() };
+        self.frames.push(Frame {
+            kind,
+            label,
+            end_label,
+            else_label,
+            has_else: false,
+            height: self.stack.len(),
+            arity: block_arity(ty)?,
+        });
+        self.emit(format!("{}:", start));
+        Ok(())
+    }
+
+    // Skips unreachable code until the end or else of the current frame
+    fn skip(&self, code: &[Instruction], mut i: usize) -> usize {
+        let mut depth = 0;
+        while i < code.len() {
+            match &code[i] {
+                Block(_) | Loop(_) | If(_) => depth += 1,
+                Else if depth == 0 => return i,
+                End if depth == 0 => return i,
+                End => depth -= 1,
+                _ => {}
+            }
+            i += 1;
+        }
+        i
+    }
+
+    fn call(&mut self, module: &Module, f: u32) {
+        let ft = function_type(module, f as usize);
+        let params = ft.params().len();
+        self.flush();
+        let len = self.stack.len();
+        for i in 0..params {
+            let from = self.slot(len - params + i);
+            self.load(0, from);
+            self.store(frame_base(f as usize) + i as u64, 0);
+        }
+        self.stack.truncate(len - params);
+        self.emit(format!("call f{}", f));
+        if ft.results().len() == 1 {
+            self.stack.push(Some(0));
+        }
+    }
+
+    fn global_addr(&self, idx: u32) -> u64 {
+        GLOBALS_BASE + idx as u64
+    }
+
+    fn instruction(&mut self, module: &Module, inst: &Instruction) -> Result<(), String> {
+        match inst {
+            Nop => {}
+            Unreachable => {
+                self.emit("trap".to_string());
+                self.dead = true;
+            }
+            Block(ty) => {
+                self.flush();
+                self.start_frame(FrameKind::Block, ty)?;
+            }
+            Loop(ty) => {
+                self.flush();
+                self.start_frame(FrameKind::Loop, ty)?;
+            }
+            If(ty) => {
+                let c = self.pop()?;
+                self.flush();
+                self.start_frame(FrameKind::If, ty)?;
+                let frame = self.frames[self.frames.len() - 1].clone();
+                let then_label = self.new_label();
+                self.emit(format!("jnz r{}, {}", c, then_label));
+                self.emit(format!("jmp {}", frame.else_label));
+                self.emit(format!("{}:", then_label));
+            }
+            Else => {
+                let frame = self.frames.pop().unwrap();
+                self.finish_frame(&frame);
+                if !self.dead {
+                    self.emit(format!("jmp {}", frame.end_label));
+                }
+                self.emit(format!("{}:", frame.else_label));
+                self.dead = false;
+                self.frames.push(Frame { has_else: true, ..frame });
+            }
+            End => {
+                let frame = self.frames.pop().unwrap();
+                self.finish_frame(&frame);
+                if frame.kind == FrameKind::If && !frame.has_else {
+                    self.emit(format!("{}:", frame.else_label));
+                }
+                self.emit(format!("{}:", frame.end_label));
+                self.dead = false;
+                for _i in 0..frame.arity {
+                    self.stack.push(None);
+                }
+            }
+            Br(d) => {
+                self.branch(*d);
+                self.dead = true;
+            }
+            BrIf(d) => self.branch_if(*d)?,
+            BrTable(data) => {
+                self.branch_table(&data.table, data.default)?;
+                self.dead = true;
+            }
+            Return => {
+                self.branch(self.frames.len() as u32 - 1);
+                self.dead = true;
+            }
+            Call(f) => self.call(module, *f),
+            Drop => {
+                self.stack.pop();
+            }
+            Select => {
+                self.top_in_reg(3)?;
+                let c = self.stack.pop().unwrap().unwrap();
+                let (a, b) = self.pop2()?;
+                // a + (b - a) * (c == 0)
+                self.emit("const r3, 0".to_string());
+                self.emit(format!("eq r{}, r{}, r3", c, c));
+                self.emit(format!("sub r{}, r{}, r{}", b, b, a));
+                self.emit(format!("mul r{}, r{}, r{}", b, b, c));
+                self.emit(format!("add r{}, r{}, r{}", a, a, b));
+                self.stack.push(Some(a));
+            }
+            GetLocal(x) => {
+                let r = self.push_new()?;
+                self.load(r, self.base + *x as u64);
+            }
+            SetLocal(x) => {
+                let r = self.pop()?;
+                self.store(self.base + *x as u64, r);
+            }
+            TeeLocal(x) => {
+                self.top_in_reg(1)?;
+                let r = self.stack[self.stack.len() - 1].unwrap();
+                self.store(self.base + *x as u64, r);
+            }
+            GetGlobal(x) => {
+                let r = self.push_new()?;
+                self.load(r, self.global_addr(*x));
+            }
+            SetGlobal(x) => {
+                let r = self.pop()?;
+                self.store(self.global_addr(*x), r);
+            }
+            I32Const(v) => {
+                let r = self.push_new()?;
+                self.load_const(r, *v as u32 as u64);
+            }
+            I64Const(v) => {
+                let r = self.push_new()?;
+                self.load_const(r, *v as u64);
+            }
+
+            I32Eqz | I64Eqz => {
+                let a = self.pop()?;
+                self.emit("const r3, 0".to_string());
+                self.emit(format!("eq r{}, r{}, r3", a, a));
+                self.stack.push(Some(a));
+            }
+            I32Eq => self.compare("eq", true, false, false)?,
+            I32Ne => self.compare("eq", true, false, true)?,
+            I32LtS => self.compare("lt_s", true, false, false)?,
+            I32LtU => self.compare("lt_u", true, false, false)?,
+            I32GtS => self.compare("lt_s", true, true, false)?,
+            I32GtU => self.compare("lt_u", true, true, false)?,
+            I32LeS => self.compare("lt_s", true, true, true)?,
+            I32LeU => self.compare("lt_u", true, true, true)?,
+            I32GeS => self.compare("lt_s", true, false, true)?,
+            I32GeU => self.compare("lt_u", true, false, true)?,
+            I64Eq => self.compare("eq", false, false, false)?,
+            I64Ne => self.compare("eq", false, false, true)?,
+            I64LtS => self.compare("lt_s", false, false, false)?,
+            I64LtU => self.compare("lt_u", false, false, false)?,
+            I64GtS => self.compare("lt_s", false, true, false)?,
+            I64GtU => self.compare("lt_u", false, true, false)?,
+            I64LeS => self.compare("lt_s", false, true, true)?,
+            I64LeU => self.compare("lt_u", false, true, true)?,
+            I64GeS => self.compare("lt_s", false, false, true)?,
+            I64GeU => self.compare("lt_u", false, false, true)?,
+
+            I32Add => self.arith("add", true)?,
+            I32Sub => self.arith("sub", true)?,
+            I32Mul => self.arith("mul", true)?,
+            I32And => self.arith("and", true)?,
+            I32Or => self.arith("or", true)?,
+            I32Xor => self.arith("xor", true)?,
+            I64Add => self.arith("add", false)?,
+            I64Sub => self.arith("sub", false)?,
+            I64Mul => self.arith("mul", false)?,
+            I64And => self.arith("and", false)?,
+            I64Or => self.arith("or", false)?,
+            I64Xor => self.arith("xor", false)?,
+
+            I32DivS => self.div("div_s", true, true)?,
+            I32DivU => self.div("div_u", true, false)?,
+            I32RemS => self.div("rem_s", true, true)?,
+            I32RemU => self.div("rem_u", true, false)?,
+            I64DivS => self.div("div_s", false, true)?,
+            I64DivU => self.div("div_u", false, false)?,
+            I64RemS => self.div("rem_s", false, true)?,
+            I64RemU => self.div("rem_u", false, false)?,
+
+            I32Shl => self.shift("shl", true)?,
+            I32ShrS => self.shift("shr_s", true)?,
+            I32ShrU => self.shift("shr_u", true)?,
+            I64Shl => self.shift("shl", false)?,
+            I64ShrS => self.shift("shr_s", false)?,
+            I64ShrU => self.shift("shr_u", false)?,
+            I32Rotl => self.rotate(true, true)?,
+            I32Rotr => self.rotate(false, true)?,
+            I64Rotl => self.rotate(true, false)?,
+            I64Rotr => self.rotate(false, false)?,
+
+            I32Clz => self.count_bits("clz", true)?,
+            I32Ctz => self.count_bits("ctz", true)?,
+            I32Popcnt => self.count_bits("popcnt", true)?,
+            I64Clz => self.count_bits("clz", false)?,
+            I64Ctz => self.count_bits("ctz", false)?,
+            I64Popcnt => self.count_bits("popcnt", false)?,
+
+            I32WrapI64 => {
+                let a = self.pop()?;
+                self.mask32(a);
+                self.stack.push(Some(a));
+            }
+            I64ExtendUI32 => {}
+            I64ExtendSI32 => {
+                let a = self.pop()?;
+                self.sext32(a);
+                self.stack.push(Some(a));
+            }
+
+            I32Load(_, off) => self.load_mem(*off, 4, false, true),
+            I64Load(_, off) => self.load_mem(*off, 8, false, false),
+            I32Load8S(_, off) => self.load_mem(*off, 1, true, true),
+            I32Load8U(_, off) => self.load_mem(*off, 1, false, true),
+            I32Load16S(_, off) => self.load_mem(*off, 2, true, true),
+            I32Load16U(_, off) => self.load_mem(*off, 2, false, true),
+            I64Load8S(_, off) => self.load_mem(*off, 1, true, false),
+            I64Load8U(_, off) => self.load_mem(*off, 1, false, false),
+            I64Load16S(_, off) => self.load_mem(*off, 2, true, false),
+            I64Load16U(_, off) => self.load_mem(*off, 2, false, false),
+            I64Load32S(_, off) => self.load_mem(*off, 4, true, false),
+            I64Load32U(_, off) => self.load_mem(*off, 4, false, false),
+            I32Store(_, off) => self.store_mem(*off, 4),
+            I64Store(_, off) => self.store_mem(*off, 8),
+            I32Store8(_, off) | I64Store8(_, off) => self.store_mem(*off, 1),
+            I32Store16(_, off) | I64Store16(_, off) => self.store_mem(*off, 2),
+            I64Store32(_, off) => self.store_mem(*off, 4),
+            CurrentMemory(_) => {
+                let r = self.push_new()?;
+                self.load_const(r, self.memory_size / PAGE_SIZE);
+            }
+            GrowMemory(_) => {
+                // growing is allowed to fail
+                let r = self.pop()?;
+                self.load_const(r, 0xffffffff);
+                self.stack.push(Some(r));
+            }
+            CallIndirect(_, _) => return Err("call_indirect not supported, tables are not compiled".to_string()),
+            _ => return Err(format!("instruction {} not supported", inst)),
+        }
+        Ok(())
+    }
+}
+
+fn frame_base(f: usize) -> u64 {
+    FRAMES_BASE + f as u64 * FRAME_SLOTS
+}
+
+fn compile_function(module: &Module, f: usize, out: &mut Vec<String>, label_count: &mut usize) -> Result<(), String> {
+    let ft = function_type(module, f);
+    let body = &module.code_section().unwrap().bodies()[f];
+    let num_params = ft.params().len();
+    let num_locals = num_params + body.locals().iter().map(|l| l.count() as usize).sum::<usize>();
+    let mut c = FuncCompiler {
+        out,
+        label_count,
+        base: frame_base(f),
+        num_locals,
+        stack: vec![],
+        frames: vec![],
+        dead: false,
+        memory_size: memory_size(module)?,
+    };
+    c.check_frame()?;
+    c.emit(format!("f{}:", f));
+    // locals start from zero
+    if num_locals > num_params {
+        c.emit("const r0, 0".to_string());
+        for i in num_params..num_locals {
+            c.store(c.base + i as u64, 0);
+        }
+    }
+    c.frames.push(Frame {
+        kind: FrameKind::Block,
+        label: format!("f{}_end", f),
+        end_label: format!("f{}_end", f),
+        else_label: format!("f{}_end", f),
+        has_else: false,
+        height: 0,
+        arity: ft.results().len(),
+    });
+    let code = body.code().elements();
+    let mut i = 0;
+    while i < code.len() {
+        if c.dead {
+            i = c.skip(code, i);
+            if i == code.len() {
+                break;
+            }
+        }
+        c.instruction(module, &code[i])?;
+        c.check_frame()?;
+        i += 1;
+    }
+    if ft.results().len() == 1 {
+        let addr = c.slot(0);
+        c.load(0, addr);
+    }
+    c.emit("ret".to_string());
+    Ok(())
+}
+
+// Functions in checked call no function recursively, each one is visited once
+fn check_recursion(module: &Module, f: usize, path: &mut Vec<usize>, checked: &mut Vec<bool>) -> Result<(), String> {
+    if checked[f] {
+        return Ok(());
+    }
+    if path.contains(&f) {
+        return Err(format!("recursive call to function {}", f));
+    }
+    path.push(f);
+    for inst in module.code_section().unwrap().bodies()[f].code().elements().iter() {
+        if let Call(g) = inst {
+            if *g as usize >= checked.len() {
+                return Err(format!("call to unknown function {}", g));
+            }
+            check_recursion(module, *g as usize, path, checked)?;
+        }
+    }
+    path.pop();
+    checked[f] = true;
+    Ok(())
+}
+
+// Assembly for calling the entry function, the result is left in r0
+pub fn compile_text(module: &Module, entry: usize) -> Result<String, String> {
+    if module.import_count(ImportCountType::Function) > 0 {
+        return Err("imported functions not supported".to_string());
+    }
+    if module.table_section().is_some() || module.import_count(ImportCountType::Table) > 0 {
+        return Err("tables not supported".to_string());
+    }
+    let num_funcs = module.code_section().map(|s| s.bodies().len()).unwrap_or(0);
+    if entry >= num_funcs {
+        return Err(format!("entry function {} does not exist", entry));
+    }
+    let mut checked = vec![false; num_funcs];
+    for f in 0..num_funcs {
+        check_recursion(module, f, &mut vec![], &mut checked)?;
+    }
+    let memory_size = memory_size(module)?;
+    let mut out = vec![];
+    {
+        let mut c = FuncCompiler {
+            out: &mut out,
+            label_count: &mut 0,
+            base: 0,
+            num_locals: 0,
+            stack: vec![],
+            frames: vec![],
+            dead: false,
+            memory_size,
+        };
+        if let Some(globals) = module.global_section() {
+            for (i, g) in globals.entries().iter().enumerate() {
+                c.load_const(0, const_value(g.init_expr().code())?);
+                c.store(GLOBALS_BASE + i as u64, 0);
+            }
+        }
+        // data segments are written byte by byte, memory starts as zeros
+        if let Some(data) = module.data_section() {
+            for segment in data.entries().iter() {
+                let offset = match segment.offset() {
+                    Some(expr) => const_value(expr.code())?,
+                    None => return Err("passive data segments not supported".to_string()),
+                };
+                if offset + segment.value().len() as u64 > memory_size {
+                    return Err("data segment out of bounds".to_string());
+                }
+                for (i, byte) in segment.value().iter().enumerate() {
+                    if *byte != 0 {
+                        c.emit(format!("const r0, {}", byte));
+                        c.store(MEMORY_BASE + offset + i as u64, 0);
+                    }
+                }
+            }
+        }
+    }
+    out.push(format!("call f{}", entry));
+    out.push("jmp __end".to_string());
+    let mut label_count = 0;
+    for f in 0..num_funcs {
+        compile_function(module, f, &mut out, &mut label_count)?;
+    }
+    out.push("__end:".to_string());
+    Ok(out.join("\n"))
+}
+
+pub fn compile(module: &Module, entry: usize) -> Result<Program, String> {
+    assemble(&compile_text(module, entry)?)
+}
+
+#[cfg(test)]
+fn make_module(funcs: Vec<(Vec<ValueType>, Vec<ValueType>, u32, Vec<Instruction>)>) -> Module {
+    let mut types = vec![];
+    let mut entries = vec![];
+    let mut bodies = vec![];
+    for (i, (params, results, locals, code)) in funcs.into_iter().enumerate() {
+        types.push(Type::Function(FunctionType::new(params, results)));
+        entries.push(Func::new(i as u32));
+        bodies.push(FuncBody::new(vec![Local::new(locals, ValueType::I64)], Instructions::new(code)));
+    }
+    Module::new(vec![
+        Section::Type(TypeSection::with_types(types)),
+        Section::Function(FunctionSection::with_entries(entries)),
+        Section::Code(CodeSection::with_bodies(bodies)),
+    ])
+}
+
+// Adds mutable i32 globals with constant initializers
+#[cfg(test)]
+fn with_globals(module: Module, values: Vec<i32>) -> Module {
+    let mut module = module;
+    let entries = values.into_iter().map(|v| {
+        GlobalEntry::new(GlobalType::new(ValueType::I32, true), InitExpr::new(vec![I32Const(v), End]))
+    }).collect();
+    // global section goes before the code section
+    module.sections_mut().insert(2, Section::Global(GlobalSection::with_entries(entries)));
+    module
+}
+
+// Adds a memory of the given number of pages and data segments
+#[cfg(test)]
+fn with_memory(module: Module, pages: u32, data: Vec<(i32, Vec<u8>)>) -> Module {
+    let mut module = module;
+    let pos = module.sections().iter().position(|s| matches!(s, Section::Global(_) | Section::Code(_))).unwrap();
+    module.sections_mut().insert(pos, Section::Memory(MemorySection::with_entries(vec![MemoryType::new(pages, None)])));
+    let entries = data.into_iter().map(|(offset, bytes)| {
+        DataSegment::new(0, Some(InitExpr::new(vec![I32Const(offset), End])), bytes)
+    }).collect();
+    module.sections_mut().push(Section::Data(DataSection::with_entries(entries)));
+    module
+}
+
+#[cfg(test)]
+fn run_module(module: &Module) -> u64 {
+    use crate::vm::Interpreter;
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let mut interp = Interpreter::new(&params, compile(module, 0).unwrap());
+    interp.run();
+    assert!(!interp.trapped);
+    assert_eq!(interp.control.len(), 0);
+    interp.registers[0]
+}
+
+#[cfg(test)]
+fn traps(module: &Module) -> bool {
+    use crate::vm::Interpreter;
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let mut interp = Interpreter::new(&params, compile(module, 0).unwrap());
+    interp.run();
+    interp.trapped
+}
+
+#[test]
+fn test_compile_loop_and_call() {
+    use ValueType::*;
+    // sum of 1..n with a loop, called from the entry function
+    let main = vec![I32Const(10), Call(1), I32Const(1), I32Add, End];
+    let sum = vec![
+        Block(BlockType::NoResult),
+        Loop(BlockType::NoResult),
+        GetLocal(0), I32Eqz, BrIf(1),
+        GetLocal(1), GetLocal(0), I32Add, SetLocal(1),
+        GetLocal(0), I32Const(1), I32Sub, SetLocal(0),
+        Br(0),
+        End,
+        End,
+        GetLocal(1),
+        End,
+    ];
+    let module = make_module(vec![
+        (vec![], vec![I32], 0, main),
+        (vec![I32], vec![I32], 1, sum),
+    ]);
+    assert_eq!(run_module(&module), 56);
+}
+
+#[test]
+fn test_compile_semantics() {
+    use ValueType::*;
+    let cases : Vec<(Vec<Instruction>, u64)> = vec![
+        (vec![I32Const(-1), I32Const(1), I32Add], 0),
+        (vec![I32Const(-7), I32Const(2), I32DivS], (-3i32) as u32 as u64),
+        (vec![I32Const(-7), I32Const(2), I32RemS], (-1i32) as u32 as u64),
+        (vec![I32Const(-8), I32Const(33), I32ShrS], (-4i32) as u32 as u64),
+        (vec![I32Const(-1), I32Const(1), I32LtS], 1),
+        (vec![I32Const(-1), I32Const(1), I32GtU], 1),
+        (vec![I32Const(5), I32Const(5), I32GeS], 1),
+        (vec![I64Const(-1), I64Const(1 << 40), I64Add], (1u64 << 40) - 1),
+        (vec![I32Const(-2), I64ExtendSI32], (-2i64) as u64),
+        (vec![I64Const(0x1234_5678_9abc), I32WrapI64], 0x5678_9abc),
+        (vec![I32Const(3), I32Const(4), I32Const(0), Select], 4),
+        (vec![I32Const(3), I32Const(4), I32Const(1), Select], 3),
+        (vec![I32Const(i32::MIN), I32Const(33), I32Rotl], 1),
+        (vec![I32Const(1), I32Const(1), I32Rotr], 0x80000000),
+        (vec![I32Const(-2), I32Const(0), I32Rotl], 0xfffffffe),
+        (vec![I64Const(1), I64Const(1), I64Rotr], 1 << 63),
+        (vec![I64Const(0x1234), I64Const(64), I64Rotl], 0x1234),
+        (vec![I32Const(1), I32Clz], 31),
+        (vec![I32Const(0), I32Clz], 32),
+        (vec![I64Const(1 << 40), I64Clz], 23),
+        (vec![I32Const(8), I32Ctz], 3),
+        (vec![I32Const(0), I32Ctz], 32),
+        (vec![I64Const(0), I64Ctz], 64),
+        (vec![I32Const(-1), I32Popcnt], 32),
+        (vec![I64Const(0x1234), I64Popcnt], 5),
+        // more values than registers
+        (vec![I32Const(1), I32Const(2), I32Const(3), I32Const(4), I32Const(5), I32Add, I32Add, I32Add, I32Add], 15),
+        (vec![I32Const(1), I32Const(2), I32Const(3), I32Const(4), I32Const(5), I32Sub, I32Sub, I32Sub, I32Sub], 3),
+    ];
+    for (code, expected) in cases {
+        let mut code = code.clone();
+        code.push(End);
+        let module = make_module(vec![(vec![], vec![I64], 0, code)]);
+        assert_eq!(run_module(&module), expected);
+    }
+}
+
+#[test]
+fn test_compile_blocks() {
+    use ValueType::*;
+    // if with results and branches out of blocks with values
+    let code = vec![
+        I32Const(100),
+        Block(BlockType::Value(I32)),
+        I32Const(9),
+        I32Const(1),
+        If(BlockType::Value(I32)),
+        I32Const(2),
+        Else,
+        I32Const(3),
+        End,
+        I32Const(1),
+        BrIf(0),
+        Drop,
+        Drop,
+        I32Const(7),
+        End,
+        I32Add,
+        End,
+    ];
+    // the branch leaves 2 from the if as the result of the block
+    let module = make_module(vec![(vec![], vec![I32], 0, code)]);
+    assert_eq!(run_module(&module), 102);
+
+    let code = vec![
+        I32Const(0),
+        If(BlockType::NoResult),
+        I32Const(1),
+        SetGlobal(0),
+        End,
+        GetGlobal(0),
+        I32Const(5),
+        I32Add,
+        Return,
+        Unreachable,
+        End,
+    ];
+    // the if is not taken, so the global keeps its initial value
+    let module = with_globals(make_module(vec![(vec![], vec![I32], 0, code)]), vec![4]);
+    assert!(module.global_section().is_some());
+    assert_eq!(run_module(&module), 9);
+}
+
+#[test]
+fn test_compile_br_table() {
+    use ValueType::*;
+    let table = |table: Vec<u32>, default: u32| BrTable(Box::new(BrTableData { table: table.into_boxed_slice(), default }));
+    for (idx, expected) in vec![(0, 10), (1, 20), (2, 30), (9, 30)] {
+        let code = vec![
+            Block(BlockType::NoResult),
+            Block(BlockType::NoResult),
+            Block(BlockType::NoResult),
+            I32Const(idx),
+            table(vec![0, 1], 2),
+            End,
+            I32Const(10),
+            Return,
+            End,
+            I32Const(20),
+            Return,
+            End,
+            I32Const(30),
+            End,
+        ];
+        let module = make_module(vec![(vec![], vec![I32], 0, code)]);
+        assert_eq!(run_module(&module), expected);
+    }
+    // the branch value is moved to the target block, the index ends up in r0
+    for (idx, expected) in vec![(0, 11), (1, 1), (9, 11)] {
+        let code = vec![
+            Block(BlockType::Value(I32)),
+            Block(BlockType::Value(I32)),
+            I32Const(0),
+            I32Const(7),
+            I32Const(1),
+            I32Const(idx),
+            table(vec![0, 1], 0),
+            End,
+            I32Const(10),
+            I32Add,
+            End,
+            End,
+        ];
+        let module = make_module(vec![(vec![], vec![I32], 0, code)]);
+        assert_eq!(run_module(&module), expected);
+    }
+}
+
+#[test]
+fn test_compile_circuit() {
+    use ValueType::*;
+    use crate::vm::{RegisterVMCircuit, Interpreter};
+    use crate::hash::generate_params;
+    use ark_mnt4_298::Fr;
+    use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem, ConstraintSystemRef};
+    // loop, call and globals, the compiled program is proven by the register machine circuit
+    let main = vec![I32Const(3), Call(1), GetGlobal(0), I32Add, End];
+    let sum = vec![
+        Block(BlockType::NoResult),
+        Loop(BlockType::NoResult),
+        GetLocal(0), I32Eqz, BrIf(1),
+        GetLocal(1), GetLocal(0), I32Add, SetLocal(1),
+        GetLocal(0), I32Const(1), I32Sub, SetLocal(0),
+        Br(0),
+        End,
+        End,
+        GetLocal(1),
+        End,
+    ];
+    let module = with_globals(make_module(vec![
+        (vec![], vec![I32], 0, main),
+        (vec![I32], vec![I32], 1, sum),
+    ]), vec![10]);
+    let params = generate_params();
+    let program = compile(&module, 0).unwrap();
+    let num_steps = Interpreter::new(&params, program.clone()).run().len();
+    let labels = program.labels.len();
+    let circuit = RegisterVMCircuit::new(&params, program, num_steps.next_power_of_two(), labels);
+    assert_eq!(circuit.end.registers[0], 16);
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    assert!(cs.is_satisfied().unwrap());
+}
+
+#[test]
+fn test_compile_traps() {
+    use ValueType::*;
+    use crate::vm::{RegisterVMCircuit, Interpreter};
+    use crate::hash::generate_params;
+    use ark_mnt4_298::Fr;
+    use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem, ConstraintSystemRef};
+    let cases : Vec<(Vec<Instruction>, bool)> = vec![
+        (vec![I32Const(1), Unreachable], true),
+        (vec![I32Const(7), I32Const(0), I32DivU], true),
+        (vec![I32Const(7), I32Const(0), I32RemS], true),
+        (vec![I64Const(7), I64Const(0), I64DivS], true),
+        (vec![I32Const(i32::MIN), I32Const(-1), I32DivS], true),
+        (vec![I64Const(i64::MIN), I64Const(-1), I64DivS], true),
+        // the remainder is defined
+        (vec![I32Const(i32::MIN), I32Const(-1), I32RemS], false),
+        (vec![I64Const(i64::MIN + 1), I64Const(-1), I64DivS], false),
+        (vec![I32Const(i32::MIN), I32Const(1), I32DivS], false),
+    ];
+    for (code, trapped) in cases {
+        let mut code = code.clone();
+        code.push(End);
+        let module = make_module(vec![(vec![], vec![I64], 0, code)]);
+        assert_eq!(traps(&module), trapped);
+    }
+
+    // a program that reaches unreachable cannot be proven
+    let module = make_module(vec![(vec![], vec![I32], 0, vec![I32Const(1), Unreachable, End])]);
+    let params = generate_params();
+    let program = compile(&module, 0).unwrap();
+    let num_steps = Interpreter::new(&params, program.clone()).run().len();
+    let labels = program.labels.len();
+    let circuit = RegisterVMCircuit::new(&params, program, num_steps.next_power_of_two(), labels);
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    assert!(!cs.is_satisfied().unwrap());
+}
+
+#[test]
+fn test_compile_memory() {
+    use ValueType::*;
+    let cases : Vec<(Vec<Instruction>, u64)> = vec![
+        (vec![I32Const(8), I64Const(0x1122334455667788), I64Store(3, 0), I32Const(8), I64Load(3, 0)], 0x1122334455667788),
+        (vec![I32Const(8), I64Const(0x1122334455667788), I64Store(3, 0), I32Const(10), I32Load16U(1, 0)], 0x5566),
+        // unaligned with an offset
+        (vec![I32Const(1), I32Const(-2), I32Store(2, 4), I32Const(3), I32Load(2, 2)], 0xfffffffe),
+        (vec![I32Const(5), I32Const(0x1ff), I32Store8(0, 0), I32Const(5), I64Load8S(0, 0)], (-1i64) as u64),
+        (vec![I32Const(5), I64Const(-1), I64Store32(2, 0), I32Const(5), I64Load32U(2, 0)], 0xffffffff),
+        // data segment
+        (vec![I32Const(16), I32Load8S(0, 0)], 0xffffffff),
+        (vec![I32Const(16), I32Load16U(1, 0)], 0x01ff),
+        (vec![I32Const(17), I64Load16S(1, 0)], 0x2a01),
+        (vec![I32Const(100), I64Load(3, 0)], 0),
+        (vec![CurrentMemory(0)], 1),
+        (vec![I32Const(1), GrowMemory(0)], 0xffffffff),
+        // last bytes of the memory
+        (vec![I32Const(65532), I32Const(7), I32Store(2, 0), I32Const(65532), I32Load(2, 0)], 7),
+    ];
+    for (code, expected) in cases {
+        let mut code = code.clone();
+        code.push(End);
+        let module = with_memory(make_module(vec![(vec![], vec![I64], 0, code)]), 1, vec![(16, vec![0xff, 0x01, 0x2a])]);
+        assert_eq!(run_module(&module), expected);
+    }
+
+    // out of bounds accesses trap
+    for code in vec![
+        vec![I32Const(65533), I32Load(2, 0)],
+        vec![I32Const(65532), I32Load(2, 1)],
+        vec![I32Const(-1), I32Const(1), I32Store8(0, 0), I32Const(0)],
+    ] {
+        let mut code = code.clone();
+        code.push(End);
+        let module = with_memory(make_module(vec![(vec![], vec![I32], 0, code)]), 1, vec![]);
+        assert!(traps(&module));
+    }
+}
+
+#[test]
+fn test_compile_errors() {
+    use ValueType::*;
+    let module = make_module(vec![(vec![], vec![I32], 0, vec![I32Const(0), CallIndirect(0, 0), End])]);
+    assert!(compile(&module, 0).is_err());
+    // recursion
+    let module = make_module(vec![(vec![], vec![], 0, vec![Call(0), End])]);
+    assert!(compile(&module, 0).is_err());
+    let module = make_module(vec![(vec![], vec![F32], 0, vec![F32Const(0), End])]);
+    assert!(compile(&module, 0).is_err());
+    // no such entry function, or callee
+    let module = make_module(vec![(vec![], vec![], 0, vec![End])]);
+    assert!(compile(&module, 1).is_err());
+    let module = make_module(vec![(vec![], vec![], 0, vec![Call(3), End])]);
+    assert!(compile(&module, 0).is_err());
+    // locals, and then values on the stack, that don't fit in the frame
+    let module = make_module(vec![(vec![], vec![], FRAME_SLOTS as u32, vec![End])]);
+    assert!(compile(&module, 0).is_err());
+    let mut code = vec![I32Const(1); FRAME_SLOTS as usize];
+    code.push(End);
+    let module = make_module(vec![(vec![], vec![], 0, code)]);
+    assert!(compile(&module, 0).is_err());
+    // operands missing from the stack
+    let module = make_module(vec![(vec![], vec![I32], 0, vec![I32Const(1), I32Add, End])]);
+    assert!(compile(&module, 0).is_err());
+}
+
+#[test]
+fn test_compile_call_chain() {
+    // every function calls the next one twice, without memoising the check visits 2^40 calls
+    let funcs = (0..40).map(|i| {
+        let code = if i < 39 { vec![Call(i + 1), Call(i + 1), End] } else { vec![End] };
+        (vec![], vec![], 0, code)
+    }).collect();
+    assert!(compile(&make_module(funcs), 0).is_ok());
+}
@@ -40,13 +40,11 @@ impl ConstraintSynthesizer<Fr> for EndCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         use crate::ControlFrame::LoopFrame;
         let before = self.before.clone();
         let after = self.after.clone();
 
-        println!("before {:?}", before);
-        println!("after {:?}", after);
-
         let LoopFrame(cont, start) = before.control_stack.last().unwrap().clone();
 
         let cont_hash = hash_code(&self.params, &cont);
@@ -102,12 +100,10 @@ impl ConstraintSynthesizer<Fr> for EndCircuit {
             control_after_var.clone(),
         ]).unwrap();
 
-        println!("pc before hash {}", hash_code(&self.params, &before.pc));
         // println!("pc before hash {}", hash_pc_before_var.value().unwrap());
 
         // println!("pc other hash {}", hash_pc_other_var.value().unwrap());
 
-        println!("pc after hash {}", hash_code(&self.params, &after.pc));
         // println!("pc after hash {}", cont_var.value().unwrap());
         
         // Compute VM hash before
@@ -132,8 +128,6 @@ impl ConstraintSynthesizer<Fr> for EndCircuit {
         let hash_transition_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_transition).unwrap();
         hash_transition_gadget.enforce_equal(&public_var)?;
     
-        println!("Made circuit");
-        println!("before {}, after {}", before.hash(&self.params), after.hash(&self.params));
         // println!("before {}, after {}", hash_vm_before_gadget.value().unwrap(), hash_vm_after_gadget.value().unwrap());
 
         Ok(())
@@ -0,0 +1,1033 @@
+// IEEE-754 binary32/binary64 arithmetic (enabled with the "float" feature)
+//
+// Native functions work on raw bit patterns and are the reference for the gadgets,
+// each gadget produces exactly the same bits. Rounding is always round to nearest even
+// and every NaN result is the canonical quiet NaN, so that execution is deterministic.
+
+use ark_r1cs_std::alloc::AllocVar;
+use ark_r1cs_std::{
+    fields::fp::{AllocatedFp, FpVar},
+};
+use ark_mnt4_298::Fr;
+use crate::hash::fr_to_u128;
+use ark_relations::r1cs::ConstraintSystemRef;
+use ark_relations::r1cs::ConstraintSystem;
+use ark_r1cs_std::eq::EqGadget;
+use ark_r1cs_std::boolean::{AllocatedBool,Boolean};
+use ark_r1cs_std::fields::FieldVar;
+use ark_r1cs_std::R1CSVar;
+use ark_ff::{Field, PrimeField};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloatFormat {
+    pub exp_bits: usize,
+    pub frac_bits: usize,
+}
+
+pub const F32: FloatFormat = FloatFormat { exp_bits: 8, frac_bits: 23 };
+pub const F64: FloatFormat = FloatFormat { exp_bits: 11, frac_bits: 52 };
+
+impl FloatFormat {
+    pub fn width(&self) -> usize {
+        1 + self.exp_bits + self.frac_bits
+    }
+    // significand bits including the implicit one
+    fn precision(&self) -> usize {
+        self.frac_bits + 1
+    }
+    fn bias(&self) -> i64 {
+        (1i64 << (self.exp_bits - 1)) - 1
+    }
+    fn max_exp(&self) -> u64 {
+        (1u64 << self.exp_bits) - 1
+    }
+    // exponent of the lowest bit of a subnormal number
+    fn emin(&self) -> i64 {
+        1 - self.bias() - self.frac_bits as i64
+    }
+    fn sign_bit(&self) -> u64 {
+        1u64 << (self.width() - 1)
+    }
+    pub fn canonical_nan(&self) -> u64 {
+        (self.max_exp() << self.frac_bits) | (1u64 << (self.frac_bits - 1))
+    }
+    pub fn infinity(&self, sign: bool) -> u64 {
+        (self.max_exp() << self.frac_bits) | if sign { self.sign_bit() } else { 0 }
+    }
+    pub fn zero(&self, sign: bool) -> u64 {
+        if sign { self.sign_bit() } else { 0 }
+    }
+}
+
+//// Native reference
+
+#[derive(Debug, Clone, Copy)]
+struct Unpacked {
+    sign: bool,
+    exp: u64,
+    frac: u64,
+}
+
+fn unpack(fmt: FloatFormat, a: u64) -> Unpacked {
+    Unpacked {
+        sign: (a >> (fmt.width() - 1)) & 1 == 1,
+        exp: (a >> fmt.frac_bits) & fmt.max_exp(),
+        frac: a & ((1u64 << fmt.frac_bits) - 1),
+    }
+}
+
+impl Unpacked {
+    fn is_nan(&self, fmt: FloatFormat) -> bool {
+        self.exp == fmt.max_exp() && self.frac != 0
+    }
+    fn is_inf(&self, fmt: FloatFormat) -> bool {
+        self.exp == fmt.max_exp() && self.frac == 0
+    }
+    fn is_zero(&self) -> bool {
+        self.exp == 0 && self.frac == 0
+    }
+    // value is sig * 2^e
+    fn sig(&self, fmt: FloatFormat) -> u128 {
+        if self.exp == 0 {
+            self.frac as u128
+        } else {
+            (self.frac | (1u64 << fmt.frac_bits)) as u128
+        }
+    }
+    fn e(&self, fmt: FloatFormat) -> i64 {
+        std::cmp::max(self.exp as i64, 1) - fmt.bias() - fmt.frac_bits as i64
+    }
+    fn mag(&self, fmt: FloatFormat) -> u64 {
+        (self.exp << fmt.frac_bits) | self.frac
+    }
+}
+
+fn bit_len(a: u128) -> usize {
+    (128 - a.leading_zeros()) as usize
+}
+
+// shift right, bits shifted out are or-ed into the lowest bit
+fn shift_right_jam(a: u128, n: u64) -> u128 {
+    if n == 0 {
+        a
+    } else if n >= 128 {
+        (a != 0) as u128
+    } else {
+        (a >> n) | ((a & ((1u128 << n) - 1)) != 0) as u128
+    }
+}
+
+// move the top bit of a nonzero significand to position precision-1
+fn normalize(fmt: FloatFormat, sig: u128, e: i64) -> (u128, i64) {
+    let lz = fmt.precision() - bit_len(sig);
+    (sig << lz, e - lz as i64)
+}
+
+fn isqrt(a: u128) -> u128 {
+    let mut a = a;
+    let mut res = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > a {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if a >= res + bit {
+            a -= res + bit;
+            res = (res >> 1) + bit;
+        } else {
+            res >>= 1;
+        }
+        bit >>= 2;
+    }
+    res
+}
+
+// Rounds sig * 2^e to the format. The lowest bit of sig may be a sticky bit.
+fn round(fmt: FloatFormat, sign: bool, sig: u128, e: i64) -> u64 {
+    if sig == 0 {
+        return fmt.zero(sign);
+    }
+    let p = fmt.precision() as i64;
+    let e_lsb = std::cmp::max(e + bit_len(sig) as i64 - p, fmt.emin());
+    let rs = e_lsb - e;
+    let m = if rs <= 0 {
+        sig << (-rs)
+    } else {
+        // keep guard bit and sticky bit
+        let t = if rs == 1 { sig << 1 } else { shift_right_jam(sig, (rs - 2) as u64) };
+        let guard = (t >> 1) & 1 == 1;
+        let sticky = t & 1 == 1;
+        let m = t >> 2;
+        if guard && (sticky || m & 1 == 1) { m + 1 } else { m }
+    };
+    // a carry out of the significand moves into the exponent field
+    let packed = (((e_lsb - fmt.emin()) as u128) << fmt.frac_bits) + m;
+    let max = (fmt.max_exp() as u128) << fmt.frac_bits;
+    if packed >= max {
+        fmt.infinity(sign)
+    } else {
+        fmt.zero(sign) | packed as u64
+    }
+}
+
+pub fn add(fmt: FloatFormat, a: u64, b: u64) -> u64 {
+    let ua = unpack(fmt, a);
+    let ub = unpack(fmt, b);
+    if ua.is_nan(fmt) || ub.is_nan(fmt) || (ua.is_inf(fmt) && ub.is_inf(fmt) && ua.sign != ub.sign) {
+        return fmt.canonical_nan();
+    }
+    if ua.is_inf(fmt) {
+        return fmt.infinity(ua.sign);
+    }
+    if ub.is_inf(fmt) {
+        return fmt.infinity(ub.sign);
+    }
+    let (big, small) = if ua.e(fmt) < ub.e(fmt) { (ub, ua) } else { (ua, ub) };
+    // three extra bits are enough for correct rounding when the smaller operand is jammed
+    let x = big.sig(fmt) << 3;
+    let y = shift_right_jam(small.sig(fmt) << 3, (big.e(fmt) - small.e(fmt)) as u64);
+    let e = big.e(fmt) - 3;
+    let (sign, mag) = if big.sign == small.sign {
+        (big.sign, x + y)
+    } else if x < y {
+        (small.sign, y - x)
+    } else {
+        (big.sign, x - y)
+    };
+    if mag == 0 {
+        return fmt.zero(ua.sign && ub.sign);
+    }
+    round(fmt, sign, mag, e)
+}
+
+pub fn sub(fmt: FloatFormat, a: u64, b: u64) -> u64 {
+    add(fmt, a, b ^ fmt.sign_bit())
+}
+
+pub fn mul(fmt: FloatFormat, a: u64, b: u64) -> u64 {
+    let ua = unpack(fmt, a);
+    let ub = unpack(fmt, b);
+    if ua.is_nan(fmt) || ub.is_nan(fmt) || (ua.is_inf(fmt) && ub.is_zero()) || (ua.is_zero() && ub.is_inf(fmt)) {
+        return fmt.canonical_nan();
+    }
+    let sign = ua.sign != ub.sign;
+    if ua.is_inf(fmt) || ub.is_inf(fmt) {
+        return fmt.infinity(sign);
+    }
+    round(fmt, sign, ua.sig(fmt) * ub.sig(fmt), ua.e(fmt) + ub.e(fmt))
+}
+
+pub fn div(fmt: FloatFormat, a: u64, b: u64) -> u64 {
+    let ua = unpack(fmt, a);
+    let ub = unpack(fmt, b);
+    if ua.is_nan(fmt) || ub.is_nan(fmt) || (ua.is_inf(fmt) && ub.is_inf(fmt)) || (ua.is_zero() && ub.is_zero()) {
+        return fmt.canonical_nan();
+    }
+    let sign = ua.sign != ub.sign;
+    if ua.is_inf(fmt) || ub.is_zero() {
+        return fmt.infinity(sign);
+    }
+    if ua.is_zero() || ub.is_inf(fmt) {
+        return fmt.zero(sign);
+    }
+    let (sa, ea) = normalize(fmt, ua.sig(fmt), ua.e(fmt));
+    let (sb, eb) = normalize(fmt, ub.sig(fmt), ub.e(fmt));
+    let k = fmt.precision() + 2;
+    let n = sa << k;
+    let q = n / sb;
+    let r = n % sb;
+    round(fmt, sign, (q << 1) | (r != 0) as u128, ea - eb - k as i64 - 1)
+}
+
+pub fn sqrt(fmt: FloatFormat, a: u64) -> u64 {
+    let ua = unpack(fmt, a);
+    if ua.is_nan(fmt) || (ua.sign && !ua.is_zero()) {
+        return fmt.canonical_nan();
+    }
+    if ua.is_zero() || ua.is_inf(fmt) {
+        return a;
+    }
+    let (s, e) = normalize(fmt, ua.sig(fmt), ua.e(fmt));
+    // make the exponent even and leave enough bits for the root
+    let s0 = fmt.precision() as i64 + 5;
+    let shift = s0 + (e - s0).rem_euclid(2);
+    let x = s << shift;
+    let q = isqrt(x);
+    let rem = x - q*q;
+    round(fmt, false, (q << 1) | (rem != 0) as u128, (e - shift)/2 - 1)
+}
+
+pub fn eq(fmt: FloatFormat, a: u64, b: u64) -> bool {
+    let ua = unpack(fmt, a);
+    let ub = unpack(fmt, b);
+    if ua.is_nan(fmt) || ub.is_nan(fmt) {
+        return false;
+    }
+    (ua.is_zero() && ub.is_zero()) || a == b
+}
+
+pub fn ne(fmt: FloatFormat, a: u64, b: u64) -> bool {
+    !eq(fmt, a, b)
+}
+
+pub fn lt(fmt: FloatFormat, a: u64, b: u64) -> bool {
+    let ua = unpack(fmt, a);
+    let ub = unpack(fmt, b);
+    if ua.is_nan(fmt) || ub.is_nan(fmt) || (ua.is_zero() && ub.is_zero()) {
+        return false;
+    }
+    match (ua.sign, ub.sign) {
+        (false, false) => ua.mag(fmt) < ub.mag(fmt),
+        (true, true) => ub.mag(fmt) < ua.mag(fmt),
+        (true, false) => true,
+        (false, true) => false,
+    }
+}
+
+pub fn le(fmt: FloatFormat, a: u64, b: u64) -> bool {
+    lt(fmt, a, b) || eq(fmt, a, b)
+}
+
+pub fn gt(fmt: FloatFormat, a: u64, b: u64) -> bool {
+    lt(fmt, b, a)
+}
+
+pub fn ge(fmt: FloatFormat, a: u64, b: u64) -> bool {
+    le(fmt, b, a)
+}
+
+// Wasm semantics: NaN if either is NaN, -0 is smaller than +0
+pub fn min(fmt: FloatFormat, a: u64, b: u64) -> u64 {
+    let ua = unpack(fmt, a);
+    let ub = unpack(fmt, b);
+    if ua.is_nan(fmt) || ub.is_nan(fmt) {
+        return fmt.canonical_nan();
+    }
+    if lt(fmt, b, a) || (ua.is_zero() && ub.is_zero() && ub.sign) { b } else { a }
+}
+
+pub fn max(fmt: FloatFormat, a: u64, b: u64) -> u64 {
+    let ua = unpack(fmt, a);
+    let ub = unpack(fmt, b);
+    if ua.is_nan(fmt) || ub.is_nan(fmt) {
+        return fmt.canonical_nan();
+    }
+    if lt(fmt, a, b) || (ua.is_zero() && ub.is_zero() && !ub.sign) { b } else { a }
+}
+
+// f32.promote_f64 and f64.demote_f32
+pub fn convert(from: FloatFormat, to: FloatFormat, a: u64) -> u64 {
+    let ua = unpack(from, a);
+    if ua.is_nan(from) {
+        return to.canonical_nan();
+    }
+    if ua.is_inf(from) {
+        return to.infinity(ua.sign);
+    }
+    round(to, ua.sign, ua.sig(from), ua.e(from))
+}
+
+// convert an n-bit integer
+pub fn from_int(fmt: FloatFormat, x: u64, n: usize, signed: bool) -> u64 {
+    let x = (x as u128) & ((1u128 << n) - 1);
+    let sign = signed && (x >> (n - 1)) & 1 == 1;
+    let mag = if sign { (1u128 << n) - x } else { x };
+    round(fmt, sign, mag, 0)
+}
+
+// truncation to an n-bit integer, None when Wasm would trap
+pub fn trunc(fmt: FloatFormat, a: u64, n: usize, signed: bool) -> Option<u64> {
+    let ua = unpack(fmt, a);
+    if ua.is_nan(fmt) || ua.is_inf(fmt) {
+        return None;
+    }
+    let sig = ua.sig(fmt);
+    let e = ua.e(fmt);
+    let t = if e >= 0 {
+        if e > n as i64 {
+            if sig == 0 { 0 } else { return None }
+        } else {
+            sig << e
+        }
+    } else if -e >= 128 {
+        0
+    } else {
+        sig >> (-e)
+    };
+    let in_range = match (signed, ua.sign) {
+        (true, false) => t < 1u128 << (n - 1),
+        (true, true) => t <= 1u128 << (n - 1),
+        (false, false) => t < 1u128 << n,
+        (false, true) => t == 0,
+    };
+    if !in_range {
+        return None;
+    }
+    let res = if ua.sign && t != 0 { (1u128 << n) - t } else { t };
+    Some(res as u64)
+}
+
+//// Gadgets
+
+// Exponents are handled as field elements with an offset so that they stay positive
+const EOFF: u64 = 1 << 14;
+const EBITS: usize = 16;
+
+fn fr_i64(a: i64) -> Fr {
+    if a < 0 { -Fr::from((-a) as u64) } else { Fr::from(a as u64) }
+}
+
+fn constant(a: u128) -> FpVar<Fr> {
+    FpVar::constant(Fr::from(a))
+}
+
+fn pow2(n: usize) -> FpVar<Fr> {
+    FpVar::constant(Fr::from(2u32).pow(&[n as u64]))
+}
+
+fn num_bits(a: usize) -> usize {
+    bit_len(a as u128)
+}
+
+// Witness the n lowest bits of v and check that they give v, so v < 2^n
+pub fn to_bits_n(cs: &ConstraintSystemRef<Fr>, v: &FpVar<Fr>, n: usize) -> Vec<Boolean<Fr>> {
+    let mut bits = vec![];
+    for i in 0..n {
+        let v = v.clone();
+        let bool_var = AllocatedBool::<Fr>::new_witness(cs.clone(), || {
+            let a = fr_to_u128(v.value()?);
+            Ok((a >> i) & 1 == 1)
+        }).unwrap();
+        bits.push(Boolean::from(bool_var));
+    }
+    Boolean::le_bits_to_fp_var(&bits).unwrap().enforce_equal(v).unwrap();
+    bits
+}
+
+// a < b, both have to be smaller than 2^n
+fn is_less(cs: &ConstraintSystemRef<Fr>, a: &FpVar<Fr>, b: &FpVar<Fr>, n: usize) -> Boolean<Fr> {
+    let x = a.clone() + constant(1u128 << n) - b.clone();
+    let bits = to_bits_n(cs, &x, n+1);
+    bits[n].not()
+}
+
+// 2^k where k is given as little endian bits
+fn pow2_var(bits: &[Boolean<Fr>]) -> FpVar<Fr> {
+    let mut acc = FpVar::constant(Fr::from(1u32));
+    for (i, b) in bits.iter().enumerate() {
+        let factor = b.select(&pow2(1 << i), &FpVar::constant(Fr::from(1u32))).unwrap();
+        acc = acc * factor;
+    }
+    acc
+}
+
+// Barrel shifter, with jam the bits shifted out are or-ed into the lowest bit
+fn shift_right_gadget(bits: &[Boolean<Fr>], amount: &[Boolean<Fr>], jam: bool) -> Vec<Boolean<Fr>> {
+    let mut res = bits.to_vec();
+    for (i, s) in amount.iter().enumerate() {
+        let k = 1usize << i;
+        let lost = Boolean::kary_or(&res[0..std::cmp::min(k, res.len())]).unwrap();
+        let mut next = vec![];
+        for j in 0..res.len() {
+            let mut shifted = if j + k < res.len() { res[j+k].clone() } else { Boolean::FALSE };
+            if j == 0 && jam {
+                shifted = shifted.or(&lost).unwrap();
+            }
+            next.push(s.select(&shifted, &res[j]).unwrap());
+        }
+        res = next;
+    }
+    res
+}
+
+// Shift sig < 2^w left so that bit w-1 is set. Returns the bits, shift amount and zero flag.
+fn normalize_gadget(cs: &ConstraintSystemRef<Fr>, sig: &FpVar<Fr>, w: usize) -> (Vec<Boolean<Fr>>, FpVar<Fr>, Boolean<Fr>) {
+    let mut lz_bits = vec![];
+    for i in 0..num_bits(w) {
+        let sig = sig.clone();
+        let bool_var = AllocatedBool::<Fr>::new_witness(cs.clone(), || {
+            let a = fr_to_u128(sig.value()?);
+            let lz = if a == 0 { 0 } else { w - bit_len(a) };
+            Ok((lz >> i) & 1 == 1)
+        }).unwrap();
+        lz_bits.push(Boolean::from(bool_var));
+    }
+    let shifted = sig.clone() * pow2_var(&lz_bits);
+    let bits = to_bits_n(cs, &shifted, w);
+    let is_zero = sig.is_eq(&FpVar::constant(Fr::from(0u32))).unwrap();
+    bits[w-1].or(&is_zero).unwrap().enforce_equal(&Boolean::TRUE).unwrap();
+    (bits, Boolean::le_bits_to_fp_var(&lz_bits).unwrap(), is_zero)
+}
+
+// Same as the native round: sig < 2^w, w >= precision + 2, ebar is the offset exponent
+fn round_gadget(
+    cs: &ConstraintSystemRef<Fr>,
+    fmt: FloatFormat,
+    sign: &Boolean<Fr>,
+    sig: &FpVar<Fr>,
+    w: usize,
+    ebar: &FpVar<Fr>,
+) -> Vec<Boolean<Fr>> {
+    let p = fmt.precision();
+    assert!(w >= p + 2);
+    let (bits, lz, is_zero) = normalize_gadget(cs, sig, w);
+    let ebar_n = ebar.clone() - lz;
+
+    let emin_var = FpVar::constant(fr_i64(fmt.emin() + EOFF as i64));
+    let e_top = ebar_n.clone() + constant((w - p) as u128);
+    let below = is_less(cs, &e_top, &emin_var, EBITS);
+    let e_lsb = below.select(&emin_var, &e_top).unwrap();
+
+    // shift keeping the guard bit and a sticky bit, the amount is at least w-p-2
+    let amount = e_lsb.clone() - ebar_n - constant(2);
+    let too_big = is_less(cs, &constant(w as u128), &amount, EBITS);
+    let amount = too_big.select(&constant(w as u128), &amount).unwrap();
+    let amount_bits = to_bits_n(cs, &amount, num_bits(w));
+    let t = shift_right_gadget(&bits, &amount_bits, true);
+
+    let sticky = t[0].clone();
+    let guard = t[1].clone();
+    let round_up = guard.and(&sticky.or(&t[2]).unwrap()).unwrap();
+    let m = Boolean::le_bits_to_fp_var(&t[2..]).unwrap() + FpVar::from(round_up);
+
+    let packed = (e_lsb - emin_var) * pow2(fmt.frac_bits) + m;
+    let max = constant((fmt.max_exp() as u128) << fmt.frac_bits);
+    let overflow = is_less(cs, &packed, &max, fmt.frac_bits + EBITS + 2).not();
+    let mag = overflow.select(&max, &packed).unwrap();
+    let mag = is_zero.select(&FpVar::constant(Fr::from(0u32)), &mag).unwrap();
+
+    let res = FpVar::from(sign.clone()) * pow2(fmt.width() - 1) + mag;
+    to_bits_n(cs, &res, fmt.width())
+}
+
+#[derive(Debug, Clone)]
+pub struct FloatVar {
+    pub fmt: FloatFormat,
+    pub bits: Vec<Boolean<Fr>>,
+}
+
+#[derive(Debug, Clone)]
+struct UnpackedVar {
+    sign: Boolean<Fr>,
+    is_nan: Boolean<Fr>,
+    is_inf: Boolean<Fr>,
+    is_zero: Boolean<Fr>,
+    sig: FpVar<Fr>,
+    ebar: FpVar<Fr>,
+    mag: FpVar<Fr>,
+}
+
+impl FloatVar {
+    pub fn new_witness(cs: &ConstraintSystemRef<Fr>, fmt: FloatFormat, a: u64) -> Self {
+        let mut bits = vec![];
+        for i in 0..fmt.width() {
+            let b = (a >> i) & 1 == 1;
+            bits.push(Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(b)).unwrap()));
+        }
+        FloatVar { fmt, bits }
+    }
+    pub fn constant(fmt: FloatFormat, a: u64) -> Self {
+        let bits = (0..fmt.width()).map(|i| Boolean::constant((a >> i) & 1 == 1)).collect();
+        FloatVar { fmt, bits }
+    }
+    // takes a value from the machine, it must fit the format
+    pub fn from_fp(cs: &ConstraintSystemRef<Fr>, fmt: FloatFormat, v: &FpVar<Fr>) -> Self {
+        FloatVar { fmt, bits: to_bits_n(cs, v, fmt.width()) }
+    }
+    pub fn to_fp(&self) -> FpVar<Fr> {
+        Boolean::le_bits_to_fp_var(&self.bits).unwrap()
+    }
+    pub fn value(&self) -> u64 {
+        let mut res = 0u64;
+        for (i, b) in self.bits.iter().enumerate() {
+            if b.value().unwrap() {
+                res |= 1u64 << i;
+            }
+        }
+        res
+    }
+    fn neg(&self) -> Self {
+        let mut bits = self.bits.clone();
+        let w = bits.len();
+        bits[w-1] = bits[w-1].not();
+        FloatVar { fmt: self.fmt, bits }
+    }
+    fn unpack(&self) -> UnpackedVar {
+        let fmt = self.fmt;
+        let fb = fmt.frac_bits;
+        let w = fmt.width();
+        let frac_bits = &self.bits[0..fb];
+        let exp_bits = &self.bits[fb..w-1];
+        let exp_zero = Boolean::kary_or(exp_bits).unwrap().not();
+        let exp_max = Boolean::kary_and(exp_bits).unwrap();
+        let frac_zero = Boolean::kary_or(frac_bits).unwrap().not();
+        let frac = Boolean::le_bits_to_fp_var(frac_bits).unwrap();
+        let exp = Boolean::le_bits_to_fp_var(exp_bits).unwrap();
+        let sig = frac + FpVar::from(exp_zero.not()) * pow2(fb);
+        let ebar = exp + FpVar::from(exp_zero.clone()) + FpVar::constant(fr_i64(EOFF as i64 - fmt.bias() - fb as i64));
+        UnpackedVar {
+            sign: self.bits[w-1].clone(),
+            is_nan: exp_max.and(&frac_zero.not()).unwrap(),
+            is_inf: exp_max.and(&frac_zero).unwrap(),
+            is_zero: exp_zero.and(&frac_zero).unwrap(),
+            sig,
+            ebar,
+            mag: Boolean::le_bits_to_fp_var(&self.bits[0..w-1]).unwrap(),
+        }
+    }
+}
+
+fn select_bits(cond: &Boolean<Fr>, a: &[Boolean<Fr>], b: &[Boolean<Fr>]) -> Vec<Boolean<Fr>> {
+    a.iter().zip(b.iter()).map(|(x, y)| cond.select(x, y).unwrap()).collect()
+}
+
+// NaN and infinity override the rounded result
+fn finish(fmt: FloatFormat, nan: &Boolean<Fr>, inf: &Boolean<Fr>, inf_sign: &Boolean<Fr>, rounded: Vec<Boolean<Fr>>) -> FloatVar {
+    let w = fmt.width();
+    let mut inf_bits = FloatVar::constant(fmt, fmt.infinity(false)).bits;
+    inf_bits[w-1] = inf_sign.clone();
+    let nan_bits = FloatVar::constant(fmt, fmt.canonical_nan()).bits;
+    let bits = select_bits(inf, &inf_bits, &rounded);
+    let bits = select_bits(nan, &nan_bits, &bits);
+    FloatVar { fmt, bits }
+}
+
+pub fn add_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> FloatVar {
+    let fmt = a.fmt;
+    let p = fmt.precision();
+    let ua = a.unpack();
+    let ub = b.unpack();
+    let opposite = ua.sign.xor(&ub.sign).unwrap();
+    let nan = Boolean::kary_or(&[
+        ua.is_nan.clone(),
+        ub.is_nan.clone(),
+        Boolean::kary_and(&[ua.is_inf.clone(), ub.is_inf.clone(), opposite.clone()]).unwrap(),
+    ]).unwrap();
+    let inf = ua.is_inf.or(&ub.is_inf).unwrap();
+    let inf_sign = ua.is_inf.select(&ua.sign, &ub.sign).unwrap();
+
+    let swap = is_less(cs, &ua.ebar, &ub.ebar, EBITS);
+    let big_sig = swap.select(&ub.sig, &ua.sig).unwrap();
+    let big_e = swap.select(&ub.ebar, &ua.ebar).unwrap();
+    let big_sign = swap.select(&ub.sign, &ua.sign).unwrap();
+    let small_sig = swap.select(&ua.sig, &ub.sig).unwrap();
+    let small_e = swap.select(&ua.ebar, &ub.ebar).unwrap();
+
+    let x = big_sig * constant(8);
+    let d = big_e.clone() - small_e;
+    let d_big = is_less(cs, &constant((p + 3) as u128), &d, EBITS);
+    let d = d_big.select(&constant((p + 3) as u128), &d).unwrap();
+    let d_bits = to_bits_n(cs, &d, num_bits(p + 3));
+    let small_bits = to_bits_n(cs, &(small_sig * constant(8)), p + 3);
+    let y = Boolean::le_bits_to_fp_var(&shift_right_gadget(&small_bits, &d_bits, true)).unwrap();
+
+    let neg = is_less(cs, &x, &y, p + 3).and(&opposite).unwrap();
+    let diff = neg.select(&(y.clone() - x.clone()), &(x.clone() - y.clone())).unwrap();
+    let mag = opposite.select(&diff, &(x + y)).unwrap();
+    let sign = big_sign.xor(&neg).unwrap();
+    let exact_zero = mag.is_eq(&FpVar::constant(Fr::from(0u32))).unwrap();
+    let sign = exact_zero.select(&ua.sign.and(&ub.sign).unwrap(), &sign).unwrap();
+
+    let rounded = round_gadget(cs, fmt, &sign, &mag, p + 4, &(big_e - constant(3)));
+    finish(fmt, &nan, &inf, &inf_sign, rounded)
+}
+
+pub fn sub_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> FloatVar {
+    add_gadget(cs, a, &b.neg())
+}
+
+pub fn mul_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> FloatVar {
+    let fmt = a.fmt;
+    let p = fmt.precision();
+    let ua = a.unpack();
+    let ub = b.unpack();
+    let nan = Boolean::kary_or(&[
+        ua.is_nan.clone(),
+        ub.is_nan.clone(),
+        ua.is_inf.and(&ub.is_zero).unwrap(),
+        ua.is_zero.and(&ub.is_inf).unwrap(),
+    ]).unwrap();
+    let sign = ua.sign.xor(&ub.sign).unwrap();
+    let inf = ua.is_inf.or(&ub.is_inf).unwrap();
+    let mag = ua.sig * ub.sig;
+    let ebar = ua.ebar + ub.ebar - constant(EOFF as u128);
+    let rounded = round_gadget(cs, fmt, &sign, &mag, 2*p, &ebar);
+    finish(fmt, &nan, &inf, &sign, rounded)
+}
+
+pub fn div_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> FloatVar {
+    let fmt = a.fmt;
+    let p = fmt.precision();
+    let ua = a.unpack();
+    let ub = b.unpack();
+    let nan = Boolean::kary_or(&[
+        ua.is_nan.clone(),
+        ub.is_nan.clone(),
+        ua.is_inf.and(&ub.is_inf).unwrap(),
+        ua.is_zero.and(&ub.is_zero).unwrap(),
+    ]).unwrap();
+    let sign = ua.sign.xor(&ub.sign).unwrap();
+    let inf = ua.is_inf.or(&ub.is_zero).unwrap();
+    let zero = ua.is_zero.or(&ub.is_inf).unwrap();
+
+    let (bits_a, lz_a, _) = normalize_gadget(cs, &ua.sig, p);
+    let (bits_b, lz_b, _) = normalize_gadget(cs, &ub.sig, p);
+    let sa = Boolean::le_bits_to_fp_var(&bits_a).unwrap();
+    // keep the division satisfiable when dividing by zero
+    let sb = ub.is_zero.select(&pow2(p - 1), &Boolean::le_bits_to_fp_var(&bits_b).unwrap()).unwrap();
+
+    let k = p + 2;
+    let n = sa * pow2(k);
+    let q = {
+        let n = n.clone();
+        let sb = sb.clone();
+        FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || {
+            Ok(Fr::from(fr_to_u128(n.value()?) / fr_to_u128(sb.value()?)))
+        }).unwrap())
+    };
+    let r = n - q.clone() * sb.clone();
+    to_bits_n(cs, &q, p + 3);
+    to_bits_n(cs, &r, p);
+    is_less(cs, &r, &sb, p).enforce_equal(&Boolean::TRUE).unwrap();
+    let sticky = r.is_neq(&FpVar::constant(Fr::from(0u32))).unwrap();
+    let mag = q * constant(2) + FpVar::from(sticky);
+    let ebar = ua.ebar - lz_a - ub.ebar + lz_b + constant(EOFF as u128) - constant((k + 1) as u128);
+
+    let rounded = round_gadget(cs, fmt, &sign, &mag, p + 4, &ebar);
+    let mut zero_bits = FloatVar::constant(fmt, 0).bits;
+    zero_bits[fmt.width()-1] = sign.clone();
+    let rounded = select_bits(&zero, &zero_bits, &rounded);
+    finish(fmt, &nan, &inf, &sign, rounded)
+}
+
+pub fn sqrt_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar) -> FloatVar {
+    let fmt = a.fmt;
+    let p = fmt.precision();
+    let ua = a.unpack();
+    let nan = ua.is_nan.or(&ua.sign.and(&ua.is_zero.not()).unwrap()).unwrap();
+    let inf = ua.is_inf.clone();
+
+    let (bits, lz, _) = normalize_gadget(cs, &ua.sig, p);
+    let s = Boolean::le_bits_to_fp_var(&bits).unwrap();
+    let ebar_n = ua.ebar - lz;
+    // EOFF is even so the parity of the offset exponent is the parity of the exponent
+    let parity = to_bits_n(cs, &ebar_n, EBITS)[0].clone();
+    let s0 = p + 5;
+    let t = if s0 % 2 == 1 { parity.not() } else { parity };
+    let shift = constant(s0 as u128) + FpVar::from(t.clone());
+    let x = s * pow2(s0) * t.select(&constant(2), &constant(1)).unwrap();
+
+    let q = {
+        let x = x.clone();
+        FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || {
+            Ok(Fr::from(isqrt(fr_to_u128(x.value()?))))
+        }).unwrap())
+    };
+    to_bits_n(cs, &q, p + 3);
+    // q^2 <= x < (q+1)^2
+    let d = x - q.clone() * q.clone();
+    to_bits_n(cs, &d, p + 4);
+    to_bits_n(cs, &(q.clone() * constant(2) - d.clone()), p + 4);
+    let sticky = d.is_neq(&FpVar::constant(Fr::from(0u32))).unwrap();
+    let mag = q * constant(2) + FpVar::from(sticky);
+
+    let half = Fr::from(2u32).inverse().unwrap();
+    let ebar = (ebar_n - constant(EOFF as u128) - shift) * FpVar::constant(half) + constant((EOFF - 1) as u128);
+    let rounded = round_gadget(cs, fmt, &ua.sign, &mag, p + 4, &ebar);
+    finish(fmt, &nan, &inf, &Boolean::FALSE, rounded)
+}
+
+fn both_zero(ua: &UnpackedVar, ub: &UnpackedVar) -> Boolean<Fr> {
+    ua.is_zero.and(&ub.is_zero).unwrap()
+}
+
+fn any_nan(ua: &UnpackedVar, ub: &UnpackedVar) -> Boolean<Fr> {
+    ua.is_nan.or(&ub.is_nan).unwrap()
+}
+
+fn lt_unpacked(cs: &ConstraintSystemRef<Fr>, fmt: FloatFormat, ua: &UnpackedVar, ub: &UnpackedVar) -> Boolean<Fr> {
+    let w = fmt.width();
+    let mag_lt = is_less(cs, &ua.mag, &ub.mag, w - 1);
+    let mag_gt = is_less(cs, &ub.mag, &ua.mag, w - 1);
+    let neg_pos = ua.sign.and(&ub.sign.not()).unwrap().and(&both_zero(ua, ub).not()).unwrap();
+    let pos_pos = ua.sign.not().and(&ub.sign.not()).unwrap().and(&mag_lt).unwrap();
+    let neg_neg = ua.sign.and(&ub.sign).unwrap().and(&mag_gt).unwrap();
+    let lt = Boolean::kary_or(&[neg_pos, pos_pos, neg_neg]).unwrap();
+    lt.and(&any_nan(ua, ub).not()).unwrap()
+}
+
+pub fn eq_gadget(_cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> Boolean<Fr> {
+    let ua = a.unpack();
+    let ub = b.unpack();
+    let same = a.to_fp().is_eq(&b.to_fp()).unwrap();
+    same.or(&both_zero(&ua, &ub)).unwrap().and(&any_nan(&ua, &ub).not()).unwrap()
+}
+
+pub fn ne_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> Boolean<Fr> {
+    eq_gadget(cs, a, b).not()
+}
+
+pub fn lt_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> Boolean<Fr> {
+    lt_unpacked(cs, a.fmt, &a.unpack(), &b.unpack())
+}
+
+pub fn le_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> Boolean<Fr> {
+    lt_gadget(cs, a, b).or(&eq_gadget(cs, a, b)).unwrap()
+}
+
+pub fn gt_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> Boolean<Fr> {
+    lt_gadget(cs, b, a)
+}
+
+pub fn ge_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> Boolean<Fr> {
+    le_gadget(cs, b, a)
+}
+
+pub fn min_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> FloatVar {
+    let fmt = a.fmt;
+    let ua = a.unpack();
+    let ub = b.unpack();
+    let pick_b = lt_unpacked(cs, fmt, &ub, &ua).or(&both_zero(&ua, &ub).and(&ub.sign).unwrap()).unwrap();
+    let bits = select_bits(&pick_b, &b.bits, &a.bits);
+    finish(fmt, &any_nan(&ua, &ub), &Boolean::FALSE, &Boolean::FALSE, bits)
+}
+
+pub fn max_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, b: &FloatVar) -> FloatVar {
+    let fmt = a.fmt;
+    let ua = a.unpack();
+    let ub = b.unpack();
+    let pick_b = lt_unpacked(cs, fmt, &ua, &ub).or(&both_zero(&ua, &ub).and(&ub.sign.not()).unwrap()).unwrap();
+    let bits = select_bits(&pick_b, &b.bits, &a.bits);
+    finish(fmt, &any_nan(&ua, &ub), &Boolean::FALSE, &Boolean::FALSE, bits)
+}
+
+pub fn convert_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, to: FloatFormat) -> FloatVar {
+    let from = a.fmt;
+    let ua = a.unpack();
+    // pad so that the rounding gadget has room for the guard bits
+    let pad = if to.precision() + 2 > from.precision() { to.precision() + 2 - from.precision() } else { 0 };
+    let sig = ua.sig * pow2(pad);
+    let ebar = ua.ebar - constant(pad as u128);
+    let rounded = round_gadget(cs, to, &ua.sign, &sig, from.precision() + pad, &ebar);
+    finish(to, &ua.is_nan, &ua.is_inf, &ua.sign, rounded)
+}
+
+// x is an integer given as n little endian bits
+pub fn from_int_gadget(cs: &ConstraintSystemRef<Fr>, fmt: FloatFormat, x: &[Boolean<Fr>], signed: bool) -> FloatVar {
+    let n = x.len();
+    let sign = if signed { x[n-1].clone() } else { Boolean::FALSE };
+    let v = Boolean::le_bits_to_fp_var(x).unwrap();
+    let mag = sign.select(&(constant(1u128 << n) - v.clone()), &v).unwrap();
+    let pad = fmt.precision() + 2;
+    let rounded = round_gadget(cs, fmt, &sign, &(mag * pow2(pad)), n + pad, &constant((EOFF as u128) - pad as u128));
+    FloatVar { fmt, bits: rounded }
+}
+
+// Returns the n-bit integer and a flag telling if the conversion is valid (Wasm does not trap)
+pub fn trunc_gadget(cs: &ConstraintSystemRef<Fr>, a: &FloatVar, n: usize, signed: bool) -> (FpVar<Fr>, Boolean<Fr>) {
+    let fmt = a.fmt;
+    let p = fmt.precision();
+    let ua = a.unpack();
+    let zero = FpVar::constant(Fr::from(0u32));
+    let eoff = constant(EOFF as u128);
+    let non_negative = is_less(cs, &ua.ebar, &eoff, EBITS).not();
+
+    // exponent >= 0, larger shifts than n+1 overflow anyway
+    let left = non_negative.select(&(ua.ebar.clone() - eoff.clone()), &zero).unwrap();
+    let left_big = is_less(cs, &constant((n + 1) as u128), &left, EBITS);
+    let left = left_big.select(&constant((n + 1) as u128), &left).unwrap();
+    let left_bits = to_bits_n(cs, &left, num_bits(n + 1));
+    let t_left = ua.sig.clone() * pow2_var(&left_bits);
+
+    // exponent < 0, the fraction is truncated
+    let right = non_negative.select(&zero, &(eoff - ua.ebar.clone())).unwrap();
+    let right_big = is_less(cs, &constant(p as u128), &right, EBITS);
+    let right = right_big.select(&constant(p as u128), &right).unwrap();
+    let right_bits = to_bits_n(cs, &right, num_bits(p));
+    let sig_bits = to_bits_n(cs, &ua.sig, p);
+    let t_right = Boolean::le_bits_to_fp_var(&shift_right_gadget(&sig_bits, &right_bits, false)).unwrap();
+
+    let t = non_negative.select(&t_left, &t_right).unwrap();
+    let bound = if signed {
+        ua.sign.select(&constant((1u128 << (n - 1)) + 1), &constant(1u128 << (n - 1))).unwrap()
+    } else {
+        ua.sign.select(&constant(1), &constant(1u128 << n)).unwrap()
+    };
+    let in_range = is_less(cs, &t, &bound, p + n + 2);
+    let valid = in_range.and(&ua.is_nan.or(&ua.is_inf).unwrap().not()).unwrap();
+
+    let t_zero = t.is_eq(&zero).unwrap();
+    let neg = ua.sign.and(&t_zero.not()).unwrap();
+    let res = neg.select(&(constant(1u128 << n) - t.clone()), &t).unwrap();
+    (valid.select(&res, &zero).unwrap(), valid)
+}
+
+fn test_values(fmt: FloatFormat) -> Vec<u64> {
+    if fmt == F32 {
+        vec![
+            0x00000000, 0x80000000, 0x3f800000, 0xbf800000, 0x3fc00000, 0x40490fdb,
+            0x00000001, 0x80000001, 0x007fffff, 0x00800000, 0x00800001, 0x7f7fffff, 0xff7fffff,
+            0x7f800000, 0xff800000, 0x7fc00000, 0x7f800001, 0xffc00000,
+            0x3eaaaaab, 0x4b800000, 0x33800000, 0x33800001, 0x4f000000, 0xcf000000, 0x5f800000,
+        ]
+    } else {
+        vec![
+            0x0000000000000000, 0x8000000000000000, 0x3ff0000000000000, 0xbff0000000000000,
+            0x3ff8000000000000, 0x400921fb54442d18,
+            0x0000000000000001, 0x8000000000000001, 0x000fffffffffffff, 0x0010000000000000,
+            0x7fefffffffffffff, 0xffefffffffffffff,
+            0x7ff0000000000000, 0xfff0000000000000, 0x7ff8000000000000, 0x7ff0000000000001, 0xfff8000000000000,
+            0x3fd5555555555555, 0x4340000000000000, 0x3ca0000000000000, 0x3ca0000000000001,
+            0x41e0000000000000, 0xc1e0000000000000, 0x43e0000000000000, 0x36a0000000000000,
+        ]
+    }
+}
+
+fn check_compare(
+    fmt: FloatFormat,
+    native: fn(FloatFormat, u64, u64) -> bool,
+    gadget: fn(&ConstraintSystemRef<Fr>, &FloatVar, &FloatVar) -> Boolean<Fr>,
+) {
+    for a in test_values(fmt) {
+        for b in test_values(fmt) {
+            let cs_sys = ConstraintSystem::<Fr>::new();
+            let cs = ConstraintSystemRef::new(cs_sys);
+            let a_var = FloatVar::new_witness(&cs, fmt, a);
+            let b_var = FloatVar::new_witness(&cs, fmt, b);
+            let res = gadget(&cs, &a_var, &b_var);
+            assert!(cs.is_satisfied().unwrap(), "unsatisfied {:x} {:x}", a, b);
+            assert_eq!(res.value().unwrap(), native(fmt, a, b), "mismatch {:x} {:x}", a, b);
+        }
+    }
+}
+
+fn check_binary(
+    fmt: FloatFormat,
+    native: fn(FloatFormat, u64, u64) -> u64,
+    gadget: fn(&ConstraintSystemRef<Fr>, &FloatVar, &FloatVar) -> FloatVar,
+) {
+    for a in test_values(fmt) {
+        for b in test_values(fmt) {
+            let cs_sys = ConstraintSystem::<Fr>::new();
+            let cs = ConstraintSystemRef::new(cs_sys);
+            let a_var = FloatVar::new_witness(&cs, fmt, a);
+            let b_var = FloatVar::new_witness(&cs, fmt, b);
+            let res = gadget(&cs, &a_var, &b_var);
+            assert!(cs.is_satisfied().unwrap(), "unsatisfied {:x} {:x}", a, b);
+            assert_eq!(res.value(), native(fmt, a, b), "mismatch {:x} {:x}", a, b);
+        }
+    }
+}
+
+fn canonical(fmt: FloatFormat, a: u64) -> u64 {
+    if unpack(fmt, a).is_nan(fmt) { fmt.canonical_nan() } else { a }
+}
+
+#[test]
+fn test_native_f32() {
+    for a in test_values(F32) {
+        let x = f32::from_bits(a as u32);
+        for b in test_values(F32) {
+            let y = f32::from_bits(b as u32);
+            assert_eq!(add(F32, a, b), canonical(F32, (x + y).to_bits() as u64));
+            assert_eq!(sub(F32, a, b), canonical(F32, (x - y).to_bits() as u64));
+            assert_eq!(mul(F32, a, b), canonical(F32, (x * y).to_bits() as u64));
+            assert_eq!(div(F32, a, b), canonical(F32, (x / y).to_bits() as u64));
+            assert_eq!(eq(F32, a, b), x == y);
+            assert_eq!(lt(F32, a, b), x < y);
+            assert_eq!(le(F32, a, b), x <= y);
+        }
+        assert_eq!(sqrt(F32, a), canonical(F32, x.sqrt().to_bits() as u64));
+        assert_eq!(convert(F32, F64, a), canonical(F64, (x as f64).to_bits()));
+        if let Some(v) = trunc(F32, a, 32, true) {
+            assert_eq!(v as u32 as i32, x as i32);
+        }
+    }
+    for x in [0u64, 1, 0x7fffffff, 0x80000000, 0xffffffff, 0x01000001, 0x7fffffc0] {
+        assert_eq!(from_int(F32, x, 32, true), (x as u32 as i32 as f32).to_bits() as u64);
+        assert_eq!(from_int(F32, x, 32, false), (x as u32 as f32).to_bits() as u64);
+    }
+}
+
+#[test]
+fn test_native_f64() {
+    for a in test_values(F64) {
+        let x = f64::from_bits(a);
+        for b in test_values(F64) {
+            let y = f64::from_bits(b);
+            assert_eq!(add(F64, a, b), canonical(F64, (x + y).to_bits()));
+            assert_eq!(sub(F64, a, b), canonical(F64, (x - y).to_bits()));
+            assert_eq!(mul(F64, a, b), canonical(F64, (x * y).to_bits()));
+            assert_eq!(div(F64, a, b), canonical(F64, (x / y).to_bits()));
+            assert_eq!(eq(F64, a, b), x == y);
+            assert_eq!(lt(F64, a, b), x < y);
+            assert_eq!(le(F64, a, b), x <= y);
+        }
+        assert_eq!(sqrt(F64, a), canonical(F64, x.sqrt().to_bits()));
+        assert_eq!(convert(F64, F32, a), canonical(F32, (x as f32).to_bits() as u64));
+        if let Some(v) = trunc(F64, a, 64, false) {
+            assert_eq!(v, x as u64);
+        }
+    }
+    for x in [0u64, 1, u64::MAX, 1 << 63, (1 << 53) + 1, 0x7ffffffffffffc00] {
+        assert_eq!(from_int(F64, x, 64, true), (x as i64 as f64).to_bits());
+        assert_eq!(from_int(F64, x, 64, false), (x as f64).to_bits());
+    }
+}
+
+// Every gadget against the native implementation on the edge cases of the format
+fn check_gadgets(fmt: FloatFormat) {
+    check_binary(fmt, add, add_gadget);
+    check_binary(fmt, sub, sub_gadget);
+    check_binary(fmt, mul, mul_gadget);
+    check_binary(fmt, div, div_gadget);
+    check_binary(fmt, min, min_gadget);
+    check_binary(fmt, max, max_gadget);
+    check_compare(fmt, eq, eq_gadget);
+    check_compare(fmt, ne, ne_gadget);
+    check_compare(fmt, lt, lt_gadget);
+    check_compare(fmt, le, le_gadget);
+    check_compare(fmt, gt, gt_gadget);
+    check_compare(fmt, ge, ge_gadget);
+    let other = if fmt == F32 { F64 } else { F32 };
+    for a in test_values(fmt) {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let a_var = FloatVar::new_witness(&cs, fmt, a);
+        assert_eq!(sqrt_gadget(&cs, &a_var).value(), sqrt(fmt, a), "sqrt {:x}", a);
+        assert_eq!(convert_gadget(&cs, &a_var, other).value(), convert(fmt, other, a), "convert {:x}", a);
+        for (n, signed) in [(32, true), (32, false), (64, true), (64, false)] {
+            let (v, valid) = trunc_gadget(&cs, &a_var, n, signed);
+            match trunc(fmt, a, n, signed) {
+                Some(x) => {
+                    assert!(valid.value().unwrap(), "trunc {:x} {} {}", a, n, signed);
+                    assert_eq!(v.value().unwrap(), Fr::from(x), "trunc {:x} {} {}", a, n, signed);
+                }
+                None => assert!(!valid.value().unwrap(), "trunc {:x} {} {}", a, n, signed),
+            }
+        }
+        assert!(cs.is_satisfied().unwrap(), "unsatisfied {:x}", a);
+    }
+    // the bit patterns of the float edge cases are also used as integers
+    let ints = [0u64, 1, 0x7fffffff, 0x80000000, 0xffffffff, 0x01000001, 0x7fffffc0, u64::MAX, 1 << 63, (1 << 53) + 1, 0x7ffffffffffffc00];
+    for x in test_values(fmt).into_iter().chain(ints) {
+        for (n, signed) in [(32, true), (32, false), (64, true), (64, false)] {
+            let cs_sys = ConstraintSystem::<Fr>::new();
+            let cs = ConstraintSystemRef::new(cs_sys);
+            let bits : Vec<Boolean<Fr>> = (0..n).map(|i| Boolean::new_witness(cs.clone(), || Ok((x >> i) & 1 == 1)).unwrap()).collect();
+            let res = from_int_gadget(&cs, fmt, &bits, signed);
+            assert_eq!(res.value(), from_int(fmt, x, n, signed), "from_int {:x} {} {}", x, n, signed);
+            assert!(cs.is_satisfied().unwrap(), "unsatisfied from_int {:x} {} {}", x, n, signed);
+        }
+    }
+}
+
+#[test]
+fn test_gadgets_f32() {
+    check_gadgets(F32);
+}
+
+#[test]
+fn test_gadgets_f64() {
+    check_gadgets(F64);
+}
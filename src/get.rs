@@ -13,7 +13,7 @@ use ark_r1cs_std::eq::EqGadget;
 use ark_sponge::poseidon::PoseidonParameters;
 use ark_r1cs_std::boolean::{AllocatedBool,Boolean};
 
-use crate::{VM,Transition,hash_list,hash_code,InstructionCircuit};
+use crate::{VM,Transition,hash_code,InstructionCircuit};
 
 // use ark_r1cs_std::R1CSVar;
 
@@ -42,12 +42,10 @@ impl ConstraintSynthesizer<Fr> for GetCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let before = self.before.clone();
         let after = self.after.clone();
 
-        println!("before {:?}", before);
-        println!("after {:?}", after);
-    
         let pc_hash = hash_code(&self.params, &after.pc);
         let stack_hash = before.hash_stack(&self.params);
         // let locals_hash = before.hash_locals(&self.params);
@@ -92,7 +90,6 @@ impl ConstraintSynthesizer<Fr> for GetCircuit {
         inputs_pc.push(hash_pc_after_var.clone());
         let hash_pc_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_pc).unwrap();
     
-        println!("pc hash {}", hash_code(&self.params, &before.pc));
         // println!("pc hash {}", hash_pc_gadget.value().unwrap());
         
         let mut inputs_stack_after = Vec::new();
@@ -102,7 +99,6 @@ impl ConstraintSynthesizer<Fr> for GetCircuit {
         ));
         let hash_stack_after_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_stack_after).unwrap();
 
-        println!("stack after {}", hash_list(&self.params, &after.expr_stack.iter().map(|a| Fr::from(*a)).collect::<Vec<Fr>>()));
         // println!("stack after {}", hash_stack_after_gadget.value().unwrap());
 
         // Compute VM hash before
@@ -127,8 +123,6 @@ impl ConstraintSynthesizer<Fr> for GetCircuit {
         let hash_transition_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_transition).unwrap();
         hash_transition_gadget.enforce_equal(&public_var)?;
     
-        println!("Made circuit");
-        println!("before {}, after {}", before.hash(&self.params), after.hash(&self.params));
         // println!("before {}, after {}", hash_vm_before_gadget.value().unwrap(), hash_vm_after_gadget.value().unwrap());
 
         Ok(())
@@ -15,6 +15,7 @@ use ark_relations::r1cs::ConstraintSystem;
 
 use ark_std::UniformRand;
 use ark_ff::Field;
+use ark_ff::PrimeField;
 use ark_r1cs_std::fields::FieldVar;
 use ark_r1cs_std::R1CSVar;
 
@@ -205,6 +206,7 @@ impl ConstraintSynthesizer<Fr> for TestCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
         // make each step
         let mut vars = vec![];
@@ -213,7 +215,6 @@ impl ConstraintSynthesizer<Fr> for TestCircuit {
             vars.push(var);
         }
         // println!("num constraints {}, valid {}", cs.num_constraints(), cs.is_satisfied().unwrap());
-        println!("num constraints {}", cs.num_constraints());
         Ok(())
     }
 }
@@ -241,14 +242,18 @@ pub fn make_path(cs: ConstraintSystemRef<Fr>, num: usize, params : &Params, elem
     let selectors = &proof.selectors;
     let mut idx = FpVar::constant(Fr::from(0));
     let mut pow2 = FpVar::constant(Fr::from(1));
+    let mut prev_skip = Boolean::FALSE;
     for i in 0..num {
         let elem = if path.len() > i { path[i] } else { Fr::from(0) };
         let sel = if selectors.len() > i { selectors[i] } else { false };
         let skip = selectors.len() <= i;
         let sel_bool = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(sel)).unwrap());
-        let skip_bool = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(skip)).unwrap()); // these might need a correctness check (perhaps not)
+        let skip_bool = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(skip)).unwrap());
+        // levels are skipped only at the top, otherwise the index would not match the path
+        skip_bool.or(&prev_skip).unwrap().enforce_equal(&skip_bool).unwrap();
+        prev_skip = skip_bool.clone();
         let new_idx = idx.clone() + sel_bool.select(&pow2, &FpVar::constant(Fr::from(0))).unwrap();
-        let new_pow2 = pow2.clone() * pow2.clone();
+        let new_pow2 = pow2.clone() + pow2.clone();
 
         let elem_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(elem.clone())).unwrap());
         let leaf1 = sel_bool.select(&elem_var, &acc).unwrap();
@@ -262,6 +267,50 @@ pub fn make_path(cs: ConstraintSystemRef<Fr>, num: usize, params : &Params, elem
     (acc, idx)
 }
 
+// Native tree matching make_path, a level with odd length is padded with a zero
+fn merkle_levels(params: &Params, leaves: &[Fr]) -> Vec<Vec<Fr>> {
+    let mut level = leaves.to_vec();
+    if level.len() == 0 {
+        level.push(Fr::from(0));
+    }
+    let mut levels = vec![];
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(Fr::from(0));
+        }
+        let next = level.chunks(2).map(|pair| poseidon(params, vec![pair[0], pair[1]])).collect();
+        levels.push(level);
+        level = next;
+    }
+    levels.push(level);
+    levels
+}
+
+pub fn merkle_root(params: &Params, leaves: &[Fr]) -> Fr {
+    let levels = merkle_levels(params, leaves);
+    levels[levels.len()-1][0]
+}
+
+pub fn merkle_proof(params: &Params, leaves: &[Fr], idx: usize) -> Proof {
+    let levels = merkle_levels(params, leaves);
+    let mut path = vec![];
+    let mut selectors = vec![];
+    let mut idx = idx;
+    for level in levels[0..levels.len()-1].iter() {
+        path.push(level[idx ^ 1]);
+        selectors.push(idx % 2 == 1);
+        idx = idx / 2;
+    }
+    Proof { path, selectors }
+}
+
+// Low 128 bits of a field element
+pub fn fr_to_u128(a: Fr) -> u128 {
+    let repr = a.into_repr();
+    let limbs = repr.as_ref();
+    (limbs[0] as u128) | ((limbs[1] as u128) << 64)
+}
+
 pub fn test(_params: &PoseidonParameters<Fr>) {
     use ark_std::test_rng;
     use crate::InnerSNARK;
@@ -295,3 +344,19 @@ pub fn test(_params: &PoseidonParameters<Fr>) {
     let proof = InnerSNARK::prove(&pk, circuit.clone(), &mut rng).unwrap();
     */
 }
+
+#[test]
+fn test_make_path_index() {
+    let params = generate_params();
+    let leaves : Vec<Fr> = (0..8).map(|i| Fr::from(i + 10)).collect();
+    for idx in 0..8 {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let elem = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(leaves[idx])).unwrap());
+        let (root, path_idx) = make_path(cs.clone(), 20, &params, elem, &merkle_proof(&params, &leaves, idx));
+        assert!(cs.is_satisfied().unwrap());
+        assert_eq!(root.value().unwrap(), merkle_root(&params, &leaves));
+        // the selector of level i has weight 2^i, squaring the weight gave index 2 for leaf 3
+        assert_eq!(path_idx.value().unwrap(), Fr::from(idx as u64));
+    }
+}
@@ -22,6 +22,9 @@ use ark_r1cs_std::boolean::Boolean;
 use crate::{VM,Transition,hash_list,hash_code};
 use crate::InstructionCircuit;
 use ark_r1cs_std::boolean::AllocatedBool;
+use ark_r1cs_std::uint8::UInt8;
+use ark_r1cs_std::ToBitsGadget;
+use ark_ff::PrimeField;
 
 fn shr_bool(a: &[Boolean<Fr>], r: usize) -> Vec<Boolean<Fr>> {
     let mut out = vec![];
@@ -253,22 +256,22 @@ fn chi(inp: Vec<Boolean<Fr>>) -> Vec<Boolean<Fr>> {
     out
 }
 
-fn iota(inp: Vec<Boolean<Fr>>, r: usize) -> Vec<Boolean<Fr>> {
+const ROUND_CONSTANTS: [u64; 24] = [
+    0x0000000000000001u64, 0x0000000000008082, 0x800000000000808A,
+    0x8000000080008000, 0x000000000000808B, 0x0000000080000001,
+    0x8000000080008081, 0x8000000000008009, 0x000000000000008A,
+    0x0000000000000088, 0x0000000080008009, 0x000000008000000A,
+    0x000000008000808B, 0x800000000000008B, 0x8000000000008089,
+    0x8000000000008003, 0x8000000000008002, 0x8000000000000080,
+    0x000000000000800A, 0x800000008000000A, 0x8000000080008081,
+    0x8000000000008080, 0x0000000080000001, 0x8000000080008008
+];
 
-    let rc_arr = vec![
-        0x0000000000000001u64, 0x0000000000008082, 0x800000000000808A,
-        0x8000000080008000, 0x000000000000808B, 0x0000000080000001,
-        0x8000000080008081, 0x8000000000008009, 0x000000000000008A,
-        0x0000000000000088, 0x0000000080008009, 0x000000008000000A,
-        0x000000008000808B, 0x800000000000008B, 0x8000000000008089,
-        0x8000000000008003, 0x8000000000008002, 0x8000000000000080,
-        0x000000000000800A, 0x800000008000000A, 0x8000000080008081,
-        0x8000000000008080, 0x0000000080000001, 0x8000000080008008
-    ];
+fn iota(inp: Vec<Boolean<Fr>>, r: usize) -> Vec<Boolean<Fr>> {
 
     let mut rc = vec![];
     for i in 0..64 {
-        rc.push(Boolean::constant(((rc_arr[r] >> i) & 1) == 1));
+        rc.push(Boolean::constant(((ROUND_CONSTANTS[r] >> i) & 1) == 1));
     }
 
     let mut res = xor_bool(&inp[0..64], &rc);
@@ -392,6 +395,10 @@ fn keccacf_round(inp: Vec<Boolean<Fr>>, r: usize) -> Vec<Boolean<Fr>> {
     iota(r3, r)
 }
 
+// 6400 constraints per round: theta needs one product per bit and chi two. Packing the lanes
+// as unreduced sums of bits (or digits of a sparse base) only defers the reduction in theta,
+// because chi is cubic in its inputs and they have to be reduced bits. Without lookups that
+// saves 320 constraints per round, so there is no second keccak-f.
 fn keccakf(inp: Vec<Boolean<Fr>>) -> Vec<Boolean<Fr>> {
     let mut res = inp;
     for i in 0..24 {
@@ -411,9 +418,6 @@ fn absorb(block: &[Boolean<Fr>], s: Vec<Boolean<Fr>>) -> Vec<Boolean<Fr>> {
     }
     // println!("first {}, block {}", inp.len(), block.len());
 
-    print_bytes(&inp);
-
-
     keccakf(inp)
 }
 
@@ -481,6 +485,129 @@ fn finalize_blocks(cs: ConstraintSystemRef<Fr>, inp: Vec<Boolean<Fr>>, sel: Vec<
     absorb(&last_block, s)
 }
 
+pub const BLOCK_BYTES: usize = 136;
+
+fn to_usize(v: Fr) -> usize {
+    v.into_repr().as_ref()[0] as usize
+}
+
+// Padded blocks of the message and whether each block is absorbed
+fn pad_blocks(bytes: &[UInt8<Fr>], len: &FpVar<Fr>) -> Vec<(Vec<Boolean<Fr>>, Boolean<Fr>)> {
+    assert!(bytes.len() > 0 && bytes.len() % BLOCK_BYTES == 0);
+    let cs = len.cs().or(bytes.cs());
+
+    // sel[j] is true for bytes of the message
+    let mut sel = vec![];
+    for j in 0..bytes.len() {
+        sel.push(Boolean::new_witness(cs.clone(), || len.value().map(|l| j < to_usize(l))).unwrap());
+    }
+    correct_sel(&sel);
+    count(&sel).enforce_equal(len).unwrap();
+
+    let mut res = vec![];
+    for i in 0..bytes.len() / BLOCK_BYTES {
+        // block is absorbed if the message reaches it, the last absorbed block has the final padding bit
+        let active = if i == 0 { Boolean::TRUE } else { sel[i*BLOCK_BYTES-1].clone() };
+        let last = active.and(&sel[(i+1)*BLOCK_BYTES-1].not()).unwrap();
+        let mut block = vec![];
+        for j in i*BLOCK_BYTES .. (i+1)*BLOCK_BYTES {
+            let bits = bytes[j].to_bits_le().unwrap();
+            let first = if j == 0 { sel[0].not() } else { sel[j-1].and(&sel[j].not()).unwrap() };
+            block.push(sel[j].and(&bits[0]).unwrap().or(&first).unwrap());
+            for k in 1..7 {
+                block.push(sel[j].and(&bits[k]).unwrap())
+            }
+            let b7 = sel[j].and(&bits[7]).unwrap();
+            block.push(if j == (i+1)*BLOCK_BYTES-1 { b7.or(&last).unwrap() } else { b7 });
+        }
+        res.push((block, active));
+    }
+    res
+}
+
+// Keccak-256 of the first len bytes. The input is zero padded to a multiple of 136 bytes,
+// the number of blocks is the maximum, and len must be less than the input length.
+pub fn keccak256_gadget(bytes: &[UInt8<Fr>], len: &FpVar<Fr>) -> Vec<UInt8<Fr>> {
+    let mut s = vec![Boolean::FALSE; 1600];
+    for (block, active) in pad_blocks(bytes, len) {
+        let aux = absorb(&block, s.clone());
+        s = aux.iter().zip(s.iter()).map(|(a, b)| active.select(a, b).unwrap()).collect();
+    }
+
+    let mut out = vec![];
+    for i in 0..32 {
+        out.push(UInt8::from_bits_le(&s[i*8..(i+1)*8]))
+    }
+    out
+}
+
+// Witnesses for a message of at most max_blocks*136-1 bytes
+pub fn alloc_message(cs: ConstraintSystemRef<Fr>, msg: &[u8], max_blocks: usize) -> (Vec<UInt8<Fr>>, FpVar<Fr>) {
+    assert!(msg.len() < max_blocks*BLOCK_BYTES);
+    let mut padded = msg.to_vec();
+    padded.resize(max_blocks*BLOCK_BYTES, 0);
+    let bytes = UInt8::new_witness_vec(cs.clone(), &padded).unwrap();
+    let len = FpVar::new_witness(cs, || Ok(Fr::from(msg.len() as u64))).unwrap();
+    (bytes, len)
+}
+
+const ROTATIONS: [u32; 25] = [
+    0, 1, 62, 28, 27,
+    36, 44, 6, 55, 20,
+    3, 10, 43, 25, 39,
+    41, 45, 15, 21, 8,
+    18, 2, 61, 56, 14,
+];
+
+fn keccakf_native(a: &mut [u64; 25]) {
+    for r in 0..24 {
+        let mut c = [0u64; 5];
+        for x in 0..5 {
+            c[x] = a[x] ^ a[x+5] ^ a[x+10] ^ a[x+15] ^ a[x+20];
+        }
+        for x in 0..5 {
+            let d = c[(x+4)%5] ^ c[(x+1)%5].rotate_left(1);
+            for y in 0..5 {
+                a[x+5*y] ^= d;
+            }
+        }
+        let mut b = [0u64; 25];
+        for x in 0..5 {
+            for y in 0..5 {
+                b[y + 5*((2*x+3*y)%5)] = a[x+5*y].rotate_left(ROTATIONS[x+5*y]);
+            }
+        }
+        for x in 0..5 {
+            for y in 0..5 {
+                a[x+5*y] = b[x+5*y] ^ (!b[(x+1)%5+5*y] & b[(x+2)%5+5*y]);
+            }
+        }
+        a[0] ^= ROUND_CONSTANTS[r];
+    }
+}
+
+pub fn keccak256(msg: &[u8]) -> [u8; 32] {
+    let mut padded = msg.to_vec();
+    padded.push(0x01);
+    while padded.len() % BLOCK_BYTES != 0 {
+        padded.push(0)
+    }
+    let n = padded.len();
+    padded[n-1] |= 0x80;
+    let mut s = [0u64; 25];
+    for block in padded.chunks(BLOCK_BYTES) {
+        for i in 0..BLOCK_BYTES/8 {
+            s[i] ^= u64::from_le_bytes(block[i*8..(i+1)*8].try_into().unwrap());
+        }
+        keccakf_native(&mut s);
+    }
+    let mut out = [0u8; 32];
+    for i in 0..4 {
+        out[i*8..(i+1)*8].copy_from_slice(&s[i].to_le_bytes());
+    }
+    out
+}
+
 #[derive(Debug, Clone)]
 pub struct TestCircuit {
     pub steps: usize,
@@ -488,6 +615,7 @@ pub struct TestCircuit {
 
 impl ConstraintSynthesizer<Fr> for TestCircuit {
     fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         for _i in 0..self.steps {
             let mut inp = vec![];
             for _j in 0..136*8 {
@@ -502,7 +630,6 @@ impl ConstraintSynthesizer<Fr> for TestCircuit {
             finalize(inp, init);
         }
         // println!("num constraints {}, valid {}", cs.num_constraints(), cs.is_satisfied().unwrap());
-        println!("num constraints {}", cs.num_constraints());
         Ok(())
     }
 }
@@ -576,3 +703,48 @@ pub fn test() {
     let proof = InnerSNARK::prove(&pk, circuit.clone(), &mut rng).unwrap();
     */
 }
+
+#[cfg(test)]
+fn to_hex(v: &[u8]) -> String {
+    v.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_keccak_native() {
+    use tiny_keccak::{Hasher, Keccak};
+    assert_eq!(to_hex(&keccak256(b"")), "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470");
+    assert_eq!(to_hex(&keccak256(b"abc")), "4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45");
+    for len in [1, 55, 135, 136, 137, 271, 272, 500] {
+        let msg: Vec<u8> = (0..len).map(|i| (i * 7 + 3) as u8).collect();
+        let mut hasher = Keccak::v256();
+        hasher.update(&msg);
+        let mut expected = [0u8; 32];
+        hasher.finalize(&mut expected);
+        assert_eq!(keccak256(&msg), expected);
+    }
+}
+
+#[test]
+fn test_keccak_gadget() {
+    for len in [0, 3, 135, 136, 271] {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let msg: Vec<u8> = (0..len).map(|i| (i * 7 + 3) as u8).collect();
+        let (bytes, len_var) = alloc_message(cs.clone(), &msg, 2);
+        let res = keccak256_gadget(&bytes, &len_var);
+        let res: Vec<u8> = res.iter().map(|b| b.value().unwrap()).collect();
+        assert_eq!(res, keccak256(&msg).to_vec());
+        assert!(cs.is_satisfied().unwrap());
+    }
+}
+
+#[test]
+fn test_keccak_gadget_length() {
+    // message has to leave room for the padding in the last block
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let (bytes, _) = alloc_message(cs.clone(), b"abc", 1);
+    let len = FpVar::new_witness(cs.clone(), || Ok(Fr::from(136u64))).unwrap();
+    keccak256_gadget(&bytes, &len);
+    assert!(!cs.is_satisfied().unwrap());
+}
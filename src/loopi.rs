@@ -41,13 +41,11 @@ impl ConstraintSynthesizer<Fr> for LoopCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         use crate::CodeTree::CLoop;
         let before = self.before.clone();
         let after = self.after.clone();
 
-        println!("before {:?}", before);
-        println!("after {:?}", after);
-
         let cont = match before.pc[0].clone() {
             CLoop(cont) => cont,
             _ => panic!("Wrong instruction"),
@@ -106,7 +104,6 @@ impl ConstraintSynthesizer<Fr> for LoopCircuit {
         inputs_pc.push(hash_pc_after_var.clone());
         let hash_pc_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_pc).unwrap();
     
-        println!("pc hash {}", hash_code(&self.params, &before.pc));
         // println!("pc hash {}", hash_pc_gadget.value().unwrap());
         
         // Compute VM hash before
@@ -131,8 +128,6 @@ impl ConstraintSynthesizer<Fr> for LoopCircuit {
         let hash_transition_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_transition).unwrap();
         hash_transition_gadget.enforce_equal(&public_var)?;
     
-        println!("Made circuit");
-        println!("before {}, after {}", before.hash(&self.params), after.hash(&self.params));
         // println!("before {}, after {}", hash_vm_before_gadget.value().unwrap(), hash_vm_after_gadget.value().unwrap());
 
         Ok(())
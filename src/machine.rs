@@ -14,10 +14,18 @@ use ark_sponge::poseidon::PoseidonParameters;
 use ark_r1cs_std::boolean::{AllocatedBool,Boolean};
 use ark_r1cs_std::fields::FieldVar;
 use ark_r1cs_std::ToBitsGadget;
+use ark_r1cs_std::R1CSVar;
+use ark_ff::PrimeField;
 
 use crate::{VM,Transition,hash_code};
 use crate::InstructionCircuit;
-use crate::hash::{Params, poseidon_gadget, Proof, make_path, poseidon};
+use crate::hash::{Params, poseidon_gadget, Proof, make_path, poseidon, merkle_root, merkle_proof, fr_to_u128};
+#[cfg(feature = "float")]
+use crate::float::{self, FloatFormat, FloatVar, F32, F64};
+#[cfg(feature = "keccak")]
+use crate::keccak::{keccak256_gadget, keccak256, BLOCK_BYTES};
+#[cfg(feature = "keccak")]
+use ark_r1cs_std::uint8::UInt8;
 
 #[derive(Debug, Clone)]
 pub struct Machine {
@@ -104,6 +112,7 @@ pub struct Module {
     internalsOffset: FpVar<Fr>,
 }
 
+#[derive(Debug, Clone)]
 pub struct ModuleHint {
     globalsMerkleRoot: Fr,
     moduleMemory: Fr,
@@ -122,6 +131,15 @@ impl ModuleHint {
             internalsOffset: Fr::from(0),
         }
     }
+    fn hash(&self, params: &Params) -> Fr {
+        poseidon(&params, vec![
+            self.globalsMerkleRoot.clone(),
+            self.moduleMemory.clone(),
+            self.tablesMerkleRoot.clone(),
+            self.functionsMerkleRoot.clone(),
+            self.internalsOffset.clone(),
+        ])
+    }
     fn convert(&self, cs: ConstraintSystemRef<Fr>) -> Module {
         Module {
             globalsMerkleRoot: witness(&cs, &self.globalsMerkleRoot),
@@ -164,7 +182,7 @@ pub struct Value {
 
 #[derive(Debug, Clone)]
 pub struct ValueHint {
-    value: u64,
+    value: u128,
     ty: u32,
 }
 
@@ -211,6 +229,12 @@ impl InstructionHint {
             argumentData: 0,
         }
     }
+    fn hash(&self, params: &Params) -> Fr {
+        poseidon(&params, vec![
+            Fr::from(self.opcode),
+            Fr::from(self.argumentData),
+        ])
+    }
     
     fn convert(&self, cs: ConstraintSystemRef<Fr>) -> Instruction {
         Instruction {
@@ -319,7 +343,6 @@ pub fn hash_machine_with_stack(params: &Params, mach: &MachineWithStack) -> FpVa
     hash_machine(params, &elim_stack(params, mach))
 }
 
-// There can be savings by sharing the hashing of stacks ...
 pub fn elim_stack(params : &Params, mach: &MachineWithStack) -> Machine {
     Machine {
         valueStack : hash_stack(params, &mach.valueStack),
@@ -361,14 +384,14 @@ pub fn check_instruction(mach: &MachineWithStack, expected: u32) -> MachineWithS
     mach
 }
 
+// Old module has to be in the current modules root, the root is replaced by the one with the new module.
+// Only sets the valid flag, other alternatives are computed with dummy witnesses.
 pub fn change_module(cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack, old_mole: &Module, mod_proof: &Proof) -> MachineWithStack {
     let mole_hash = hash_module(params, &mach.mole);
     let (mole_root, mole_idx) = make_path(cs.clone(), 16, params, mole_hash, mod_proof);
 
     let old_mole_hash = hash_module(params, &old_mole);
     let (old_mole_root, old_mole_idx) = make_path(cs.clone(), 16, params, old_mole_hash, mod_proof);
-    mole_root.enforce_equal(&mach.modulesRoot).unwrap();
-    mole_idx.enforce_equal(&mach.moduleIdx).unwrap();
 
     let mut mach = mach.clone();
     mach.valid = mach.valid.and(&old_mole_idx.is_eq(&mach.moduleIdx).unwrap()).unwrap();
@@ -388,26 +411,49 @@ pub fn execute_const(params: &Params, mach: &MachineWithStack, ty: u32) -> Machi
     mach
 }
 
+// Like in the arbitrator, pc points to the next instruction while executing
+fn next_pc(mach: &MachineWithStack) -> MachineWithStack {
+    let mut mach = mach.clone();
+    mach.functionPc = mach.functionPc.clone() + FpVar::constant(Fr::from(1));
+    mach
+}
+
+fn restore_pc(before: &MachineWithStack, mach: &MachineWithStack) -> MachineWithStack {
+    let mut before = before.clone();
+    before.functionPc = mach.functionPc.clone();
+    before
+}
+
 trait Inst {
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack);
-    fn code() -> u32;
+    fn code(&self) -> u32;
+    // opcodes that select this alternative
+    fn opcodes(&self) -> Vec<u32> {
+        vec![self.code()]
+    }
     fn execute(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
-        let (before, after) = self.execute_internal(params, mach);
-        let after = check_instruction(&after, Self::code());
+        let (before, after) = self.execute_internal(params, &next_pc(mach));
+        let before = restore_pc(&before, mach);
+        let after = check_instruction(&after, self.code());
         (before, after)
     }
 }
 
 trait InstCS {
     fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack);
-    fn code() -> u32;
+    fn code(&self) -> u32;
+    fn opcodes(&self) -> Vec<u32> {
+        vec![self.code()]
+    }
     fn execute(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
-        let (before, after) = self.execute_internal(cs, params, mach);
-        let after = check_instruction(&after, Self::code());
+        let (before, after) = self.execute_internal(cs, params, &next_pc(mach));
+        let before = restore_pc(&before, mach);
+        let after = check_instruction(&after, self.code());
         (before, after)
     }
 }
 
+#[derive(Debug, Clone)]
 struct InstConstHint {
 }
 
@@ -441,8 +487,8 @@ impl InstConstHint {
 }
 
 impl Inst for InstConst {
-    fn code() -> u32 {
-        12
+    fn code(&self) -> u32 {
+        0x41 + self.ty
     }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let before = mach.clone();
@@ -476,14 +522,22 @@ pub fn enforce_i32(v: FpVar<Fr>) {
     res.enforce_equal(&v).unwrap();
 }
 
+// Like enforce_i32, but for values that only matter if the alternative is selected
+pub fn is_i32(v: FpVar<Fr>) -> Boolean<Fr> {
+    let bits = v.to_bits_le().unwrap();
+    let res = Boolean::le_bits_to_fp_var(&bits[0..32]).unwrap();
+    res.is_eq(&v).unwrap()
+}
+
 pub fn execute_drop(_params: &Params, mach: &MachineWithStack) -> MachineWithStack {
     let mut mach = mach.clone();
     let _popped = mach.valueStack.pop();
     mach
 }
 
+#[derive(Debug, Clone)]
 struct InstDropHint {
-    val: u64,
+    val: Fr,
 }
 
 struct InstDrop {
@@ -491,9 +545,7 @@ struct InstDrop {
 }
 
 impl Inst for InstDrop {
-    fn code() -> u32 {
-        12
-    }
+    fn code(&self) -> u32 { 0x1a }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
         mach.valueStack.push(self.val.clone());
@@ -506,12 +558,12 @@ impl Inst for InstDrop {
 impl InstDropHint {
     pub fn default() -> Self {
         InstDropHint {
-            val: 0,
+            val: Fr::from(0),
         }
     }
     fn convert(&self, cs: &ConstraintSystemRef<Fr>) -> InstDrop {
         InstDrop {
-            val: FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(self.val))).unwrap()),
+            val: witness(cs, &self.val),
         }
     }
 }
@@ -524,14 +576,17 @@ fn drop_default_machine() -> MachineWithStack {
 }
 */
 
-pub fn execute_select(_params: &Params, mach: &MachineWithStack) -> MachineWithStack {
+// a is selected when the i32 selector is not zero
+pub fn execute_select(params: &Params, mach: &MachineWithStack, selector: &Value) -> MachineWithStack {
     let mut mach = mach.clone();
-    let selector = mach.valueStack.pop();
+    let selector_hash = mach.valueStack.pop();
     let b = mach.valueStack.pop();
     let a = mach.valueStack.pop();
+    selector_hash.enforce_equal(&hash_value(params, selector)).unwrap();
+    mach.valid = mach.valid.and(&selector.ty.is_eq(&FpVar::constant(Fr::from(I32_TYPE))).unwrap()).unwrap();
 
-    let sel_bool = selector.is_eq(&FpVar::constant(Fr::from(0))).unwrap();
-    let a_b = sel_bool.select(&a, &b).unwrap();
+    let sel_bool = selector.value.is_eq(&FpVar::constant(Fr::from(0))).unwrap();
+    let a_b = sel_bool.select(&b, &a).unwrap();
     mach.valueStack.push(a_b);
     mach
 }
@@ -539,24 +594,25 @@ pub fn execute_select(_params: &Params, mach: &MachineWithStack) -> MachineWithS
 struct InstSelect {
     val1: FpVar<Fr>,
     val2: FpVar<Fr>,
-    val3: FpVar<Fr>,
+    val3: Value,
 }
 
+#[derive(Debug, Clone)]
 struct InstSelectHint {
     val1: Fr,
     val2: Fr,
-    val3: Fr,
+    val3: ValueHint,
 }
 
 impl Inst for InstSelect {
-    fn code() -> u32 { 23 }
+    fn code(&self) -> u32 { 0x1b }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
         mach.valueStack.push(self.val1.clone());
         mach.valueStack.push(self.val2.clone());
-        mach.valueStack.push(self.val3.clone());
+        mach.valueStack.push(hash_value(params, &self.val3));
         let before = mach.clone();
-        let after = execute_select(params, &mach);
+        let after = execute_select(params, &mach, &self.val3);
         (before, after)
     }
 }
@@ -566,14 +622,14 @@ impl InstSelectHint {
         InstSelectHint {
             val1: Fr::from(0),
             val2: Fr::from(0),
-            val3: Fr::from(0),
+            val3: ValueHint::default(),
         }
     }
     fn convert(&self, cs: &ConstraintSystemRef<Fr>) -> InstSelect {
         InstSelect {
             val1: witness(&cs, &self.val1),
             val2: witness(&cs, &self.val2),
-            val3: witness(&cs, &self.val3),
+            val3: self.val3.convert(cs),
         }
     }
 }
@@ -586,6 +642,7 @@ pub fn execute_block(_params: &Params, mach: &MachineWithStack) -> MachineWithSt
     mach
 }
 
+#[derive(Debug, Clone)]
 struct InstBlockHint {
 }
 
@@ -593,7 +650,7 @@ struct InstBlock {
 }
 
 impl Inst for InstBlock {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x02 }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let before = mach.clone();
         let after = execute_block(params, &mach);
@@ -619,20 +676,18 @@ pub fn execute_branch(_params: &Params, mach: &MachineWithStack) -> MachineWithS
 }
 
 struct InstBranch {
-    val: FpVar<Fr>,
     block: FpVar<Fr>,
 }
 
+#[derive(Debug, Clone)]
 struct InstBranchHint {
-    val: Fr,
     block: Fr,
 }
 
 impl Inst for InstBranch {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x0c }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
-        mach.valueStack.push(self.val.clone());
         mach.blockStack.push(self.block.clone());
         let before = mach.clone();
         let after = execute_branch(params, &mach);
@@ -643,54 +698,53 @@ impl Inst for InstBranch {
 impl InstBranchHint {
     pub fn default() -> Self {
         InstBranchHint {
-            val: Fr::from(0),
             block: Fr::from(0),
         }
     }
     fn convert(&self, cs: &ConstraintSystemRef<Fr>) -> InstBranch {
         InstBranch {
-            val: witness(&cs, &self.val),
             block: witness(&cs, &self.block),
         }
     }
 }
 
-pub fn execute_branch_if(params: &Params, mach: &MachineWithStack) -> MachineWithStack {
+// Branches when the i32 selector is not zero
+pub fn execute_branch_if(params: &Params, mach: &MachineWithStack, selector: &Value) -> MachineWithStack {
     let mut mach = mach.clone();
-    let selector = mach.valueStack.pop();
+    let selector_hash = mach.valueStack.pop();
+    selector_hash.enforce_equal(&hash_value(params, selector)).unwrap();
+    mach.valid = mach.valid.and(&selector.ty.is_eq(&FpVar::constant(Fr::from(I32_TYPE))).unwrap()).unwrap();
 
-    let sel_bool = selector.is_eq(&FpVar::constant(Fr::from(0))).unwrap();
+    let stay = selector.value.is_eq(&FpVar::constant(Fr::from(0))).unwrap();
     // There are two alternative block stacks, they have to be computed here
     let mut bs_1 = mach.blockStack.clone();
     let bs_2 = mach.blockStack.clone();
-    let _popped = bs_1.pop();
+    let target = bs_1.pop();
 
-    mach.functionPc = sel_bool.select(&mach.blockStack.pop(), &mach.functionPc).unwrap();
-    mach.blockStack = Stack::based(sel_bool.select(&hash_stack(params, &bs_1), &hash_stack(params, &bs_2)).unwrap());
+    mach.functionPc = stay.select(&mach.functionPc, &target).unwrap();
+    mach.blockStack = Stack::based(stay.select(&hash_stack(params, &bs_2), &hash_stack(params, &bs_1)).unwrap());
     mach
 }
 
 struct InstBranchIf {
-    val1: FpVar<Fr>,
-    val2: FpVar<Fr>,
+    val: Value,
     block: FpVar<Fr>,
 }
 
+#[derive(Debug, Clone)]
 struct InstBranchIfHint {
-    val1: Fr,
-    val2: Fr,
+    val: ValueHint,
     block: Fr,
 }
 
 impl Inst for InstBranchIf {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x0d }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
-        mach.valueStack.push(self.val1.clone());
-        mach.valueStack.push(self.val2.clone());
+        mach.valueStack.push(hash_value(params, &self.val));
         mach.blockStack.push(self.block.clone());
         let before = mach.clone();
-        let after = execute_branch_if(params, &mach);
+        let after = execute_branch_if(params, &mach, &self.val);
         (before, after)
     }
 }
@@ -698,15 +752,13 @@ impl Inst for InstBranchIf {
 impl InstBranchIfHint {
     pub fn default() -> Self {
         InstBranchIfHint {
-            val1: Fr::from(0),
-            val2: Fr::from(0),
+            val: ValueHint::default(),
             block: Fr::from(0),
         }
     }
     fn convert(&self, cs: &ConstraintSystemRef<Fr>) -> InstBranchIf {
         InstBranchIf {
-            val1: witness(&cs, &self.val1),
-            val2: witness(&cs, &self.val2),
+            val: self.val.convert(cs),
             block: witness(&cs, &self.block),
         }
     }
@@ -748,6 +800,14 @@ impl StackFrameHint {
             callerModuleInternals: Fr::from(0),
         }
     }
+    fn hash(&self, params: &Params) -> Fr {
+        poseidon(&params, vec![
+            self.returnPc.hash(params),
+            self.localsMerkleRoot.clone(),
+            self.callerModule.clone(),
+            self.callerModuleInternals.clone(),
+        ])
+    }
     fn convert(&self, cs: &ConstraintSystemRef<Fr>) -> StackFrame {
         StackFrame {
             returnPc: self.returnPc.convert(cs),
@@ -780,6 +840,7 @@ pub fn execute_return(params: &Params, mach: &MachineWithStack, frame: &StackFra
     mach
 }
 
+#[derive(Debug, Clone)]
 struct InstReturnHint {
     frame: StackFrameHint,
 }
@@ -789,7 +850,7 @@ struct InstReturn {
 }
 
 impl Inst for InstReturn {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x0f }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
         mach.frameStack.push(hash_stack_frame(&params, &self.frame));
@@ -831,7 +892,7 @@ pub fn execute_call(params: &Params, mach: &MachineWithStack, frame: &StackFrame
     mach.valueStack.push(hash_value(params, &create_i32_value(frame.callerModule.clone())));
     mach.valueStack.push(hash_value(params, &create_i32_value(frame.callerModuleInternals.clone())));
     mach.functionIdx = mach.inst.argumentData.clone();
-    enforce_i32(mach.inst.argumentData.clone());
+    mach.valid = mach.valid.and(&is_i32(mach.inst.argumentData.clone())).unwrap();
     mach.functionPc = FpVar::constant(Fr::from(0));
     mach
 }
@@ -840,12 +901,13 @@ struct InstCall {
     frame: StackFrame,
 }
 
+#[derive(Debug, Clone)]
 struct InstCallHint {
     frame: StackFrameHint,
 }
 
 impl Inst for InstCall {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x10 }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
         mach.frameStack.push(hash_stack_frame(&params, &self.frame));
@@ -879,11 +941,12 @@ pub fn execute_cross_module_call(params: &Params, mach: &MachineWithStack) -> Ma
 struct InstCrossCall {
 }
 
+#[derive(Debug, Clone)]
 struct InstCrossCallHint {
 }
 
 impl Inst for InstCrossCall {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x8009 }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mach = mach.clone();
         let before = mach.clone();
@@ -917,6 +980,7 @@ struct InstLocalGet {
     proof: Proof,
 }
 
+#[derive(Debug, Clone)]
 struct InstLocalGetHint {
     frame: StackFrameHint,
     val: Fr,
@@ -924,7 +988,7 @@ struct InstLocalGetHint {
 }
 
 impl InstCS for InstLocalGet {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x20 }
     fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
         mach.frameStack.push(hash_stack_frame(&params, &self.frame));
@@ -973,6 +1037,7 @@ struct InstLocalSet {
     proof: Proof,
 }
 
+#[derive(Debug, Clone)]
 struct InstLocalSetHint {
     frame: StackFrameHint,
     val: Fr,
@@ -981,13 +1046,13 @@ struct InstLocalSetHint {
 }
 
 impl InstCS for InstLocalSet {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x21 }
     fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
         mach.frameStack.push(hash_stack_frame(&params, &self.frame));
-        mach.valueStack.push(self.old_val.clone());
+        mach.valueStack.push(self.val.clone());
         let before = mach.clone();
-        let after = execute_local_get(cs.clone(), params, &mach, &self.proof, self.val.clone(), &self.frame);
+        let after = execute_local_set(cs.clone(), params, &mach, &mach.inst, &self.proof, &self.old_val, &self.frame);
         (before, after)
     }
 }
@@ -1025,13 +1090,14 @@ struct InstGlobalGet {
     proof: Proof,
 }
 
+#[derive(Debug, Clone)]
 struct InstGlobalGetHint {
     val: Fr,
     proof: Proof,
 }
 
 impl InstCS for InstGlobalGet {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x23 }
     fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mach = mach.clone();
         let before = mach.clone();
@@ -1076,6 +1142,7 @@ struct InstGlobalSet {
     mod_proof: Proof,
 }
 
+#[derive(Debug, Clone)]
 struct InstGlobalSetHint {
     val: Fr,
     old_val: Fr,
@@ -1084,12 +1151,12 @@ struct InstGlobalSetHint {
 }
 
 impl InstCS for InstGlobalSet {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x24 }
     fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
-        mach.valueStack.push(self.old_val.clone());
+        mach.valueStack.push(self.val.clone());
         let before = mach.clone();
-        let after = execute_global_get(cs.clone(), params, &mach, &self.proof, self.val.clone());
+        let after = execute_global_set(cs.clone(), params, &mach, &self.proof, &self.old_val);
         let after = change_module(cs.clone(), params, &after, &before.mole, &self.mod_proof);
         (before, after)
     }
@@ -1114,17 +1181,18 @@ impl InstGlobalSetHint {
     }
 }
 
-// TODO: set module after global set
-
-pub fn execute_init_frame(params: &Params, mach: &MachineWithStack, returnPc: &Value) -> MachineWithStack {
+// Frame keeps the caller module as plain values, they are pushed again by call
+pub fn execute_init_frame(params: &Params, mach: &MachineWithStack, returnPc: &Value, callerModule: &FpVar<Fr>, callerModuleInternals: &FpVar<Fr>) -> MachineWithStack {
     let mut mach = mach.clone();
-    let callerModuleInternals = mach.valueStack.pop();
-    let callerModule = mach.valueStack.pop();
+    let callerModuleInternalsHash = mach.valueStack.pop();
+    let callerModuleHash = mach.valueStack.pop();
     let returnPcHash = mach.valueStack.pop();
-    hash_value(params, &returnPc).enforce_equal(&returnPcHash).unwrap();
+    mach.valid = mach.valid.and(&hash_value(params, &returnPc).is_eq(&returnPcHash).unwrap()).unwrap();
+    mach.valid = mach.valid.and(&hash_value(params, &create_i32_value(callerModule.clone())).is_eq(&callerModuleHash).unwrap()).unwrap();
+    mach.valid = mach.valid.and(&hash_value(params, &create_i32_value(callerModuleInternals.clone())).is_eq(&callerModuleInternalsHash).unwrap()).unwrap();
     let frame = StackFrame {
-        callerModuleInternals,
-        callerModule,
+        callerModuleInternals: callerModuleInternals.clone(),
+        callerModule: callerModule.clone(),
         returnPc: returnPc.clone(),
         localsMerkleRoot: mach.inst.argumentData.clone(),
     };
@@ -1133,28 +1201,27 @@ pub fn execute_init_frame(params: &Params, mach: &MachineWithStack, returnPc: &V
 }
 
 struct InstInitFrame {
-    val1: FpVar<Fr>,
-    val2: FpVar<Fr>,
-    val3: FpVar<Fr>,
     return_pc: Value,
+    caller_module: FpVar<Fr>,
+    caller_module_internals: FpVar<Fr>,
 }
 
+#[derive(Debug, Clone)]
 struct InstInitFrameHint {
-    val1: Fr,
-    val2: Fr,
-    val3: Fr,
     return_pc: ValueHint,
+    caller_module: Fr,
+    caller_module_internals: Fr,
 }
 
 impl Inst for InstInitFrame {
-    fn code() -> u32 { 234 }
+    fn code(&self) -> u32 { 0x8002 }
     fn execute_internal(&self, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
         let mut mach = mach.clone();
-        mach.valueStack.push(self.val1.clone());
-        mach.valueStack.push(self.val2.clone());
-        mach.valueStack.push(self.val3.clone());
+        mach.valueStack.push(hash_value(params, &self.return_pc));
+        mach.valueStack.push(hash_value(params, &create_i32_value(self.caller_module.clone())));
+        mach.valueStack.push(hash_value(params, &create_i32_value(self.caller_module_internals.clone())));
         let before = mach.clone();
-        let after = execute_init_frame(params, &mach, &self.return_pc);
+        let after = execute_init_frame(params, &mach, &self.return_pc, &self.caller_module, &self.caller_module_internals);
         (before, after)
     }
 }
@@ -1162,18 +1229,423 @@ impl Inst for InstInitFrame {
 impl InstInitFrameHint {
     pub fn default() -> Self {
         InstInitFrameHint {
-            val1: Fr::from(0),
-            val2: Fr::from(0),
-            val3: Fr::from(0),
             return_pc: ValueHint::default(),
+            caller_module: Fr::from(0),
+            caller_module_internals: Fr::from(0),
         }
     }
     pub fn convert(&self, cs: &ConstraintSystemRef<Fr>) -> InstInitFrame {
         InstInitFrame {
-            val1: witness(cs, &self.val1),
-            val2: witness(cs, &self.val2),
-            val3: witness(cs, &self.val3),
             return_pc: self.return_pc.convert(cs),
+            caller_module: witness(cs, &self.caller_module),
+            caller_module_internals: witness(cs, &self.caller_module_internals),
+        }
+    }
+}
+
+// Keccak precompile: pops the first input leaf, the length in bytes and the output leaf.
+// Linear memory is a tree of 32 byte leaves. The input starts at a leaf and is read from
+// keccak_leaves(blocks) leaves, the digest replaces the output leaf. The maximum number of
+// blocks is set on the machine, it is part of the shape of a keccak step.
+// One block keeps the step small but only fits messages shorter than BLOCK_BYTES (135 bytes
+// with the padding). Programs that hash longer messages set NativeMachine::keccak_blocks,
+// a message that doesn't fit traps in the native machine.
+#[cfg(feature = "keccak")]
+pub const KECCAK_DEFAULT_BLOCKS : usize = 1;
+#[cfg(feature = "keccak")]
+fn keccak_leaves(blocks: usize) -> usize {
+    (blocks*BLOCK_BYTES + 31) / 32
+}
+#[cfg(feature = "keccak")]
+const MEMORY_DEPTH : usize = 20;
+
+#[cfg(feature = "keccak")]
+fn bytes_to_leaf(bytes: &[UInt8<Fr>]) -> FpVar<Fr> {
+    let mut bits = vec![];
+    for b in bytes.iter() {
+        bits.extend(b.to_bits_le().unwrap());
+    }
+    Boolean::le_bits_to_fp_var(&bits).unwrap()
+}
+
+#[cfg(feature = "keccak")]
+fn execute_keccak(cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack, inst: &InstKeccak) -> MachineWithStack {
+    let mut mach = mach.clone();
+    let out_hash = mach.valueStack.pop();
+    let len_hash = mach.valueStack.pop();
+    let in_hash = mach.valueStack.pop();
+    mach.valid = mach.valid.and(&hash_value(params, &create_i32_value(inst.in_idx.clone())).is_eq(&in_hash).unwrap()).unwrap();
+    mach.valid = mach.valid.and(&hash_value(params, &create_i32_value(inst.len.clone())).is_eq(&len_hash).unwrap()).unwrap();
+    mach.valid = mach.valid.and(&hash_value(params, &create_i32_value(inst.out_idx.clone())).is_eq(&out_hash).unwrap()).unwrap();
+
+    for i in 0..keccak_leaves(inst.blocks) {
+        let leaf = bytes_to_leaf(&inst.bytes[i*32..(i+1)*32]);
+        let (root, idx) = make_path(cs.clone(), MEMORY_DEPTH, params, leaf, &inst.in_proofs[i]);
+        mach.valid = mach.valid.and(&root.is_eq(&mach.mole.moduleMemory).unwrap()).unwrap();
+        mach.valid = mach.valid.and(&idx.is_eq(&(inst.in_idx.clone() + FpVar::constant(Fr::from(i as u32)))).unwrap()).unwrap();
+    }
+    let digest = keccak256_gadget(&inst.bytes[0..inst.blocks*BLOCK_BYTES], &inst.len);
+
+    let (root, idx) = make_path(cs.clone(), MEMORY_DEPTH, params, inst.old_out.clone(), &inst.out_proof);
+    mach.valid = mach.valid.and(&root.is_eq(&mach.mole.moduleMemory).unwrap()).unwrap();
+    mach.valid = mach.valid.and(&idx.is_eq(&inst.out_idx).unwrap()).unwrap();
+    let (root2, idx2) = make_path(cs.clone(), MEMORY_DEPTH, params, bytes_to_leaf(&digest), &inst.out_proof);
+    idx2.enforce_equal(&idx).unwrap();
+    mach.mole.moduleMemory = root2;
+    mach
+}
+
+#[cfg(feature = "keccak")]
+struct InstKeccak {
+    blocks: usize,
+    in_idx: FpVar<Fr>,
+    len: FpVar<Fr>,
+    out_idx: FpVar<Fr>,
+    bytes: Vec<UInt8<Fr>>,
+    in_proofs: Vec<Proof>,
+    old_out: FpVar<Fr>,
+    out_proof: Proof,
+    mod_proof: Proof,
+}
+
+#[cfg(feature = "keccak")]
+#[derive(Debug, Clone)]
+struct InstKeccakHint {
+    blocks: usize,
+    in_idx: u64,
+    len: u64,
+    out_idx: u64,
+    bytes: Vec<u8>,
+    in_proofs: Vec<Proof>,
+    old_out: Fr,
+    out_proof: Proof,
+    mod_proof: Proof,
+}
+
+#[cfg(feature = "keccak")]
+impl InstCS for InstKeccak {
+    fn code(&self) -> u32 { 0x8010 }
+    fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
+        let mut mach = mach.clone();
+        mach.valueStack.push(hash_value(params, &create_i32_value(self.in_idx.clone())));
+        mach.valueStack.push(hash_value(params, &create_i32_value(self.len.clone())));
+        mach.valueStack.push(hash_value(params, &create_i32_value(self.out_idx.clone())));
+        let before = mach.clone();
+        let after = execute_keccak(cs.clone(), params, &mach, self);
+        let after = change_module(cs.clone(), params, &after, &before.mole, &self.mod_proof);
+        (before, after)
+    }
+}
+
+#[cfg(feature = "keccak")]
+impl InstKeccakHint {
+    pub fn default() -> Self {
+        InstKeccakHint {
+            blocks: KECCAK_DEFAULT_BLOCKS,
+            in_idx: 0,
+            len: 0,
+            out_idx: 0,
+            bytes: vec![0; keccak_leaves(KECCAK_DEFAULT_BLOCKS)*32],
+            in_proofs: vec![Proof::default(); keccak_leaves(KECCAK_DEFAULT_BLOCKS)],
+            old_out: Fr::from(0),
+            out_proof: Proof::default(),
+            mod_proof: Proof::default(),
+        }
+    }
+    fn convert(&self, cs: &ConstraintSystemRef<Fr>) -> InstKeccak {
+        InstKeccak {
+            blocks: self.blocks,
+            in_idx: witness(cs, &Fr::from(self.in_idx)),
+            len: witness(cs, &Fr::from(self.len)),
+            out_idx: witness(cs, &Fr::from(self.out_idx)),
+            bytes: UInt8::new_witness_vec(cs.clone(), &self.bytes).unwrap(),
+            in_proofs: self.in_proofs.clone(),
+            old_out: witness(cs, &self.old_out),
+            out_proof: self.out_proof.clone(),
+            mod_proof: self.mod_proof.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "float")]
+const I64_TYPE : u32 = 1u32;
+#[cfg(feature = "float")]
+const F32_TYPE : u32 = 2u32;
+#[cfg(feature = "float")]
+const F64_TYPE : u32 = 3u32;
+
+// Binary float operations: add, sub, mul, div, min, max, then the comparisons eq, ne, lt, gt, le, ge
+#[cfg(feature = "float")]
+const F32_BINOPS : [u32; 12] = [0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x5b, 0x5c, 0x5d, 0x5e, 0x5f, 0x60];
+#[cfg(feature = "float")]
+const F64_BINOPS : [u32; 12] = [0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66];
+
+// Unary float operations: sqrt, truncation to i32 signed and unsigned, to i64 signed and unsigned,
+// then promotion or demotion to the other float type
+#[cfg(feature = "float")]
+const F32_UNOPS : [u32; 6] = [0x91, 0xa8, 0xa9, 0xae, 0xaf, 0xbb];
+#[cfg(feature = "float")]
+const F64_UNOPS : [u32; 6] = [0x9f, 0xaa, 0xab, 0xb0, 0xb1, 0xb6];
+
+// Integer to float conversions: to f32 signed and unsigned, then to f64 signed and unsigned
+#[cfg(feature = "float")]
+const I32_TO_FLOAT : [u32; 4] = [0xb2, 0xb3, 0xb7, 0xb8];
+#[cfg(feature = "float")]
+const I64_TO_FLOAT : [u32; 4] = [0xb4, 0xb5, 0xb9, 0xba];
+
+#[cfg(feature = "float")]
+fn float_type(fmt: FloatFormat) -> u32 {
+    if fmt == F32 { F32_TYPE } else { F64_TYPE }
+}
+
+// Pushes the result selected by op. Results are (value, type, valid), the machine is invalid
+// if op selects nothing or the selected operation traps.
+#[cfg(feature = "float")]
+fn push_float_result(params: &Params, mach: &mut MachineWithStack, op: &FpVar<Fr>, results: Vec<(FpVar<Fr>, u32, Boolean<Fr>)>) {
+    let mut res = FpVar::constant(Fr::from(0));
+    let mut ty = FpVar::constant(Fr::from(0));
+    let mut valid = FpVar::constant(Fr::from(0));
+    for (i, (v, t, ok)) in results.into_iter().enumerate() {
+        let sel : FpVar<Fr> = From::from(op.is_eq(&FpVar::constant(Fr::from(i as u32))).unwrap());
+        res = res + v * sel.clone();
+        ty = ty + FpVar::constant(Fr::from(t)) * sel.clone();
+        let ok : FpVar<Fr> = From::from(ok);
+        valid = valid + ok * sel;
+    }
+    mach.valid = mach.valid.and(&valid.is_eq(&FpVar::constant(Fr::from(1))).unwrap()).unwrap();
+    mach.valueStack.push(hash_value(params, &Value { value: res, ty }));
+}
+
+// The committed opcode has to be the one of the operation op
+#[cfg(feature = "float")]
+fn check_float_opcode(after: &mut MachineWithStack, codes: &[u32], op: &FpVar<Fr>) {
+    let mut expected = FpVar::constant(Fr::from(0));
+    for (i, code) in codes.iter().enumerate() {
+        let sel : FpVar<Fr> = From::from(op.is_eq(&FpVar::constant(Fr::from(i as u32))).unwrap());
+        expected = expected + sel * FpVar::constant(Fr::from(*code));
+    }
+    after.valid = after.valid.and(&after.inst.opcode.is_eq(&expected).unwrap()).unwrap();
+}
+
+#[cfg(feature = "float")]
+pub fn execute_float_binop(cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack, fmt: FloatFormat, val1: &FloatVar, val2: &FloatVar, op: &FpVar<Fr>) -> MachineWithStack {
+    let mut mach = mach.clone();
+    let ty = FpVar::constant(Fr::from(float_type(fmt)));
+    let b = mach.valueStack.pop();
+    let a = mach.valueStack.pop();
+    a.enforce_equal(&hash_value(params, &Value { value: val1.to_fp(), ty: ty.clone() })).unwrap();
+    b.enforce_equal(&hash_value(params, &Value { value: val2.to_fp(), ty })).unwrap();
+    let num = |r: FloatVar| (r.to_fp(), float_type(fmt), Boolean::TRUE);
+    let cmp = |r: Boolean<Fr>| (FpVar::from(r), I32_TYPE, Boolean::TRUE);
+    let results = vec![
+        num(float::add_gadget(&cs, val1, val2)),
+        num(float::sub_gadget(&cs, val1, val2)),
+        num(float::mul_gadget(&cs, val1, val2)),
+        num(float::div_gadget(&cs, val1, val2)),
+        num(float::min_gadget(&cs, val1, val2)),
+        num(float::max_gadget(&cs, val1, val2)),
+        cmp(float::eq_gadget(&cs, val1, val2)),
+        cmp(float::ne_gadget(&cs, val1, val2)),
+        cmp(float::lt_gadget(&cs, val1, val2)),
+        cmp(float::gt_gadget(&cs, val1, val2)),
+        cmp(float::le_gadget(&cs, val1, val2)),
+        cmp(float::ge_gadget(&cs, val1, val2)),
+    ];
+    push_float_result(params, &mut mach, op, results);
+    mach
+}
+
+#[cfg(feature = "float")]
+pub fn execute_float_unop(cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack, fmt: FloatFormat, val: &FloatVar, op: &FpVar<Fr>) -> MachineWithStack {
+    let mut mach = mach.clone();
+    let a = mach.valueStack.pop();
+    a.enforce_equal(&hash_value(params, &Value { value: val.to_fp(), ty: FpVar::constant(Fr::from(float_type(fmt))) })).unwrap();
+    let other = if fmt == F32 { F64 } else { F32 };
+    let (i32_s, i32_s_ok) = float::trunc_gadget(&cs, val, 32, true);
+    let (i32_u, i32_u_ok) = float::trunc_gadget(&cs, val, 32, false);
+    let (i64_s, i64_s_ok) = float::trunc_gadget(&cs, val, 64, true);
+    let (i64_u, i64_u_ok) = float::trunc_gadget(&cs, val, 64, false);
+    let results = vec![
+        (float::sqrt_gadget(&cs, val).to_fp(), float_type(fmt), Boolean::TRUE),
+        (i32_s, I32_TYPE, i32_s_ok),
+        (i32_u, I32_TYPE, i32_u_ok),
+        (i64_s, I64_TYPE, i64_s_ok),
+        (i64_u, I64_TYPE, i64_u_ok),
+        (float::convert_gadget(&cs, val, other).to_fp(), float_type(other), Boolean::TRUE),
+    ];
+    push_float_result(params, &mut mach, op, results);
+    mach
+}
+
+// val is an integer with the given number of bits
+#[cfg(feature = "float")]
+pub fn execute_int_to_float(cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack, bits: usize, val: &FpVar<Fr>, op: &FpVar<Fr>) -> MachineWithStack {
+    let mut mach = mach.clone();
+    let ty = if bits == 32 { I32_TYPE } else { I64_TYPE };
+    let a = mach.valueStack.pop();
+    a.enforce_equal(&hash_value(params, &Value { value: val.clone(), ty: FpVar::constant(Fr::from(ty)) })).unwrap();
+    let x = float::to_bits_n(&cs, val, bits);
+    let num = |r: FloatVar| (r.to_fp(), float_type(r.fmt), Boolean::TRUE);
+    let results = vec![
+        num(float::from_int_gadget(&cs, F32, &x, true)),
+        num(float::from_int_gadget(&cs, F32, &x, false)),
+        num(float::from_int_gadget(&cs, F64, &x, true)),
+        num(float::from_int_gadget(&cs, F64, &x, false)),
+    ];
+    push_float_result(params, &mut mach, op, results);
+    mach
+}
+
+#[cfg(feature = "float")]
+struct InstFloatBinop {
+    fmt: FloatFormat,
+    val1: FloatVar,
+    val2: FloatVar,
+    op: FpVar<Fr>,
+}
+
+#[cfg(feature = "float")]
+#[derive(Debug, Clone)]
+struct InstFloatBinopHint {
+    val1: u64,
+    val2: u64,
+    op: u32,
+}
+
+#[cfg(feature = "float")]
+impl InstCS for InstFloatBinop {
+    fn code(&self) -> u32 {
+        self.opcodes()[0]
+    }
+    fn opcodes(&self) -> Vec<u32> {
+        if self.fmt == F32 { F32_BINOPS.to_vec() } else { F64_BINOPS.to_vec() }
+    }
+    fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
+        let ty = FpVar::constant(Fr::from(float_type(self.fmt)));
+        let mut mach = mach.clone();
+        mach.valueStack.push(hash_value(params, &Value { value: self.val1.to_fp(), ty: ty.clone() }));
+        mach.valueStack.push(hash_value(params, &Value { value: self.val2.to_fp(), ty }));
+        let before = mach.clone();
+        let after = execute_float_binop(cs, params, &mach, self.fmt, &self.val1, &self.val2, &self.op);
+        (before, after)
+    }
+    // opcode depends on the operation
+    fn execute(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
+        let (before, mut after) = self.execute_internal(cs, params, &next_pc(mach));
+        let before = restore_pc(&before, mach);
+        check_float_opcode(&mut after, &self.opcodes(), &self.op);
+        (before, after)
+    }
+}
+
+#[cfg(feature = "float")]
+impl InstFloatBinopHint {
+    pub fn default() -> Self {
+        InstFloatBinopHint {
+            val1: 0,
+            val2: 0,
+            op: 0,
+        }
+    }
+    fn convert(&self, cs: &ConstraintSystemRef<Fr>, fmt: FloatFormat) -> InstFloatBinop {
+        InstFloatBinop {
+            fmt,
+            val1: FloatVar::new_witness(cs, fmt, self.val1),
+            val2: FloatVar::new_witness(cs, fmt, self.val2),
+            op: witness(cs, &Fr::from(self.op)),
+        }
+    }
+}
+
+#[cfg(feature = "float")]
+struct InstFloatUnop {
+    fmt: FloatFormat,
+    val: FloatVar,
+    op: FpVar<Fr>,
+}
+
+#[cfg(feature = "float")]
+struct InstIntToFloat {
+    bits: usize,
+    val: FpVar<Fr>,
+    op: FpVar<Fr>,
+}
+
+// Operand bits and the operation, for both unary float operations and integer conversions
+#[cfg(feature = "float")]
+#[derive(Debug, Clone)]
+struct InstFloatUnopHint {
+    val: u64,
+    op: u32,
+}
+
+#[cfg(feature = "float")]
+impl InstCS for InstFloatUnop {
+    fn code(&self) -> u32 {
+        self.opcodes()[0]
+    }
+    fn opcodes(&self) -> Vec<u32> {
+        if self.fmt == F32 { F32_UNOPS.to_vec() } else { F64_UNOPS.to_vec() }
+    }
+    fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
+        let mut mach = mach.clone();
+        mach.valueStack.push(hash_value(params, &Value { value: self.val.to_fp(), ty: FpVar::constant(Fr::from(float_type(self.fmt))) }));
+        let before = mach.clone();
+        let after = execute_float_unop(cs, params, &mach, self.fmt, &self.val, &self.op);
+        (before, after)
+    }
+    fn execute(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
+        let (before, mut after) = self.execute_internal(cs, params, &next_pc(mach));
+        let before = restore_pc(&before, mach);
+        check_float_opcode(&mut after, &self.opcodes(), &self.op);
+        (before, after)
+    }
+}
+
+#[cfg(feature = "float")]
+impl InstCS for InstIntToFloat {
+    fn code(&self) -> u32 {
+        self.opcodes()[0]
+    }
+    fn opcodes(&self) -> Vec<u32> {
+        if self.bits == 32 { I32_TO_FLOAT.to_vec() } else { I64_TO_FLOAT.to_vec() }
+    }
+    fn execute_internal(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
+        let ty = if self.bits == 32 { I32_TYPE } else { I64_TYPE };
+        let mut mach = mach.clone();
+        mach.valueStack.push(hash_value(params, &Value { value: self.val.clone(), ty: FpVar::constant(Fr::from(ty)) }));
+        let before = mach.clone();
+        let after = execute_int_to_float(cs, params, &mach, self.bits, &self.val, &self.op);
+        (before, after)
+    }
+    fn execute(&self, cs: ConstraintSystemRef<Fr>, params: &Params, mach: &MachineWithStack) -> (MachineWithStack, MachineWithStack) {
+        let (before, mut after) = self.execute_internal(cs, params, &next_pc(mach));
+        let before = restore_pc(&before, mach);
+        check_float_opcode(&mut after, &self.opcodes(), &self.op);
+        (before, after)
+    }
+}
+
+#[cfg(feature = "float")]
+impl InstFloatUnopHint {
+    pub fn default() -> Self {
+        InstFloatUnopHint {
+            val: 0,
+            op: 0,
+        }
+    }
+    fn convert(&self, cs: &ConstraintSystemRef<Fr>, fmt: FloatFormat) -> InstFloatUnop {
+        InstFloatUnop {
+            fmt,
+            val: FloatVar::new_witness(cs, fmt, self.val),
+            op: witness(cs, &Fr::from(self.op)),
+        }
+    }
+    fn convert_int(&self, cs: &ConstraintSystemRef<Fr>, bits: usize) -> InstIntToFloat {
+        InstIntToFloat {
+            bits,
+            val: witness(cs, &Fr::from(self.val)),
+            op: witness(cs, &Fr::from(self.op)),
         }
     }
 }
@@ -1183,6 +1655,7 @@ impl InstInitFrameHint {
 in the end, maybe just select a valid alternative
 */
 
+#[derive(Debug, Clone)]
 enum InstProof {
     ConstI32(InstConstHint),
     ConstI64(InstConstHint),
@@ -1201,6 +1674,20 @@ enum InstProof {
     GlobalGet(InstGlobalGetHint),
     GlobalSet(InstGlobalSetHint),
     InitFrame(InstInitFrameHint),
+    #[cfg(feature = "float")]
+    F32Binop(InstFloatBinopHint),
+    #[cfg(feature = "float")]
+    F64Binop(InstFloatBinopHint),
+    #[cfg(feature = "float")]
+    F32Unop(InstFloatUnopHint),
+    #[cfg(feature = "float")]
+    F64Unop(InstFloatUnopHint),
+    #[cfg(feature = "float")]
+    I32ToFloat(InstFloatUnopHint),
+    #[cfg(feature = "float")]
+    I64ToFloat(InstFloatUnopHint),
+    #[cfg(feature = "keccak")]
+    Keccak(InstKeccakHint),
 }
 
 struct InstWitness {
@@ -1221,9 +1708,22 @@ struct InstWitness {
     global_get: InstGlobalGet,
     global_set: InstGlobalSet,
     init_frame: InstInitFrame,
-}
-
-fn proof_to_witness(proof: InstProof, cs: ConstraintSystemRef<Fr>) -> InstWitness {
+    #[cfg(feature = "float")]
+    f32_binop: InstFloatBinop,
+    #[cfg(feature = "float")]
+    f64_binop: InstFloatBinop,
+    #[cfg(feature = "float")]
+    f32_unop: InstFloatUnop,
+    #[cfg(feature = "float")]
+    f64_unop: InstFloatUnop,
+    #[cfg(feature = "float")]
+    i32_to_float: InstIntToFloat,
+    #[cfg(feature = "float")]
+    i64_to_float: InstIntToFloat,
+}
+
+// Keccak steps have their own circuit, they don't have a witness among the basic instructions
+fn proof_to_witness(proof: InstProof, cs: ConstraintSystemRef<Fr>) -> Result<InstWitness, SynthesisError> {
     let mut hint_const_i32 = InstConstHint::default();
     let mut hint_const_i64 = InstConstHint::default();
     let mut hint_const_f32 = InstConstHint::default();
@@ -1241,6 +1741,18 @@ fn proof_to_witness(proof: InstProof, cs: ConstraintSystemRef<Fr>) -> InstWitnes
     let mut hint_global_get = InstGlobalGetHint::default();
     let mut hint_global_set = InstGlobalSetHint::default();
     let mut hint_init_frame = InstInitFrameHint::default();
+    #[cfg(feature = "float")]
+    let mut hint_f32_binop = InstFloatBinopHint::default();
+    #[cfg(feature = "float")]
+    let mut hint_f64_binop = InstFloatBinopHint::default();
+    #[cfg(feature = "float")]
+    let mut hint_f32_unop = InstFloatUnopHint::default();
+    #[cfg(feature = "float")]
+    let mut hint_f64_unop = InstFloatUnopHint::default();
+    #[cfg(feature = "float")]
+    let mut hint_i32_to_float = InstFloatUnopHint::default();
+    #[cfg(feature = "float")]
+    let mut hint_i64_to_float = InstFloatUnopHint::default();
     use crate::machine::InstProof::*;
     match proof {
         ConstI32(hint) => {
@@ -1294,8 +1806,34 @@ fn proof_to_witness(proof: InstProof, cs: ConstraintSystemRef<Fr>) -> InstWitnes
         InitFrame(hint) => {
             hint_init_frame = hint;
         }
+        #[cfg(feature = "float")]
+        F32Binop(hint) => {
+            hint_f32_binop = hint;
+        }
+        #[cfg(feature = "float")]
+        F64Binop(hint) => {
+            hint_f64_binop = hint;
+        }
+        #[cfg(feature = "float")]
+        F32Unop(hint) => {
+            hint_f32_unop = hint;
+        }
+        #[cfg(feature = "float")]
+        F64Unop(hint) => {
+            hint_f64_unop = hint;
+        }
+        #[cfg(feature = "float")]
+        I32ToFloat(hint) => {
+            hint_i32_to_float = hint;
+        }
+        #[cfg(feature = "float")]
+        I64ToFloat(hint) => {
+            hint_i64_to_float = hint;
+        }
+        #[cfg(feature = "keccak")]
+        Keccak(_) => return Err(SynthesisError::Unsatisfiable),
     };
-    InstWitness {
+    Ok(InstWitness {
         const_i32: hint_const_i32.convert(&cs, 0),
         const_i64: hint_const_i64.convert(&cs, 1),
         const_f32: hint_const_f32.convert(&cs, 2),
@@ -1313,26 +1851,119 @@ fn proof_to_witness(proof: InstProof, cs: ConstraintSystemRef<Fr>) -> InstWitnes
         global_get: hint_global_get.convert(&cs),
         global_set: hint_global_set.convert(&cs),
         init_frame: hint_init_frame.convert(&cs),
+        #[cfg(feature = "float")]
+        f32_binop: hint_f32_binop.convert(&cs, F32),
+        #[cfg(feature = "float")]
+        f64_binop: hint_f64_binop.convert(&cs, F64),
+        #[cfg(feature = "float")]
+        f32_unop: hint_f32_unop.convert(&cs, F32),
+        #[cfg(feature = "float")]
+        f64_unop: hint_f64_unop.convert(&cs, F64),
+        #[cfg(feature = "float")]
+        i32_to_float: hint_i32_to_float.convert_int(&cs, 32),
+        #[cfg(feature = "float")]
+        i64_to_float: hint_i64_to_float.convert_int(&cs, 64),
+    })
+}
+
+// Opcodes selecting the alternative, and the machine before and after executing it
+type Alternative = (Vec<u32>, (MachineWithStack, MachineWithStack));
+
+// One-hot selector derived from the committed opcode. Valid flags depend on witnesses that
+// the prover chooses, so they are only used to check that the selected alternative is valid.
+fn instruction_selectors(v: &[Alternative]) -> Vec<FpVar<Fr>> {
+    let mut codes : Vec<u32> = v.iter().flat_map(|(codes, _)| codes.clone()).collect();
+    let num_codes = codes.len();
+    codes.sort();
+    codes.dedup();
+    assert_eq!(codes.len(), num_codes, "alternatives must have different opcodes");
+
+    let mut found = FpVar::constant(Fr::from(0));
+    let mut valid = FpVar::constant(Fr::from(0));
+    let mut sel = vec![];
+    for (codes, (_be,af)) in v.iter() {
+        let mut is_selected = FpVar::constant(Fr::from(0));
+        for code in codes.iter() {
+            let is_code : FpVar<Fr> = From::from(af.inst.opcode.is_eq(&FpVar::constant(Fr::from(*code))).unwrap());
+            is_selected = is_selected + is_code;
+        }
+        let is_valid : FpVar<Fr> = From::from(af.valid.clone());
+        found = found + is_selected.clone();
+        valid = valid + is_selected.clone() * is_valid;
+        sel.push(is_selected);
     }
+    found.enforce_equal(&FpVar::constant(Fr::from(1))).unwrap();
+    valid.enforce_equal(&FpVar::constant(Fr::from(1))).unwrap();
+    sel
 }
 
-fn select_machine(params: &Params, v: Vec<(MachineWithStack, MachineWithStack)>) -> (FpVar<Fr>, FpVar<Fr>) {
-    let mut valid = FpVar::constant(Fr::from(0));
+// Hashes every alternative separately, only used to compare constraint counts
+#[cfg(test)]
+fn select_machine_unshared(params: &Params, v: Vec<Alternative>) -> (FpVar<Fr>, FpVar<Fr>) {
+    let sel = instruction_selectors(&v);
     let mut before = FpVar::constant(Fr::from(0));
     let mut after = FpVar::constant(Fr::from(0));
-    for (be,af) in v {
-        let is_valid : FpVar<Fr> = From::from(af.valid.clone());
-        valid = valid + is_valid.clone();
+    for (is_selected, (_codes, (be,af))) in sel.into_iter().zip(v.into_iter()) {
         let hash_be = hash_machine_with_stack(params, &be);
         let hash_af = hash_machine_with_stack(params, &af);
-        before = before + hash_be*is_valid.clone();
-        after = after + hash_af*is_valid.clone();
+        before = before + hash_be*is_selected.clone();
+        after = after + hash_af*is_selected;
     }
-    valid.enforce_equal(&FpVar::constant(Fr::from(1))).unwrap();
     (before, after)
 }
 
-fn make_proof(
+fn select_field(sel: &[FpVar<Fr>], v: Vec<FpVar<Fr>>) -> FpVar<Fr> {
+    let mut res = FpVar::constant(Fr::from(0));
+    for (s, x) in sel.iter().zip(v.into_iter()) {
+        res = res + s.clone() * x;
+    }
+    res
+}
+
+// All alternatives have a stack of the form base + pushed values. The base and the
+// value at each position are selected, and one hash per position is enough.
+fn select_stack(params: &Params, sel: &[FpVar<Fr>], stacks: Vec<&Stack>) -> FpVar<Fr> {
+    let mut root = select_field(sel, stacks.iter().map(|st| st.base.clone()).collect());
+    let max_len = stacks.iter().map(|st| st.values.len()).max().unwrap_or(0);
+    for i in 0..max_len {
+        let mut active = FpVar::constant(Fr::from(0));
+        let mut el = FpVar::constant(Fr::from(0));
+        for (s, st) in sel.iter().zip(stacks.iter()) {
+            if st.values.len() > i {
+                active = active + s.clone();
+                el = el + s.clone() * st.values[i].clone();
+            }
+        }
+        let hashed = poseidon_gadget(&params, vec![el, root.clone()]);
+        root = root.clone() + active * (hashed - root);
+    }
+    root
+}
+
+fn select_components(params: &Params, sel: &[FpVar<Fr>], v: Vec<&MachineWithStack>) -> Machine {
+    Machine {
+        valueStack : select_stack(params, sel, v.iter().map(|m| &m.valueStack).collect()),
+        internalStack : select_stack(params, sel, v.iter().map(|m| &m.internalStack).collect()),
+        blockStack : select_stack(params, sel, v.iter().map(|m| &m.blockStack).collect()),
+        frameStack : select_stack(params, sel, v.iter().map(|m| &m.frameStack).collect()),
+
+        globalStateHash : select_field(sel, v.iter().map(|m| m.globalStateHash.clone()).collect()),
+        moduleIdx : select_field(sel, v.iter().map(|m| m.moduleIdx.clone()).collect()),
+        functionIdx : select_field(sel, v.iter().map(|m| m.functionIdx.clone()).collect()),
+        functionPc : select_field(sel, v.iter().map(|m| m.functionPc.clone()).collect()),
+        modulesRoot : select_field(sel, v.iter().map(|m| m.modulesRoot.clone()).collect()),
+    }
+}
+
+// Components of the selected alternative are selected first, so the machine is hashed only once
+fn select_machine(params: &Params, v: Vec<Alternative>) -> (FpVar<Fr>, FpVar<Fr>) {
+    let sel = instruction_selectors(&v);
+    let before = select_components(params, &sel, v.iter().map(|(_codes, (be,_af))| be).collect());
+    let after = select_components(params, &sel, v.iter().map(|(_codes, (_be,af))| af).collect());
+    (hash_machine(params, &before), hash_machine(params, &after))
+}
+
+fn execute_alternatives(
     cs: ConstraintSystemRef<Fr>,
     params: &Params,
     machine_hint: &MachineHint,
@@ -1342,7 +1973,7 @@ fn make_proof(
     mod_proof: &Proof,
     inst_proof: &Proof,
     func_proof: &Proof
-) -> (FpVar<Fr>, FpVar<Fr>) {
+) -> Result<Vec<Alternative>, SynthesisError> {
     let base_machine = machine_hint.convert(cs.clone());
     let inst = convert_instruction(inst, cs.clone());
     let mole = mole.convert(cs.clone());
@@ -1362,26 +1993,32 @@ fn make_proof(
     );
 
     let base_machine = intro_stack(&base_machine, &inst, &mole);
-    let witness = proof_to_witness(proof, cs.clone());
-    let const_i32 = witness.const_i32.execute(params, &base_machine);
-    let const_i64 = witness.const_i64.execute(params, &base_machine);
-    let const_f32 = witness.const_f32.execute(params, &base_machine);
-    let const_f64 = witness.const_f64.execute(params, &base_machine);
-    let drop = witness.drop.execute(params, &base_machine);
-    let select = witness.select.execute(params, &base_machine);
-    let branch = witness.branch.execute(params, &base_machine);
-    let branch_if = witness.branch_if.execute(params, &base_machine);
-    let block = witness.block.execute(params, &base_machine);
-    let retvrn = witness.retvrn.execute(params, &base_machine);
-    let call = witness.call.execute(params, &base_machine);
-    let cross_call = witness.cross_call.execute(params, &base_machine);
-    let local_get = witness.local_get.execute(cs.clone(), params, &base_machine);
-    let local_set = witness.local_set.execute(cs.clone(), params, &base_machine);
-    let global_get = witness.global_get.execute(cs.clone(), params, &base_machine);
-    let global_set = witness.global_set.execute(cs.clone(), params, &base_machine);
-    let init_frame = witness.init_frame.execute(params, &base_machine);
-
-    select_machine(params, vec![
+    #[cfg(feature = "keccak")]
+    if let InstProof::Keccak(hint) = &proof {
+        let keccak = hint.convert(&cs);
+        return Ok(vec![(keccak.opcodes(), keccak.execute(cs.clone(), params, &base_machine))]);
+    }
+    let witness = proof_to_witness(proof, cs.clone())?;
+    let const_i32 = (witness.const_i32.opcodes(), witness.const_i32.execute(params, &base_machine));
+    let const_i64 = (witness.const_i64.opcodes(), witness.const_i64.execute(params, &base_machine));
+    let const_f32 = (witness.const_f32.opcodes(), witness.const_f32.execute(params, &base_machine));
+    let const_f64 = (witness.const_f64.opcodes(), witness.const_f64.execute(params, &base_machine));
+    let drop = (witness.drop.opcodes(), witness.drop.execute(params, &base_machine));
+    let select = (witness.select.opcodes(), witness.select.execute(params, &base_machine));
+    let branch = (witness.branch.opcodes(), witness.branch.execute(params, &base_machine));
+    let branch_if = (witness.branch_if.opcodes(), witness.branch_if.execute(params, &base_machine));
+    let block = (witness.block.opcodes(), witness.block.execute(params, &base_machine));
+    let retvrn = (witness.retvrn.opcodes(), witness.retvrn.execute(params, &base_machine));
+    let call = (witness.call.opcodes(), witness.call.execute(params, &base_machine));
+    let cross_call = (witness.cross_call.opcodes(), witness.cross_call.execute(params, &base_machine));
+    let local_get = (witness.local_get.opcodes(), witness.local_get.execute(cs.clone(), params, &base_machine));
+    let local_set = (witness.local_set.opcodes(), witness.local_set.execute(cs.clone(), params, &base_machine));
+    let global_get = (witness.global_get.opcodes(), witness.global_get.execute(cs.clone(), params, &base_machine));
+    let global_set = (witness.global_set.opcodes(), witness.global_set.execute(cs.clone(), params, &base_machine));
+    let init_frame = (witness.init_frame.opcodes(), witness.init_frame.execute(params, &base_machine));
+
+    #[allow(unused_mut)]
+    let mut alternatives = vec![
         const_i32,
         const_i64,
         const_f32,
@@ -1399,7 +2036,108 @@ fn make_proof(
         global_get,
         global_set,
         init_frame,
-    ])
+    ];
+    #[cfg(feature = "float")]
+    {
+        alternatives.push((witness.f32_binop.opcodes(), witness.f32_binop.execute(cs.clone(), params, &base_machine)));
+        alternatives.push((witness.f64_binop.opcodes(), witness.f64_binop.execute(cs.clone(), params, &base_machine)));
+        alternatives.push((witness.f32_unop.opcodes(), witness.f32_unop.execute(cs.clone(), params, &base_machine)));
+        alternatives.push((witness.f64_unop.opcodes(), witness.f64_unop.execute(cs.clone(), params, &base_machine)));
+        alternatives.push((witness.i32_to_float.opcodes(), witness.i32_to_float.execute(cs.clone(), params, &base_machine)));
+        alternatives.push((witness.i64_to_float.opcodes(), witness.i64_to_float.execute(cs.clone(), params, &base_machine)));
+    }
+    Ok(alternatives)
+}
+
+fn make_proof(
+    cs: ConstraintSystemRef<Fr>,
+    params: &Params,
+    machine_hint: &MachineHint,
+    proof: InstProof,
+    inst: InstructionHint,
+    mole: &ModuleHint,
+    mod_proof: &Proof,
+    inst_proof: &Proof,
+    func_proof: &Proof
+) -> Result<(FpVar<Fr>, FpVar<Fr>), SynthesisError> {
+    let alternatives = execute_alternatives(cs, params, machine_hint, proof, inst, mole, mod_proof, inst_proof, func_proof)?;
+    Ok(select_machine(params, alternatives))
+}
+
+// Witnesses for one step of the machine
+#[derive(Debug, Clone)]
+pub struct MachineStep {
+    machine: MachineHint,
+    proof: InstProof,
+    inst: InstructionHint,
+    mole: ModuleHint,
+    mod_proof: Proof,
+    inst_proof: Proof,
+    func_proof: Proof,
+}
+
+// Kinds of steps that have different circuits, so that the other steps don't pay for precompiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepKind {
+    Basic,
+    #[cfg(feature = "keccak")]
+    Keccak,
+}
+
+impl MachineStep {
+    pub fn kind(&self) -> StepKind {
+        match self.proof {
+            #[cfg(feature = "keccak")]
+            InstProof::Keccak(_) => StepKind::Keccak,
+            _ => StepKind::Basic,
+        }
+    }
+}
+
+// Chains the steps, the after hash of a step is the before hash of the next one.
+// Only the first and last machine hashes are public inputs. The shape of the circuit
+// depends on the kinds of the steps, so a key is for one sequence of step kinds.
+#[derive(Debug, Clone)]
+pub struct MachineCircuit {
+    params: Params,
+    steps: Vec<MachineStep>,
+}
+
+impl ConstraintSynthesizer<Fr> for MachineCircuit {
+    fn generate_constraints(
+        self,
+        cs: ConstraintSystemRef<Fr>,
+    ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        let mut first = None;
+        let mut last : Option<FpVar<Fr>> = None;
+        for step in self.steps {
+            let (before, after) = make_proof(
+                cs.clone(),
+                &self.params,
+                &step.machine,
+                step.proof,
+                step.inst,
+                &step.mole,
+                &step.mod_proof,
+                &step.inst_proof,
+                &step.func_proof,
+            )?;
+            match last {
+                Some(prev) => prev.enforce_equal(&before)?,
+                None => first = Some(before),
+            }
+            last = Some(after);
+        }
+        // no steps, nothing to chain
+        let first = first.ok_or(SynthesisError::Unsatisfiable)?;
+        let last = last.ok_or(SynthesisError::Unsatisfiable)?;
+        let first_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || first.value()).unwrap());
+        let last_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || last.value()).unwrap());
+        first_var.enforce_equal(&first)?;
+        last_var.enforce_equal(&last)?;
+        Ok(())
+    }
 }
 
 pub fn test() {
@@ -1417,12 +2155,1057 @@ pub fn test() {
         &params,
         &MachineHint::default(),
         InstProof::Drop(InstDropHint::default()),
-        InstructionHint::default(),
+        InstructionHint { opcode: 0x1a, argumentData: 0 },
         &ModuleHint::default(),
         &Proof::default(),
         &Proof::default(),
         &Proof::default(),
-    );
-    println!("constraints {}", cs.num_constraints());
+    ).unwrap();
+    tracing::debug!(constraints = cs.num_constraints(), "machine step");
+}
+
+
+#[test]
+fn test_shared_stack_hashing() {
+    use ark_relations::r1cs::ConstraintSystem;
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let run = |shared: bool| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let alternatives = execute_alternatives(
+            cs.clone(),
+            &params,
+            &MachineHint::default(),
+            InstProof::Select(InstSelectHint::default()),
+            InstructionHint { opcode: 0x1b, argumentData: 0 },
+            &ModuleHint::default(),
+            &Proof::default(),
+            &Proof::default(),
+            &Proof::default(),
+        ).unwrap();
+        let start = cs.num_constraints();
+        let (before, after) = if shared {
+            select_machine(&params, alternatives)
+        } else {
+            select_machine_unshared(&params, alternatives)
+        };
+        (cs.num_constraints() - start, before.value().unwrap(), after.value().unwrap())
+    };
+    let (shared, before1, after1) = run(true);
+    let (unshared, before2, after2) = run(false);
+    tracing::debug!(shared, unshared, "selection constraints");
+    assert_eq!(before1, before2);
+    assert_eq!(after1, after2);
+    assert!(shared * 3 < unshared);
 }
 
+/* Native model of linked modules.
+   The module table is committed as modulesRoot, a tree of module hashes indexed by moduleIdx.
+   Imported functions become stubs that make a cross module call to the export.
+*/
+
+// Module before linking. Like in Wasm, imported functions come first in the function index space.
+#[derive(Debug, Clone)]
+pub struct ModuleCode {
+    pub name: String,
+    pub imports: Vec<(String, String)>,
+    pub exports: Vec<(String, u64)>,
+    pub functions: Vec<Vec<(u64, u64)>>,
+    pub globals: Vec<(u128, u32)>,
+    pub memory: Vec<u8>,
+    pub internals_offset: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LinkedModule {
+    functions: Vec<Vec<InstructionHint>>,
+    globals: Vec<ValueHint>,
+    memory: Vec<u8>,
+    internals_offset: u64,
+}
+
+fn cross_module_call_arg(module: u64, func: u64) -> u64 {
+    func | (module << 32)
+}
+
+pub fn link(codes: &[ModuleCode]) -> Result<Vec<LinkedModule>, String> {
+    let mut res = vec![];
+    for code in codes.iter() {
+        let mut functions = vec![];
+        for (module_name, name) in code.imports.iter() {
+            let module = codes.iter().position(|c| &c.name == module_name)
+                .ok_or(format!("import from unknown module {}", module_name))?;
+            let func = codes[module].exports.iter().find(|(n, _)| n == name)
+                .ok_or(format!("{} is not exported by {}", name, module_name))?.1;
+            functions.push(vec![
+                InstructionHint { opcode: 0x8002, argumentData: 0 },
+                InstructionHint { opcode: 0x8009, argumentData: cross_module_call_arg(module as u64, func) },
+                InstructionHint { opcode: 0x0f, argumentData: 0 },
+            ]);
+        }
+        for f in code.functions.iter() {
+            functions.push(f.iter().map(|(opcode, arg)| InstructionHint { opcode: *opcode, argumentData: *arg }).collect());
+        }
+        let mut memory = code.memory.clone();
+        memory.resize((memory.len() + 31) / 32 * 32, 0);
+        res.push(LinkedModule {
+            functions,
+            globals: code.globals.iter().map(|(value, ty)| ValueHint { value: *value, ty: *ty }).collect(),
+            memory,
+            internals_offset: code.internals_offset,
+        })
+    }
+    Ok(res)
+}
+
+impl LinkedModule {
+    fn function_roots(&self, params: &Params) -> Vec<Fr> {
+        self.functions.iter().map(|f| {
+            merkle_root(params, &f.iter().map(|inst| inst.hash(params)).collect::<Vec<Fr>>())
+        }).collect()
+    }
+    fn global_hashes(&self, params: &Params) -> Vec<Fr> {
+        self.globals.iter().map(|v| v.hash(params)).collect()
+    }
+    // Memory leaves are 32 bytes as little endian numbers
+    fn memory_leaves(&self) -> Vec<Fr> {
+        self.memory.chunks(32).map(|leaf| Fr::from_le_bytes_mod_order(leaf)).collect()
+    }
+    fn hint(&self, params: &Params) -> ModuleHint {
+        ModuleHint {
+            globalsMerkleRoot: merkle_root(params, &self.global_hashes(params)),
+            moduleMemory: merkle_root(params, &self.memory_leaves()),
+            tablesMerkleRoot: Fr::from(0),
+            functionsMerkleRoot: merkle_root(params, &self.function_roots(params)),
+            internalsOffset: Fr::from(self.internals_offset),
+        }
+    }
+}
+
+fn native_stack_hash(params: &Params, values: &[Fr]) -> Fr {
+    let mut root = Fr::from(0);
+    for el in values.iter() {
+        root = poseidon(&params, vec![el.clone(), root]);
+    }
+    root
+}
+
+// Leaves of the locals tree, unset locals are zero
+fn local_hashes(params: &Params, locals: &[Option<ValueHint>]) -> Vec<Fr> {
+    locals.iter().map(|l| l.as_ref().map(|v| v.hash(params)).unwrap_or(Fr::from(0))).collect()
+}
+
+// Binary operation op of F32_BINOPS or F64_BINOPS
+#[cfg(feature = "float")]
+fn native_float_binop(fmt: FloatFormat, op: u32, a: u64, b: u64) -> ValueHint {
+    let num = |v: u64| ValueHint { value: v as u128, ty: float_type(fmt) };
+    let cmp = |v: bool| i32_value(v as u64);
+    match op {
+        0 => num(float::add(fmt, a, b)),
+        1 => num(float::sub(fmt, a, b)),
+        2 => num(float::mul(fmt, a, b)),
+        3 => num(float::div(fmt, a, b)),
+        4 => num(float::min(fmt, a, b)),
+        5 => num(float::max(fmt, a, b)),
+        6 => cmp(float::eq(fmt, a, b)),
+        7 => cmp(float::ne(fmt, a, b)),
+        8 => cmp(float::lt(fmt, a, b)),
+        9 => cmp(float::gt(fmt, a, b)),
+        10 => cmp(float::le(fmt, a, b)),
+        _ => cmp(float::ge(fmt, a, b)),
+    }
+}
+
+// Unary operation op of F32_UNOPS or F64_UNOPS, None if it traps
+#[cfg(feature = "float")]
+fn native_float_unop(fmt: FloatFormat, op: u32, a: u64) -> Option<ValueHint> {
+    let other = if fmt == F32 { F64 } else { F32 };
+    let int = |bits: usize, signed: bool, ty: u32| float::trunc(fmt, a, bits, signed).map(|v| ValueHint { value: v as u128, ty });
+    match op {
+        0 => Some(ValueHint { value: float::sqrt(fmt, a) as u128, ty: float_type(fmt) }),
+        1 => int(32, true, I32_TYPE),
+        2 => int(32, false, I32_TYPE),
+        3 => int(64, true, I64_TYPE),
+        4 => int(64, false, I64_TYPE),
+        _ => Some(ValueHint { value: float::convert(fmt, other, a) as u128, ty: float_type(other) }),
+    }
+}
+
+// Runs the machine natively and produces the witnesses for each step
+#[derive(Debug, Clone)]
+pub struct NativeMachine {
+    pub modules: Vec<LinkedModule>,
+    value_stack: Vec<ValueHint>,
+    frame_stack: Vec<StackFrameHint>,
+    block_stack: Vec<u64>,
+    // locals of each frame, empty for frames whose locals root is not the one of a fresh frame
+    locals: Vec<Vec<Option<ValueHint>>>,
+    module_idx: u64,
+    function_idx: u64,
+    function_pc: u64,
+    // stopped at an instruction that traps, it stays at that instruction
+    pub trapped: bool,
+    // blocks read by the keccak precompile, keccak steps for longer messages trap
+    #[cfg(feature = "keccak")]
+    pub keccak_blocks: usize,
+}
+
+fn internal_ref(module: u64, func: u64, pc: u64) -> ValueHint {
+    ValueHint {
+        value: (pc as u128) | ((func as u128) << 32) | ((module as u128) << 64),
+        ty: INTERNAL_TYPE_REF,
+    }
+}
+
+fn i32_value(v: u64) -> ValueHint {
+    ValueHint { value: v as u128, ty: I32_TYPE }
+}
+
+impl NativeMachine {
+    // Starts a function like a call from outside, returning to the given reference
+    pub fn new(modules: Vec<LinkedModule>, module: u64, func: u64) -> Self {
+        NativeMachine {
+            modules,
+            value_stack: vec![internal_ref(0, 0, 0), i32_value(0), i32_value(0)],
+            frame_stack: vec![],
+            block_stack: vec![],
+            locals: vec![],
+            module_idx: module,
+            function_idx: func,
+            function_pc: 0,
+            trapped: false,
+            #[cfg(feature = "keccak")]
+            keccak_blocks: KECCAK_DEFAULT_BLOCKS,
+        }
+    }
+
+    fn module_hashes(&self, params: &Params) -> Vec<Fr> {
+        self.modules.iter().map(|m| m.hint(params).hash(params)).collect()
+    }
+
+    // Machine hint with some elements removed from the tops of the stacks
+    fn machine_hint(&self, params: &Params, values_popped: usize, frames_popped: usize, blocks_popped: usize) -> MachineHint {
+        let values : Vec<Fr> = self.value_stack.iter().map(|v| v.hash(params)).collect();
+        let frames : Vec<Fr> = self.frame_stack.iter().map(|f| f.hash(params)).collect();
+        let blocks : Vec<Fr> = self.block_stack.iter().map(|b| Fr::from(*b)).collect();
+        MachineHint {
+            valueStack: native_stack_hash(params, &values[0..values.len()-values_popped]),
+            internalStack: Fr::from(0),
+            blockStack: native_stack_hash(params, &blocks[0..blocks.len()-blocks_popped]),
+            frameStack: native_stack_hash(params, &frames[0..frames.len()-frames_popped]),
+            globalStateHash: Fr::from(0),
+            moduleIdx: Fr::from(self.module_idx),
+            functionIdx: Fr::from(self.function_idx),
+            functionPc: Fr::from(self.function_pc),
+            modulesRoot: merkle_root(params, &self.module_hashes(params)),
+        }
+    }
+
+    pub fn hash(&self, params: &Params) -> Fr {
+        let m = self.machine_hint(params, 0, 0, 0);
+        poseidon(&params, vec![
+            m.valueStack,
+            m.internalStack,
+            m.blockStack,
+            m.frameStack,
+            m.globalStateHash,
+            m.moduleIdx,
+            m.functionIdx,
+            m.functionPc,
+            m.modulesRoot,
+        ])
+    }
+
+    // Runs until the function that was started returns or the machine traps
+    pub fn run(&mut self, params: &Params) -> Vec<MachineStep> {
+        let mut steps = vec![];
+        while let Some(step) = self.step(params) {
+            steps.push(step);
+            if self.frame_stack.len() == 0 {
+                break;
+            }
+        }
+        steps
+    }
+
+    pub fn global(&self, module: usize, idx: usize) -> u128 {
+        self.modules[module].globals[idx].value
+    }
+
+    pub fn memory(&self, module: usize) -> &[u8] {
+        &self.modules[module].memory
+    }
+
+    // None if the instruction traps, the machine is then trapped and has no more steps
+    pub fn step(&mut self, params: &Params) -> Option<MachineStep> {
+        if self.trapped {
+            return None;
+        }
+        let inst = match self.modules.get(self.module_idx as usize)
+            .and_then(|m| m.functions.get(self.function_idx as usize))
+            .and_then(|f| f.get(self.function_pc as usize)) {
+            Some(inst) => inst.clone(),
+            // called a function that does not exist, or ran past the end of one
+            None => return self.trap(),
+        };
+        let module = &self.modules[self.module_idx as usize];
+        let func = &module.functions[self.function_idx as usize];
+        let mole = module.hint(params);
+        let mod_proof = merkle_proof(params, &self.module_hashes(params), self.module_idx as usize);
+        let func_proof = merkle_proof(params, &module.function_roots(params), self.function_idx as usize);
+        let inst_proof = merkle_proof(params, &func.iter().map(|i| i.hash(params)).collect::<Vec<Fr>>(), self.function_pc as usize);
+        let arg = inst.argumentData;
+        let (machine, proof) = match inst.opcode {
+            0x41..=0x44 => {
+                let ty = (inst.opcode - 0x41) as u32;
+                let machine = self.machine_hint(params, 0, 0, 0);
+                self.value_stack.push(ValueHint { value: arg as u128, ty });
+                let proof = match ty {
+                    0 => InstProof::ConstI32(InstConstHint::default()),
+                    1 => InstProof::ConstI64(InstConstHint::default()),
+                    2 => InstProof::ConstF32(InstConstHint::default()),
+                    _ => InstProof::ConstF64(InstConstHint::default()),
+                };
+                (machine, proof)
+            }
+            0x1a => {
+                if !self.has_operands(1, 0) {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 1, 0, 0);
+                let val = self.value_stack.pop().unwrap();
+                (machine, InstProof::Drop(InstDropHint { val: val.hash(params) }))
+            }
+            0x23 => {
+                if arg as usize >= module.globals.len() {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 0, 0, 0);
+                let globals = module.global_hashes(params);
+                let val = module.globals[arg as usize].clone();
+                self.value_stack.push(val.clone());
+                (machine, InstProof::GlobalGet(InstGlobalGetHint {
+                    val: val.hash(params),
+                    proof: merkle_proof(params, &globals, arg as usize),
+                }))
+            }
+            0x24 => {
+                if !self.has_operands(1, 0) || arg as usize >= module.globals.len() {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 1, 0, 0);
+                let globals = module.global_hashes(params);
+                let val = self.value_stack.pop().unwrap();
+                let proof = InstProof::GlobalSet(InstGlobalSetHint {
+                    val: val.hash(params),
+                    old_val: globals[arg as usize],
+                    proof: merkle_proof(params, &globals, arg as usize),
+                    mod_proof: mod_proof.clone(),
+                });
+                self.modules[self.module_idx as usize].globals[arg as usize] = val;
+                (machine, proof)
+            }
+            0x10 => {
+                if !self.has_operands(0, 1) {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 0, 1, 0);
+                let frame = self.frame_stack[self.frame_stack.len()-1].clone();
+                self.value_stack.push(internal_ref(self.module_idx, self.function_idx, self.function_pc + 1));
+                self.value_stack.push(ValueHint { value: fr_to_u128(frame.callerModule), ty: I32_TYPE });
+                self.value_stack.push(ValueHint { value: fr_to_u128(frame.callerModuleInternals), ty: I32_TYPE });
+                self.function_idx = arg;
+                self.function_pc = 0;
+                return Some(self.make_step(machine, InstProof::Call(InstCallHint { frame }), inst, mole, mod_proof, inst_proof, func_proof));
+            }
+            0x8009 => {
+                let machine = self.machine_hint(params, 0, 0, 0);
+                self.value_stack.push(internal_ref(self.module_idx, self.function_idx, self.function_pc + 1));
+                self.value_stack.push(i32_value(self.module_idx));
+                self.value_stack.push(i32_value(module.internals_offset));
+                self.module_idx = arg >> 32;
+                self.function_idx = arg & 0xffffffff;
+                self.function_pc = 0;
+                return Some(self.make_step(machine, InstProof::CrossCall(InstCrossCallHint {}), inst, mole, mod_proof, inst_proof, func_proof));
+            }
+            0x8002 => {
+                if !self.has_operands(3, 0) {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 3, 0, 0);
+                let caller_module_internals = self.value_stack.pop().unwrap();
+                let caller_module = self.value_stack.pop().unwrap();
+                let return_pc = self.value_stack.pop().unwrap();
+                self.frame_stack.push(StackFrameHint {
+                    returnPc: return_pc.clone(),
+                    localsMerkleRoot: Fr::from(arg),
+                    callerModule: Fr::from(caller_module.value),
+                    callerModuleInternals: Fr::from(caller_module_internals.value),
+                });
+                // only the locals of a fresh frame, a single empty local, are known
+                self.locals.push(if arg == 0 { vec![None] } else { vec![] });
+                (machine, InstProof::InitFrame(InstInitFrameHint {
+                    return_pc,
+                    caller_module: Fr::from(caller_module.value),
+                    caller_module_internals: Fr::from(caller_module_internals.value),
+                }))
+            }
+            0x0f => {
+                if !self.has_operands(0, 1) {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 0, 1, 0);
+                let frame = self.frame_stack.pop().unwrap();
+                self.locals.pop();
+                let pc = frame.returnPc.value;
+                self.function_pc = (pc & 0xffffffff) as u64;
+                self.function_idx = ((pc >> 32) & 0xffffffff) as u64;
+                self.module_idx = ((pc >> 64) & 0xffffffff) as u64;
+                return Some(self.make_step(machine, InstProof::Return(InstReturnHint { frame }), inst, mole, mod_proof, inst_proof, func_proof));
+            }
+            #[cfg(feature = "keccak")]
+            0x8010 => {
+                if !self.has_operands(3, 0) {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 3, 0, 0);
+                let n = self.value_stack.len();
+                let out_idx = self.value_stack[n-1].value as usize;
+                let len = self.value_stack[n-2].value as usize;
+                let in_idx = self.value_stack[n-3].value as usize;
+                let blocks = self.keccak_blocks;
+                let num_leaves = keccak_leaves(blocks);
+                // the circuit reads num_leaves full leaves, and the padding needs a byte after the message
+                let full_leaves = module.memory.len() / 32;
+                if len >= blocks*BLOCK_BYTES || in_idx.saturating_add(num_leaves) > full_leaves || out_idx >= full_leaves {
+                    self.trapped = true;
+                    return None;
+                }
+                self.value_stack.truncate(n-3);
+                let leaves = module.memory_leaves();
+                let bytes = module.memory[in_idx*32..(in_idx+num_leaves)*32].to_vec();
+                let digest = keccak256(&bytes[0..len]);
+                let proof = InstProof::Keccak(InstKeccakHint {
+                    blocks,
+                    in_idx: in_idx as u64,
+                    len: len as u64,
+                    out_idx: out_idx as u64,
+                    bytes,
+                    in_proofs: (0..num_leaves).map(|i| merkle_proof(params, &leaves, in_idx + i)).collect(),
+                    old_out: leaves[out_idx],
+                    out_proof: merkle_proof(params, &leaves, out_idx),
+                    mod_proof: mod_proof.clone(),
+                });
+                self.modules[self.module_idx as usize].memory[out_idx*32..(out_idx+1)*32].copy_from_slice(&digest);
+                (machine, proof)
+            }
+            0x1b => {
+                if !self.has_operands(3, 0) || self.value_stack[self.value_stack.len()-1].ty != I32_TYPE {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 3, 0, 0);
+                let selector = self.value_stack.pop().unwrap();
+                let b = self.value_stack.pop().unwrap();
+                let a = self.value_stack.pop().unwrap();
+                let proof = InstProof::Select(InstSelectHint {
+                    val1: a.hash(params),
+                    val2: b.hash(params),
+                    val3: selector.clone(),
+                });
+                self.value_stack.push(if selector.value != 0 { a } else { b });
+                (machine, proof)
+            }
+            0x02 => {
+                let machine = self.machine_hint(params, 0, 0, 0);
+                self.block_stack.push(self.function_pc + 1);
+                (machine, InstProof::Block(InstBlockHint {}))
+            }
+            0x0c => {
+                if self.block_stack.len() == 0 {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 0, 0, 1);
+                let block = self.block_stack.pop().unwrap();
+                self.function_pc = block;
+                return Some(self.make_step(machine, InstProof::Branch(InstBranchHint { block: Fr::from(block) }), inst, mole, mod_proof, inst_proof, func_proof));
+            }
+            0x0d => {
+                // the circuit needs a block even when the branch is not taken
+                if !self.has_operands(1, 0) || self.block_stack.len() == 0 || self.value_stack[self.value_stack.len()-1].ty != I32_TYPE {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 1, 0, 1);
+                let val = self.value_stack.pop().unwrap();
+                let block = self.block_stack[self.block_stack.len()-1];
+                let proof = InstProof::BranchIf(InstBranchIfHint { val: val.clone(), block: Fr::from(block) });
+                if val.value != 0 {
+                    self.block_stack.pop();
+                    self.function_pc = block;
+                    return Some(self.make_step(machine, proof, inst, mole, mod_proof, inst_proof, func_proof));
+                }
+                (machine, proof)
+            }
+            0x20 => {
+                let known = self.locals.last().and_then(|l| l.get(arg as usize)).cloned().flatten();
+                let val = match known {
+                    Some(val) => val,
+                    // unset locals and locals of frames that are not fresh
+                    None => return self.trap(),
+                };
+                let machine = self.machine_hint(params, 0, 1, 0);
+                let frame = self.frame_stack[self.frame_stack.len()-1].clone();
+                let leaves = local_hashes(params, self.locals.last().unwrap());
+                self.value_stack.push(val.clone());
+                (machine, InstProof::LocalGet(InstLocalGetHint {
+                    frame,
+                    val: val.hash(params),
+                    proof: merkle_proof(params, &leaves, arg as usize),
+                }))
+            }
+            0x21 => {
+                if !self.has_operands(1, 1) || arg as usize >= self.locals.last().unwrap().len() {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 1, 1, 0);
+                let frame = self.frame_stack[self.frame_stack.len()-1].clone();
+                let leaves = local_hashes(params, self.locals.last().unwrap());
+                let val = self.value_stack.pop().unwrap();
+                let proof = InstProof::LocalSet(InstLocalSetHint {
+                    frame,
+                    val: val.hash(params),
+                    old_val: leaves[arg as usize],
+                    proof: merkle_proof(params, &leaves, arg as usize),
+                });
+                let n = self.locals.len();
+                self.locals[n-1][arg as usize] = Some(val);
+                let root = merkle_root(params, &local_hashes(params, &self.locals[n-1]));
+                let n = self.frame_stack.len();
+                self.frame_stack[n-1].localsMerkleRoot = root;
+                (machine, proof)
+            }
+            #[cfg(feature = "float")]
+            op if F32_BINOPS.contains(&(op as u32)) || F64_BINOPS.contains(&(op as u32)) => {
+                let (fmt, ops) = if F32_BINOPS.contains(&(op as u32)) { (F32, F32_BINOPS) } else { (F64, F64_BINOPS) };
+                let n = self.value_stack.len();
+                if n < 2 || self.value_stack[n-1].ty != float_type(fmt) || self.value_stack[n-2].ty != float_type(fmt) {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 2, 0, 0);
+                let b = self.value_stack.pop().unwrap().value as u64;
+                let a = self.value_stack.pop().unwrap().value as u64;
+                let idx = ops.iter().position(|c| *c == op as u32).unwrap() as u32;
+                self.value_stack.push(native_float_binop(fmt, idx, a, b));
+                let hint = InstFloatBinopHint { val1: a, val2: b, op: idx };
+                (machine, if fmt == F32 { InstProof::F32Binop(hint) } else { InstProof::F64Binop(hint) })
+            }
+            #[cfg(feature = "float")]
+            op if F32_UNOPS.contains(&(op as u32)) || F64_UNOPS.contains(&(op as u32)) => {
+                let (fmt, ops) = if F32_UNOPS.contains(&(op as u32)) { (F32, F32_UNOPS) } else { (F64, F64_UNOPS) };
+                let n = self.value_stack.len();
+                if n < 1 || self.value_stack[n-1].ty != float_type(fmt) {
+                    return self.trap();
+                }
+                let a = self.value_stack[n-1].value as u64;
+                let idx = ops.iter().position(|c| *c == op as u32).unwrap() as u32;
+                let res = match native_float_unop(fmt, idx, a) {
+                    Some(res) => res,
+                    None => return self.trap(),
+                };
+                let machine = self.machine_hint(params, 1, 0, 0);
+                self.value_stack.pop();
+                self.value_stack.push(res);
+                let hint = InstFloatUnopHint { val: a, op: idx };
+                (machine, if fmt == F32 { InstProof::F32Unop(hint) } else { InstProof::F64Unop(hint) })
+            }
+            #[cfg(feature = "float")]
+            op if I32_TO_FLOAT.contains(&(op as u32)) || I64_TO_FLOAT.contains(&(op as u32)) => {
+                let (bits, ty, ops) = if I32_TO_FLOAT.contains(&(op as u32)) { (32, I32_TYPE, I32_TO_FLOAT) } else { (64, I64_TYPE, I64_TO_FLOAT) };
+                let n = self.value_stack.len();
+                if n < 1 || self.value_stack[n-1].ty != ty {
+                    return self.trap();
+                }
+                let machine = self.machine_hint(params, 1, 0, 0);
+                let a = self.value_stack.pop().unwrap().value as u64;
+                let idx = ops.iter().position(|c| *c == op as u32).unwrap();
+                let fmt = if idx < 2 { F32 } else { F64 };
+                let res = float::from_int(fmt, a, bits, idx % 2 == 0);
+                self.value_stack.push(ValueHint { value: res as u128, ty: float_type(fmt) });
+                let hint = InstFloatUnopHint { val: a, op: idx as u32 };
+                (machine, if bits == 32 { InstProof::I32ToFloat(hint) } else { InstProof::I64ToFloat(hint) })
+            }
+            // the circuit has no alternative for the opcode
+            _ => return self.trap(),
+        };
+        self.function_pc += 1;
+        Some(self.make_step(machine, proof, inst, mole, mod_proof, inst_proof, func_proof))
+    }
+
+    fn trap(&mut self) -> Option<MachineStep> {
+        self.trapped = true;
+        None
+    }
+
+    // the stacks hold the operands of the instruction
+    fn has_operands(&self, values: usize, frames: usize) -> bool {
+        self.value_stack.len() >= values && self.frame_stack.len() >= frames
+    }
+
+    fn make_step(&self, machine: MachineHint, proof: InstProof, inst: InstructionHint, mole: ModuleHint, mod_proof: Proof, inst_proof: Proof, func_proof: Proof) -> MachineStep {
+        MachineStep { machine, proof, inst, mole, mod_proof, inst_proof, func_proof }
+    }
+}
+
+// Machine at the start of a function that has a single instruction, so that empty proofs are enough
+#[cfg(test)]
+fn single_instruction_hints(params: &Params, inst: &InstructionHint) -> (MachineHint, ModuleHint) {
+    let mut mole = ModuleHint::default();
+    mole.functionsMerkleRoot = inst.hash(params);
+    let mut mach = MachineHint::default();
+    mach.modulesRoot = mole.hash(params);
+    (mach, mole)
+}
+
+#[cfg(test)]
+fn check_single_instruction(proof: InstProof, inst: InstructionHint) -> bool {
+    use ark_relations::r1cs::ConstraintSystem;
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let (mach, mole) = single_instruction_hints(&params, &inst);
+    make_proof(
+        cs.clone(),
+        &params,
+        &mach,
+        proof,
+        inst,
+        &mole,
+        &Proof::default(),
+        &Proof::default(),
+        &Proof::default(),
+    ).unwrap();
+    cs.is_satisfied().unwrap()
+}
+
+#[test]
+fn test_valid_instruction() {
+    assert!(check_single_instruction(InstProof::Drop(InstDropHint::default()), InstructionHint { opcode: 0x1a, argumentData: 0 }));
+    assert!(check_single_instruction(InstProof::LocalGet(InstLocalGetHint::default()), InstructionHint { opcode: 0x20, argumentData: 0 }));
+    // invalid witnesses for alternatives that are not selected do not matter
+    let mut hint = InstLocalGetHint::default();
+    hint.val = Fr::from(5);
+    assert!(check_single_instruction(InstProof::LocalGet(hint), InstructionHint { opcode: 0x1a, argumentData: 0 }));
+}
+
+#[test]
+fn test_unsupported_opcode() {
+    // no alternative is selected
+    assert!(!check_single_instruction(InstProof::Drop(InstDropHint::default()), InstructionHint { opcode: 0x00, argumentData: 0 }));
+}
+
+#[test]
+fn test_invalid_selected_instruction() {
+    // local.get with a value that is not in the locals, other alternatives would be valid
+    let mut hint = InstLocalGetHint::default();
+    hint.val = Fr::from(5);
+    assert!(!check_single_instruction(InstProof::LocalGet(hint), InstructionHint { opcode: 0x20, argumentData: 0 }));
+    // return with a frame that has no return address
+    assert!(!check_single_instruction(InstProof::Return(InstReturnHint::default()), InstructionHint { opcode: 0x0f, argumentData: 0 }));
+}
+
+#[cfg(feature = "float")]
+#[test]
+fn test_float_opcode_mismatch() {
+    let mut hint = InstFloatBinopHint::default();
+    hint.op = 2;
+    // f32.add committed, but the hint claims multiplication
+    assert!(!check_single_instruction(InstProof::F32Binop(hint), InstructionHint { opcode: 0x92, argumentData: 0 }));
+    let mut hint = InstFloatBinopHint::default();
+    hint.op = 2;
+    assert!(check_single_instruction(InstProof::F32Binop(hint), InstructionHint { opcode: 0x94, argumentData: 0 }));
+}
+
+#[cfg(feature = "float")]
+#[test]
+fn test_float_alternatives() {
+    // f32.lt of 1.0 and 2.0, the operation has to match the opcode
+    let hint = InstFloatBinopHint { val1: 0x3f800000, val2: 0x40000000, op: 8 };
+    assert!(check_single_instruction(InstProof::F32Binop(hint.clone()), InstructionHint { opcode: 0x5d, argumentData: 0 }));
+    assert!(!check_single_instruction(InstProof::F32Binop(hint), InstructionHint { opcode: 0x5e, argumentData: 0 }));
+    // f64.sqrt of 4.0
+    let hint = InstFloatUnopHint { val: 0x4010000000000000, op: 0 };
+    assert!(check_single_instruction(InstProof::F64Unop(hint), InstructionHint { opcode: 0x9f, argumentData: 0 }));
+    // i32.trunc_f32_s of 1.5, and of NaN which traps
+    let hint = InstFloatUnopHint { val: 0x3fc00000, op: 1 };
+    assert!(check_single_instruction(InstProof::F32Unop(hint), InstructionHint { opcode: 0xa8, argumentData: 0 }));
+    let hint = InstFloatUnopHint { val: 0x7fc00000, op: 1 };
+    assert!(!check_single_instruction(InstProof::F32Unop(hint), InstructionHint { opcode: 0xa8, argumentData: 0 }));
+    // f64.promote_f32
+    let hint = InstFloatUnopHint { val: 0x3fc00000, op: 5 };
+    assert!(check_single_instruction(InstProof::F32Unop(hint), InstructionHint { opcode: 0xbb, argumentData: 0 }));
+    // f64.convert_i64_u
+    let hint = InstFloatUnopHint { val: u64::MAX, op: 3 };
+    assert!(check_single_instruction(InstProof::I64ToFloat(hint), InstructionHint { opcode: 0xba, argumentData: 0 }));
+}
+
+// Steps that push i32 constants
+#[cfg(test)]
+fn const_steps(params: &Params, n: usize) -> Vec<MachineStep> {
+    let code = ModuleCode {
+        name: "main".to_string(),
+        imports: vec![],
+        exports: vec![],
+        functions: vec![vec![(0x41, 7); n]],
+        globals: vec![],
+        memory: vec![],
+        internals_offset: 0,
+    };
+    let mut mach = NativeMachine::new(link(&[code]).unwrap(), 0, 0);
+    (0..n).map(|_| mach.step(params).unwrap()).collect()
+}
+
+#[test]
+fn test_machine_circuit() {
+    use ark_relations::r1cs::ConstraintSystem;
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let circuit = MachineCircuit {
+        steps: const_steps(&params, 3),
+        params: params.clone(),
+    };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    assert!(cs.is_satisfied().unwrap());
+    // constant one and the two hashes
+    assert_eq!(cs.num_instance_variables(), 3);
+
+    // second step does not continue from the first one
+    let mut steps = const_steps(&params, 3);
+    steps[1].machine.valueStack = Fr::from(0);
+    let circuit = MachineCircuit { steps, params: params.clone() };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    assert!(!cs.is_satisfied().unwrap());
+
+    let empty = MachineCircuit { steps: vec![], params };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    assert!(matches!(
+        empty.generate_constraints(ConstraintSystemRef::new(cs_sys)),
+        Err(SynthesisError::Unsatisfiable)
+    ));
+}
+
+#[test]
+fn test_cross_module_call() {
+    use ark_relations::r1cs::ConstraintSystem;
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let main = ModuleCode {
+        name: "main".to_string(),
+        imports: vec![("lib".to_string(), "answer".to_string())],
+        exports: vec![],
+        // function 0 is the imported one
+        functions: vec![vec![(0x8002, 0), (0x10, 0), (0x1a, 0), (0x0f, 0)]],
+        globals: vec![],
+        memory: vec![],
+        internals_offset: 0,
+    };
+    let lib = ModuleCode {
+        name: "lib".to_string(),
+        imports: vec![],
+        exports: vec![("answer".to_string(), 0)],
+        functions: vec![vec![(0x8002, 0), (0x41, 42), (0x24, 0), (0x23, 0), (0x0f, 0)]],
+        globals: vec![(0, I32_TYPE)],
+        memory: vec![],
+        internals_offset: 3,
+    };
+    let mut mach = NativeMachine::new(link(&[main, lib]).unwrap(), 0, 1);
+    let start = mach.hash(&params);
+    let steps = mach.run(&params);
+    assert_eq!(steps.len(), 12);
+    assert_eq!(mach.module_idx, 0);
+    assert_eq!(mach.global(1, 0), 42);
+
+    let circuit = MachineCircuit { steps, params: params.clone() };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    assert!(cs.is_satisfied().unwrap());
+    let inputs = cs.borrow().unwrap().instance_assignment.clone();
+    assert_eq!(inputs[1], start);
+    assert_eq!(inputs[2], mach.hash(&params));
+}
+
+#[test]
+fn test_link_errors() {
+    let module = |name: &str, imports: Vec<(String, String)>| ModuleCode {
+        name: name.to_string(),
+        imports,
+        exports: vec![],
+        functions: vec![],
+        globals: vec![],
+        memory: vec![],
+        internals_offset: 0,
+    };
+    let main = module("main", vec![("lib".to_string(), "answer".to_string())]);
+    assert!(link(&[main.clone()]).is_err());
+    assert!(link(&[main, module("lib", vec![])]).is_err());
+}
+
+#[cfg(test)]
+fn single_function(functions: Vec<(u64, u64)>) -> NativeMachine {
+    let code = ModuleCode {
+        name: "main".to_string(),
+        imports: vec![],
+        exports: vec![],
+        functions: vec![functions],
+        globals: vec![],
+        memory: vec![],
+        internals_offset: 0,
+    };
+    NativeMachine::new(link(&[code]).unwrap(), 0, 0)
+}
+
+// Proves the steps, checking that they go from start to end
+#[cfg(test)]
+fn check_steps(params: &Params, steps: Vec<MachineStep>, start: Fr, end: Fr) -> bool {
+    use ark_relations::r1cs::ConstraintSystem;
+    let circuit = MachineCircuit { steps, params: params.clone() };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    let inputs = cs.borrow().unwrap().instance_assignment.clone();
+    cs.is_satisfied().unwrap() && inputs[1] == start && inputs[2] == end
+}
+
+#[test]
+fn test_native_control_flow() {
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let mut mach = single_function(vec![
+        (0x8002, 0),
+        // local 0 is 1
+        (0x41, 1), (0x21, 0),
+        (0x02, 0), (0x02, 0),
+        // loops once, clearing local 0
+        (0x20, 0), (0x41, 0), (0x21, 0), (0x0d, 0),
+        // selector 0 selects 8
+        (0x41, 7), (0x41, 8), (0x20, 0), (0x1b, 0),
+        (0x21, 0),
+        (0x0f, 0),
+    ]);
+    let start = mach.hash(&params);
+    let steps = mach.run(&params);
+    assert!(!mach.trapped);
+    assert_eq!(steps.len(), 19);
+    assert_eq!(mach.block_stack, vec![4]);
+    assert!(check_steps(&params, steps, start, mach.hash(&params)));
+
+    // the first branch goes back to the block, the second one has no block left
+    let mut mach = single_function(vec![(0x8002, 0), (0x02, 0), (0x41, 1), (0x1a, 0), (0x0c, 0)]);
+    let start = mach.hash(&params);
+    let steps = mach.run(&params);
+    assert!(mach.trapped);
+    assert_eq!(steps.len(), 7);
+    assert_eq!(mach.function_pc, 4);
+    assert!(check_steps(&params, steps, start, mach.hash(&params)));
+}
+
+#[test]
+fn test_native_trap() {
+    use crate::hash::generate_params;
+    let params = generate_params();
+    // i32.add has no alternative in the circuit
+    let mut mach = single_function(vec![(0x41, 1), (0x41, 2), (0x6a, 0)]);
+    assert!(mach.step(&params).is_some());
+    assert!(mach.step(&params).is_some());
+    let before = mach.hash(&params);
+    assert!(mach.step(&params).is_none());
+    assert!(mach.trapped);
+    assert_eq!(mach.hash(&params), before);
+
+    // running past the end of the function
+    let mut mach = single_function(vec![(0x41, 1)]);
+    assert!(mach.step(&params).is_some());
+    assert!(mach.step(&params).is_none());
+
+    // locals of a frame that is not fresh are not known
+    let mut mach = single_function(vec![(0x8002, 5), (0x20, 0)]);
+    assert!(mach.step(&params).is_some());
+    assert!(mach.step(&params).is_none());
+
+    // the selector of select is not an i32
+    let mut mach = single_function(vec![(0x41, 1), (0x41, 2), (0x42, 1), (0x1b, 0)]);
+    assert_eq!(mach.run(&params).len(), 3);
+    assert!(mach.trapped);
+}
+
+#[cfg(feature = "float")]
+#[test]
+fn test_native_float() {
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let mut mach = single_function(vec![
+        (0x43, 0x3f800000), (0x43, 0x40000000),
+        // sqrt(1.0 + 2.0) truncated to 1, then back to 1.0
+        (0x92, 0), (0x91, 0), (0xa8, 0), (0xb2, 0),
+        (0x43, 0x40000000), (0x5d, 0),
+        (0x44, 0x4010000000000000), (0x9f, 0),
+    ]);
+    let start = mach.hash(&params);
+    let steps : Vec<MachineStep> = (0..10).map(|_| mach.step(&params).unwrap()).collect();
+    let n = mach.value_stack.len();
+    assert_eq!((mach.value_stack[n-2].value, mach.value_stack[n-2].ty), (1, I32_TYPE));
+    assert_eq!((mach.value_stack[n-1].value, mach.value_stack[n-1].ty), (0x4000000000000000, F64_TYPE));
+    assert!(check_steps(&params, steps, start, mach.hash(&params)));
+
+    // truncating NaN traps, and so do operands of the wrong type
+    let mut mach = single_function(vec![(0x43, 0x7fc00000), (0xa8, 0)]);
+    assert!(mach.step(&params).is_some());
+    assert!(mach.step(&params).is_none());
+    let mut mach = single_function(vec![(0x41, 1), (0x43, 0x3f800000), (0x92, 0)]);
+    assert_eq!(mach.run(&params).len(), 2);
+    assert!(mach.trapped);
+}
+
+#[cfg(feature = "keccak")]
+#[test]
+fn test_keccak_precompile() {
+    use ark_relations::r1cs::ConstraintSystem;
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let mut memory = b"abc".to_vec();
+    memory.resize(8*32, 0);
+    let code = ModuleCode {
+        name: "main".to_string(),
+        imports: vec![],
+        exports: vec![],
+        // hash 3 bytes from leaf 0 into leaf 6
+        functions: vec![vec![(0x41, 0), (0x41, 3), (0x41, 6), (0x8010, 0)]],
+        globals: vec![],
+        memory,
+        internals_offset: 0,
+    };
+    let mut mach = NativeMachine::new(link(&[code]).unwrap(), 0, 0);
+    let start = mach.hash(&params);
+    let steps : Vec<MachineStep> = (0..4).map(|_| mach.step(&params).unwrap()).collect();
+    assert_eq!(&mach.memory(0)[6*32..7*32], &keccak256(b"abc"));
+    assert_eq!(steps[2].kind(), StepKind::Basic);
+    assert_eq!(steps[3].kind(), StepKind::Keccak);
+
+    let circuit = MachineCircuit { steps: steps.clone(), params: params.clone() };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    assert!(cs.is_satisfied().unwrap());
+    let inputs = cs.borrow().unwrap().instance_assignment.clone();
+    assert_eq!(inputs[1], start);
+    assert_eq!(inputs[2], mach.hash(&params));
+
+    // input bytes that are not in memory
+    let mut steps = steps;
+    if let InstProof::Keccak(hint) = &mut steps[3].proof {
+        hint.bytes[1] = b'x';
+    }
+    let circuit = MachineCircuit { steps, params };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    assert!(!cs.is_satisfied().unwrap());
+}
+
+#[cfg(feature = "keccak")]
+#[test]
+fn test_keccak_trap() {
+    use crate::hash::generate_params;
+    let params = generate_params();
+    let code = ModuleCode {
+        name: "main".to_string(),
+        imports: vec![],
+        exports: vec![],
+        // a full block leaves no room for the padding
+        functions: vec![vec![(0x41, 0), (0x41, 136), (0x41, 6), (0x8010, 0)]],
+        globals: vec![],
+        memory: vec![0; 8*32],
+        internals_offset: 0,
+    };
+    let mut mach = NativeMachine::new(link(&[code]).unwrap(), 0, 0);
+    for _ in 0..3 {
+        mach.step(&params).unwrap();
+    }
+    let before = mach.hash(&params);
+    assert!(mach.step(&params).is_none());
+    // the machine stays at the keccak instruction with its operands and memory
+    assert!(mach.trapped);
+    assert_eq!(mach.function_pc, 3);
+    assert_eq!(mach.value_stack.len(), 6);
+    assert_eq!(mach.hash(&params), before);
+    assert!(mach.memory(0).iter().all(|b| *b == 0));
+    assert!(mach.step(&params).is_none());
+
+    // two blocks fit the same message
+    let code = ModuleCode {
+        name: "main".to_string(),
+        imports: vec![],
+        exports: vec![],
+        functions: vec![vec![(0x41, 0), (0x41, 136), (0x41, 10), (0x8010, 0)]],
+        globals: vec![],
+        memory: vec![0; 12*32],
+        internals_offset: 0,
+    };
+    let mut mach = NativeMachine::new(link(&[code]).unwrap(), 0, 0);
+    mach.keccak_blocks = 2;
+    for _ in 0..4 {
+        mach.step(&params).unwrap();
+    }
+    assert!(!mach.trapped);
+    assert_eq!(&mach.memory(0)[10*32..11*32], &keccak256(&[0; 136]));
+}
+
+#[cfg(feature = "keccak")]
+#[test]
+fn test_keccak_witness() {
+    use ark_relations::r1cs::ConstraintSystem;
+    let cs = ConstraintSystemRef::new(ConstraintSystem::<Fr>::new());
+    // keccak steps are not among the basic alternatives
+    assert!(proof_to_witness(InstProof::Keccak(InstKeccakHint::default()), cs.clone()).is_err());
+    assert!(proof_to_witness(InstProof::Drop(InstDropHint::default()), cs).is_ok());
+}
+
+#[cfg(feature = "keccak")]
+#[test]
+fn test_keccak_blocks() {
+    use ark_relations::r1cs::ConstraintSystem;
+    use crate::hash::generate_params;
+    let params = generate_params();
+    // 200 bytes need two blocks, read from 9 leaves
+    let msg: Vec<u8> = (0..200).map(|i| (i * 7 + 3) as u8).collect();
+    let mut memory = msg.clone();
+    memory.resize(12*32, 0);
+    let code = ModuleCode {
+        name: "main".to_string(),
+        imports: vec![],
+        exports: vec![],
+        functions: vec![vec![(0x41, 0), (0x41, 200), (0x41, 10), (0x8010, 0)]],
+        globals: vec![],
+        memory,
+        internals_offset: 0,
+    };
+    let mut mach = NativeMachine::new(link(&[code]).unwrap(), 0, 0);
+    mach.keccak_blocks = 2;
+    let start = mach.hash(&params);
+    let steps : Vec<MachineStep> = (0..4).map(|_| mach.step(&params).unwrap()).collect();
+    assert_eq!(&mach.memory(0)[10*32..11*32], &keccak256(&msg));
+
+    let circuit = MachineCircuit { steps, params: params.clone() };
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    assert!(cs.is_satisfied().unwrap());
+    let inputs = cs.borrow().unwrap().instance_assignment.clone();
+    assert_eq!(inputs[1], start);
+    assert_eq!(inputs[2], mach.hash(&params));
+}
@@ -158,73 +158,31 @@ fn hash_many(params: &PoseidonParameters<Fr>, lst: &[Fr]) -> Fr {
     CRH::<Fr>::evaluate(&params, inputs).unwrap()
 }
 
+// tag and immediate of an instruction as hashed by hash_code, loops also hash their continuation
+fn code_args(op: &CodeTree) -> Vec<Fr> {
+    match &*op {
+        CAdd => vec![Fr::from(1)],
+        CSub => vec![Fr::from(2)],
+        CGt => vec![Fr::from(3)],
+        CGetLocal(x) => vec![Fr::from(4), Fr::from(*x)],
+        CSetLocal(x) => vec![Fr::from(5), Fr::from(*x)],
+        CConst(x) => vec![Fr::from(6), Fr::from(*x)],
+        CBreakIf(x) => vec![Fr::from(7), Fr::from(*x)],
+        CLoop(_) => vec![Fr::from(8)],
+        CEnd => vec![Fr::from(9)],
+    }
+}
+
 fn hash_code(params: &PoseidonParameters<Fr>, code: &[CodeTree]) -> Fr {
     let mut res = Fr::zero();
     for op in code.iter().rev() {
         // println!("hashing {:?}", op);
-        match &*op {
-            CAdd => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(1));
-                inputs.push(res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-            }
-            CSub => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(2));
-                inputs.push(res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-            }
-            CGt => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(3));
-                inputs.push(res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-            }
-            CGetLocal(x) => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(4));
-                inputs.push(Fr::from(*x));
-                inputs.push(res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-            }
-            CSetLocal(x) => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(5));
-                inputs.push(Fr::from(*x));
-                inputs.push(res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-            }
-            CConst(x) => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(6));
-                inputs.push(Fr::from(*x));
-                inputs.push(res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-            }
-            CBreakIf(x) => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(7));
-                inputs.push(Fr::from(*x));
-                inputs.push(res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-            }
-            CLoop(cont) => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(8));
-                inputs.push(hash_code(&params, cont));
-                inputs.push(res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-            }
-            CEnd => {
-                let mut inputs = vec![];
-                inputs.push(Fr::from(9));
-                inputs.push(res);
-                // println!("cend {} {} {}", &inputs[0], &inputs[1], &res);
-                res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
-                // println!("cend {}", &res);
-            }
+        let mut inputs = code_args(op);
+        if let CLoop(cont) = op {
+            inputs.push(hash_code(&params, cont));
         }
+        inputs.push(res);
+        res = CRH::<Fr>::evaluate(&params, inputs).unwrap();
     }
     res
 }
@@ -545,14 +503,20 @@ pub mod as_waksman;
 
 pub mod bucket;
 pub mod tree;
+pub mod tree_old;
+pub mod codehash;
 pub mod truncate;
 pub mod addmany;
 pub mod hash;
 
 pub mod vm;
+pub mod compile;
 
 pub mod keccak;
 pub mod machine;
+pub mod metrics;
+#[cfg(feature = "float")]
+pub mod float;
 
 #[allow(dead_code)]
 fn test_circuit<T: ConstraintSynthesizer<Fr>>(circuit: T) {
@@ -572,13 +536,21 @@ fn test_circuit2<T: ConstraintSynthesizer<MNT6Fr>>(circuit: T) {
     println!("Satified: {}", cs.is_satisfied().unwrap());
 }
 
-fn setup_circuit<T: InstructionCircuit>(circuit: T) -> (InnerSNARKPK, InnerSNARKVK) {
+// Key for batches of one kind
+// one key per batch kind, set up with the first batch the kind can prove
+fn setup_batch_keys(params: &PoseidonParameters<Fr>, trs: &[Transition], batch: usize) -> Result<Vec<(InnerSNARKPK, InnerSNARKVK)>, String> {
+    KINDS.iter().map(|kind| {
+        let chunk = trs.chunks(batch).find(|chunk| chunk.iter().all(|tr| kind.allows(op_index(tr))))
+            .ok_or(format!("no batch of {} transitions for {:?}", batch, kind))?;
+        Ok(setup_batch(&BatchCircuit::new(params, chunk, *kind)?))
+    }).collect()
+}
+
+fn setup_batch(circuit: &BatchCircuit) -> (InnerSNARKPK, InnerSNARKVK) {
     let mut rng = test_rng();
-    println!("Setting up circuit");
-    let (pk, vk) = InnerSNARK::setup(circuit.clone(), &mut rng).unwrap();
-    println!("Testing prove");
-    let proof = InnerSNARK::prove(&pk, circuit.clone(), &mut rng).unwrap();
-    println!("proof: {}", InnerSNARK::verify(&vk, &vec![circuit.calc_hash().clone()], &proof).unwrap());
+    let (pk, vk) = metrics::setup::<BatchCircuit, _, _>(|| InnerSNARK::setup(circuit.clone(), &mut rng).unwrap());
+    let proof = metrics::prove::<BatchCircuit, _, _>(|| InnerSNARK::prove(&pk, circuit.clone(), &mut rng).unwrap());
+    tracing::debug!(verified = InnerSNARK::verify(&vk, &vec![circuit.calc_hash()], &proof).unwrap(), "test proof");
     (pk, vk)
 }
 
@@ -608,7 +580,8 @@ fn get_transitions(c: &Collector) -> Vec<Transition> {
 }
 
 use crate::aggfinal::InnerAggregateFinal;
-use crate::select::make_circuits;
+use crate::select::make_batch_circuits;
+use crate::addmany::{BatchCircuit, BatchKind, KINDS, op_index};
 use crate::select::SelectionCircuit;
 use crate::aggtransition::aggregate_list2;
 use crate::aggtransition::aggregate_list1;
@@ -618,7 +591,9 @@ use crate::aggtransition::OuterSetup;
 use crate::aggtransition::HashCircuit;
 
 fn main() {
+    crate::metrics::init();
     crate::machine::test();
+    crate::metrics::write_report("metrics.json");
 }
 
 fn main2() {
@@ -656,59 +631,43 @@ fn main3() {
             breakno: vec![],
             breakyes: vec![],
         };
-        for i in 0..32 {
+        // 32 batches of transitions
+        let batch = 4;
+        for i in 0..32*batch {
             vm.step(&params, &mut c);
             println!("{}: vm hash {}", i, vm.hash(&params));
             // println!("vm state {:?}", vm);
         }
 
-        let trs = get_transitions(&c);
-
-        crate::addmany::test(&params, (c.add[0].before.clone(), c.add[0].after.clone()));
-        return;
+        // execution order, so that a batch proves consecutive steps
+        let mut trs = get_transitions(&c);
+        trs.sort_by_key(|tr| tr.before.step_counter);
 
         // memory::test_memory(&params, trs);
 
         // return;
 
-        let (loop_proof, loop_vk, start_st, end_st) = merkleloop::handle_loop(&params, trs);
-
-        let mut keys = vec![];
-        keys.push(setup_circuit(c.add[0].clone()));
-        keys.push(setup_circuit(c.sub[0].clone()));
-        keys.push(setup_circuit(c.gt[0].clone()));
-        keys.push(setup_circuit(c.constant[0].clone()));
-        keys.push(setup_circuit(c.get[0].clone()));
-        keys.push(setup_circuit(c.set[0].clone()));
-        keys.push(setup_circuit(c.loopi[0].clone()));
-        if c.endi.len() > 0 {
-            keys.push(setup_circuit(c.endi[0].clone()));
-        } else {
-            keys.push(keys[0].clone());
-        }
-        if c.breakno.len() > 0 {
-            keys.push(setup_circuit(c.breakno[0].clone()));
-        } else {
-            keys.push(keys[0].clone());
-        }
-        if c.breakyes.len() > 0 {
-            keys.push(setup_circuit(c.breakyes[0].clone()));
-        } else {
-            keys.push(keys[0].clone());
-        }
-        while keys.len() < 16 {
-            keys.push(keys[0].clone());
-        }
+        let (loop_proof, loop_vk, start_st, end_st) = merkleloop::handle_loop(&params, trs.clone());
+
+        let keys = match setup_batch_keys(&params, &trs, batch) {
+            Ok(keys) => keys,
+            Err(err) => {
+                tracing::error!(%err, "cannot set up batch keys");
+                return;
+            }
+        };
 
         let mut rng = test_rng();
 
-        let proof = InnerSNARK::prove(&keys[0].0, c.add[0].clone(), &mut rng).unwrap();
+        let idx = BatchKind::select(&trs[0..batch]);
+        let first = BatchCircuit::new(&params, &trs[0..batch], KINDS[idx]).unwrap();
+        let proof = InnerSNARK::prove(&keys[idx].0, first.clone(), &mut rng).unwrap();
 
         let circuit = SelectionCircuit {
-            hash : c.add[0].calc_hash().clone(),
+            hash : first.calc_hash(),
             proof: proof,
             keys: keys.iter().map(|a| a.1.clone()).collect(),
-            idx: 12,
+            idx: idx as u32,
             // transition: c.add[0].transition(),
         };
 
@@ -749,19 +708,9 @@ fn main3() {
             agg_circuits1.push(agg_circuit_out);
         }
 
-        // First step is proving all instructions and then making them uniform
+        // First step is proving the batches of transitions in execution order
         let mut circuits = vec![];
-
-        make_circuits(&mut circuits, &c.add, &keys, 0);
-        make_circuits(&mut circuits, &c.sub, &keys, 1);
-        make_circuits(&mut circuits, &c.gt, &keys, 2);
-        make_circuits(&mut circuits, &c.constant, &keys, 3);
-        make_circuits(&mut circuits, &c.get, &keys, 4);
-        make_circuits(&mut circuits, &c.set, &keys, 5);
-        make_circuits(&mut circuits, &c.loopi, &keys, 6);
-        make_circuits(&mut circuits, &c.endi, &keys, 7);
-        make_circuits(&mut circuits, &c.breakno, &keys, 8);
-        make_circuits(&mut circuits, &c.breakyes, &keys, 9);
+        make_batch_circuits(&mut circuits, &params, &trs, &keys, batch).unwrap();
 
         println!("Got circuits {}", circuits.len());
 
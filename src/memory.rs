@@ -14,11 +14,13 @@ use ark_sponge::poseidon::PoseidonParameters;
 use ark_r1cs_std::boolean::{AllocatedBool,Boolean};
 use std::cmp::Ordering;
 
-use crate::{VM,Transition,hash_code};
+use crate::{VM,Transition,hash_code,hash_pair,hash_many};
 use crate::InstructionCircuit;
 use crate::CodeTree;
-
-use ark_r1cs_std::R1CSVar;
+use crate::InnerSNARK;
+use crate::{InnerSNARKProof, InnerSNARKVK};
+use crate::aggloop::{LoopCircuit, prove_loops};
+use ark_crypto_primitives::SNARK;
 
 /*
 Why does it work?
@@ -30,24 +32,213 @@ Why does it work?
 
 */
 
+/*
+Memory order:
+ * addresses are tagged with a segment (locals, globals, linear memory), each segment has 32 bit indices
+ * operations are sorted by address and then by step, an operation of an instruction uses the step after the instruction
+ * initial memory is a list of cells committed by a merkle root and the number of cells, a cell is an operation at step 0
+ * cells are used in the same order as in the initial memory, so the index of the next cell is part of the state
+ * at the end of the log the index has to be the number of cells, so that no cell is skipped
+ * reading an address that has no previous operation gives 0
+ * batches of any length are chained using the start and end states
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Segment {
+    Local = 0,
+    Global = 1,
+    Memory = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: Segment,
+    pub idx: u32,
+}
+
+impl Address {
+    // Field encoding, 0 is smaller than all addresses and is used for the initial state
+    pub fn key(&self) -> u64 {
+        (((self.segment as u64) << 32) | (self.idx as u64)) + 1
+    }
+}
+
+// Memory operation that doesn't come from a VM transition
+#[derive(Debug, Clone)]
+pub struct MemAccess {
+    pub addr: Address,
+    pub step: u64,
+    pub value: u64,
+    pub is_set: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum MemEntry {
+    // address, value, index of the cell in initial memory, merkle path
+    Init(Address, u64, u64, Vec<Fr>),
+    Access(MemAccess),
+    Instruction(Transition),
+}
+
+// address of a transition that is not a local.get or local.set
+fn instruction_address(tr: &Transition) -> Result<Address, String> {
+    match get_info(&tr.before) {
+        Some((addr, _)) => Ok(addr),
+        None => Err(format!("Transition at step {} is not a memory instruction", tr.before.step_counter)),
+    }
+}
+
+impl MemEntry {
+    fn key(&self) -> Result<(u64, u64), String> {
+        match self {
+            MemEntry::Init(addr, _, _, _) => Ok((addr.key(), 0)),
+            MemEntry::Access(a) => Ok((a.addr.key(), a.step)),
+            MemEntry::Instruction(tr) => Ok((instruction_address(tr)?.key(), tr.before.step_counter as u64 + 1)),
+        }
+    }
+
+    fn value(&self) -> Result<u64, String> {
+        match self {
+            MemEntry::Init(_, value, _, _) => Ok(*value),
+            MemEntry::Access(a) => Ok(a.value),
+            MemEntry::Instruction(tr) => {
+                let addr = instruction_address(tr)?;
+                match tr.after.locals.get(addr.idx as usize) {
+                    Some(v) => Ok(*v as u64),
+                    None => Err(format!("Local {} at step {} does not exist", addr.idx, tr.before.step_counter)),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryPoint {
+    pub addr: u64,
+    pub step: u64,
+    pub value: u64,
+    pub init_idx: u64,
+}
+
+impl MemoryPoint {
+    pub fn start() -> Self {
+        MemoryPoint { addr: 0, step: 0, value: 0, init_idx: 0 }
+    }
+
+    // State after the finish slot of MemoryChunk, the address is larger than all addresses
+    pub fn end() -> Self {
+        MemoryPoint { addr: Address { segment: Segment::Memory, idx: u32::MAX }.key() + 1, step: 0, value: 0, init_idx: 0 }
+    }
+
+    pub fn after(&self, entries: &[MemEntry]) -> Result<Self, String> {
+        let mut res = *self;
+        for e in entries {
+            let (addr, step) = e.key()?;
+            res.addr = addr;
+            res.step = step;
+            res.value = e.value()?;
+            if let MemEntry::Init(_, _, idx, _) = e {
+                res.init_idx = idx + 1;
+            }
+        }
+        Ok(res)
+    }
+
+    fn inputs(&self) -> Vec<Fr> {
+        vec![Fr::from(self.addr), Fr::from(self.step), Fr::from(self.value), Fr::from(self.init_idx)]
+    }
+}
+
+// Initial memory, the tree has 2^depth leaves padded with zeros
+#[derive(Debug, Clone)]
+pub struct InitMemory {
+    pub cells: Vec<(Address, u64)>,
+    pub depth: usize,
+}
+
+fn init_leaf(params: &PoseidonParameters<Fr>, addr: Address, value: u64) -> Fr {
+    hash_pair(params, &Fr::from(addr.key()), &Fr::from(value))
+}
+
+impl InitMemory {
+    pub fn new(cells: Vec<(Address, u64)>) -> Result<Self, String> {
+        let mut cells = cells;
+        cells.sort();
+        for i in 1..cells.len() {
+            if cells[i-1].0 == cells[i].0 {
+                return Err(format!("Address {:?} initialized twice", cells[i].0));
+            }
+        }
+        let mut depth = 0;
+        while (1 << depth) < cells.len() {
+            depth += 1;
+        }
+        Ok(InitMemory { cells, depth })
+    }
+
+    fn levels(&self, params: &PoseidonParameters<Fr>) -> Vec<Vec<Fr>> {
+        let mut level = self.cells.iter().map(|(addr, value)| init_leaf(params, *addr, *value)).collect::<Vec<_>>();
+        level.resize(1 << self.depth, Fr::from(0));
+        let mut levels = vec![];
+        while level.len() > 1 {
+            let next = level.chunks(2).map(|pair| hash_pair(params, &pair[0], &pair[1])).collect();
+            levels.push(level);
+            level = next;
+        }
+        levels.push(level);
+        levels
+    }
+
+    // root of the tree of cells
+    pub fn tree_root(&self, params: &PoseidonParameters<Fr>) -> Fr {
+        self.levels(params)[self.depth][0]
+    }
+
+    pub fn count(&self) -> u64 {
+        self.cells.len() as u64
+    }
+
+    // commits to the cells and their number
+    pub fn root(&self, params: &PoseidonParameters<Fr>) -> Fr {
+        hash_pair(params, &self.tree_root(params), &Fr::from(self.count()))
+    }
+
+    // Cells as entries of the memory order
+    pub fn entries(&self, params: &PoseidonParameters<Fr>) -> Vec<MemEntry> {
+        let levels = self.levels(params);
+        let mut res = vec![];
+        for (i, (addr, value)) in self.cells.iter().enumerate() {
+            let path = (0..self.depth).map(|d| levels[d][(i >> d) ^ 1]).collect();
+            res.push(MemEntry::Init(*addr, *value, i as u64, path));
+        }
+        res
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MemoryCircuit {
-    pub transitions: Vec<Transition>,
+    pub entries: Vec<MemEntry>,
     pub params: PoseidonParameters<Fr>,
-    pub start_addr: u32,
-    pub start_step: u32,
-    pub start_value: u32,
-
-    pub end_addr: u32,
-    pub end_step: u32,
-    pub end_value: u32,
+    pub init_root: Fr,
+    pub init_tree: Fr,
+    pub init_count: u64,
+    pub init_depth: usize,
+    pub start: MemoryPoint,
+    pub end: MemoryPoint,
+    // hash chain of the leaves of the memory order, batches extend the chain of the previous batch
+    pub start_chain: Fr,
+    pub end_chain: Fr,
+    // the last batch checks that all initial cells were used
+    pub is_last: bool,
 }
 
-fn get_info(vm: &VM) -> (u32, bool) {
+// Only local.get and local.set are memory instructions of the VM, globals and linear memory
+// are not executed by the VM yet, their operations are given as MemAccess
+fn get_info(vm: &VM) -> Option<(Address, bool)> {
     match vm.pc[0].clone() {
-        CodeTree::CSetLocal(i) => (i, true),
-        CodeTree::CGetLocal(i) => (i, false),
-        _ => panic!("Not a memory instruction")
+        CodeTree::CSetLocal(i) => Some((Address { segment: Segment::Local, idx: i }, true)),
+        CodeTree::CGetLocal(i) => Some((Address { segment: Segment::Local, idx: i }, false)),
+        _ => None,
     }
 }
 
@@ -56,6 +247,117 @@ struct MemoryState {
     addr: FpVar<Fr>,
     step: FpVar<Fr>,
     value: FpVar<Fr>,
+    init_idx: FpVar<Fr>,
+}
+
+fn state_var(cs: &ConstraintSystemRef<Fr>, p: &MemoryPoint) -> MemoryState {
+    let mut vars = p.inputs().iter().map(|v| {
+        FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(*v)).unwrap())
+    }).collect::<Vec<_>>();
+    let init_idx = vars.pop().unwrap();
+    let value = vars.pop().unwrap();
+    let step = vars.pop().unwrap();
+    let addr = vars.pop().unwrap();
+    MemoryState { addr, step, value, init_idx }
+}
+
+// Opens the initial memory root, returns the root of the tree of cells and the number of cells
+fn open_init_root(
+    cs: &ConstraintSystemRef<Fr>,
+    params_g: &CRHParametersVar::<Fr>,
+    init_root: &FpVar<Fr>,
+    tree_root: Fr,
+    count: u64,
+) -> Result<(FpVar<Fr>, FpVar<Fr>), SynthesisError> {
+    let tree_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(tree_root)).unwrap());
+    let count_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(count))).unwrap());
+    init_root.enforce_equal(&CRHGadget::<Fr>::evaluate(&params_g, &vec![tree_var.clone(), count_var.clone()]).unwrap())?;
+    Ok((tree_var, count_var))
+}
+
+// Returns if the address is the same as before and if the operation comes after the previous one
+fn check_order(state: &MemoryState, addr_var: &FpVar<Fr>, step_var: &FpVar<Fr>) -> Result<(Boolean<Fr>, Boolean<Fr>), SynthesisError> {
+    let same_address_var = state.addr.is_eq(addr_var)?;
+    let step_counter_larger = step_var.is_cmp(&state.step, Ordering::Greater, false)?;
+    let address_greater = addr_var.is_cmp(&state.addr, Ordering::Greater, false)?;
+    let valid = same_address_var.and(&step_counter_larger)?.or(&address_greater)?;
+    Ok((same_address_var, valid))
+}
+
+// Value read from an address, new addresses that are not in the initial memory have 0
+fn check_get(state: &MemoryState, same_address_var: &Boolean<Fr>, value_var: &FpVar<Fr>) -> Result<Boolean<Fr>, SynthesisError> {
+    let default_var = FpVar::Constant(Fr::from(0));
+    let check_var = same_address_var.select(&state.value, &default_var)?;
+    check_var.is_eq(value_var)
+}
+
+fn generate_init(
+    cs: ConstraintSystemRef<Fr>,
+    params_g: &CRHParametersVar::<Fr>,
+    init_tree: &FpVar<Fr>,
+    state: MemoryState,
+    addr: Address,
+    value: u64,
+    idx: u64,
+    path: &[Fr],
+) -> Result<(Boolean<Fr>, MemoryState), SynthesisError> {
+    let addr_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(addr.key()))).unwrap());
+    let value_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(value))).unwrap());
+    let step_var = FpVar::Constant(Fr::from(0));
+
+    // Cells are used in order
+    let (_, valid_order) = check_order(&state, &addr_var, &step_var)?;
+
+    let mut idx_bits = vec![];
+    for i in 0..path.len() {
+        idx_bits.push(Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok((idx >> i) & 1 == 1)).unwrap()));
+    }
+    let idx_var = if path.len() > 0 { Boolean::le_bits_to_fp_var(&idx_bits)? } else { FpVar::Constant(Fr::from(0)) };
+    let valid_idx = idx_var.is_eq(&state.init_idx)?;
+
+    let mut acc = CRHGadget::<Fr>::evaluate(&params_g, &vec![addr_var.clone(), value_var.clone()]).unwrap();
+    for (i, elem) in path.iter().enumerate() {
+        let elem_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(*elem)).unwrap());
+        let left = idx_bits[i].select(&elem_var, &acc)?;
+        let right = idx_bits[i].select(&acc, &elem_var)?;
+        acc = CRHGadget::<Fr>::evaluate(&params_g, &vec![left, right]).unwrap();
+    }
+    let valid = valid_order.and(&valid_idx)?.and(&init_tree.is_eq(&acc)?)?;
+
+    Ok((valid, MemoryState {
+        addr: addr_var,
+        step: step_var,
+        value: value_var,
+        init_idx: idx_var + FpVar::Constant(Fr::from(1)),
+    }))
+}
+
+fn generate_access(
+    cs: ConstraintSystemRef<Fr>,
+    params_g: &CRHParametersVar::<Fr>,
+    state: MemoryState,
+    access: &MemAccess,
+) -> Result<(Boolean<Fr>, FpVar<Fr>, MemoryState), SynthesisError> {
+    let addr_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(access.addr.key()))).unwrap());
+    let step_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(access.step))).unwrap());
+    let value_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(access.value))).unwrap());
+    let bool_var = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(access.is_set)).unwrap());
+
+    let (same_address_var, valid_set) = check_order(&state, &addr_var, &step_var)?;
+    let valid_get = check_get(&state, &same_address_var, &value_var)?.and(&valid_set)?;
+    let valid_var = bool_var.select(&valid_set, &valid_get)?;
+
+    let leaf_var = CRHGadget::<Fr>::evaluate(&params_g, &vec![
+        addr_var.clone(), step_var.clone(), value_var.clone(), FpVar::from(bool_var),
+    ]).unwrap();
+
+    let next_state = MemoryState {
+        addr: addr_var,
+        step: step_var,
+        value: value_var,
+        init_idx: state.init_idx,
+    };
+    Ok((valid_var, leaf_var, next_state))
 }
 
 fn generate_step(
@@ -65,9 +367,10 @@ fn generate_step(
     before: VM,
     after: VM,
     state: MemoryState,
-    idx: u32, // memory index
+    addr: Address,
     is_set: bool, // is get or set
-) -> Result<(FpVar<Fr>, MemoryState), SynthesisError> {
+) -> Result<(Boolean<Fr>, FpVar<Fr>, MemoryState), SynthesisError> {
+    let idx = addr.idx;
     // Generate variables
     let step_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(before.step_counter as u32))).unwrap());
     let step_after_var = step_var.clone() + FpVar::Constant(Fr::from(1));
@@ -87,6 +390,7 @@ fn generate_step(
     let pc_after_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(pc_after_hash)).unwrap());
 
     let idx_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(idx as u32))).unwrap());
+    let addr_var = idx_var.clone() + FpVar::Constant(Fr::from(addr.key() - idx as u64));
 
     let bool_var = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(is_set)).unwrap());
 
@@ -100,13 +404,8 @@ fn generate_step(
     ]).unwrap();
 
     //// Validity of set
-    // Two cases, same address or new address (larger)
-    let same_address_var = state.addr.is_eq(&idx_var).unwrap();
-    // Check if step counter is larger
-    let step_counter_larger = step_var.is_cmp(&state.step, Ordering::Greater, false)?;
-    let address_greater = idx_var.is_cmp(&state.addr, Ordering::Greater, false).unwrap();
-
-    let valid_set = same_address_var.and(&step_counter_larger).unwrap().or(&address_greater)?;
+    // Two cases, same address with a later step or new address (larger)
+    let (same_address_var, valid_set) = check_order(&state, &addr_var, &step_after_var)?;
 
     // Generate get
     let stack_before_get = stack_base_var.clone();
@@ -119,9 +418,7 @@ fn generate_step(
 
     // Validity of get
     // Two cases, default value (new address) or stored value
-    let default_var = FpVar::Constant(Fr::from(0));
-    let check_var = same_address_var.select(&state.value, &default_var)?;
-    let valid_get = check_var.is_eq(&read_after_var)?.and(&valid_set)?;
+    let valid_get = check_get(&state, &same_address_var, &read_after_var)?.and(&valid_set)?;
 
     // Select set or get
     let stack_before_var = bool_var.select(&stack_before_set, &stack_before_get).unwrap();
@@ -130,15 +427,6 @@ fn generate_step(
 
     let valid_var = bool_var.select(&valid_set, &valid_get).unwrap();
 
-    println!(
-        "is set: {}, valid_set {}, valid get {}, valid {}",
-        is_set,
-        valid_set.value().unwrap(),
-        valid_get.value().unwrap(),
-        valid_var.value().unwrap(),
-    );
-    valid_var.enforce_equal(&Boolean::constant(true))?;
-
     // Compute VM hash before
     let mut inputs_vm_before = Vec::new();
     inputs_vm_before.push(hash_pc_before_var);
@@ -153,7 +441,7 @@ fn generate_step(
     let mut inputs_vm_after = Vec::new();
     inputs_vm_after.push(pc_after_var);
     inputs_vm_after.push(stack_after_var);
-    inputs_vm_after.push(step_after_var);
+    inputs_vm_after.push(step_after_var.clone());
     inputs_vm_after.push(control_var.clone());
     let hash_vm_after_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_vm_after).unwrap();
 
@@ -162,28 +450,37 @@ fn generate_step(
     inputs_transition.push(hash_vm_after_gadget.clone());
     let hash_transition_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_transition).unwrap();
 
-    println!("Made memory op");
-    println!("before {}, after {}", before.hash_mem(&params), after.hash_mem(&params));
-    println!("before {}, after {}", hash_vm_before_gadget.value().unwrap(), hash_vm_after_gadget.value().unwrap());
-
     let next_state = MemoryState {
-        addr: idx_var,
-        step: step_var,
+        addr: addr_var,
+        step: step_after_var,
         value: read_after_var,
+        init_idx: state.init_idx,
     };
 
-    Ok((hash_transition_gadget, next_state))
-    
+    Ok((valid_var, hash_transition_gadget, next_state))
 }
 
-use crate::hash_pair;
+fn leaf_hash(params: &PoseidonParameters<Fr>, e: &MemEntry) -> Option<Fr> {
+    match e {
+        MemEntry::Init(_, _, _, _) => None,
+        MemEntry::Access(a) => {
+            let inputs = vec![Fr::from(a.addr.key()), Fr::from(a.step), Fr::from(a.value), Fr::from(a.is_set)];
+            Some(CRH::<Fr>::evaluate(&params, inputs).unwrap())
+        }
+        MemEntry::Instruction(tr) => Some(hash_pair(&params, &tr.before.hash_mem(&params), &tr.after.hash_mem(&params))),
+    }
+}
 
-fn hash_tree(params: &PoseidonParameters<Fr>, trs: Vec<Transition>) -> Fr {
-    let mut tree = vec![];
-    for tr in trs {
-        tree.push(hash_pair(&params, &tr.before.hash_mem(&params), &tr.after.hash_mem(&params)));
+// Levels with odd length are padded with zero, an empty batch has root 0
+fn leaf_root(params: &PoseidonParameters<Fr>, leaves: Vec<Fr>) -> Fr {
+    let mut tree = leaves;
+    if tree.len() == 0 {
+        return Fr::from(0);
     }
     while tree.len() > 1 {
+        if tree.len() % 2 == 1 {
+            tree.push(Fr::from(0));
+        }
         let mut next_vars = vec![];
         for i in 0..tree.len()/2 {
             let hash_var = hash_pair(&params, &tree[2*i].clone(), &tree[2*i+1].clone());
@@ -194,51 +491,307 @@ fn hash_tree(params: &PoseidonParameters<Fr>, trs: Vec<Transition>) -> Fr {
     tree[0]
 }
 
+impl MemoryCircuit {
+    pub fn new(params: &PoseidonParameters<Fr>, init: &InitMemory, entries: Vec<MemEntry>, start: MemoryPoint, start_chain: Fr) -> Result<Self, String> {
+        let end = start.after(&entries)?;
+        let end_chain = leaf_chain(params, start_chain, &entries);
+        Ok(MemoryCircuit {
+            entries,
+            params: params.clone(),
+            init_root: init.root(params),
+            init_tree: init.tree_root(params),
+            init_count: init.count(),
+            init_depth: init.depth,
+            start,
+            end,
+            start_chain,
+            end_chain,
+            is_last: true,
+        })
+    }
+
+    // start state, end state, initial memory root, start and end of the chain of the operations
+    pub fn public_inputs(&self) -> Vec<Fr> {
+        let mut res = self.start.inputs();
+        res.extend(self.end.inputs());
+        res.push(self.init_root);
+        res.push(self.start_chain);
+        res.push(self.end_chain);
+        res
+    }
+}
+
+
 impl ConstraintSynthesizer<Fr> for MemoryCircuit {
     fn generate_constraints(
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
         ////
-        let first_step_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(Fr::from(self.start_step))).unwrap());
-        let first_addr_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(Fr::from(self.start_addr))).unwrap());
-        let first_value_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(Fr::from(self.start_value))).unwrap());
-        let end_step_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(Fr::from(self.end_step))).unwrap());
-        let end_addr_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(Fr::from(self.end_addr))).unwrap());
-        let end_value_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(Fr::from(self.end_value))).unwrap());
-
-        let root_hash = hash_tree(&self.params, self.transitions.clone());
-        let root_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(root_hash)).unwrap());
-
-        let mut state = MemoryState {
-            step: first_step_var,
-            addr: first_addr_var,
-            value: first_value_var,
-        };
-        let mut tr_vars = vec![];
-        for tr in self.transitions.clone() {
-            let (idx, is_set) = get_info(&tr.before);
-            let (tr_var, next_state) = generate_step(cs.clone(), &self.params, &params_g, tr.before, tr.after, state, idx, is_set)?;
-            state = next_state;
-            tr_vars.push(tr_var);
+        let mut state = state_var(&cs, &self.start);
+        let end = state_var(&cs, &self.end);
+        let init_root_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(self.init_root)).unwrap());
+        let (init_tree_var, init_count_var) = open_init_root(&cs, &params_g, &init_root_var, self.init_tree, self.init_count)?;
+
+        let mut chain = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(self.start_chain)).unwrap());
+        let end_chain_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(self.end_chain)).unwrap());
+
+        for e in self.entries.iter() {
+            match e {
+                MemEntry::Init(addr, value, idx, path) => {
+                    if path.len() != self.init_depth {
+                        return Err(SynthesisError::Unsatisfiable);
+                    }
+                    let (valid, next_state) = generate_init(cs.clone(), &params_g, &init_tree_var, state, *addr, *value, *idx, path)?;
+                    valid.enforce_equal(&Boolean::constant(true))?;
+                    state = next_state;
+                }
+                MemEntry::Access(access) => {
+                    let (valid, tr_var, next_state) = generate_access(cs.clone(), &params_g, state, access)?;
+                    valid.enforce_equal(&Boolean::constant(true))?;
+                    state = next_state;
+                    chain = CRHGadget::<Fr>::evaluate(&params_g, &vec![tr_var, chain]).unwrap();
+                }
+                MemEntry::Instruction(tr) => {
+                    let (addr, is_set) = get_info(&tr.before).ok_or(SynthesisError::Unsatisfiable)?;
+                    let (valid, tr_var, next_state) = generate_step(cs.clone(), &self.params, &params_g, tr.before.clone(), tr.after.clone(), state, addr, is_set)?;
+                    valid.enforce_equal(&Boolean::constant(true))?;
+                    state = next_state;
+                    chain = CRHGadget::<Fr>::evaluate(&params_g, &vec![tr_var, chain]).unwrap();
+                }
+            }
         }
 
         // Check end state
-        end_step_var.enforce_equal(&state.step)?;
-        end_addr_var.enforce_equal(&state.addr)?;
-        end_value_var.enforce_equal(&state.value)?;
-
-        // Generate merkle tree
-        while tr_vars.len() > 1 {
-            let mut next_vars = vec![];
-            for i in 0..tr_vars.len()/2 {
-                let hash_var = CRHGadget::<Fr>::evaluate(&params_g, &vec![tr_vars[2*i].clone(), tr_vars[2*i+1].clone()]).unwrap();
-                next_vars.push(hash_var);
+        end.step.enforce_equal(&state.step)?;
+        end.addr.enforce_equal(&state.addr)?;
+        end.value.enforce_equal(&state.value)?;
+        end.init_idx.enforce_equal(&state.init_idx)?;
+        if self.is_last {
+            state.init_idx.enforce_equal(&init_count_var)?;
+        }
+
+        end_chain_var.enforce_equal(&chain)?;
+
+        Ok(())
+    }
+}
+
+// Same tree as leaf_root
+fn tree_gadget(params_g: &CRHParametersVar::<Fr>, leaves: Vec<FpVar<Fr>>) -> FpVar<Fr> {
+    let mut tr_vars = leaves;
+    if tr_vars.len() == 0 {
+        tr_vars.push(FpVar::Constant(Fr::from(0)));
+    }
+    while tr_vars.len() > 1 {
+        if tr_vars.len() % 2 == 1 {
+            tr_vars.push(FpVar::Constant(Fr::from(0)));
+        }
+        let mut next_vars = vec![];
+        for i in 0..tr_vars.len()/2 {
+            let hash_var = CRHGadget::<Fr>::evaluate(&params_g, &vec![tr_vars[2*i].clone(), tr_vars[2*i+1].clone()]).unwrap();
+            next_vars.push(hash_var);
+        }
+        tr_vars = next_vars;
+    }
+    tr_vars[0].clone()
+}
+
+/*
+Permutation between execution order and memory order.
+The execution order is committed by the public inputs of the execution proofs: the root of the tree of
+transition hashes H(before.hash, after.hash) of merkleloop, with the transition of step i at leaf i.
+Every transition is opened to the parts of its VM hashes, and the memory leaf of a local.get/local.set
+is computed from the same parts with the step counter i instead of the locals, so it is bound to the
+transition that was executed at that step. Operations that don't come from VM transitions are
+committed by the root of their leaves in execution order.
+The memory order is committed by the chain of its leaves, which is the end chain of the last memory batch
+or of the last MemoryChunk. With a challenge gamma derived from the three commitments, the lists have the
+same leaves if prod (gamma - exec_i) = prod (gamma - sorted_i).
+Leaves include the step, so they are all different.
+*/
+
+// A transition of the execution proof opened to the parts of the VM hashes
+#[derive(Debug, Clone)]
+pub struct ExecStep {
+    // code, stack, locals and control hashes
+    pub before: Vec<Fr>,
+    pub after: Vec<Fr>,
+    // code_args of the first instruction padded to two elements, loops add the hash of their body
+    pub op: Vec<Fr>,
+    pub short: bool,
+    // hash of the code after the first instruction
+    pub rest: Fr,
+}
+
+fn vm_parts(params: &PoseidonParameters<Fr>, vm: &VM) -> Vec<Fr> {
+    vec![hash_code(params, &vm.pc), vm.hash_stack(params), vm.hash_locals(params), vm.hash_control(params)]
+}
+
+impl ExecStep {
+    pub fn new(params: &PoseidonParameters<Fr>, tr: &Transition) -> Self {
+        let first = &tr.before.pc[0];
+        let mut op = crate::code_args(first);
+        if let CodeTree::CLoop(body) = first {
+            op.push(hash_code(params, body));
+        }
+        let short = op.len() == 1;
+        op.resize(2, Fr::from(0));
+        ExecStep {
+            before: vm_parts(params, &tr.before),
+            after: vm_parts(params, &tr.after),
+            op,
+            short,
+            rest: hash_code(params, &tr.before.pc[1..]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PermutationCircuit {
+    pub params: PoseidonParameters<Fr>,
+    pub steps: Vec<ExecStep>,
+    pub accesses: Vec<Fr>,
+    pub sorted: Vec<Fr>,
+}
+
+// Memory operations in the order they were executed, initial memory cells are dropped
+pub fn execution_order(entries: &[MemEntry]) -> Result<Vec<MemEntry>, String> {
+    let mut res = vec![];
+    for e in entries.iter().filter(|e| !matches!(e, MemEntry::Init(_, _, _, _))) {
+        res.push((e.key()?.1, e.clone()));
+    }
+    res.sort_by_key(|(step, _)| *step);
+    Ok(res.into_iter().map(|(_, e)| e).collect())
+}
+
+// Root of the execution proofs, same tree as merkleloop for a power of two number of transitions
+pub fn transition_root(params: &PoseidonParameters<Fr>, ts: &[Transition]) -> Fr {
+    leaf_root(params, ts.iter().map(|tr| hash_pair(params, &tr.before.hash(params), &tr.after.hash(params))).collect())
+}
+
+impl PermutationCircuit {
+    // ts are all transitions of the execution in order, entries is the memory order
+    pub fn new(params: &PoseidonParameters<Fr>, ts: &[Transition], entries: &[MemEntry]) -> Result<Self, String> {
+        for (i, tr) in ts.iter().enumerate() {
+            if tr.before.step_counter != i {
+                return Err(format!("Transitions have to start at step 0 and be in order, step {} is at {}", tr.before.step_counter, i));
             }
-            tr_vars = next_vars;
         }
-        root_var.enforce_equal(&tr_vars[0])?;
+        let accesses = execution_order(entries)?.iter()
+            .filter(|e| matches!(e, MemEntry::Access(_)))
+            .filter_map(|e| leaf_hash(params, e))
+            .collect();
+        Ok(PermutationCircuit {
+            params: params.clone(),
+            steps: ts.iter().map(|tr| ExecStep::new(params, tr)).collect(),
+            accesses,
+            sorted: entries.iter().filter_map(|e| leaf_hash(params, e)).collect(),
+        })
+    }
+
+    fn exec_root(&self) -> Fr {
+        let leaves = self.steps.iter().map(|st| {
+            hash_pair(&self.params, &hash_many(&self.params, &st.before), &hash_many(&self.params, &st.after))
+        }).collect();
+        leaf_root(&self.params, leaves)
+    }
+
+    // root of the execution proofs, root of the other accesses, chain of the memory order
+    pub fn public_inputs(&self) -> Vec<Fr> {
+        let mut chain = Fr::from(0);
+        for leaf in self.sorted.iter() {
+            chain = hash_pair(&self.params, leaf, &chain);
+        }
+        vec![self.exec_root(), leaf_root(&self.params, self.accesses.clone()), chain]
+    }
+}
+
+fn witness_list(cs: &ConstraintSystemRef<Fr>, lst: &[Fr]) -> Vec<FpVar<Fr>> {
+    lst.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(*v)).unwrap())).collect()
+}
+
+// Returns the leaf of the execution proof, if the transition is a memory instruction and its memory leaf
+fn generate_exec_step(
+    cs: &ConstraintSystemRef<Fr>,
+    params_g: &CRHParametersVar::<Fr>,
+    step: usize,
+    st: &ExecStep,
+) -> Result<(FpVar<Fr>, Boolean<Fr>, FpVar<Fr>), SynthesisError> {
+    let before = witness_list(cs, &st.before);
+    let after = witness_list(cs, &st.after);
+    let op = witness_list(cs, &st.op);
+    let rest = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(st.rest)).unwrap());
+    let short = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(st.short)).unwrap());
+
+    // first instruction of the code before
+    let code_short = CRHGadget::<Fr>::evaluate(&params_g, &vec![op[0].clone(), rest.clone()]).unwrap();
+    let code_long = CRHGadget::<Fr>::evaluate(&params_g, &vec![op[0].clone(), op[1].clone(), rest]).unwrap();
+    before[0].enforce_equal(&short.select(&code_short, &code_long)?)?;
+    let is_get = op[0].is_eq(&FpVar::Constant(Fr::from(4)))?;
+    let is_set = op[0].is_eq(&FpVar::Constant(Fr::from(5)))?;
+    let is_mem = is_get.or(&is_set)?;
+
+    let exec_leaf = CRHGadget::<Fr>::evaluate(&params_g, &vec![
+        CRHGadget::<Fr>::evaluate(&params_g, &before).unwrap(),
+        CRHGadget::<Fr>::evaluate(&params_g, &after).unwrap(),
+    ]).unwrap();
+    let mem_before = vec![before[0].clone(), before[1].clone(), FpVar::Constant(Fr::from(step as u32)), before[3].clone()];
+    let mem_after = vec![after[0].clone(), after[1].clone(), FpVar::Constant(Fr::from(step as u32 + 1)), after[3].clone()];
+    let mem_leaf = CRHGadget::<Fr>::evaluate(&params_g, &vec![
+        CRHGadget::<Fr>::evaluate(&params_g, &mem_before).unwrap(),
+        CRHGadget::<Fr>::evaluate(&params_g, &mem_after).unwrap(),
+    ]).unwrap();
+    Ok((exec_leaf, is_mem, mem_leaf))
+}
+
+impl ConstraintSynthesizer<Fr> for PermutationCircuit {
+    fn generate_constraints(
+        self,
+        cs: ConstraintSystemRef<Fr>,
+    ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
+        let inputs = self.public_inputs();
+        let exec_root_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(inputs[0])).unwrap());
+        let access_root_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(inputs[1])).unwrap());
+        let sorted_chain_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(inputs[2])).unwrap());
+
+        let mut exec_leaves = vec![];
+        let mut mem_leaves = vec![];
+        for (i, st) in self.steps.iter().enumerate() {
+            let (exec_leaf, is_mem, mem_leaf) = generate_exec_step(&cs, &params_g, i, st)?;
+            exec_leaves.push(exec_leaf);
+            mem_leaves.push((is_mem, mem_leaf));
+        }
+        exec_root_var.enforce_equal(&tree_gadget(&params_g, exec_leaves))?;
+
+        let access_vars = witness_list(&cs, &self.accesses);
+        access_root_var.enforce_equal(&tree_gadget(&params_g, access_vars.clone()))?;
+
+        let sorted_vars = witness_list(&cs, &self.sorted);
+        let mut chain = FpVar::Constant(Fr::from(0));
+        for v in sorted_vars.iter() {
+            chain = CRHGadget::<Fr>::evaluate(&params_g, &vec![v.clone(), chain]).unwrap();
+        }
+        sorted_chain_var.enforce_equal(&chain)?;
+
+        let gamma = CRHGadget::<Fr>::evaluate(&params_g, &vec![exec_root_var, access_root_var, sorted_chain_var]).unwrap();
+        let one = FpVar::Constant(Fr::from(1));
+        let mut prod_exec = one.clone();
+        for (is_mem, leaf) in mem_leaves.iter() {
+            prod_exec = prod_exec * is_mem.select(&(gamma.clone() - leaf), &one)?;
+        }
+        for v in access_vars.iter() {
+            prod_exec = prod_exec * (gamma.clone() - v);
+        }
+        let mut prod_sorted = one.clone();
+        for v in sorted_vars.iter() {
+            prod_sorted = prod_sorted * (gamma.clone() - v);
+        }
+        prod_exec.enforce_equal(&prod_sorted)?;
 
         Ok(())
     }
@@ -246,52 +799,570 @@ impl ConstraintSynthesizer<Fr> for MemoryCircuit {
 
 // Sorting memory ops
 
-struct MemOp {
-    tr: Transition,
-    idx: u32,
-    step: u32,
+// Initial memory cells, memory instructions of the transitions and other accesses sorted by address and step
+pub fn get_memory(params: &PoseidonParameters<Fr>, init: &InitMemory, ts: Vec<Transition>, accesses: Vec<MemAccess>) -> Vec<MemEntry> {
+    let mut res = init.entries(params);
+    for t in ts {
+        if get_info(&t.before).is_some() {
+            res.push(MemEntry::Instruction(t));
+        }
+    }
+    for a in accesses {
+        res.push(MemEntry::Access(a));
+    }
+    // only memory instructions were added, so every entry has a key
+    res.sort_by_key(|a| a.key().unwrap_or_default());
+    res
 }
 
-fn get_memory(ts: Vec<Transition>) -> Vec<Transition> {
+// Split the sorted operations into circuits with at most size entries, each batch starts from the end of the previous one
+pub fn batches(params: &PoseidonParameters<Fr>, init: &InitMemory, entries: &[MemEntry], size: usize) -> Result<Vec<MemoryCircuit>, String> {
     let mut res = vec![];
-    for t in ts {
-        match t.before.pc[0] {
-            CodeTree::CSetLocal(idx) => res.push(MemOp {tr: t.clone(), idx, step: t.before.step_counter as u32}),
-            CodeTree::CGetLocal(idx) => res.push(MemOp {tr: t.clone(), idx, step: t.before.step_counter as u32}),
-            _ => {},
+    let mut start = MemoryPoint::start();
+    let mut chain = Fr::from(0);
+    for chunk in entries.chunks(size) {
+        let mut circuit = MemoryCircuit::new(params, init, chunk.to_vec(), start, chain)?;
+        circuit.is_last = false;
+        start = circuit.end;
+        chain = circuit.end_chain;
+        res.push(circuit);
+    }
+    if let Some(last) = res.last_mut() {
+        last.is_last = true;
+    }
+    Ok(res)
+}
+
+/*
+Chunks for recursion. Every chunk has the same number of slots and the same shape, so that they share the keys.
+A slot is an initial memory cell, an access, an instruction, the finish slot or empty. Leaves of the memory order are
+collected into a hash chain, and the state of a chunk is the hash of the memory state and the chain.
+Chunks are aggregated with aggloop, that checks the end state of a chunk is the start state of the next one,
+and the root there is the initial memory root.
+The finish slot after the last entry checks that all initial cells were used and moves to MemoryPoint::end(),
+so the aggregated proof has to go from chunk_state(start, 0) to chunk_state(end, chain of the memory order),
+which verify_memory checks.
+*/
+#[derive(Debug, Clone)]
+pub enum Slot {
+    Entry(MemEntry),
+    Finish,
+    Empty,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryChunk {
+    pub params: PoseidonParameters<Fr>,
+    pub slots: Vec<Slot>,
+    pub init_root: Fr,
+    pub init_tree: Fr,
+    pub init_count: u64,
+    pub init_depth: usize,
+    pub start: MemoryPoint,
+    pub start_chain: Fr,
+    pub end: MemoryPoint,
+    pub end_chain: Fr,
+}
+
+fn point_hash(params: &PoseidonParameters<Fr>, p: &MemoryPoint) -> Fr {
+    CRH::<Fr>::evaluate(&params, p.inputs()).unwrap()
+}
+
+pub fn chunk_state(params: &PoseidonParameters<Fr>, p: &MemoryPoint, chain: Fr) -> Fr {
+    hash_pair(params, &point_hash(params, p), &chain)
+}
+
+fn leaf_chain(params: &PoseidonParameters<Fr>, chain: Fr, entries: &[MemEntry]) -> Fr {
+    let mut res = chain;
+    for e in entries {
+        if let Some(leaf) = leaf_hash(params, e) {
+            res = hash_pair(params, &leaf, &res);
+        }
+    }
+    res
+}
+
+impl MemoryChunk {
+    // The last chunk has the finish slot after its entries
+    pub fn new(params: &PoseidonParameters<Fr>, init: &InitMemory, entries: &[MemEntry], size: usize, start: MemoryPoint, start_chain: Fr, finish: bool) -> Result<Self, String> {
+        if entries.len() + (finish as usize) > size {
+            return Err(format!("{} entries do not fit in a chunk of size {}", entries.len(), size));
+        }
+        let mut slots = entries.iter().map(|e| Slot::Entry(e.clone())).collect::<Vec<_>>();
+        if finish {
+            slots.push(Slot::Finish);
+        }
+        slots.resize(size, Slot::Empty);
+        let end = if finish { MemoryPoint::end() } else { start.after(entries)? };
+        Ok(MemoryChunk {
+            params: params.clone(),
+            slots,
+            init_root: init.root(params),
+            init_tree: init.tree_root(params),
+            init_count: init.count(),
+            init_depth: init.depth,
+            start,
+            start_chain,
+            end,
+            end_chain: leaf_chain(params, start_chain, entries),
+        })
+    }
+}
+
+impl LoopCircuit for MemoryChunk {
+    fn get_inputs(&self) -> Vec<Fr> {
+        let (start_st, end_st, root) = self.get();
+        vec![start_st, end_st, root]
+    }
+    fn get(&self) -> (Fr, Fr, Fr) {
+        (
+            chunk_state(&self.params, &self.start, self.start_chain),
+            chunk_state(&self.params, &self.end, self.end_chain),
+            self.init_root,
+        )
+    }
+}
+
+fn point_var(cs: &ConstraintSystemRef<Fr>, p: &MemoryPoint) -> MemoryState {
+    let mut vars = p.inputs().iter().map(|v| {
+        FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(*v)).unwrap())
+    }).collect::<Vec<_>>();
+    let init_idx = vars.pop().unwrap();
+    let value = vars.pop().unwrap();
+    let step = vars.pop().unwrap();
+    let addr = vars.pop().unwrap();
+    MemoryState { addr, step, value, init_idx }
+}
+
+fn point_const(p: &MemoryPoint) -> MemoryState {
+    let mut vars = p.inputs().iter().map(|v| FpVar::Constant(*v)).collect::<Vec<_>>();
+    let init_idx = vars.pop().unwrap();
+    let value = vars.pop().unwrap();
+    let step = vars.pop().unwrap();
+    let addr = vars.pop().unwrap();
+    MemoryState { addr, step, value, init_idx }
+}
+
+fn state_hash(params_g: &CRHParametersVar::<Fr>, state: &MemoryState, chain: &FpVar<Fr>) -> FpVar<Fr> {
+    let point = CRHGadget::<Fr>::evaluate(&params_g, &vec![
+        state.addr.clone(), state.step.clone(), state.value.clone(), state.init_idx.clone(),
+    ]).unwrap();
+    CRHGadget::<Fr>::evaluate(&params_g, &vec![point, chain.clone()]).unwrap()
+}
+
+fn select_state(cond: &Boolean<Fr>, a: &MemoryState, b: &MemoryState) -> Result<MemoryState, SynthesisError> {
+    Ok(MemoryState {
+        addr: cond.select(&a.addr, &b.addr)?,
+        step: cond.select(&a.step, &b.step)?,
+        value: cond.select(&a.value, &b.value)?,
+        init_idx: cond.select(&a.init_idx, &b.init_idx)?,
+    })
+}
+
+// All kinds of entries are checked, the kind of the slot selects the result, witnesses for the others are dummy values
+fn generate_slot(
+    cs: ConstraintSystemRef<Fr>,
+    params: &PoseidonParameters<Fr>,
+    params_g: &CRHParametersVar::<Fr>,
+    init_tree: &FpVar<Fr>,
+    init_depth: usize,
+    state: MemoryState,
+    init_count: &FpVar<Fr>,
+    chain: FpVar<Fr>,
+    slot: &Slot,
+) -> Result<(MemoryState, FpVar<Fr>), SynthesisError> {
+    let zero_addr = Address { segment: Segment::Local, idx: 0 };
+    let mut init = (zero_addr, 0, 0, vec![Fr::from(0); init_depth]);
+    let mut access = MemAccess { addr: zero_addr, step: 0, value: 0, is_set: false };
+    let dummy_vm = VM::new(vec![CodeTree::CGetLocal(0)]);
+    let mut tr = Transition { before: dummy_vm.clone(), after: dummy_vm };
+    let mut kind = (false, false, false, false);
+    match slot {
+        Slot::Entry(MemEntry::Init(addr, value, idx, path)) => {
+            if path.len() != init_depth {
+                return Err(SynthesisError::Unsatisfiable);
+            }
+            init = (*addr, *value, *idx, path.clone());
+            kind.0 = true;
         }
+        Slot::Entry(MemEntry::Access(a)) => {
+            access = a.clone();
+            kind.1 = true;
+        }
+        Slot::Entry(MemEntry::Instruction(t)) => {
+            tr = t.clone();
+            kind.2 = true;
+        }
+        Slot::Finish => {
+            kind.3 = true;
+        }
+        Slot::Empty => {}
+    }
+    let is_init = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(kind.0)).unwrap());
+    let is_access = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(kind.1)).unwrap());
+    let is_instr = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(kind.2)).unwrap());
+    let is_finish = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(kind.3)).unwrap());
+    // At most one kind
+    let count = FpVar::from(is_init.clone()) + FpVar::from(is_access.clone()) + FpVar::from(is_instr.clone()) + FpVar::from(is_finish.clone());
+    let has_kind = count.is_eq(&FpVar::Constant(Fr::from(1)))?;
+    has_kind.or(&count.is_zero()?)?.enforce_equal(&Boolean::constant(true))?;
+
+    let (valid_init, state_init) = generate_init(cs.clone(), params_g, init_tree, state.clone(), init.0, init.1, init.2, &init.3)?;
+    let (valid_access, leaf_access, state_access) = generate_access(cs.clone(), params_g, state.clone(), &access)?;
+    let (addr, is_set) = get_info(&tr.before).ok_or(SynthesisError::Unsatisfiable)?;
+    let (valid_instr, leaf_instr, state_instr) = generate_step(cs.clone(), params, params_g, tr.before, tr.after, state.clone(), addr, is_set)?;
+
+    // All initial cells were used
+    let valid_finish = state.init_idx.is_eq(init_count)?;
+    let state_finish = point_const(&MemoryPoint::end());
+
+    let valid_other = is_finish.select(&valid_finish, &Boolean::constant(true))?;
+    let valid = is_init.select(&valid_init, &is_access.select(&valid_access, &is_instr.select(&valid_instr, &valid_other)?)?)?;
+    valid.enforce_equal(&Boolean::constant(true))?;
+
+    let state_other = select_state(&is_finish, &state_finish, &state)?;
+    let next_state = select_state(&is_init, &state_init, &select_state(&is_access, &state_access, &select_state(&is_instr, &state_instr, &state_other)?)?)?;
+    let leaf = is_access.select(&leaf_access, &leaf_instr)?;
+    let next_chain = CRHGadget::<Fr>::evaluate(&params_g, &vec![leaf, chain.clone()]).unwrap();
+    let chain = is_access.or(&is_instr)?.select(&next_chain, &chain)?;
+    Ok((next_state, chain))
+}
+
+impl ConstraintSynthesizer<Fr> for MemoryChunk {
+    fn generate_constraints(
+        self,
+        cs: ConstraintSystemRef<Fr>,
+    ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
+        let (start_st, end_st, root) = self.get();
+        let start_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(start_st)).unwrap());
+        let end_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(end_st)).unwrap());
+        let init_root_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(root)).unwrap());
+        let (init_tree_var, init_count_var) = open_init_root(&cs, &params_g, &init_root_var, self.init_tree, self.init_count)?;
+
+        let mut state = point_var(&cs, &self.start);
+        let mut chain = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(self.start_chain)).unwrap());
+        start_var.enforce_equal(&state_hash(&params_g, &state, &chain))?;
+
+        for slot in self.slots.iter() {
+            let (next_state, next_chain) = generate_slot(cs.clone(), &self.params, &params_g, &init_tree_var, self.init_depth, state, &init_count_var, chain, slot)?;
+            state = next_state;
+            chain = next_chain;
+        }
+        end_var.enforce_equal(&state_hash(&params_g, &state, &chain))?;
+
+        Ok(())
+    }
+}
+
+// Splits the sorted operations and the finish slot into chunks, the number of chunks is a power of 4 so that aggloop
+// ends with a single circuit
+pub fn chunks(params: &PoseidonParameters<Fr>, init: &InitMemory, entries: &[MemEntry], size: usize) -> Result<Vec<MemoryChunk>, String> {
+    let mut num = 4;
+    while num * size < entries.len() + 1 {
+        num = num * 4;
+    }
+    let mut res = vec![];
+    let mut start = MemoryPoint::start();
+    let mut chain = Fr::from(0);
+    for i in 0..num {
+        let lo = std::cmp::min(i * size, entries.len());
+        let hi = std::cmp::min((i + 1) * size, entries.len());
+        let finish = i * size <= entries.len() && entries.len() < (i + 1) * size;
+        let chunk = MemoryChunk::new(params, init, &entries[lo..hi], size, start, chain, finish)?;
+        start = chunk.end;
+        chain = chunk.end_chain;
+        res.push(chunk);
+    }
+    Ok(res)
+}
+
+// Public inputs of the aggregated memory proof. It starts from the start state with an empty chain and ends
+// after the finish slot with the chain of the memory order, that PermutationCircuit links to the execution.
+pub fn memory_inputs(params: &PoseidonParameters<Fr>, init_root: Fr, chain: Fr) -> Vec<Fr> {
+    vec![
+        chunk_state(params, &MemoryPoint::start(), Fr::from(0)),
+        chunk_state(params, &MemoryPoint::end(), chain),
+        init_root,
+    ]
+}
+
+// Proves the whole memory log, returns the proof with its key and the start and end states
+pub fn prove_memory(params: &PoseidonParameters<Fr>, init: &InitMemory, entries: &[MemEntry], size: usize) -> Result<(InnerSNARKProof, InnerSNARKVK, Fr, Fr), String> {
+    let circuits = chunks(params, init, entries, size)?;
+    Ok(prove_loops("memory", &circuits))
+}
+
+pub fn verify_memory(params: &PoseidonParameters<Fr>, vk: &InnerSNARKVK, proof: &InnerSNARKProof, init_root: Fr, chain: Fr) -> bool {
+    InnerSNARK::verify(vk, &memory_inputs(params, init_root, chain), proof).unwrap()
+}
+
+pub fn test_chunks(params: &PoseidonParameters<Fr>, ts: Vec<Transition>) -> Result<(), String> {
+    let init = InitMemory::new(vec![])?;
+    let entries = get_memory(params, &init, ts, vec![]);
+    let (proof, vk, start_st, end_st) = prove_memory(params, &init, &entries, 4)?;
+    let verified = verify_memory(params, &vk, &proof, init.root(params), leaf_chain(params, Fr::from(0), &entries));
+    tracing::debug!(start = %start_st, end = %end_st, verified, "memory proof");
+    assert!(verified);
+    Ok(())
+}
+
+pub fn test_memory(params: &PoseidonParameters<Fr>, ts: Vec<Transition>) -> Result<(), String> {
+    let init = InitMemory::new(vec![])?;
+    let entries = get_memory(params, &init, ts, vec![]);
+    tracing::debug!(entries = entries.len(), "memory log");
+    let circuit = MemoryCircuit::new(params, &init, entries, MemoryPoint::start(), Fr::from(0))?;
+    crate::test_circuit(circuit);
+    Ok(())
+}
+
+#[cfg(test)]
+fn is_satisfied(circuit: MemoryCircuit) -> bool {
+    use ark_relations::r1cs::ConstraintSystem;
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let inputs = circuit.public_inputs();
+    circuit.generate_constraints(cs.clone()).unwrap();
+    let instance = cs.borrow().unwrap().instance_assignment.clone();
+    assert_eq!(instance[1..].to_vec(), inputs);
+    cs.is_satisfied().unwrap()
+}
+
+#[cfg(test)]
+fn local_transitions(params: &PoseidonParameters<Fr>) -> Vec<Transition> {
+    use crate::CodeTree::*;
+    let mut vm = VM::new(vec![CConst(5), CSetLocal(1), CGetLocal(1), CGetLocal(0), CConst(7), CSetLocal(0), CGetLocal(0), CEnd]);
+    let mut c = crate::Collector {
+        add: vec![], sub: vec![], gt: vec![], get: vec![], set: vec![],
+        constant: vec![], loopi: vec![], endi: vec![], breakno: vec![], breakyes: vec![],
     };
-    res.sort_by(|a, b| ((a.idx,a.step)).cmp(&(b.idx,b.step)));
-    res.iter().map(|a| a.tr.clone()).collect()
+    for _i in 0..8 {
+        vm.step(params, &mut c);
+    }
+    crate::get_transitions(&c)
 }
 
-fn get_last_value(ts: &Vec<Transition>) -> u32 {
-    for tr in ts.iter().rev() {
-        match tr.before.pc[0] {
-            CodeTree::CSetLocal(_) => return *tr.before.expr_stack.last().unwrap(),
-            _ => {},
+#[test]
+fn test_memory_segments() {
+    let params = crate::generate_hash();
+    let global = |idx| Address { segment: Segment::Global, idx };
+    let mem = |idx| Address { segment: Segment::Memory, idx };
+    let init = InitMemory::new(vec![(global(0), 10), (mem(1 << 20), 3), (global(2), 7)]).unwrap();
+    let accesses = vec![
+        MemAccess { addr: global(0), step: 20, value: 10, is_set: false },
+        MemAccess { addr: global(1), step: 21, value: 0, is_set: false },
+        MemAccess { addr: global(0), step: 22, value: 11, is_set: true },
+        MemAccess { addr: global(0), step: 23, value: 11, is_set: false },
+        MemAccess { addr: mem(1 << 20), step: 24, value: 3, is_set: false },
+        MemAccess { addr: mem(u32::MAX), step: 25, value: 1 << 40, is_set: true },
+    ];
+    let entries = get_memory(&params, &init, local_transitions(&params), accesses.clone());
+    // initial cells, accesses and the five local.get/local.set transitions
+    assert_eq!(entries.len(), 3 + 6 + 5);
+    let whole = MemoryCircuit::new(&params, &init, entries.clone(), MemoryPoint::start(), Fr::from(0)).unwrap();
+    assert_eq!(whole.end.init_idx, 3);
+    assert!(is_satisfied(whole.clone()));
+
+    // Batches of any length chain to the same end state
+    for size in [1, 4, 7] {
+        let circuits = batches(&params, &init, &entries, size).unwrap();
+        assert_eq!(circuits.last().unwrap().end, whole.end);
+        for c in circuits {
+            assert!(is_satisfied(c));
         }
+    }
+
+    // Reading a wrong initial value
+    let mut bad = accesses.clone();
+    bad[0].value = 9;
+    let entries = get_memory(&params, &init, vec![], bad);
+    assert!(!is_satisfied(MemoryCircuit::new(&params, &init, entries, MemoryPoint::start(), Fr::from(0)).unwrap()));
+
+    // Skipping an initial cell
+    let entries = get_memory(&params, &init, vec![], accesses.clone());
+    let skipped = entries.iter().filter(|e| !matches!(e, MemEntry::Init(_, _, 1, _))).cloned().collect();
+    assert!(!is_satisfied(MemoryCircuit::new(&params, &init, skipped, MemoryPoint::start(), Fr::from(0)).unwrap()));
+
+    // Skipping the last initial cell, no access reads it
+    let entries = get_memory(&params, &init, vec![], accesses[0..4].to_vec());
+    assert!(is_satisfied(MemoryCircuit::new(&params, &init, entries.clone(), MemoryPoint::start(), Fr::from(0)).unwrap()));
+    let skipped: Vec<MemEntry> = entries.iter().filter(|e| !matches!(e, MemEntry::Init(_, _, 2, _))).cloned().collect();
+    assert!(!is_satisfied(MemoryCircuit::new(&params, &init, skipped.clone(), MemoryPoint::start(), Fr::from(0)).unwrap()));
+    // only the last batch checks the number of cells
+    let circuits = batches(&params, &init, &skipped, 3).unwrap();
+    assert!(is_satisfied(circuits[0].clone()));
+    assert!(!is_satisfied(circuits.last().unwrap().clone()));
+
+    // Initial memory that doesn't match the root
+    let other = InitMemory::new(vec![(global(0), 10), (mem(1 << 20), 4), (global(2), 7)]).unwrap();
+    let mut circuit = MemoryCircuit::new(&params, &other, get_memory(&params, &other, vec![], vec![]), MemoryPoint::start(), Fr::from(0)).unwrap();
+    circuit.init_root = init.root(&params);
+    assert!(!is_satisfied(circuit));
+
+    // Merkle path of the wrong length is an error, not a panic
+    let mut circuit = whole.clone();
+    for e in circuit.entries.iter_mut() {
+        if let MemEntry::Init(_, _, _, path) = e {
+            path.pop();
+            break;
+        }
+    }
+    let cs_sys = ark_relations::r1cs::ConstraintSystem::<Fr>::new();
+    assert!(circuit.generate_constraints(ConstraintSystemRef::new(cs_sys)).is_err());
+}
+
+#[test]
+fn test_memory_permutation() {
+    use ark_relations::r1cs::ConstraintSystem;
+    let params = crate::generate_hash();
+    let global = |idx| Address { segment: Segment::Global, idx };
+    let init = InitMemory::new(vec![(global(0), 10)]).unwrap();
+    let accesses = vec![
+        MemAccess { addr: global(1), step: 3, value: 5, is_set: true },
+        MemAccess { addr: global(0), step: 20, value: 10, is_set: false },
+        MemAccess { addr: global(1), step: 21, value: 5, is_set: false },
+    ];
+    let ts = local_transitions(&params);
+    let entries = get_memory(&params, &init, ts.clone(), accesses);
+    let check = |circuit: PermutationCircuit| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        circuit.generate_constraints(cs.clone()).unwrap();
+        cs.is_satisfied().unwrap()
     };
-    return 0;
-}
-
-pub fn test_memory(params: &PoseidonParameters<Fr>, ts: Vec<Transition>) {
-    let transitions = get_memory(ts)[0..8].iter().map(|a| a.clone()).collect::<Vec<_>>().clone();
-    println!("{} mem ops", transitions.len());
-    let last = transitions.last().unwrap().clone();
-    let first = transitions[0].clone();
-    let end_value = get_last_value(&transitions);
-    let circuit = MemoryCircuit {
-        transitions,
-        params: params.clone(),
-        start_addr: 0,
-        start_step: (first.before.step_counter-1) as u32,
-        start_value: 0,
-    
-        end_addr: 1,
-        end_step: last.before.step_counter as u32,
-        end_value,
+
+    let circuit = PermutationCircuit::new(&params, &ts, &entries).unwrap();
+    // execution order is committed by the root of the execution proofs
+    assert_eq!(circuit.public_inputs()[0], transition_root(&params, &ts));
+    // memory order is the chain of the memory circuit, also when it is split into batches
+    let memory = MemoryCircuit::new(&params, &init, entries.clone(), MemoryPoint::start(), Fr::from(0)).unwrap();
+    assert_eq!(circuit.public_inputs()[2], memory.public_inputs()[10]);
+    let last = batches(&params, &init, &entries, 3).unwrap().pop().unwrap();
+    assert_eq!(circuit.public_inputs()[2], last.end_chain);
+    assert_eq!(circuit.steps.len(), 8);
+    assert_eq!(circuit.accesses.len(), 3);
+    assert!(check(circuit.clone()));
+
+    // a memory instruction that doesn't appear in memory order
+    let mut bad = circuit.clone();
+    bad.sorted.remove(2);
+    assert!(!check(bad));
+
+    // memory order has another operation
+    let mut bad = circuit.clone();
+    bad.sorted[2] = Fr::from(1);
+    assert!(!check(bad));
+
+    // other accesses replaced by a duplicate
+    let mut bad = circuit.clone();
+    bad.accesses[1] = bad.accesses[0];
+    assert!(!check(bad));
+
+    // memory order of another execution
+    let mut other = ts.clone();
+    other.truncate(4);
+    let other_entries = get_memory(&params, &init, other, vec![]);
+    let mut bad = circuit.clone();
+    bad.sorted = PermutationCircuit::new(&params, &ts[0..4], &other_entries).unwrap().sorted;
+    bad.accesses = vec![];
+    assert!(!check(bad));
+}
+
+#[test]
+fn test_memory_chunks() {
+    use ark_relations::r1cs::ConstraintSystem;
+    let params = crate::generate_hash();
+    let global = |idx| Address { segment: Segment::Global, idx };
+    let init = InitMemory::new(vec![(global(0), 10), (global(3), 4)]).unwrap();
+    let accesses = vec![
+        MemAccess { addr: global(0), step: 20, value: 10, is_set: false },
+        MemAccess { addr: global(0), step: 21, value: 12, is_set: true },
+        MemAccess { addr: global(3), step: 22, value: 4, is_set: false },
+    ];
+    let entries = get_memory(&params, &init, local_transitions(&params), accesses);
+    assert_eq!(entries.len(), 10);
+    let circuits = chunks(&params, &init, &entries, 3).unwrap();
+    assert_eq!(circuits.len(), 4);
+
+    let whole = MemoryCircuit::new(&params, &init, entries.clone(), MemoryPoint::start(), Fr::from(0)).unwrap();
+    let perm = PermutationCircuit::new(&params, &local_transitions(&params), &entries).unwrap();
+    assert_eq!(whole.end_chain, perm.public_inputs()[2]);
+    // the aggregated proof goes from the start state to the finish state with the chain of the memory order
+    let expected = memory_inputs(&params, init.root(&params), perm.public_inputs()[2]);
+    assert_eq!(circuits[0].get().0, expected[0]);
+    assert_eq!(circuits.last().unwrap().get().1, expected[1]);
+    assert!(matches!(circuits[3].slots[1], Slot::Finish));
+
+    // Chunks have the same shape and chain together
+    let mut num_constraints = vec![];
+    for i in 0..circuits.len() {
+        if i > 0 {
+            assert_eq!(circuits[i-1].get().1, circuits[i].get().0);
+        }
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        circuits[i].clone().generate_constraints(cs.clone()).unwrap();
+        assert!(cs.is_satisfied().unwrap());
+        num_constraints.push(cs.num_constraints());
+    }
+    assert!(num_constraints.iter().all(|n| *n == num_constraints[0]));
+
+    // Wrong value read in the last chunk
+    let mut bad_entries = entries.clone();
+    if let MemEntry::Access(a) = &mut bad_entries[9] {
+        a.value = 5;
+    }
+    let bad = MemoryChunk::new(&params, &init, &bad_entries[9..], 3, circuits[3].start, circuits[3].start_chain, true).unwrap();
+    let check = |circuit: MemoryChunk| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        circuit.generate_constraints(cs.clone()).unwrap();
+        cs.is_satisfied().unwrap()
     };
-    crate::test_circuit(circuit);
+    assert!(!check(bad));
+
+    // Skipping the last initial cell, the finish slot fails
+    let skipped: Vec<MemEntry> = entries[0..8].to_vec();
+    let circuits = chunks(&params, &init, &skipped, 3).unwrap();
+    assert!(matches!(circuits[2].slots[2], Slot::Finish));
+    assert!(check(circuits[1].clone()));
+    assert!(!check(circuits[2].clone()));
+
+    // A finish slot in the middle can't be followed by other entries
+    let mut bad = MemoryChunk::new(&params, &init, &[], 3, MemoryPoint::end(), Fr::from(0), false).unwrap();
+    bad.slots[0] = Slot::Entry(entries[9].clone());
+    assert!(!check(bad));
+
+    // Merkle path of the wrong length
+    let mut bad = MemoryChunk::new(&params, &init, &entries[0..3], 3, MemoryPoint::start(), Fr::from(0), false).unwrap();
+    let init_slot = bad.slots.iter_mut().find(|s| matches!(s, Slot::Entry(MemEntry::Init(_, _, _, _)))).unwrap();
+    if let Slot::Entry(MemEntry::Init(_, _, _, path)) = init_slot {
+        path.push(Fr::from(0));
+    }
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    assert!(bad.generate_constraints(ConstraintSystemRef::new(cs_sys)).is_err());
 }
 
+#[test]
+fn test_memory_errors() {
+    let params = crate::generate_hash();
+    let global = |idx| Address { segment: Segment::Global, idx };
+    assert!(InitMemory::new(vec![(global(0), 10), (global(0), 11)]).is_err());
+    let init = InitMemory::new(vec![]).unwrap();
+    let ts = local_transitions(&params);
+    // a transition that doesn't access memory has no place in the memory order
+    let constant = ts.iter().find(|tr| get_info(&tr.before).is_none()).unwrap().clone();
+    let entries = vec![MemEntry::Instruction(constant)];
+    assert!(entries[0].key().is_err());
+    assert!(MemoryPoint::start().after(&entries).is_err());
+    assert!(MemoryCircuit::new(&params, &init, entries.clone(), MemoryPoint::start(), Fr::from(0)).is_err());
+    assert!(PermutationCircuit::new(&params, &ts, &entries).is_err());
+    // transitions out of order
+    let mut rev = ts.clone();
+    rev.reverse();
+    assert!(PermutationCircuit::new(&params, &rev, &get_memory(&params, &init, ts.clone(), vec![])).is_err());
+    // more entries than slots
+    let entries = get_memory(&params, &init, ts, vec![]);
+    assert!(MemoryChunk::new(&params, &init, &entries[0..3], 3, MemoryPoint::start(), Fr::from(0), true).is_err());
+}
@@ -85,7 +85,6 @@ fn merkle_loop(cs: ConstraintSystemRef<Fr>, params : &PoseidonParameters<Fr>, pa
     last.enforce_equal(&end_var).unwrap();
 
     // println!("Testing {}", cs.is_satisfied().unwrap());
-    println!("circuit has {} constraints", cs.num_constraints());
 
 }
 
@@ -109,6 +108,7 @@ impl LoopCircuit for MerkleLoop {
 
 impl ConstraintSynthesizer<Fr> for MerkleLoop {
     fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         merkle_loop(cs, &self.params, &self.paths, &self.leafs, self.root, self.selectors);
         Ok(())
     }
@@ -162,6 +162,8 @@ use crate::aggloop::inner_to_outer;
 use crate::aggloop::outer_to_inner;
 use crate::aggloop::{aggregate_list1, aggregate_list2};
 use crate::aggloop::LoopCircuit2;
+use crate::aggloop::{InnerAggregateLoop, OuterAggregateLoop};
+use crate::metrics;
 use crate::OuterSNARK;
 use crate::InnerSNARKProof;
 use crate::InnerSNARKVK;
@@ -183,7 +185,7 @@ pub fn handle_loop(params : &PoseidonParameters<Fr>, transitions: Vec<Transition
         level1.push(hash_pair(params, &tr.before.hash(params), &tr.after.hash(params)));
     }
     let levels = compute_levels(params, &level1);
-    println!("Got levels {}", levels.len());
+    tracing::debug!(levels = levels.len(), "transition tree");
 
     for (i,tr) in transitions.iter().enumerate() {
         let idx = tr.before.step_counter;
@@ -226,11 +228,9 @@ pub fn handle_loop(params : &PoseidonParameters<Fr>, transitions: Vec<Transition
     let circuit = circuits[0].clone();
 
     let mut rng = test_rng();
-    println!("Setting up circuit");
-    let (pk, vk) = InnerSNARK::setup(circuit.clone(), &mut rng).unwrap();
-    println!("Testing prove");
-    let proof = InnerSNARK::prove(&pk, circuit.clone(), &mut rng).unwrap();
-    println!("proof: {}", InnerSNARK::verify(&vk, &circuit.get_inputs(), &proof).unwrap());
+    let (pk, vk) = metrics::setup::<MerkleLoop, _, _>(|| InnerSNARK::setup(circuit.clone(), &mut rng).unwrap());
+    let proof = metrics::prove::<MerkleLoop, _, _>(|| InnerSNARK::prove(&pk, circuit.clone(), &mut rng).unwrap());
+    tracing::debug!(verified = InnerSNARK::verify(&vk, &circuit.get_inputs(), &proof).unwrap(), "test proof");
 
     let setup1 = InnerSetup {
         pk,
@@ -256,32 +256,37 @@ pub fn handle_loop(params : &PoseidonParameters<Fr>, transitions: Vec<Transition
         agg_circuits1.push(agg_circuit_out);
     }
 
-    println!("Starting aggregation");
-
-    let level1 = aggregate_list1(&circuits, &setup1);
+    let level1 = {
+        let _level = metrics::level("loop", 0, circuits.len());
+        aggregate_list1(&circuits, &setup1)
+    };
 
     crate::test_circuit2(level1[0].clone());
 
     let mut prev_level = level1;
     for i in 0..2 {
-        println!("Level {} first len {}", i, prev_level.len());
-        let level2 = aggregate_list2(&prev_level, &setups1[i]);
-        println!("Level {} second len {}", i, level2.len());
+        let level2 = {
+            let _level = metrics::level("loop", 2*i + 1, prev_level.len());
+            aggregate_list2(&prev_level, &setups1[i])
+        };
         if level2.len() == 1 {
             let last = level2[0].clone();
             let setup = setups2[i].clone();
-            let proof1 = InnerSNARK::prove(&setup.pk, last.clone(), &mut rng).unwrap();
-            println!("last proof: {}", InnerSNARK::verify(&setup.vk, &last.get_inputs(), &proof1).unwrap());
+            let proof1 = metrics::prove::<OuterAggregateLoop, _, _>(|| InnerSNARK::prove(&setup.pk, last.clone(), &mut rng).unwrap());
+            tracing::debug!(verified = InnerSNARK::verify(&setup.vk, &last.get_inputs(), &proof1).unwrap(), "last proof");
             return (proof1.clone(), setup.vk.clone(), leafs[0].clone(), leafs.last().unwrap().clone())
         }
-        prev_level = aggregate_list1(&level2, &setups2[i]);
+        prev_level = {
+            let _level = metrics::level("loop", 2*i + 2, level2.len());
+            aggregate_list1(&level2, &setups2[i])
+        };
     }
 
     {
         let last = prev_level[0].clone();
         let setup = setups1[1].clone();
-        let proof1 = OuterSNARK::prove(&setup.pk, last.clone(), &mut rng).unwrap();
-        println!("last proof (outer): {}", OuterSNARK::verify(&setup.vk, &last.get_inputs(), &proof1).unwrap());
+        let proof1 = metrics::prove::<InnerAggregateLoop, _, _>(|| OuterSNARK::prove(&setup.pk, last.clone(), &mut rng).unwrap());
+        tracing::debug!(verified = OuterSNARK::verify(&setup.vk, &last.get_inputs(), &proof1).unwrap(), "last proof (outer)");
     }
     panic!("Wrong kind of last proof");
 
@@ -0,0 +1,174 @@
+// Tracing spans and metrics for circuits and proofs
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use std::fs::File;
+use std::io::Write;
+
+use ark_ff::Field;
+use ark_relations::r1cs::ConstraintSystemRef;
+use tracing::span::EnteredSpan;
+
+#[derive(Debug, Clone, Default)]
+pub struct CircuitMetrics {
+    pub constraints: usize,
+    pub syntheses: usize,
+    pub witness_ms: u128,
+    pub setups: usize,
+    pub setup_ms: u128,
+    pub proofs: usize,
+    pub prove_ms: u128,
+}
+
+static METRICS: Mutex<BTreeMap<String, CircuitMetrics>> = Mutex::new(BTreeMap::new());
+
+// Log level comes from RUST_LOG, for example RUST_LOG=wasm_test=debug
+pub fn init() {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .try_init();
+}
+
+// Name of the circuit type with the module paths removed, also inside generic arguments
+pub fn name<C>() -> String {
+    let mut res = String::new();
+    // start of the current path in res
+    let mut start = 0;
+    let mut chars = std::any::type_name::<C>().chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            res.truncate(start);
+        } else {
+            res.push(c);
+            if !(c.is_alphanumeric() || c == '_') {
+                start = res.len();
+            }
+        }
+    }
+    res
+}
+
+fn update<F: FnOnce(&mut CircuitMetrics)>(name: &str, f: F) {
+    let mut metrics = METRICS.lock().unwrap();
+    f(metrics.entry(name.to_string()).or_default());
+}
+
+// Span for generate_constraints, records the number of constraints and the witness time when dropped
+pub struct Synthesis<F: Field> {
+    name: String,
+    cs: ConstraintSystemRef<F>,
+    start: Instant,
+    _span: EnteredSpan,
+}
+
+pub fn synthesis<C, F: Field>(cs: &ConstraintSystemRef<F>) -> Synthesis<F> {
+    Synthesis {
+        name: name::<C>(),
+        cs: cs.clone(),
+        start: Instant::now(),
+        _span: tracing::info_span!("circuit", name = name::<C>().as_str()).entered(),
+    }
+}
+
+impl<F: Field> Drop for Synthesis<F> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed().as_millis();
+        let constraints = self.cs.num_constraints();
+        let setup = self.cs.is_in_setup_mode();
+        tracing::debug!(constraints, elapsed_ms = elapsed as u64, setup, "synthesized");
+        update(&self.name, |m| {
+            m.constraints = constraints;
+            m.syntheses += 1;
+            if !setup {
+                m.witness_ms += elapsed;
+            }
+        });
+    }
+}
+
+pub fn setup<C, T, G: FnOnce() -> T>(f: G) -> T {
+    let _span = tracing::info_span!("setup", name = name::<C>().as_str()).entered();
+    let start = Instant::now();
+    let res = f();
+    let elapsed = start.elapsed().as_millis();
+    tracing::info!(elapsed_ms = elapsed as u64, "setup done");
+    update(&name::<C>(), |m| {
+        m.setups += 1;
+        m.setup_ms += elapsed;
+    });
+    res
+}
+
+pub fn prove<C, T, G: FnOnce() -> T>(f: G) -> T {
+    let _span = tracing::info_span!("proof", name = name::<C>().as_str()).entered();
+    let start = Instant::now();
+    let res = f();
+    let elapsed = start.elapsed().as_millis();
+    tracing::info!(elapsed_ms = elapsed as u64, "proving done");
+    update(&name::<C>(), |m| {
+        m.proofs += 1;
+        m.prove_ms += elapsed;
+    });
+    res
+}
+
+// Span for one level of recursive aggregation
+pub fn level(kind: &'static str, level: usize, len: usize) -> EnteredSpan {
+    let span = tracing::info_span!("aggregation", kind, level).entered();
+    tracing::info!(circuits = len, "aggregation level");
+    span
+}
+
+pub fn report() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut circuits = serde_json::Map::new();
+    for (name, m) in metrics.iter() {
+        circuits.insert(name.clone(), serde_json::json!({
+            "constraints": m.constraints,
+            "syntheses": m.syntheses,
+            "witness_ms": m.witness_ms as u64,
+            "setups": m.setups,
+            "setup_ms": m.setup_ms as u64,
+            "proofs": m.proofs,
+            "prove_ms": m.prove_ms as u64,
+        }));
+    }
+    serde_json::to_string_pretty(&serde_json::json!({ "circuits": circuits })).unwrap()
+}
+
+pub fn write_report(fname: &str) {
+    let mut file = File::create(fname).unwrap();
+    file.write_all(report().as_bytes()).unwrap();
+}
+
+pub fn get(name: &str) -> Option<CircuitMetrics> {
+    METRICS.lock().unwrap().get(name).cloned()
+}
+
+#[test]
+fn test_metrics() {
+    use ark_mnt4_298::Fr;
+    use ark_relations::r1cs::ConstraintSystem;
+    use ark_r1cs_std::alloc::AllocVar;
+    use ark_r1cs_std::fields::fp::FpVar;
+    use ark_r1cs_std::eq::EqGadget;
+    struct Dummy;
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    {
+        let _metrics = synthesis::<Dummy, _>(&cs);
+        let a = FpVar::new_witness(cs.clone(), || Ok(Fr::from(3u32))).unwrap();
+        (a.clone() * a).enforce_equal(&FpVar::constant(Fr::from(9u32))).unwrap();
+    }
+    prove::<Dummy, _, _>(|| ());
+    let m = get("Dummy").unwrap();
+    assert_eq!(m.constraints, cs.num_constraints());
+    assert_eq!(m.syntheses, 1);
+    assert_eq!(m.proofs, 1);
+    let report: serde_json::Value = serde_json::from_str(&report()).unwrap();
+    assert_eq!(report["circuits"]["Dummy"]["proofs"], 1);
+    assert_eq!(name::<Vec<Option<String>>>(), "Vec<Option<String>>");
+    assert_eq!(name::<(u32, Dummy)>(), "(u32, Dummy)");
+}
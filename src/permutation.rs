@@ -9,10 +9,12 @@ use ark_relations::r1cs::ConstraintSynthesizer;
 use ark_crypto_primitives::{CRHSchemeGadget, CRHScheme};
 use ark_relations::r1cs::SynthesisError;
 use ark_relations::r1cs::ConstraintSystemRef;
+use ark_relations::r1cs::ConstraintSystem;
 use ark_r1cs_std::eq::EqGadget;
 use ark_sponge::poseidon::PoseidonParameters;
 use ark_r1cs_std::boolean::{AllocatedBool,Boolean};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 
 use crate::{VM,Transition,hash_code};
 use crate::InstructionCircuit;
@@ -23,25 +25,21 @@ use crate::as_waksman::IntegerPermutation;
 use ark_r1cs_std::R1CSVar;
 use crate::as_waksman::AsWaksmanRoute;
 use crate::as_waksman::AsWaksmanTopology;
-
-fn make_switch(a: FpVar<Fr>, b: FpVar<Fr>, switch: Boolean<Fr>) -> (FpVar<Fr>,FpVar<Fr>) {
-    let out1 = switch.select(&a, &b).unwrap();
-    let out2 = switch.select(&b, &a).unwrap();
-    (out1, out2)
-}
+use crate::hash::{Params, poseidon_gadget, generate_params};
 
 fn make_switch_list(a: Vec<FpVar<Fr>>, b: Vec<FpVar<Fr>>, switch: Boolean<Fr>) -> (Vec<FpVar<Fr>>,Vec<FpVar<Fr>>) {
     let mut out1 = vec![];
     let mut out2 = vec![];
     for i in 0..a.len() {
         out1.push(switch.select(&a[i], &b[i]).unwrap());
-        out2.push(switch.select(&a[i], &b[i]).unwrap());
+        out2.push(switch.select(&b[i], &a[i]).unwrap());
     }
     (out1, out2)
 }
 
 // get a list of variables and integer permutation?
-pub fn permutation(cs: ConstraintSystemRef<Fr>, lst: Vec<FpVar<Fr>>, perm: IntegerPermutation) -> Vec<FpVar<Fr>> {
+// input i ends up at position perm(i), every switch is allocated so the shape only depends on the size
+pub fn permutation_list(cs: ConstraintSystemRef<Fr>, lst: Vec<Vec<FpVar<Fr>>>, perm: IntegerPermutation) -> Vec<Vec<FpVar<Fr>>> {
     let size = lst.len();
     let topology = AsWaksmanTopology::new(size);
     let num_columns = AsWaksmanTopology::num_colunms(size);
@@ -49,26 +47,19 @@ pub fn permutation(cs: ConstraintSystemRef<Fr>, lst: Vec<FpVar<Fr>>, perm: Integ
     let mut permutation = lst.clone();
     for column_idx in 0..num_columns {
         let mut next_permutation = permutation.clone();
-        // println!("column topology {:?}", topology.topology[column_idx]);
         let mut p_idx = 0;
         while p_idx < size {
-            let switch1 = topology.topology[column_idx][p_idx];
-            match route.switches[column_idx].get(&p_idx) {
-                // If is none, means straight switch (for both)
-                None => {
-                    // println!("1: {:?}, 2: {:?}", switch1, switch2);
-                    assert!(switch1.0 == switch1.1);
-                    next_permutation[switch1.0] = permutation[p_idx].clone();
-                    p_idx += 1;
-                }
-                Some(switch_val) => {
-                    let _switch2 = topology.topology[column_idx][p_idx+1];
-                    let bool_var = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(switch_val)).unwrap());
-                    let (v1, v2) = make_switch(permutation[p_idx].clone(), permutation[p_idx+1].clone(), bool_var);
-                    next_permutation[switch1.0] = v2;
-                    next_permutation[switch1.1] = v1;
-                    p_idx += 2;
-                }
+            let (straight, cross) = topology.topology[column_idx][p_idx];
+            if straight == cross {
+                next_permutation[straight] = permutation[p_idx].clone();
+                p_idx += 1;
+            } else {
+                let switch_val = *route.switches[column_idx].get(&p_idx).unwrap_or(&false);
+                let bool_var = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(switch_val)).unwrap());
+                let (v1, v2) = make_switch_list(permutation[p_idx].clone(), permutation[p_idx+1].clone(), bool_var);
+                next_permutation[straight] = v2;
+                next_permutation[cross] = v1;
+                p_idx += 2;
             }
         }
         permutation = next_permutation;
@@ -77,41 +68,260 @@ pub fn permutation(cs: ConstraintSystemRef<Fr>, lst: Vec<FpVar<Fr>>, perm: Integ
     permutation
 }
 
-// get a list of variables and integer permutation?
-pub fn permutation_list(cs: ConstraintSystemRef<Fr>, lst: Vec<Vec<FpVar<Fr>>>, perm: IntegerPermutation) -> Vec<Vec<FpVar<Fr>>> {
-    let size = lst.len();
-    let topology = AsWaksmanTopology::new(size);
-    let num_columns = AsWaksmanTopology::num_colunms(size);
-    let route = AsWaksmanRoute::new(&perm);
-    let mut permutation = lst.clone();
-    for column_idx in 0..num_columns {
-        let mut next_permutation = permutation.clone();
-        for packet_idx in 0..size/2 {
-            let packet_idx = packet_idx*2;
-            let switch1 = topology.topology[column_idx][packet_idx];
-            let switch2 = topology.topology[column_idx][packet_idx+1];
-            match route.switches[column_idx].get(&packet_idx) {
-                // If is none, means straight switch (for both)
-                None => {
-                    assert!(switch1.0 == switch1.1 && switch2.0 == switch2.1);
-                    next_permutation[switch1.0] = permutation[packet_idx].clone();
-                    next_permutation[switch2.0] = permutation[packet_idx+1].clone();
-                }
-                Some(switch_val) => {
-                    let bool_var = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(switch_val)).unwrap());
-                    let (v1, v2) = make_switch_list(permutation[packet_idx].clone(), permutation[packet_idx+1].clone(), bool_var);
-                    next_permutation[switch1.0] = v2;
-                    next_permutation[switch1.1] = v1;
-                }
+// single variable version of permutation_list
+pub fn permutation(cs: ConstraintSystemRef<Fr>, lst: Vec<FpVar<Fr>>, perm: IntegerPermutation) -> Vec<FpVar<Fr>> {
+    let lst = lst.into_iter().map(|v| vec![v]).collect();
+    permutation_list(cs, lst, perm).into_iter().map(|mut v| v.remove(0)).collect()
+}
+
+// input i goes to position j for the given pairs, other inputs fill the free positions in order
+pub fn fill_permutation(size: usize, fixed: Vec<(usize, usize)>) -> IntegerPermutation {
+    let mut perm = IntegerPermutation::new(size);
+    let mut set = vec![false; size];
+    let mut used = vec![false; size];
+    for (i, j) in fixed {
+        perm.set(i, j);
+        set[i] = true;
+        used[j] = true;
+    }
+    let free: Vec<usize> = (0..size).filter(|j| !used[*j]).collect();
+    for (i, j) in (0..size).filter(|i| !set[*i]).zip(free.into_iter()) {
+        perm.set(i, j);
+    }
+    perm
+}
+
+// Permutation moving input i to position j for every output j with the same value.
+// Equal values are matched in order, unmatched inputs fill the remaining positions.
+fn route_values<T: Eq + std::hash::Hash + Clone>(inputs: &[T], outputs: &[T]) -> IntegerPermutation {
+    let size = inputs.len();
+    let mut positions: HashMap<T, Vec<usize>> = HashMap::new();
+    for (j, v) in outputs.iter().enumerate().rev() {
+        positions.entry(v.clone()).or_default().push(j);
+    }
+    let mut perm = IntegerPermutation::new(size);
+    let mut used = vec![false; size];
+    let mut unmatched = vec![];
+    for (i, v) in inputs.iter().enumerate() {
+        match positions.get_mut(v).and_then(|lst| lst.pop()) {
+            Some(j) => {
+                perm.set(i, j);
+                used[j] = true;
             }
+            None => unmatched.push(i),
         }
-        permutation = next_permutation;
-        // println!("{} variable states {:?}", column_idx, permutation.iter().map(|v| v.value().unwrap().to_string()).collect::<Vec<_>>());
     }
-    permutation
+    let free: Vec<usize> = (0..size).filter(|j| !used[*j]).collect();
+    for (i, j) in unmatched.into_iter().zip(free.into_iter()) {
+        perm.set(i, j);
+    }
+    perm
 }
 
-use ark_relations::r1cs::ConstraintSystem;
+// Enforces that outputs are a permutation of inputs. The routing is computed from the witness
+// values, every switch of the network is allocated so the shape only depends on the size.
+// Lists of different lengths are never a permutation, that is Unsatisfiable.
+pub fn prove_permuted(cs: ConstraintSystemRef<Fr>, inputs: &[FpVar<Fr>], outputs: &[FpVar<Fr>]) -> Result<(), SynthesisError> {
+    let single = |v: &[FpVar<Fr>]| v.iter().map(|a| vec![a.clone()]).collect::<Vec<_>>();
+    prove_permuted_list(cs, &single(inputs), &single(outputs))
+}
+
+// Same for tuples, a tuple is moved as a whole
+pub fn prove_permuted_list(cs: ConstraintSystemRef<Fr>, inputs: &[Vec<FpVar<Fr>>], outputs: &[Vec<FpVar<Fr>>]) -> Result<(), SynthesisError> {
+    if inputs.len() != outputs.len() {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+    let size = inputs.len();
+    if size < 2 {
+        for (a, b) in inputs.iter().zip(outputs.iter()) {
+            a.enforce_equal(b)?;
+        }
+        return Ok(());
+    }
+    let perm = match (inputs.value(), outputs.value()) {
+        (Ok(a), Ok(b)) => route_values(&a, &b),
+        _ => IntegerPermutation::new(size),
+    };
+    let current = permutation_list(cs, inputs.to_vec(), perm);
+    for (a, b) in current.iter().zip(outputs.iter()) {
+        a.enforce_equal(b)?;
+    }
+    Ok(())
+}
+
+// Checks that outputs are a permutation of inputs, lists of different lengths are Unsatisfiable
+pub trait PermutationArgument {
+    fn name(&self) -> &'static str;
+    fn enforce(&self, cs: ConstraintSystemRef<Fr>, inputs: &[FpVar<Fr>], outputs: &[FpVar<Fr>]) -> Result<(), SynthesisError>;
+    // tuples of the same length, compared as a whole
+    fn enforce_list(&self, cs: ConstraintSystemRef<Fr>, inputs: &[Vec<FpVar<Fr>>], outputs: &[Vec<FpVar<Fr>>]) -> Result<(), SynthesisError>;
+}
+
+// AS-Waksman network, O(n log n) switches
+pub struct WaksmanArgument;
+
+impl PermutationArgument for WaksmanArgument {
+    fn name(&self) -> &'static str { "waksman" }
+    fn enforce(&self, cs: ConstraintSystemRef<Fr>, inputs: &[FpVar<Fr>], outputs: &[FpVar<Fr>]) -> Result<(), SynthesisError> {
+        prove_permuted(cs, inputs, outputs)
+    }
+    fn enforce_list(&self, cs: ConstraintSystemRef<Fr>, inputs: &[Vec<FpVar<Fr>>], outputs: &[Vec<FpVar<Fr>>]) -> Result<(), SynthesisError> {
+        prove_permuted_list(cs, inputs, outputs)
+    }
+}
+
+// Multiset equality prod(x - a_i) = prod(x - b_i). The challenge x is hashed from both lists,
+// or from a commitment that the rest of the circuit already binds to both lists.
+// The commitment variant is only sound if the commitment is computed in the circuit from both lists
+// (for example their hashes or tree roots) or is a public input that the verifier computes that way.
+// A constant or a free witness lets the prover pick the outputs after seeing x.
+pub struct GrandProductArgument {
+    pub params: Params,
+    pub commitment: Option<FpVar<Fr>>,
+}
+
+impl GrandProductArgument {
+    pub fn new(params: &Params) -> Self {
+        GrandProductArgument { params: params.clone(), commitment: None }
+    }
+    pub fn with_commitment(params: &Params, commitment: FpVar<Fr>) -> Self {
+        assert!(!commitment.is_constant(), "commitment must be bound to the lists");
+        GrandProductArgument { params: params.clone(), commitment: Some(commitment) }
+    }
+}
+
+// Chained hash of a list, 8 elements per hash
+fn hash_chunks(params: &Params, v: &[FpVar<Fr>]) -> FpVar<Fr> {
+    let mut h = FpVar::constant(Fr::from(0));
+    for chunk in v.chunks(8) {
+        let mut inputs = chunk.to_vec();
+        inputs.push(h);
+        h = poseidon_gadget(params, inputs);
+    }
+    h
+}
+
+// Commitment to both lists for GrandProductArgument::with_commitment
+pub fn commit_lists(params: &Params, inputs: &[FpVar<Fr>], outputs: &[FpVar<Fr>]) -> FpVar<Fr> {
+    poseidon_gadget(params, vec![hash_chunks(params, inputs), hash_chunks(params, outputs)])
+}
+
+impl PermutationArgument for GrandProductArgument {
+    fn name(&self) -> &'static str {
+        if self.commitment.is_some() { "grand product (committed)" } else { "grand product" }
+    }
+    fn enforce(&self, _cs: ConstraintSystemRef<Fr>, inputs: &[FpVar<Fr>], outputs: &[FpVar<Fr>]) -> Result<(), SynthesisError> {
+        if inputs.len() != outputs.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+        let x = self.challenge(inputs, outputs);
+        grand_product(&x, inputs, outputs)
+    }
+    // tuples are compressed with powers of a second challenge y = H(x)
+    fn enforce_list(&self, _cs: ConstraintSystemRef<Fr>, inputs: &[Vec<FpVar<Fr>>], outputs: &[Vec<FpVar<Fr>>]) -> Result<(), SynthesisError> {
+        let width = inputs.first().map(|v| v.len()).unwrap_or(0);
+        if inputs.len() != outputs.len() || !inputs.iter().chain(outputs.iter()).all(|v| v.len() == width) {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+        let x = self.challenge(&inputs.concat(), &outputs.concat());
+        let y = poseidon_gadget(&self.params, vec![x.clone()]);
+        let compress = |v: &Vec<FpVar<Fr>>| {
+            let mut acc = FpVar::constant(Fr::from(0));
+            for e in v.iter().rev() {
+                acc = acc * &y + e;
+            }
+            acc
+        };
+        let a: Vec<FpVar<Fr>> = inputs.iter().map(compress).collect();
+        let b: Vec<FpVar<Fr>> = outputs.iter().map(compress).collect();
+        grand_product(&x, &a, &b)
+    }
+}
+
+impl GrandProductArgument {
+    fn challenge(&self, inputs: &[FpVar<Fr>], outputs: &[FpVar<Fr>]) -> FpVar<Fr> {
+        match &self.commitment {
+            Some(c) => poseidon_gadget(&self.params, vec![c.clone()]),
+            None => poseidon_gadget(&self.params, vec![commit_lists(&self.params, inputs, outputs)]),
+        }
+    }
+}
+
+fn grand_product(x: &FpVar<Fr>, inputs: &[FpVar<Fr>], outputs: &[FpVar<Fr>]) -> Result<(), SynthesisError> {
+    let mut prod_a = FpVar::constant(Fr::from(1));
+    let mut prod_b = FpVar::constant(Fr::from(1));
+    for (a, b) in inputs.iter().zip(outputs.iter()) {
+        prod_a = prod_a * (x.clone() - a);
+        prod_b = prod_b * (x.clone() - b);
+    }
+    prod_a.enforce_equal(&prod_b)
+}
+
+// Like permutation, but the permuted list is a witness checked with the given argument
+pub fn permute_with<P: PermutationArgument>(arg: &P, cs: ConstraintSystemRef<Fr>, lst: Vec<FpVar<Fr>>, perm: IntegerPermutation) -> Result<Vec<FpVar<Fr>>, SynthesisError> {
+    let mut sources = vec![0; lst.len()];
+    for i in 0..lst.len() {
+        sources[perm.get(i)] = i;
+    }
+    let res: Vec<FpVar<Fr>> = sources.iter().map(|i| {
+        Ok(FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || lst[*i].value())?))
+    }).collect::<Result<_, SynthesisError>>()?;
+    arg.enforce(cs, &lst, &res)?;
+    Ok(res)
+}
+
+// Like permutation_list, checked with the given argument
+pub fn permute_list_with<P: PermutationArgument>(arg: &P, cs: ConstraintSystemRef<Fr>, lst: Vec<Vec<FpVar<Fr>>>, perm: IntegerPermutation) -> Result<Vec<Vec<FpVar<Fr>>>, SynthesisError> {
+    let mut sources = vec![0; lst.len()];
+    for i in 0..lst.len() {
+        sources[perm.get(i)] = i;
+    }
+    let res: Vec<Vec<FpVar<Fr>>> = sources.iter().map(|i| {
+        lst[*i].iter().map(|v| Ok(FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || v.value())?))).collect()
+    }).collect::<Result<_, SynthesisError>>()?;
+    arg.enforce_list(cs, &lst, &res)?;
+    Ok(res)
+}
+
+// Constraints of the Waksman network, the grand product and the committed grand product for one size
+fn permutation_counts(params: &Params, size: usize) -> (usize, usize, usize) {
+    let mut perm = IntegerPermutation::new(size);
+    for i in 0..size {
+        perm.set(i, (i*7 + 3) % size);
+    }
+    // arg returns the constraint count where its own work starts
+    let count = |arg: &dyn Fn(ConstraintSystemRef<Fr>, &[FpVar<Fr>], &[FpVar<Fr>]) -> usize| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let vars: Vec<FpVar<Fr>> = (0..size).map(|i| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap())).collect();
+        let mut permuted = vars.clone();
+        for i in 0..size {
+            permuted[perm.get(i)] = vars[i].clone();
+        }
+        let start = arg(cs.clone(), &vars, &permuted);
+        assert!(cs.is_satisfied().unwrap());
+        cs.num_constraints() - start
+    };
+    let waksman = count(&|cs, a, b| { let start = cs.num_constraints(); WaksmanArgument.enforce(cs, a, b).unwrap(); start });
+    let hashed = count(&|cs, a, b| { let start = cs.num_constraints(); GrandProductArgument::new(params).enforce(cs, a, b).unwrap(); start });
+    // the commitment is assumed to exist already, so its hashing is not counted
+    let committed = count(&|cs, a, b| {
+        let c = commit_lists(params, a, b);
+        let start = cs.num_constraints();
+        GrandProductArgument::with_commitment(params, c).enforce(cs.clone(), a, b).unwrap();
+        start
+    });
+    (waksman, hashed, committed)
+}
+
+// Constraints for each permutation argument at several sizes
+pub fn bench_permutation() {
+    let params = generate_params();
+    for size in [16, 64, 256, 1024, 4096] {
+        let (waksman, hashed, committed) = permutation_counts(&params, size);
+        tracing::info!(size, waksman, grand_product = hashed, committed, "permutation constraints");
+    }
+}
 
 pub fn test_permutation() {
     let size = 1 << 10;
@@ -139,3 +349,264 @@ pub fn test_permutation() {
     let _vars_perm = permutation(cs.clone(), vars.clone(), perm.clone());
     println!("num constraints {}, valid {}", cs.num_constraints(), cs.is_satisfied().unwrap());
 }
+
+#[cfg(test)]
+fn check_permuted(inputs: &[Fr], outputs: &[Fr]) -> bool {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let inputs: Vec<FpVar<Fr>> = inputs.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(*v)).unwrap())).collect();
+    let outputs: Vec<FpVar<Fr>> = outputs.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(*v)).unwrap())).collect();
+    prove_permuted(cs.clone(), &inputs, &outputs).unwrap();
+    cs.is_satisfied().unwrap()
+}
+
+#[cfg(test)]
+fn shuffle<R: ark_std::rand::Rng>(rng: &mut R, v: &mut [Fr]) {
+    for i in (1..v.len()).rev() {
+        let j = rng.gen_range(0..i+1);
+        v.swap(i, j);
+    }
+}
+
+#[test]
+fn test_prove_permuted() {
+    use ark_std::rand::Rng;
+    let mut rng = ark_std::test_rng();
+    for _ in 0..100 {
+        let size = rng.gen_range(1..70);
+        // small values so that there are duplicates
+        let inputs: Vec<Fr> = (0..size).map(|_| Fr::from(rng.gen_range(0..size as u32))).collect();
+        let mut outputs = inputs.clone();
+        shuffle(&mut rng, &mut outputs);
+        assert!(check_permuted(&inputs, &outputs), "size {}", size);
+
+        // change one output to a value that is not an input
+        let idx = rng.gen_range(0..size);
+        outputs[idx] = Fr::from(size as u32 + 1);
+        assert!(!check_permuted(&inputs, &outputs), "size {}", size);
+    }
+}
+
+#[test]
+fn test_prove_permuted_multiset() {
+    let v = |lst: &[u32]| lst.iter().map(|x| Fr::from(*x)).collect::<Vec<Fr>>();
+    assert!(check_permuted(&v(&[]), &v(&[])));
+    assert!(check_permuted(&v(&[4]), &v(&[4])));
+    assert!(check_permuted(&v(&[1, 2, 2, 3, 5]), &v(&[2, 5, 1, 3, 2])));
+    // same values but different multiplicities
+    assert!(!check_permuted(&v(&[1, 2, 2, 3, 5]), &v(&[2, 5, 1, 3, 3])));
+    assert!(!check_permuted(&v(&[1, 1, 1]), &v(&[1, 1, 2])));
+}
+
+#[test]
+fn test_permuted_length_mismatch() {
+    let params = generate_params();
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let vars: Vec<FpVar<Fr>> = (0..3).map(|i| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap())).collect();
+    assert!(matches!(prove_permuted(cs.clone(), &vars, &vars[..2]), Err(SynthesisError::Unsatisfiable)));
+    let pairs: Vec<Vec<FpVar<Fr>>> = vars.iter().map(|v| vec![v.clone(), v.clone()]).collect();
+    assert!(matches!(prove_permuted_list(cs.clone(), &pairs[..2], &pairs), Err(SynthesisError::Unsatisfiable)));
+    let gp = GrandProductArgument::new(&params);
+    assert!(matches!(gp.enforce(cs.clone(), &vars, &vars[..1]), Err(SynthesisError::Unsatisfiable)));
+    // tuples of different widths
+    let short: Vec<Vec<FpVar<Fr>>> = vars.iter().map(|v| vec![v.clone()]).collect();
+    assert!(matches!(gp.enforce_list(cs.clone(), &pairs, &short), Err(SynthesisError::Unsatisfiable)));
+}
+
+#[test]
+fn test_prove_permuted_shape() {
+    // setup does not know the values, the number of constraints must not depend on them
+    for size in [2, 3, 7, 8, 13] {
+        let inputs: Vec<Fr> = (0..size).map(|i| Fr::from(i as u32)).collect();
+        let outputs: Vec<Fr> = inputs.iter().rev().cloned().collect();
+        let count = |inputs: &[Fr], outputs: &[Fr]| {
+            let cs_sys = ConstraintSystem::<Fr>::new();
+            let cs = ConstraintSystemRef::new(cs_sys);
+            let a: Vec<FpVar<Fr>> = inputs.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(*v)).unwrap())).collect();
+            let b: Vec<FpVar<Fr>> = outputs.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(*v)).unwrap())).collect();
+            prove_permuted(cs.clone(), &a, &b).unwrap();
+            cs.num_constraints()
+        };
+        assert_eq!(count(&inputs, &outputs), count(&inputs, &inputs));
+    }
+}
+
+#[cfg(test)]
+fn check_argument<P: PermutationArgument>(arg: &P, inputs: &[u32], outputs: &[u32]) -> (bool, usize) {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let a: Vec<FpVar<Fr>> = inputs.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(*v))).unwrap())).collect();
+    let b: Vec<FpVar<Fr>> = outputs.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(*v))).unwrap())).collect();
+    let start = cs.num_constraints();
+    arg.enforce(cs.clone(), &a, &b).unwrap();
+    (cs.is_satisfied().unwrap(), cs.num_constraints() - start)
+}
+
+#[test]
+fn test_grand_product() {
+    let params = generate_params();
+    let arg = GrandProductArgument::new(&params);
+    assert!(check_argument(&arg, &[1, 2, 2, 3, 5], &[2, 5, 1, 3, 2]).0);
+    assert!(!check_argument(&arg, &[1, 2, 2, 3, 5], &[2, 5, 1, 3, 3]).0);
+    assert!(check_argument(&WaksmanArgument, &[1, 2, 2, 3, 5], &[2, 5, 1, 3, 2]).0);
+    assert!(!check_argument(&WaksmanArgument, &[1, 2, 2, 3, 5], &[2, 5, 1, 3, 3]).0);
+}
+
+#[test]
+fn test_permute_with() {
+    let params = generate_params();
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let size = 10;
+    let vars: Vec<FpVar<Fr>> = (0..size).map(|i| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap())).collect();
+    let mut perm = IntegerPermutation::new(size);
+    for i in 0..size {
+        perm.set(i, (i*3 + 1) % size);
+    }
+    let a = permutation(cs.clone(), vars.clone(), perm.clone());
+    let b = permute_with(&GrandProductArgument::new(&params), cs.clone(), vars.clone(), perm.clone()).unwrap();
+    let c = permute_with(&WaksmanArgument, cs.clone(), vars, perm).unwrap();
+    assert_eq!(a.value().unwrap(), b.value().unwrap());
+    assert_eq!(a.value().unwrap(), c.value().unwrap());
+    assert!(cs.is_satisfied().unwrap());
+}
+
+#[test]
+fn test_switch_list() {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let a = vec![FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(1u32))).unwrap())];
+    let b = vec![FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(2u32))).unwrap())];
+    // both outputs used to take the same input, so a crossed switch duplicated one of them
+    for (switch, expected) in [(true, (1u32, 2u32)), (false, (2, 1))] {
+        let (out1, out2) = make_switch_list(a.clone(), b.clone(), Boolean::constant(switch));
+        assert_eq!(out1[0].value().unwrap(), Fr::from(expected.0));
+        assert_eq!(out2[0].value().unwrap(), Fr::from(expected.1));
+    }
+}
+
+#[test]
+fn test_permutation_list() {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let size = 7;
+    let vars: Vec<FpVar<Fr>> = (0..size).map(|i| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap())).collect();
+    let mut perm = IntegerPermutation::new(size);
+    for i in 0..size {
+        perm.set(i, (i*3 + 1) % size);
+    }
+    let lst = vars.iter().map(|v| vec![v.clone(), v.clone() + v.clone()]).collect();
+    let res = permutation_list(cs.clone(), lst, perm.clone());
+    let single = permutation(cs.clone(), vars, perm.clone());
+    for i in 0..size {
+        assert_eq!(res[perm.get(i)][0].value().unwrap(), Fr::from(i as u32));
+        assert_eq!(res[i][0].value().unwrap(), single[i].value().unwrap());
+        assert_eq!(res[perm.get(i)][1].value().unwrap(), Fr::from(2*i as u32));
+    }
+    assert!(cs.is_satisfied().unwrap());
+}
+
+#[test]
+fn test_permutation_constraints() {
+    // with a commitment, the grand product is linear in the size
+    let params = generate_params();
+    let inputs: Vec<u32> = (0..256).collect();
+    let outputs: Vec<u32> = inputs.iter().rev().cloned().collect();
+    let (ok1, waksman) = check_argument(&WaksmanArgument, &inputs, &outputs);
+    let (ok2, committed) = check_committed(&params, &inputs, &outputs);
+    assert!(ok1 && ok2);
+    assert!(committed < waksman);
+}
+
+// commitment computed from the lists, its constraints are not counted
+#[cfg(test)]
+fn check_committed(params: &Params, inputs: &[u32], outputs: &[u32]) -> (bool, usize) {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let a: Vec<FpVar<Fr>> = inputs.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(*v))).unwrap())).collect();
+    let b: Vec<FpVar<Fr>> = outputs.iter().map(|v| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(*v))).unwrap())).collect();
+    let commitment = commit_lists(params, &a, &b);
+    let start = cs.num_constraints();
+    GrandProductArgument::with_commitment(params, commitment).enforce(cs.clone(), &a, &b).unwrap();
+    (cs.is_satisfied().unwrap(), cs.num_constraints() - start)
+}
+
+#[test]
+fn test_grand_product_committed() {
+    let params = generate_params();
+    assert!(check_committed(&params, &[1, 2, 2, 3, 5], &[2, 5, 1, 3, 2]).0);
+    assert!(!check_committed(&params, &[1, 2, 2, 3, 5], &[2, 5, 1, 3, 3]).0);
+}
+
+#[test]
+#[should_panic]
+fn test_grand_product_constant_commitment() {
+    let params = generate_params();
+    GrandProductArgument::with_commitment(&params, FpVar::constant(Fr::from(1)));
+}
+
+#[test]
+fn test_permute_list_with() {
+    let params = generate_params();
+    let size = 9;
+    let mut perm = IntegerPermutation::new(size);
+    for i in 0..size {
+        perm.set(i, (i*4 + 2) % size);
+    }
+    let check = |arg: &dyn Fn(ConstraintSystemRef<Fr>, Vec<Vec<FpVar<Fr>>>) -> Vec<Vec<FpVar<Fr>>>| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        // pairs (i, i % 3), the second elements have duplicates
+        let lst: Vec<Vec<FpVar<Fr>>> = (0..size).map(|i| vec![
+            FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap()),
+            FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from((i % 3) as u32))).unwrap()),
+        ]).collect();
+        let res = arg(cs.clone(), lst);
+        for i in 0..size {
+            assert_eq!(res[perm.get(i)][0].value().unwrap(), Fr::from(i as u32));
+            assert_eq!(res[perm.get(i)][1].value().unwrap(), Fr::from((i % 3) as u32));
+        }
+        cs.is_satisfied().unwrap()
+    };
+    assert!(check(&|cs, lst| permute_list_with(&WaksmanArgument, cs, lst, perm.clone()).unwrap()));
+    assert!(check(&|cs, lst| permute_list_with(&GrandProductArgument::new(&params), cs, lst, perm.clone()).unwrap()));
+    // tuples that are not a permutation of the inputs, but the columns are
+    let mixed = |inputs: &[(u32, u32)], outputs: &[(u32, u32)], arg: &dyn PermutationArgument| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let alloc = |lst: &[(u32, u32)]| lst.iter().map(|(a, b)| vec![
+            FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(*a))).unwrap()),
+            FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(*b))).unwrap()),
+        ]).collect::<Vec<_>>();
+        arg.enforce_list(cs.clone(), &alloc(inputs), &alloc(outputs)).unwrap();
+        cs.is_satisfied().unwrap()
+    };
+    let gp = GrandProductArgument::new(&params);
+    assert!(mixed(&[(1, 2), (3, 4), (1, 5)], &[(1, 5), (1, 2), (3, 4)], &WaksmanArgument));
+    assert!(mixed(&[(1, 2), (3, 4), (1, 5)], &[(1, 5), (1, 2), (3, 4)], &gp));
+    assert!(!mixed(&[(1, 2), (3, 4)], &[(1, 4), (3, 2)], &WaksmanArgument));
+    assert!(!mixed(&[(1, 2), (3, 4)], &[(1, 4), (3, 2)], &gp));
+}
+
+#[test]
+fn test_permutation_growth() {
+    let params = generate_params();
+    let sizes = [64, 128, 256, 512];
+    let counts: Vec<(usize, usize, usize)> = sizes.iter().map(|n| permutation_counts(&params, *n)).collect();
+    // grand products are linear, doubling the size doubles the increase
+    for i in 0..2 {
+        let fields: [fn(&(usize, usize, usize)) -> usize; 2] = [|c| c.1, |c| c.2];
+        for c in fields {
+            assert_eq!(c(&counts[i+2]) - c(&counts[i+1]), 2 * (c(&counts[i+1]) - c(&counts[i])));
+        }
+    }
+    // the network grows as n log n, faster than linear with the same ratio to n log n
+    let ratio = |i: usize| counts[i].0 as f64 / (sizes[i] as f64 * (sizes[i] as f64).log2());
+    for i in 0..sizes.len() {
+        if i > 0 {
+            assert!(counts[i].0 > 2 * counts[i-1].0);
+        }
+        assert!((ratio(i) / ratio(sizes.len() - 1) - 1.0).abs() < 0.1, "waksman {:?}", counts);
+    }
+}
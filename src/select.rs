@@ -62,6 +62,8 @@ use crate::InnerSNARKPK;
 use crate::mnt6;
 use crate::InstructionCircuit2;
 use crate::InstructionCircuit;
+use crate::Transition;
+use crate::addmany::{BatchCircuit, BatchKind, KINDS};
 
 #[derive(Debug, Clone)]
 pub struct SelectionCircuit {
@@ -83,6 +85,7 @@ impl ConstraintSynthesizer<MNT6Fr> for SelectionCircuit {
         self,
         cs: ConstraintSystemRef<MNT6Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let input_gadget = <InnerSNARKGadget as SNARKGadget<
             <MNT4PairingEngine as PairingEngine>::Fr,
             <MNT4PairingEngine as PairingEngine>::Fq,
@@ -129,8 +132,10 @@ impl ConstraintSynthesizer<MNT6Fr> for SelectionCircuit {
             vk_gadget
         }).collect();
 
+        // number of keys is a power of two, idx selects one with its low bits
+        let bits = self.keys.len().trailing_zeros() as usize;
         let mut bools2 = vec![];
-        for i in bools[0..4].iter().rev() {
+        for i in bools[0..bits].iter().rev() {
             bools2.push(i.clone())
         }
 
@@ -147,22 +152,24 @@ impl ConstraintSynthesizer<MNT6Fr> for SelectionCircuit {
 
         // println!("Working: {}", cs.is_satisfied().unwrap());
 
-        println!("recursive circuit has {} constraints", cs.num_constraints());
-
         Ok(())
     }
 }
 
-pub fn make_circuits<C: InstructionCircuit>(circuits: &mut Vec<SelectionCircuit>, lst: &[C], keys: &[(InnerSNARKPK, InnerSNARKVK)], idx: usize) {
+// Proves consecutive batches of k transitions, each with the cheapest key whose kind allows all of them.
+// keys are in the order of KINDS.
+pub fn make_batch_circuits(circuits: &mut Vec<SelectionCircuit>, params: &PoseidonParameters<Fr>, trs: &[Transition], keys: &[(InnerSNARKPK, InnerSNARKVK)], k: usize) -> Result<(), String> {
     let mut rng = test_rng();
-    for i in lst {
-        let proof = InnerSNARK::prove(&keys[idx].0, i.clone(), &mut rng).unwrap();
+    for chunk in trs.chunks(k) {
+        let idx = BatchKind::select(chunk);
+        let batch = BatchCircuit::new(params, chunk, KINDS[idx])?;
+        let proof = InnerSNARK::prove(&keys[idx].0, batch.clone(), &mut rng).unwrap();
         circuits.push(SelectionCircuit {
-            hash : i.calc_hash().clone(),
+            hash : batch.calc_hash(),
             proof: proof,
             keys: keys.iter().map(|a| a.1.clone()).collect(),
             idx: idx as u32,
-            // transition: i.transition(),
         });
     }
+    Ok(())
 }
@@ -43,12 +43,10 @@ impl ConstraintSynthesizer<Fr> for SetCircuit {
         self,
         cs: ConstraintSystemRef<Fr>,
     ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
         let before = self.before.clone();
         let after = self.after.clone();
 
-        println!("before {:?}", before);
-        println!("after {:?}", after);
-    
         let pc_hash = hash_code(&self.params, &after.pc);
         let stack_hash = after.hash_stack(&self.params);
         // let locals_hash = before.hash_locals(&self.params);
@@ -100,10 +98,8 @@ impl ConstraintSynthesizer<Fr> for SetCircuit {
         inputs_pc.push(hash_pc_after_var.clone());
         let hash_pc_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_pc).unwrap();
     
-        println!("stack before {}", before.hash_stack(&self.params));
         // println!("stack before {}", stack_before_var.value().unwrap());
         
-        println!("pc hash {}", hash_code(&self.params, &before.pc));
         // println!("pc hash {}", hash_pc_gadget.value().unwrap());
         
         // Compute VM hash before
@@ -128,8 +124,6 @@ impl ConstraintSynthesizer<Fr> for SetCircuit {
         let hash_transition_gadget = CRHGadget::<Fr>::evaluate(&params_g, &inputs_transition).unwrap();
         hash_transition_gadget.enforce_equal(&public_var)?;
     
-        println!("Made circuit");
-        println!("before {}, after {}", before.hash(&self.params), after.hash(&self.params));
         // println!("before {}, after {}", hash_vm_before_gadget.value().unwrap(), hash_vm_after_gadget.value().unwrap());
 
         Ok(())
@@ -1,67 +1,230 @@
 use crate::as_waksman::IntegerPermutation;
-use crate::as_waksman::AsWaksmanRoute;
-use crate::as_waksman::AsWaksmanTopology;
-use crate::Transition;
+use crate::permutation::{permute_with, PermutationArgument, WaksmanArgument};
+use crate::CodeTree;
 
 use ark_mnt4_298::Fr;
+use ark_ff::Zero;
 use ark_crypto_primitives::crh::poseidon::constraints::CRHGadget;
 use ark_r1cs_std::fields::fp::FpVar;
 use ark_crypto_primitives::crh::poseidon::constraints::CRHParametersVar;
 use ark_crypto_primitives::CRHSchemeGadget;
 use ark_sponge::poseidon::PoseidonParameters;
 use ark_relations::r1cs::ConstraintSystemRef;
-use ark_r1cs_std::prelude::CondSelectGadget;
-use ark_r1cs_std::boolean::AllocatedBool;
-use ark_r1cs_std::boolean::Boolean;
+use ark_relations::r1cs::SynthesisError;
 use ark_r1cs_std::alloc::AllocVar;
 use ark_r1cs_std::eq::EqGadget;
 use ark_r1cs_std::fields::fp::AllocatedFp;
 use ark_relations::r1cs::ConstraintSystem;
+use std::collections::HashMap;
 
-use crate::hash_pair;
+use crate::hash_many;
 
-#[derive(Debug, Clone)]
-struct Tree {
-    nodes: Vec<(usize, usize)>,
+// Child of a node: an input, an earlier node or a constant
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ref {
+    Input(usize),
+    Node(usize),
+    Const(Fr),
 }
 
-fn hash_tree(
-    cs: &ConstraintSystemRef<Fr>,
+// Each node is the hash of its children, the last node is the root.
+// Nodes can be used many times, so this describes any DAG of hashes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tree {
+    pub nodes: Vec<Vec<Ref>>,
+}
+
+impl Tree {
+    // binary tree from (a,b) pairs, indices below num_inputs are inputs, others are nodes
+    pub fn from_pairs(num_inputs: usize, pairs: &[(usize, usize)]) -> Self {
+        let to_ref = |a: usize| if a < num_inputs { Ref::Input(a) } else { Ref::Node(a - num_inputs) };
+        Tree {
+            nodes: pairs.iter().map(|(a, b)| vec![to_ref(*a), to_ref(*b)]).collect(),
+        }
+    }
+
+    // the shape can be hidden if every input and node except the root is used exactly once
+    pub fn is_routable(&self, num_inputs: usize) -> bool {
+        self.route(num_inputs).is_some()
+    }
+
+    // for each child slot, the index of its value among the inputs followed by the nodes
+    fn route(&self, num_inputs: usize) -> Option<Vec<usize>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut used = vec![0; num_inputs + self.nodes.len()];
+        let mut res = vec![];
+        for (i, node) in self.nodes.iter().enumerate() {
+            for r in node.iter() {
+                let idx = match r {
+                    Ref::Input(a) if *a < num_inputs => *a,
+                    Ref::Node(b) if *b < i => num_inputs + *b,
+                    _ => return None,
+                };
+                used[idx] += 1;
+                res.push(idx);
+            }
+        }
+        let root = used.len() - 1;
+        let once = used.iter().enumerate().all(|(i, c)| *c == if i == root { 0 } else { 1 });
+        if once { Some(res) } else { None }
+    }
+}
+
+// values of all nodes
+pub fn eval_tree(params: &PoseidonParameters<Fr>, tree: &Tree, inputs: &[Fr]) -> Vec<Fr> {
+    let mut values: Vec<Fr> = vec![];
+    for node in tree.nodes.iter() {
+        let lst: Vec<Fr> = node.iter().map(|r| match r {
+            Ref::Input(a) => inputs[*a],
+            Ref::Node(b) => values[*b],
+            Ref::Const(c) => *c,
+        }).collect();
+        values.push(hash_many(params, &lst));
+    }
+    values
+}
+
+// root of a DAG with a known shape, the children are wired directly
+pub fn hash_tree(
+    params_g: &CRHParametersVar::<Fr>,
     tree: &Tree,
+    inputs: &[FpVar<Fr>],
+) -> FpVar<Fr> {
+    let mut nodes: Vec<FpVar<Fr>> = vec![];
+    for node in tree.nodes.iter() {
+        let lst: Vec<FpVar<Fr>> = node.iter().map(|r| match r {
+            Ref::Input(a) => inputs[*a].clone(),
+            Ref::Node(b) => nodes[*b].clone(),
+            Ref::Const(c) => FpVar::Constant(*c),
+        }).collect();
+        nodes.push(CRHGadget::<Fr>::evaluate(&params_g, &lst).unwrap());
+    }
+    nodes.last().unwrap().clone()
+}
+
+// root of a tree with a hidden shape, the circuit only depends on the number of inputs and the arities of the nodes.
+// The inputs and internal nodes are routed to the children with a permutation.
+// Trees with constant children or shared nodes are Unsatisfiable. This includes code trees, the end
+// of the code is Ref::Const and repeated code is one node, so they have to use hash_tree.
+pub fn hash_tree_routed<P: PermutationArgument>(
+    arg: &P,
+    cs: &ConstraintSystemRef<Fr>,
     params: &PoseidonParameters<Fr>,
     params_g: &CRHParametersVar::<Fr>,
-    inputs: &Vec<FpVar<Fr>>, // Some of these inputs might be zeroes
-    values: &Vec<Fr>, // computed values of inputs
-) -> FpVar<Fr> {
-    // add nodes to values
-    let mut values = values.clone();
+    tree: &Tree,
+    inputs: &[FpVar<Fr>], // Some of these inputs might be zeroes
+    values: &[Fr], // computed values of inputs
+) -> Result<FpVar<Fr>, SynthesisError> {
+    // tree has to use every input and node once
+    let route = tree.route(inputs.len()).ok_or(SynthesisError::Unsatisfiable)?;
     // inputs and internal nodes
-    let mut inputs = inputs.clone();
+    let mut lst = inputs.to_vec();
     let mut nodes = vec![];
-    // route inputs
-    let mut perm = IntegerPermutation::new(inputs.len() + tree.nodes.len() - 1);
-    for (i, (a,b)) in tree.nodes.iter().enumerate() {
-        let h = hash_pair(&params, &values[*a], &values[*b]);
-        values.push(h.clone());
-        let var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(h)).unwrap());
+    let node_values = eval_tree(params, tree, values);
+    for (i, h) in node_values.iter().enumerate() {
+        let h = h.clone();
+        let var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(h))?);
         // last node only has output
         if i != tree.nodes.len() - 1 {
-            inputs.push(var.clone());
+            lst.push(var.clone());
         }
-        nodes.push(var.clone());
-        perm.set(i*2, *a);
-        perm.set(i*2+1, *b);
+        nodes.push(var);
+    }
+    // route inputs, the value at index a goes to the slot of the child
+    let mut perm = IntegerPermutation::new(lst.len());
+    for (slot, idx) in route.into_iter().enumerate() {
+        perm.set(idx, slot);
     }
-    // println!("permutation {:?}", perm);
-    let inputs = crate::permutation::permutation(cs.clone(), inputs, perm);
+    let routed = permute_with(arg, cs.clone(), lst, perm)?;
     // create hashes, check that inner nodes are correct
-    for (i, (a,b)) in tree.nodes.iter().enumerate() {
-        let a_var = inputs[*a].clone();
-        let b_var = inputs[*b].clone();
-        let hash_var = CRHGadget::<Fr>::evaluate(&params_g, &vec![a_var, b_var]).unwrap();
-        hash_var.enforce_equal(&nodes[i]).unwrap();
+    let mut slot = 0;
+    for (i, node) in tree.nodes.iter().enumerate() {
+        let hash_var = CRHGadget::<Fr>::evaluate(&params_g, &routed[slot..slot+node.len()])?;
+        hash_var.enforce_equal(&nodes[i])?;
+        slot += node.len();
     }
-    nodes.last().unwrap().clone()
+    Ok(nodes.last().unwrap().clone())
+}
+
+fn code_node(
+    params: &PoseidonParameters<Fr>,
+    code: &[CodeTree],
+    tree: &mut Tree,
+    inputs: &mut Vec<Fr>,
+    memo: &mut HashMap<Fr, usize>,
+) -> (Ref, Fr) {
+    use crate::CodeTree::*;
+    let mut res = (Ref::Const(Fr::zero()), Fr::zero());
+    for op in code.iter().rev() {
+        let args = crate::code_args(op);
+        let sub = match op {
+            CLoop(cont) => Some(code_node(params, cont, tree, inputs, memo)),
+            _ => None,
+        };
+        let mut lst = args.clone();
+        if let Some((_, h)) = &sub {
+            lst.push(*h);
+        }
+        lst.push(res.1);
+        let h = hash_many(params, &lst);
+        // same code has the same node
+        if let Some(idx) = memo.get(&h) {
+            res = (Ref::Node(*idx), h);
+            continue;
+        }
+        let mut node = vec![];
+        for a in args {
+            node.push(Ref::Input(inputs.len()));
+            inputs.push(a);
+        }
+        if let Some((r, _)) = sub {
+            node.push(r);
+        }
+        node.push(res.0);
+        memo.insert(h, tree.nodes.len());
+        res = (Ref::Node(tree.nodes.len()), h);
+        tree.nodes.push(node);
+    }
+    res
+}
+
+// Tree for hash_code, opcodes and immediates are the inputs. Repeated code is only hashed once.
+// Empty code hashes to a constant, there is no tree for it.
+pub fn code_tree(params: &PoseidonParameters<Fr>, code: &[CodeTree]) -> Result<(Tree, Vec<Fr>), String> {
+    if code.is_empty() {
+        return Err("empty code has no root".to_string());
+    }
+    let mut tree = Tree { nodes: vec![] };
+    let mut inputs = vec![];
+    let mut memo = HashMap::new();
+    code_node(params, code, &mut tree, &mut inputs, &mut memo);
+    Ok((tree, inputs))
+}
+
+fn alloc_inputs(cs: &ConstraintSystemRef<Fr>, values: &[Fr]) -> Vec<FpVar<Fr>> {
+    values.iter().map(|fr| {
+        let fr = fr.clone();
+        FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(fr)).unwrap())
+    }).collect()
+}
+
+// balanced binary tree
+#[cfg(test)]
+fn balanced(sz: usize) -> Tree {
+    let mut sz = sz;
+    let mut acc = 0;
+    let mut nodes = vec![];
+    let num_inputs = sz;
+    while sz > 1 {
+        for i in 0..sz/2 {
+            nodes.push((acc + i*2, acc + i*2+1));
+        }
+        acc += sz;
+        sz = sz/2;
+    }
+    Tree::from_pairs(num_inputs, &nodes)
 }
 
 pub fn test_tree(params: &PoseidonParameters<Fr>) {
@@ -71,15 +234,13 @@ pub fn test_tree(params: &PoseidonParameters<Fr>) {
 
     let mut acc = 0;
     let mut sz = 1024;
-    let mut vars = vec![];
     let mut inputs = vec![];
     for i in 0..sz {
-        let fr = Fr::from(i as u32);
-        vars.push(FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(fr)).unwrap()));
-        inputs.push(fr)
+        inputs.push(Fr::from(i as u32))
     }
+    let vars = alloc_inputs(&cs, &inputs);
     let mut nodes = vec![];
-    while sz > 0 {
+    while sz > 1 {
         for i in 0..sz/2 {
             nodes.push((acc + i*2, acc + i*2+1));
         }
@@ -87,7 +248,83 @@ pub fn test_tree(params: &PoseidonParameters<Fr>) {
         sz = sz/2;
     }
     // println!("{:?}", nodes);
-    let tree = Tree {nodes};
-    let _res = hash_tree(&cs, &tree, &params, &params_g, &vars, &inputs);
-    println!("num constraints {}, valid {}", cs.num_constraints(), cs.is_satisfied().unwrap());
+    let tree = Tree::from_pairs(inputs.len(), &nodes);
+    let _res = hash_tree_routed(&WaksmanArgument, &cs, &params, &params_g, &tree, &vars, &inputs).unwrap();
+    tracing::debug!(constraints = cs.num_constraints(), satisfied = cs.is_satisfied().unwrap(), "routed tree");
+}
+
+#[cfg(test)]
+use ark_r1cs_std::R1CSVar;
+
+#[test]
+fn test_tree_routed() {
+    let params = crate::generate_hash();
+    let inputs: Vec<Fr> = (0..16).map(|i| Fr::from(i as u32)).collect();
+    // left leaning tree with the inputs in reverse order
+    let mut pairs = vec![(15, 14)];
+    for i in 1..15 {
+        pairs.push((15 + i, 14 - i));
+    }
+    let mut counts = vec![];
+    for tree in [balanced(16), Tree::from_pairs(16, &pairs)] {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+        let vars = alloc_inputs(&cs, &inputs);
+        let root = hash_tree_routed(&WaksmanArgument, &cs, &params, &params_g, &tree, &vars, &inputs).unwrap();
+        assert_eq!(root.value().unwrap(), *eval_tree(&params, &tree, &inputs).last().unwrap());
+        assert!(cs.is_satisfied().unwrap());
+        counts.push(cs.num_constraints());
+    }
+    // shape is hidden
+    assert_eq!(counts[0], counts[1]);
+    // grand product argument instead of the network
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+    let vars = alloc_inputs(&cs, &inputs);
+    let gp = crate::permutation::GrandProductArgument::new(&crate::hash::generate_params());
+    let root = hash_tree_routed(&gp, &cs, &params, &params_g, &Tree::from_pairs(16, &pairs), &vars, &inputs).unwrap();
+    assert_eq!(root.value().unwrap(), *eval_tree(&params, &Tree::from_pairs(16, &pairs), &inputs).last().unwrap());
+    assert!(cs.is_satisfied().unwrap());
+    // values have to match the inputs
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+    let vars = alloc_inputs(&cs, &inputs);
+    let mut values = inputs.clone();
+    values[3] = Fr::from(100);
+    hash_tree_routed(&WaksmanArgument, &cs, &params, &params_g, &balanced(16), &vars, &values).unwrap();
+    assert!(!cs.is_satisfied().unwrap());
+    assert!(!Tree::from_pairs(4, &[(0, 1), (0, 4)]).is_routable(4));
+    // constants and reused inputs can't be routed
+    let vars = alloc_inputs(&cs, &inputs[0..2]);
+    let constant = Tree { nodes: vec![vec![Ref::Input(0), Ref::Input(1), Ref::Const(Fr::from(1))]] };
+    assert!(!constant.is_routable(2));
+    assert!(hash_tree_routed(&WaksmanArgument, &cs, &params, &params_g, &constant, &vars, &inputs[0..2]).is_err());
+    let reused = Tree::from_pairs(2, &[(0, 0)]);
+    assert!(hash_tree_routed(&WaksmanArgument, &cs, &params, &params_g, &reused, &vars, &inputs[0..2]).is_err());
+}
+
+#[test]
+fn test_code_tree() {
+    use crate::CodeTree::*;
+    let params = crate::generate_hash();
+    let body = vec![CGetLocal(0), CConst(1), CAdd, CSetLocal(0), CGetLocal(0), CConst(10), CGt, CBreakIf(0), CEnd];
+    let code = vec![CConst(0), CSetLocal(0), CLoop(body.clone()), CLoop(body.clone()), CLoop(vec![]), CGetLocal(0), CEnd];
+    let (tree, inputs) = code_tree(&params, &code).unwrap();
+    let expected = crate::hash_code(&params, &code);
+    assert_eq!(*eval_tree(&params, &tree, &inputs).last().unwrap(), expected);
+    // second loop body and the final CEnd are shared
+    assert_eq!(tree.nodes.len(), code.len() + body.len() - 1);
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+    let vars = alloc_inputs(&cs, &inputs);
+    let root = hash_tree(&params_g, &tree, &vars);
+    assert_eq!(root.value().unwrap(), expected);
+    assert!(cs.is_satisfied().unwrap());
+    // the end of the code is a constant
+    assert!(!tree.is_routable(inputs.len()));
+    assert!(code_tree(&params, &[]).is_err());
 }
@@ -1,15 +1,15 @@
 use crate::as_waksman::IntegerPermutation;
-use crate::as_waksman::AsWaksmanRoute;
-use crate::as_waksman::AsWaksmanTopology;
-use crate::Transition;
+use crate::permutation::{fill_permutation, permute_list_with, PermutationArgument, WaksmanArgument};
+use crate::{hash_pair, hash_list, hash_many};
 
 use ark_mnt4_298::Fr;
+use ark_ff::Zero;
 use ark_crypto_primitives::crh::poseidon::constraints::CRHGadget;
 use ark_r1cs_std::fields::fp::FpVar;
 use ark_crypto_primitives::crh::poseidon::constraints::CRHParametersVar;
 use ark_crypto_primitives::CRHSchemeGadget;
 use ark_sponge::poseidon::PoseidonParameters;
-use ark_relations::r1cs::ConstraintSystemRef;
+use ark_relations::r1cs::{ConstraintSystemRef, ConstraintSynthesizer, SynthesisError};
 use ark_r1cs_std::prelude::CondSelectGadget;
 use ark_r1cs_std::boolean::AllocatedBool;
 use ark_r1cs_std::boolean::Boolean;
@@ -18,18 +18,83 @@ use ark_r1cs_std::eq::EqGadget;
 use ark_r1cs_std::fields::fp::AllocatedFp;
 use ark_relations::r1cs::ConstraintSystem;
 
+// One step of a straight line hash program: reg[c] = H(x[a], x[b]), or reg[c] = x[a] if hash is false.
+// x is the register file followed by the two inputs of the step, input1 and input2 are indices
+// of the external inputs, -1 for a zero. Every external input is used at most once.
 #[derive(Debug, Clone)]
-struct Step {
-    a: usize,
-    b: usize,
-    c: usize,
-    input1: i32,
-    input2: i32,
+pub struct Step {
+    pub a: usize,
+    pub b: usize,
+    pub c: usize,
+    pub hash: bool,
+    pub input1: i32,
+    pub input2: i32,
 }
 
-fn make_bools(cs: &ConstraintSystemRef<Fr>, mem_size: usize, a: usize) -> Vec<Boolean<Fr>> {
+impl Step {
+    pub fn hash(a: usize, b: usize, c: usize) -> Self {
+        Step { a, b, c, hash: true, input1: -1, input2: -1 }
+    }
+    pub fn copy(a: usize, c: usize) -> Self {
+        Step { a, b: 0, c, hash: false, input1: -1, input2: -1 }
+    }
+    pub fn with_inputs(&self, input1: i32, input2: i32) -> Self {
+        Step { input1, input2, ..self.clone() }
+    }
+    // (a, b, c, hash, input1 + 1, input2 + 1)
+    pub fn encode(&self) -> Vec<Fr> {
+        vec![
+            Fr::from(self.a as u64),
+            Fr::from(self.b as u64),
+            Fr::from(self.c as u64),
+            Fr::from(self.hash as u64),
+            Fr::from((self.input1 + 1) as u64),
+            Fr::from((self.input2 + 1) as u64),
+        ]
+    }
+}
+
+// hash_list of the hashes of the step encodings
+pub fn program_hash(params: &PoseidonParameters<Fr>, steps: &[Step]) -> Fr {
+    hash_list(params, &steps.iter().map(|s| hash_many(params, &s.encode())).collect::<Vec<Fr>>())
+}
+
+// external input of a step, negative for a zero
+fn input_in_range(idx: i32, num_inputs: usize) -> bool {
+    idx < 0 || (idx as usize) < num_inputs
+}
+
+// registers and inputs named by the steps exist and every input is used at most once
+fn check_steps(steps: &[Step], num_inputs: usize, mem_bits: usize) -> Result<(), String> {
+    let mut used = vec![false; num_inputs];
+    for step in steps.iter() {
+        if step.a >= 1 << mem_bits || step.b >= 1 << mem_bits || step.c >= num_regs(mem_bits) {
+            return Err(format!("step {:?} uses a register out of range", step));
+        }
+        for idx in [step.input1, step.input2] {
+            if !input_in_range(idx, num_inputs) {
+                return Err(format!("input {} is out of range, there are {} inputs", idx, num_inputs));
+            }
+            if idx >= 0 {
+                if used[idx as usize] {
+                    return Err(format!("input {} is used twice", idx));
+                }
+                used[idx as usize] = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+// number of registers, two more indices are used by the step inputs
+pub fn num_regs(mem_bits: usize) -> usize {
+    (1 << mem_bits) - 2
+}
+
+// big endian bits of a, for conditionally_select_power_of_two_vector
+fn make_bools(cs: &ConstraintSystemRef<Fr>, mem_bits: usize, a: usize) -> Vec<Boolean<Fr>> {
     let mut a_bools = vec![];
-    for i in 0..mem_size {
+    for i in (0..mem_bits).rev() {
         let is_set = (a >> i) % 2 == 1;
         let bool_var = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(is_set)).unwrap());
         a_bools.push(bool_var)
@@ -37,143 +102,230 @@ fn make_bools(cs: &ConstraintSystemRef<Fr>, mem_size: usize, a: usize) -> Vec<Bo
     a_bools
 }
 
+// returns the registers after the step and the hash of the step encoding
 fn hash_step(
     cs: &ConstraintSystemRef<Fr>,
-    step: Step,
-    params: &PoseidonParameters<Fr>,
+    step: &Step,
     params_g: &CRHParametersVar::<Fr>,
     vars: &[FpVar<Fr>], // memory
-    var_sums: &[FpVar<Fr>], // memory
-    input1: FpVar<Fr>, // input from permutation
-    input1_sum: FpVar<Fr>, // input from permutation
-    input2: FpVar<Fr>, // input from permutation
-    input2_sum: FpVar<Fr>, // input from permutation
-    mem_size: usize,
-) -> (Vec<FpVar<Fr>>, Vec<FpVar<Fr>>) { // output memory
+    slot1: &[FpVar<Fr>], // (tag, input) from permutation
+    slot2: &[FpVar<Fr>], // (tag, input) from permutation
+    mem_bits: usize,
+) -> (Vec<FpVar<Fr>>, FpVar<Fr>) { // output memory
     let mut inputs = vec![];
     for v in vars.iter() {
         inputs.push(v.clone());
     }
-    inputs.push(input1);
-    inputs.push(input2);
-    let mut input_sums = vec![];
-    for v in var_sums.iter() {
-        input_sums.push(v.clone());
-    }
-    input_sums.push(input1_sum);
-    input_sums.push(input2_sum);
+    inputs.push(slot1[1].clone());
+    inputs.push(slot2[1].clone());
 
-    let a_bools = make_bools(cs, mem_size, step.a);
-    let b_bools = make_bools(cs, mem_size, step.b);
+    let a_bools = make_bools(cs, mem_bits, step.a);
+    let b_bools = make_bools(cs, mem_bits, step.b);
 
     let a_var = FpVar::conditionally_select_power_of_two_vector(&a_bools, &inputs).unwrap();
     let b_var = FpVar::conditionally_select_power_of_two_vector(&b_bools, &inputs).unwrap();
 
-    let a_sum_var = FpVar::conditionally_select_power_of_two_vector(&a_bools, &input_sums).unwrap();
-    let b_sum_var = FpVar::conditionally_select_power_of_two_vector(&b_bools, &input_sums).unwrap();
-
-    let c_var = CRHGadget::<Fr>::evaluate(&params_g, &vec![a_var.clone(), b_var.clone()]).unwrap();
-    // let c_var = a_var + b_var;
-    // let c_sum_var = a_var + b_var;
-    let c_sum_var = a_sum_var + b_sum_var;
+    let h_var = CRHGadget::<Fr>::evaluate(&params_g, &vec![a_var.clone(), b_var.clone()]).unwrap();
+    let hash_var = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(step.hash)).unwrap());
+    let c_var = hash_var.select(&h_var, &a_var).unwrap();
 
     let mut outputs = vec![];
-    let mut output_sums = vec![];
     let c_idx_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(step.c as u32))).unwrap());
     for (i,v) in vars.iter().enumerate() {
         let idx_var = FpVar::Constant(Fr::from(i as u32));
         let bool_var = idx_var.is_eq(&c_idx_var).unwrap();
         let out_var = bool_var.select(&c_var, &v).unwrap();
-        let out_sum_var = bool_var.select(&c_sum_var, &var_sums[i]).unwrap();
         outputs.push(out_var);
-        output_sums.push(out_sum_var);
     };
-    (outputs, output_sums)
-}
 
-fn route_steps1(steps: &Vec<Step>) -> IntegerPermutation {
-    let mut list : Vec<i32> = vec![-1; steps.len()];
-    let mut num = 0;
-    for (i,step) in steps.iter().enumerate() {
-        list[i] = step.input1;
-        if step.input1 >= 0 {
-            num += 1
-        }
-    };
-    // route zeroes
-    for i in 0..list.len() {
-        if list[i] == -1 {
-            list[i] = num as i32;
-            num += 1;
-        }
-    }
-    // create permutation
-    let mut perm = IntegerPermutation::new(steps.len());
-    for i in 0..list.len() {
-        perm.set(i, list[i] as usize);
-    }
-    perm
+    // the slots have to hold the inputs named by the step
+    let tag1 = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from((step.input1 + 1) as u64))).unwrap());
+    let tag2 = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from((step.input2 + 1) as u64))).unwrap());
+    slot1[0].enforce_equal(&tag1).unwrap();
+    slot2[0].enforce_equal(&tag2).unwrap();
+
+    let bits = |b: &Vec<Boolean<Fr>>| Boolean::le_bits_to_fp_var(&b.iter().rev().cloned().collect::<Vec<_>>()).unwrap();
+    let code = CRHGadget::<Fr>::evaluate(&params_g, &vec![
+        bits(&a_bools), bits(&b_bools), c_idx_var, FpVar::from(hash_var), tag1, tag2,
+    ]).unwrap();
+    (outputs, code)
 }
 
-fn route_steps2(steps: &Vec<Step>) -> IntegerPermutation {
-    let mut list : Vec<i32> = vec![-1; steps.len()];
-    let mut num = 0;
-    for (i,step) in steps.iter().enumerate() {
-        list[i] = step.input2;
-        if step.input2 >= 0 {
-            num += 1
+// The routed list is the inputs tagged with index + 1 followed by 2*steps zeroes with tag 0.
+// input1 of step i goes to slot 2*i and input2 to slot 2*i+1, unused inputs go after the slots
+// and the zeroes fill the free slots. Inputs out of range or used twice can't be routed.
+fn route_steps(steps: &Vec<Step>, num_inputs: usize) -> Result<IntegerPermutation, SynthesisError> {
+    let slots = 2*steps.len();
+    let mut fixed = vec![];
+    let mut used = vec![false; num_inputs];
+    for (i, step) in steps.iter().enumerate() {
+        for (idx, slot) in [(step.input1, 2*i), (step.input2, 2*i+1)] {
+            if !input_in_range(idx, num_inputs) {
+                return Err(SynthesisError::Unsatisfiable);
+            }
+            if idx >= 0 {
+                if used[idx as usize] {
+                    return Err(SynthesisError::Unsatisfiable);
+                }
+                fixed.push((idx as usize, slot));
+                used[idx as usize] = true;
+            }
         }
     };
-    // route zeroes
-    for i in 0..list.len() {
-        if list[i] == -1 {
-            list[i] = num as i32;
-            num += 1;
+    for i in 0..num_inputs {
+        if !used[i] {
+            fixed.push((i, slots + i));
         }
     }
-    // create permutation
-    let mut perm = IntegerPermutation::new(steps.len());
-    for i in 0..list.len() {
-        perm.set(i, list[i] as usize);
-    }
-    perm
+    Ok(fill_permutation(num_inputs + slots, fixed))
 }
 
-fn hash_steps(
+// Runs the steps on the registers and returns the final registers and the program hash.
+// The circuit only depends on the number of steps, the number of inputs and mem_bits,
+// the program hash has to be checked against the program.
+pub fn hash_steps<P: PermutationArgument>(
+    arg: &P,
     cs: &ConstraintSystemRef<Fr>,
-    steps: Vec<Step>,
-    params: &PoseidonParameters<Fr>,
+    steps: &Vec<Step>,
     params_g: &CRHParametersVar::<Fr>,
     vars: Vec<FpVar<Fr>>, // memory
-    var_sums: Vec<FpVar<Fr>>, // memory sums (probably zeros)
     inputs: Vec<FpVar<Fr>>, // inputs, make permutation
-    input_sums: Vec<FpVar<Fr>>, // inputs, make permutation
-    mem_size: usize,
-) -> (FpVar<Fr>, FpVar<Fr>) {
+    mem_bits: usize,
+) -> Result<(Vec<FpVar<Fr>>, FpVar<Fr>), SynthesisError> {
+    if vars.len() != num_regs(mem_bits) {
+        return Err(SynthesisError::Unsatisfiable);
+    }
     // first permute inputs
-    let route1 = route_steps1(&steps);
-    let route2 = route_steps2(&steps);
-    // actually both have to be permuted the same way...
-    let mut inputs_with_sums = vec![];
-    for i in 0..inputs.len() {
-        inputs_with_sums.push(vec![inputs[i].clone(), input_sums[i].clone()])
-    }
-    let inputs_with_sums1 = crate::permutation::permutation_list(cs.clone(), inputs_with_sums.clone(), route1);
-    let inputs_with_sums2 = crate::permutation::permutation_list(cs.clone(), inputs_with_sums, route2);
+    let num_inputs = inputs.len();
+    let mut lst = vec![];
+    for (i, v) in inputs.into_iter().enumerate() {
+        lst.push(vec![FpVar::Constant(Fr::from(i as u64 + 1)), v]);
+    }
+    for _i in 0..2*steps.len() {
+        lst.push(vec![FpVar::Constant(Fr::zero()); 2]);
+    }
+    let routed = permute_list_with(arg, cs.clone(), lst, route_steps(steps, num_inputs)?)?;
     let mut vars = vars.clone();
-    let mut var_sums = var_sums.clone();
+    let mut program = FpVar::Constant(Fr::zero());
     for (i, step) in steps.iter().enumerate() {
-        let a = hash_step(cs, step.clone(), params, params_g, &vars, &var_sums,
-            inputs_with_sums1[i][0].clone(),
-            inputs_with_sums1[i][1].clone(),
-            inputs_with_sums2[i][0].clone(),
-            inputs_with_sums2[i][1].clone(),
-            mem_size,
-        );
-        vars = a.0;
-        var_sums = a.1;
+        let (next, code) = hash_step(cs, step, params_g, &vars, &routed[2*i], &routed[2*i+1], mem_bits);
+        vars = next;
+        program = CRHGadget::<Fr>::evaluate(&params_g, &vec![code, program])?;
+    }
+    Ok((vars, program))
+}
+
+// None if a step uses an input out of range
+pub fn eval_steps(params: &PoseidonParameters<Fr>, steps: &[Step], regs: &[Fr], inputs: &[Fr]) -> Option<Vec<Fr>> {
+    let mut regs = regs.to_vec();
+    let input = |idx: i32| {
+        if idx >= 0 { inputs[idx as usize] } else { Fr::zero() }
+    };
+    for step in steps.iter() {
+        if !input_in_range(step.input1, inputs.len()) || !input_in_range(step.input2, inputs.len()) {
+            return None;
+        }
+        let x = |i: usize| {
+            if i < regs.len() { regs[i] } else if i == regs.len() { input(step.input1) } else { input(step.input2) }
+        };
+        let v = if step.hash { hash_pair(params, &x(step.a), &x(step.b)) } else { x(step.a) };
+        if step.c < regs.len() {
+            regs[step.c] = v;
+        }
     }
-    (vars[0].clone(), var_sums[0].clone())
+    Some(regs)
+}
+
+// Straight line hash program. Public inputs are the initial registers, hash_list of the external
+// inputs, the program hash and the final registers. The steps and the routing of the inputs are
+// witnesses, so the verifier has to check the program hash.
+#[derive(Debug, Clone)]
+pub struct HashProgramCircuit {
+    pub params: PoseidonParameters<Fr>,
+    pub steps: Vec<Step>,
+    pub regs: Vec<Fr>,
+    pub inputs: Vec<Fr>,
+    pub mem_bits: usize,
+}
+
+impl HashProgramCircuit {
+    pub fn new(params: &PoseidonParameters<Fr>, steps: Vec<Step>, regs: Vec<Fr>, inputs: Vec<Fr>, mem_bits: usize) -> Result<Self, String> {
+        if regs.len() != num_regs(mem_bits) {
+            return Err(format!("{} registers, expected {}", regs.len(), num_regs(mem_bits)));
+        }
+        check_steps(&steps, inputs.len(), mem_bits)?;
+        Ok(HashProgramCircuit {
+            params: params.clone(),
+            steps,
+            regs,
+            inputs,
+            mem_bits,
+        })
+    }
+
+    pub fn outputs(&self) -> Option<Vec<Fr>> {
+        eval_steps(&self.params, &self.steps, &self.regs, &self.inputs)
+    }
+
+    pub fn get_inputs(&self) -> Option<Vec<Fr>> {
+        let mut res = self.regs.clone();
+        res.push(hash_list(&self.params, &self.inputs));
+        res.push(program_hash(&self.params, &self.steps));
+        res.extend(self.outputs()?);
+        Some(res)
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for HashProgramCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        if self.regs.len() != num_regs(self.mem_bits) || check_steps(&self.steps, self.inputs.len(), self.mem_bits).is_err() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+        let public = self.get_inputs().ok_or(SynthesisError::Unsatisfiable)?;
+        let public: Vec<FpVar<Fr>> = public.iter().map(|v| {
+            let v = v.clone();
+            FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(v)).unwrap())
+        }).collect();
+        let n = self.regs.len();
+        let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
+        let mut acc = FpVar::Constant(Fr::zero());
+        let mut inputs = vec![];
+        for v in self.inputs.iter() {
+            let v = v.clone();
+            let var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(v)).unwrap());
+            acc = CRHGadget::<Fr>::evaluate(&params_g, &vec![var.clone(), acc]).unwrap();
+            inputs.push(var);
+        }
+        acc.enforce_equal(&public[n]).unwrap();
+        let (res, program) = hash_steps(&WaksmanArgument, &cs, &self.steps, &params_g, public[0..n].to_vec(), inputs, self.mem_bits)?;
+        program.enforce_equal(&public[n+1]).unwrap();
+        for (a, b) in res.iter().zip(public[n+2..].iter()) {
+            a.enforce_equal(b).unwrap();
+        }
+        Ok(())
+    }
+}
+
+// Updates a leaf of a merkle tree. Inputs are the old leaf, the new leaf and the siblings,
+// registers 0 and 1 end up with the old and the new root.
+pub fn merkle_update_steps(selectors: &[bool]) -> Vec<Step> {
+    let mut steps = vec![
+        Step::copy(6, 0).with_inputs(0, -1),
+        Step::copy(6, 1).with_inputs(1, -1),
+    ];
+    for (i, right) in selectors.iter().enumerate() {
+        steps.push(Step::copy(6, 2).with_inputs(2 + i as i32, -1));
+        for r in 0..2 {
+            steps.push(if *right { Step::hash(2, r, r) } else { Step::hash(r, 2, r) });
+        }
+    }
+    steps
+}
+
+// hash_list of the inputs into register 0
+pub fn hash_list_steps(n: usize) -> Vec<Step> {
+    (0..n).map(|i| Step::hash(6, 0, 0).with_inputs(i as i32, -1)).collect()
 }
 
 pub fn test_tree(params: &PoseidonParameters<Fr>) {
@@ -182,39 +334,177 @@ pub fn test_tree(params: &PoseidonParameters<Fr>) {
     let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
 
     let size = 1024*2;
-    let mem_size_bits = 4;
-    let mem_size = 1 << mem_size_bits;
+    let mem_bits = 4;
 
     let mut vars = vec![];
     let mut inputs = vec![];
-    let mut var_sums = vec![];
-    let mut input_sums = vec![];
-    let mut steps = vec![];
     for i in 0..size {
         let var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap());
         inputs.push(var);
-        let var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap());
-        input_sums.push(var);
-        steps.push(Step {
-            a: 0,
-            b: 0,
-            c: 0,
-            input1: -1,
-            input2: -1,
-        });
-    }
-    for i in 0..mem_size-2 {
+    }
+    let steps = hash_list_steps(size);
+    for i in 0..num_regs(mem_bits) {
         let var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap());
         vars.push(var);
-        let var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(i as u32))).unwrap());
-        var_sums.push(var);
     }
-    hash_steps(&cs, steps, &params, &params_g,
+    hash_steps(&WaksmanArgument, &cs, &steps, &params_g,
         vars,
-        var_sums, // memory sums (probably zeros)
         inputs, // inputs, make permutation
-        input_sums, // inputs, make permutation
-        mem_size_bits,
-    );
-    println!("num constraints {}, valid {}", cs.num_constraints(), cs.is_satisfied().unwrap());
+        mem_bits,
+    ).unwrap();
+    tracing::debug!(constraints = cs.num_constraints(), satisfied = cs.is_satisfied().unwrap(), "hash program");
+}
+
+#[cfg(test)]
+fn check_program(circuit: HashProgramCircuit) -> (bool, usize) {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    (cs.is_satisfied().unwrap(), cs.num_constraints())
+}
+
+#[test]
+fn test_merkle_update() {
+    let params = crate::generate_hash();
+    let depth = 4;
+    let leaves: Vec<Fr> = (0..1 << depth).map(|i| Fr::from(i as u32 + 10)).collect();
+    let root = |leaves: &Vec<Fr>| {
+        let mut level = leaves.clone();
+        while level.len() > 1 {
+            level = (0..level.len()/2).map(|i| hash_pair(&params, &level[2*i], &level[2*i+1])).collect();
+        }
+        level[0]
+    };
+    let mut counts = vec![];
+    for idx in [0, 5, 15] {
+        let mut siblings = vec![];
+        let mut selectors = vec![];
+        let mut level = leaves.clone();
+        let mut pos = idx;
+        for _i in 0..depth {
+            siblings.push(level[pos ^ 1]);
+            selectors.push(pos % 2 == 1);
+            level = (0..level.len()/2).map(|i| hash_pair(&params, &level[2*i], &level[2*i+1])).collect();
+            pos = pos / 2;
+        }
+        let mut updated = leaves.clone();
+        updated[idx] = Fr::from(1000);
+        let mut inputs = vec![leaves[idx], updated[idx]];
+        inputs.extend(siblings);
+        let circuit = HashProgramCircuit::new(&params, merkle_update_steps(&selectors), vec![Fr::zero(); 6], inputs, 3).unwrap();
+        let outputs = circuit.outputs().unwrap();
+        assert_eq!(outputs[0], root(&leaves));
+        assert_eq!(outputs[1], root(&updated));
+        let (ok, count) = check_program(circuit);
+        assert!(ok);
+        counts.push(count);
+    }
+    // the path is hidden
+    assert!(counts.iter().all(|c| *c == counts[0]));
+}
+
+#[test]
+fn test_hash_program() {
+    let params = crate::generate_hash();
+    let inputs: Vec<Fr> = (0..5).map(|i| Fr::from(i as u32)).collect();
+    let regs = vec![Fr::zero(); 6];
+    let circuit = HashProgramCircuit::new(&params, hash_list_steps(5), regs.clone(), inputs.clone(), 3).unwrap();
+    assert_eq!(circuit.outputs().unwrap()[0], hash_list(&params, &inputs));
+    assert!(check_program(circuit.clone()).0);
+    // inputs in a different order give a different hash
+    let mut other = circuit.clone();
+    other.steps = vec![Step::hash(6, 0, 0).with_inputs(1, -1), Step::hash(6, 0, 0).with_inputs(0, -1)];
+    other.steps.extend(hash_list_steps(5)[2..].iter().cloned());
+    assert_ne!(other.outputs().unwrap()[0], circuit.outputs().unwrap()[0]);
+    assert!(check_program(other).0);
+    // result has to match
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+    let vars = regs.iter().map(|v| FpVar::Constant(*v)).collect();
+    let input_vars = inputs.iter().map(|v| {
+        let v = v.clone();
+        FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(v)).unwrap())
+    }).collect();
+    let (res, _) = hash_steps(&WaksmanArgument, &cs, &hash_list_steps(5), &params_g, vars, input_vars, 3).unwrap();
+    let mut rev = inputs.clone();
+    rev.reverse();
+    res[0].enforce_equal(&FpVar::Constant(hash_list(&params, &rev))).unwrap();
+    assert!(!cs.is_satisfied().unwrap());
+    // registers and second input
+    let steps = vec![
+        Step::hash(6, 7, 3).with_inputs(2, 4),
+        Step::copy(3, 4),
+        Step::hash(4, 3, 5),
+    ];
+    let circuit = HashProgramCircuit::new(&params, steps, regs, inputs.clone(), 3).unwrap();
+    let h = hash_pair(&params, &inputs[2], &inputs[4]);
+    let outputs = circuit.outputs().unwrap();
+    assert_eq!(outputs[3], h);
+    assert_eq!(outputs[4], h);
+    assert_eq!(outputs[5], hash_pair(&params, &h, &h));
+    assert!(check_program(circuit).0);
+}
+
+#[test]
+fn test_program_binding() {
+    let params = crate::generate_hash();
+    let selectors = vec![true, false, true];
+    let good = merkle_update_steps(&selectors);
+    // copies the new leaf to both roots, same shape as the update
+    let mut evil = vec![Step::copy(6, 0).with_inputs(1, -1), Step::copy(6, 1).with_inputs(1, -1)];
+    while evil.len() < good.len() {
+        evil.push(Step::copy(0, 0));
+    }
+    let inputs: Vec<Fr> = (0..5).map(|i| Fr::from(i as u32 + 10)).collect();
+    let check = |steps: &Vec<Step>, inputs: &Vec<Fr>| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+        let vars = vec![FpVar::Constant(Fr::zero()); 6];
+        let input_vars = inputs.iter().map(|v| {
+            let v = v.clone();
+            FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(v)).unwrap())
+        }).collect();
+        let (_, program) = hash_steps(&WaksmanArgument, &cs, steps, &params_g, vars, input_vars, 3).unwrap();
+        program.enforce_equal(&FpVar::Constant(program_hash(&params, &good))).unwrap();
+        cs.is_satisfied().unwrap()
+    };
+    assert!(check(&good, &inputs));
+    assert!(!check(&evil, &inputs));
+    // the routing is part of the program
+    let mut swapped = good.clone();
+    swapped[0] = swapped[0].with_inputs(1, -1);
+    swapped[1] = swapped[1].with_inputs(0, -1);
+    assert!(!check(&swapped, &inputs));
+    // unused inputs are not seen by the steps
+    let mut more = inputs.clone();
+    more.push(Fr::from(7));
+    assert!(check(&good, &more));
+}
+
+#[test]
+fn test_input_out_of_range() {
+    let params = crate::generate_hash();
+    let inputs: Vec<Fr> = (0..5).map(|i| Fr::from(i as u32)).collect();
+    let err = HashProgramCircuit::new(&params, hash_list_steps(6), vec![Fr::zero(); 6], inputs.clone(), 3).unwrap_err();
+    assert_eq!(err, "input 5 is out of range, there are 5 inputs");
+    let twice = vec![Step::hash(6, 0, 0).with_inputs(1, -1), Step::hash(6, 0, 0).with_inputs(1, -1)];
+    assert!(HashProgramCircuit::new(&params, twice.clone(), vec![Fr::zero(); 6], inputs.clone(), 3).is_err());
+    assert!(HashProgramCircuit::new(&params, vec![Step::copy(0, 6)], vec![Fr::zero(); 6], inputs.clone(), 3).is_err());
+    assert!(HashProgramCircuit::new(&params, vec![], vec![Fr::zero(); 5], inputs.clone(), 3).is_err());
+    // circuits that skip new are Unsatisfiable
+    for steps in [hash_list_steps(6), twice] {
+        let circuit = HashProgramCircuit { params: params.clone(), steps, regs: vec![Fr::zero(); 6], inputs: inputs.clone(), mem_bits: 3 };
+        assert_eq!(circuit.get_inputs().is_none(), circuit.steps.len() == 6);
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        assert!(matches!(circuit.generate_constraints(ConstraintSystemRef::new(cs_sys)), Err(SynthesisError::Unsatisfiable)));
+    }
+}
+
+#[test]
+fn test_eval_input_out_of_range() {
+    let params = crate::generate_hash();
+    assert!(eval_steps(&params, &hash_list_steps(3), &vec![Fr::zero(); 6], &[Fr::from(1), Fr::from(2)]).is_none());
+    assert!(eval_steps(&params, &hash_list_steps(2), &vec![Fr::zero(); 6], &[Fr::from(1), Fr::from(2)]).is_some());
 }
@@ -2,62 +2,344 @@ use ark_r1cs_std::alloc::AllocVar;
 use ark_r1cs_std::{
     fields::fp::{AllocatedFp, FpVar},
 };
-use ark_crypto_primitives::crh::poseidon::CRH;
 use ark_crypto_primitives::crh::poseidon::constraints::{CRHGadget, CRHParametersVar};
 use ark_mnt4_298::Fr;
 use ark_relations::r1cs::ConstraintSynthesizer;
-use ark_crypto_primitives::{CRHSchemeGadget, CRHScheme};
+use ark_crypto_primitives::CRHSchemeGadget;
 use ark_relations::r1cs::SynthesisError;
 use ark_relations::r1cs::ConstraintSystemRef;
 use ark_r1cs_std::eq::EqGadget;
 use ark_sponge::poseidon::PoseidonParameters;
-use ark_r1cs_std::boolean::Boolean;
-use ark_r1cs_std::fields::FieldVar;
-use std::cmp::Ordering;
-use ark_relations::r1cs::ConstraintSystem;
-use ark_r1cs_std::ToBitsGadget;
+use ark_r1cs_std::boolean::{AllocatedBool, Boolean};
 use ark_r1cs_std::R1CSVar;
 
-// truncate to 200 bits
+use crate::{Transition, hash_pair, hash_list, hash_many};
+use crate::aggloop::{LoopCircuit, prove_loops};
+
+// Multiset accumulator for transitions, an alternative to the merkle root of merkleloop.
+// This file started as a sum of hashes truncated to 200 bits. Sums are not binding: finding transitions
+// whose truncated hashes add up to a given sum is a generalized birthday problem, which is much easier
+// than a hash collision. So the accumulator is a product instead.
+//
+// A multiset of transition hashes h_i is accumulated as prod(x - h_i) for a challenge x. The product
+// does not depend on the order, counts multiplicities, and the accumulator of a union is the product
+// of the accumulators. Two different multisets of at most n elements have the same accumulator only if
+// x is a root of the difference of their polynomials, which has probability at most n/p when x is
+// independent of both multisets (Schwartz-Zippel).
+// Like GrandProductArgument::with_commitment, this needs x to be computed by the verifier from
+// commitments that fix both multisets, see challenge. If the prover picks x, or picks the transitions
+// after seeing it, collisions are easy.
+//
+// The accumulator is carried in the loop state H(state, acc, list) and x is the root input of LoopCircuit,
+// so the aggloop circuits check that children chain and share the challenge while verifying their proofs.
+// The accumulator only binds the multiset: the chaining of states is checked by the loop circuit.
+// list is the running hash_list of the states, so the end state shows which states x has to be computed
+// from, see verify.
 
-fn truncate(v: FpVar<Fr>) -> FpVar<Fr> {
-    let bits = v.to_bits_le().unwrap();
-    // how to convert bits to var?
-    let res = Boolean::le_bits_to_fp_var(&bits[0..200]).unwrap();
-    res
+pub fn element(x: &Fr, h: &Fr) -> Fr {
+    *x - h
 }
 
-// This will compress hashes of transitions...
-fn sum_transitions(v: Vec<FpVar<Fr>>) -> FpVar<Fr> {
-    let mut acc = FpVar::Constant(Fr::from(0));
-    for elem in v {
-        acc = acc + truncate(elem)
-    }
-    acc
+pub fn element_gadget(x: &FpVar<Fr>, h: &FpVar<Fr>) -> FpVar<Fr> {
+    x - h
+}
+
+// adds the hashes to the accumulator
+pub fn accumulate(x: &Fr, acc: Fr, hs: &[Fr]) -> Fr {
+    hs.iter().fold(acc, |acc, h| acc * element(x, h))
+}
+
+pub fn accumulate_gadget(x: &FpVar<Fr>, acc: FpVar<Fr>, hs: &[FpVar<Fr>]) -> FpVar<Fr> {
+    hs.iter().fold(acc, |acc, h| acc * element_gadget(x, h))
+}
+
+pub fn transition_hash(params: &PoseidonParameters<Fr>, tr: &Transition) -> Fr {
+    hash_pair(params, &tr.before.hash(params), &tr.after.hash(params))
+}
+
+// accumulator of a list of transitions
+pub fn accumulate_transitions(params: &PoseidonParameters<Fr>, x: &Fr, trs: &[Transition]) -> Fr {
+    let hs: Vec<Fr> = trs.iter().map(|tr| transition_hash(params, tr)).collect();
+    accumulate(x, Fr::from(1), &hs)
+}
+
+// hashes of the transitions between consecutive states
+pub fn sequence_hashes(params: &PoseidonParameters<Fr>, v: &[Fr]) -> Vec<Fr> {
+    v.windows(2).map(|w| hash_pair(params, &w[0], &w[1])).collect()
 }
 
-fn sum_sequence(
-    _params: &PoseidonParameters<Fr>,
+pub fn accumulate_sequence(params: &PoseidonParameters<Fr>, x: &Fr, v: &[Fr]) -> Fr {
+    accumulate(x, Fr::from(1), &sequence_hashes(params, v))
+}
+
+pub fn accumulate_sequence_gadget(
     params_g: &CRHParametersVar::<Fr>,
-    v: Vec<FpVar<Fr>>,
+    x: &FpVar<Fr>,
+    v: &[FpVar<Fr>],
 ) -> FpVar<Fr> {
-    let mut vars = vec![];
-    for i in 0..v.len()-1 {
-        let hash_var = CRHGadget::<Fr>::evaluate(&params_g, &vec![v[i].clone(), v[i+1].clone()]).unwrap();
-        vars.push(hash_var)
+    let hs: Vec<FpVar<Fr>> = v.windows(2).map(|w| {
+        CRHGadget::<Fr>::evaluate(&params_g, &vec![w[0].clone(), w[1].clone()]).unwrap()
+    }).collect();
+    accumulate_gadget(x, FpVar::Constant(Fr::from(1)), &hs)
+}
+
+// The verifier computes x from hash_list of the states and the commitment of the list of transitions
+// they are compared with, for example the root that merkleloop proves against. It then uses x as the
+// root input when it verifies the proof of prove_loops, so the prover cannot choose it.
+pub fn challenge(params: &PoseidonParameters<Fr>, states: &[Fr], list_commitment: &Fr) -> Fr {
+    hash_pair(params, &hash_list(params, states), list_commitment)
+}
+
+// loop state with the accumulator of the transitions and the hash_list of the states so far
+pub fn loop_state(params: &PoseidonParameters<Fr>, st: &Fr, acc: &Fr, list: &Fr) -> Fr {
+    hash_many(params, &[*st, *acc, *list])
+}
+
+// Checks the start and end loop states of prove_loops. The loops start at first with an empty
+// accumulator and end at last with acc, and the states in between hash to states_hash, which
+// together with the commitment of the other list gives x.
+pub fn verify(
+    params: &PoseidonParameters<Fr>,
+    x: &Fr,
+    states_hash: &Fr,
+    list_commitment: &Fr,
+    first: &Fr,
+    last: &Fr,
+    acc: &Fr,
+    start_st: &Fr,
+    end_st: &Fr,
+) -> bool {
+    *x == hash_pair(params, states_hash, list_commitment)
+        && *start_st == loop_state(params, first, &Fr::from(1), &hash_list(params, &[*first]))
+        && *end_st == loop_state(params, last, acc, states_hash)
+}
+
+// A loop over at most steps.len() transitions, empty slots keep the state, the accumulator and the
+// list so that all loops of the same size share the keys. Public inputs are the start and end loop
+// states and the challenge.
+#[derive(Debug, Clone)]
+pub struct AccumulatorLoop {
+    pub params: PoseidonParameters<Fr>,
+    pub challenge: Fr,
+    pub start: Fr,
+    pub start_acc: Fr,
+    pub start_list: Fr,
+    pub steps: Vec<Option<Fr>>,
+}
+
+impl AccumulatorLoop {
+    pub fn new(
+        params: &PoseidonParameters<Fr>,
+        challenge: Fr,
+        start: Fr,
+        start_acc: Fr,
+        start_list: Fr,
+        states: &[Fr],
+        size: usize,
+    ) -> Result<Self, String> {
+        if states.len() > size {
+            return Err(format!("{} states do not fit in a loop of {}", states.len(), size));
+        }
+        let mut steps = states.iter().map(|st| Some(*st)).collect::<Vec<_>>();
+        steps.resize(size, None);
+        Ok(AccumulatorLoop { params: params.clone(), challenge, start, start_acc, start_list, steps })
+    }
+
+    // last state, accumulator and list
+    pub fn end(&self) -> (Fr, Fr, Fr) {
+        let mut st = self.start;
+        let mut acc = self.start_acc;
+        let mut list = self.start_list;
+        for next in self.steps.iter().flatten() {
+            acc = acc * element(&self.challenge, &hash_pair(&self.params, &st, next));
+            list = hash_pair(&self.params, next, &list);
+            st = *next;
+        }
+        (st, acc, list)
+    }
+}
+
+impl LoopCircuit for AccumulatorLoop {
+    fn get_inputs(&self) -> Vec<Fr> {
+        let (start_st, end_st, challenge) = self.get();
+        vec![start_st, end_st, challenge]
+    }
+    fn get(&self) -> (Fr,Fr,Fr) {
+        let (end, end_acc, end_list) = self.end();
+        (
+            loop_state(&self.params, &self.start, &self.start_acc, &self.start_list),
+            loop_state(&self.params, &end, &end_acc, &end_list),
+            self.challenge,
+        )
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for AccumulatorLoop {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        let inputs = self.get_inputs();
+        let start_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(inputs[0])).unwrap());
+        let end_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(inputs[1])).unwrap());
+        let x = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(inputs[2])).unwrap());
+        let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(self.params.clone())).unwrap();
+
+        let mut st = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(self.start)).unwrap());
+        let mut acc = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(self.start_acc)).unwrap());
+        let mut list = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(self.start_list)).unwrap());
+        start_var.enforce_equal(&CRHGadget::<Fr>::evaluate(&params_g, &vec![st.clone(), acc.clone(), list.clone()]).unwrap())?;
+
+        for step in self.steps.iter() {
+            let used = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(step.is_some())).unwrap());
+            let next_value = step.unwrap_or(st.value().unwrap_or_default());
+            let next = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(next_value)).unwrap());
+            let h = CRHGadget::<Fr>::evaluate(&params_g, &vec![st.clone(), next.clone()]).unwrap();
+            let next_acc = accumulate_gadget(&x, acc.clone(), &[h]);
+            let next_list = CRHGadget::<Fr>::evaluate(&params_g, &vec![next.clone(), list.clone()]).unwrap();
+            acc = used.select(&next_acc, &acc)?;
+            list = used.select(&next_list, &list)?;
+            st = used.select(&next, &st)?;
+        }
+        end_var.enforce_equal(&CRHGadget::<Fr>::evaluate(&params_g, &vec![st, acc, list]).unwrap())?;
+        Ok(())
+    }
+}
+
+// Splits the states into loops of size transitions, the number of loops is a power of 4 so that
+// aggloop ends with a single circuit
+pub fn loops(params: &PoseidonParameters<Fr>, challenge: Fr, states: &[Fr], size: usize) -> Result<Vec<AccumulatorLoop>, String> {
+    if states.is_empty() {
+        return Err("no states".to_string());
     }
-    sum_transitions(vars)
+    if size == 0 {
+        return Err("loops have to have at least one step".to_string());
+    }
+    let steps = &states[1..];
+    let mut num = 4;
+    while num * size < steps.len() {
+        num = num * 4;
+    }
+    let mut res = vec![];
+    let mut start = states[0];
+    let mut acc = Fr::from(1);
+    let mut list = hash_list(params, &states[0..1]);
+    for i in 0..num {
+        let lo = std::cmp::min(i * size, steps.len());
+        let hi = std::cmp::min((i + 1) * size, steps.len());
+        let circuit = AccumulatorLoop::new(params, challenge, start, acc, list, &steps[lo..hi], size)?;
+        (start, acc, list) = circuit.end();
+        res.push(circuit);
+    }
+    Ok(res)
 }
 
-pub fn test(params: &PoseidonParameters<Fr>) {
+pub fn test(params: &PoseidonParameters<Fr>) -> Result<(), String> {
+    let states: Vec<Fr> = (0..10).map(|i| Fr::from(i as u32 * 7)).collect();
+    // the transitions are compared with the same list, committed with hash_list
+    let commitment = hash_list(params, &sequence_hashes(params, &states));
+    let x = challenge(params, &states, &commitment);
+    let circuits = loops(params, x, &states, 3)?;
+    let (_, _, start_st, end_st) = prove_loops("accumulator", &circuits);
+    let acc = accumulate_sequence(params, &x, &states);
+    let verified = verify(params, &x, &hash_list(params, &states), &commitment, &states[0], &states[9], &acc, &start_st, &end_st);
+    tracing::debug!(start = %start_st, end = %end_st, verified, "accumulator proof");
+    if !verified {
+        return Err("accumulator proof does not match the states".to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_accumulator() {
+    let params = crate::generate_hash();
+    let x = Fr::from(1234567u32);
+    let hs: Vec<Fr> = (0..6).map(|i| Fr::from(i as u32 + 1)).collect();
+    let acc = |lst: &[Fr]| accumulate(&x, Fr::from(1), lst);
+    let mut rev = hs.clone();
+    rev.reverse();
+    // order does not matter
+    assert_eq!(acc(&hs), acc(&rev));
+    // union is the product
+    assert_eq!(acc(&hs), acc(&hs[0..2]) * acc(&hs[2..]));
+    assert_eq!(acc(&hs), accumulate(&x, acc(&hs[0..2]), &hs[2..]));
+    // multiplicities count
+    let mut dup = hs.clone();
+    dup[1] = hs[0];
+    assert_ne!(acc(&dup), acc(&hs));
+    assert_ne!(acc(&hs[0..5]), acc(&hs));
+    // gadget gives the same values
     let cs_sys = ConstraintSystem::<Fr>::new();
     let cs = ConstraintSystemRef::new(cs_sys);
-    let _params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+    let params_g = CRHParametersVar::<Fr>::new_witness(cs.clone(), || Ok(params.clone())).unwrap();
+    let vars: Vec<FpVar<Fr>> = hs.iter().map(|h| {
+        let h = h.clone();
+        FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(h)).unwrap())
+    }).collect();
+    let x_var = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || Ok(x)).unwrap());
+    let acc_var = accumulate_gadget(&x_var, FpVar::Constant(Fr::from(1)), &vars);
+    assert_eq!(acc_var.value().unwrap(), acc(&hs));
+    let seq_var = accumulate_sequence_gadget(&params_g, &x_var, &vars);
+    assert_eq!(seq_var.value().unwrap(), accumulate_sequence(&params, &x, &hs));
+    assert!(cs.is_satisfied().unwrap());
+}
 
-    let var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(100))).unwrap());
-    let tvar = truncate(var.clone());
+#[cfg(test)]
+use ark_relations::r1cs::ConstraintSystem;
 
-    println!("truncated {}", tvar.value().unwrap());
+#[cfg(test)]
+fn is_satisfied<C: ConstraintSynthesizer<Fr>>(circuit: C) -> bool {
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.generate_constraints(cs.clone()).unwrap();
+    cs.is_satisfied().unwrap()
+}
 
-    println!("num constraints {}, valid {}", cs.num_constraints(), cs.is_satisfied().unwrap());
+#[test]
+fn test_accumulator_loop() {
+    let params = crate::generate_hash();
+    let states: Vec<Fr> = (0..9).map(|i| Fr::from(i as u32 * 7)).collect();
+    let x = challenge(&params, &states, &hash_list(&params, &sequence_hashes(&params, &states)));
+    let circuits = loops(&params, x, &states, 3).unwrap();
+    assert_eq!(circuits.len(), 4);
+    for c in circuits.iter() {
+        assert!(is_satisfied(c.clone()));
+    }
+    // loops chain and share the challenge, as checked by aggloop
+    for i in 0..3 {
+        let (_, end, root) = circuits[i].get();
+        let (start, _, root2) = circuits[i + 1].get();
+        assert_eq!(end, start);
+        assert_eq!(root, root2);
+    }
+    // the last state has the accumulator of all transitions and the hash_list of the states,
+    // the padded loop changes nothing
+    let acc = accumulate_sequence(&params, &x, &states);
+    let states_hash = hash_list(&params, &states);
+    assert_eq!(circuits[0].get().0, loop_state(&params, &states[0], &Fr::from(1), &hash_list(&params, &states[0..1])));
+    assert_eq!(circuits[3].get().1, loop_state(&params, &states[8], &acc, &states_hash));
+    assert_eq!(circuits[3].get().0, circuits[3].get().1);
+    let commitment = hash_list(&params, &sequence_hashes(&params, &states));
+    let (start_st, end_st) = (circuits[0].get().0, circuits[3].get().1);
+    assert!(verify(&params, &x, &states_hash, &commitment, &states[0], &states[8], &acc, &start_st, &end_st));
+    // x has to come from the states of the loops
+    let other_hash = hash_list(&params, &states[1..]);
+    let other_x = hash_pair(&params, &other_hash, &commitment);
+    assert!(!verify(&params, &other_x, &other_hash, &commitment, &states[0], &states[8], &acc, &start_st, &end_st));
+    assert!(!verify(&params, &x, &states_hash, &commitment, &states[0], &states[7], &acc, &start_st, &end_st));
+    // same keys for every loop
+    let sizes: Vec<usize> = circuits.iter().map(|c| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        c.clone().generate_constraints(cs.clone()).unwrap();
+        cs.num_constraints()
+    }).collect();
+    assert!(sizes.iter().all(|n| *n == sizes[0]));
+    // another multiset of transitions between the same states gives another end state
+    let list = hash_list(&params, &states[0..1]);
+    let other = AccumulatorLoop::new(&params, x, states[0], Fr::from(1), list, &[Fr::from(100), states[2]], 3).unwrap();
+    let same = AccumulatorLoop::new(&params, x, states[0], Fr::from(1), list, &states[1..3], 3).unwrap();
+    assert_eq!(other.end().0, same.end().0);
+    assert_ne!(other.get().1, same.get().1);
+    // too many states, and nothing to loop over
+    assert!(AccumulatorLoop::new(&params, x, states[0], Fr::from(1), list, &states[1..5], 3).is_err());
+    assert!(loops(&params, x, &[], 3).is_err());
 }
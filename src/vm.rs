@@ -16,17 +16,231 @@ use ark_r1cs_std::boolean::Boolean;
 use ark_r1cs_std::boolean::AllocatedBool;
 use ark_r1cs_std::select::CondSelectGadget;
 use ark_r1cs_std::fields::FieldVar;
+use ark_r1cs_std::R1CSVar;
+
+use std::collections::HashMap;
 
 use crate::hash::{Params,poseidon,poseidon_gadget,generate_params};
 
-// New version of VM
+/* New version of VM
+
+   Register machine with four 64-bit registers r0..r3. Instructions are u64 words, the code is
+   committed as a hash list (see hash_code) so that the pc of the machine is the hash of the
+   remaining code. Jump targets are looked up from a code table in memory.
+
+   Common fields:
+     bits 0..2    register a
+     bits 2..4    register b
+     bits 9..13   destination mask, the result is written to each register that has its bit set
+     bit 31       instruction class, 0 for ALU and 1 for memory / control
+     bits 32..64  immediate
+
+   ALU instructions (generate_step), bits 4..9 select the operation:
+     0 add, 1 mul, 2 sub, 3 neg, 4 const (immediate), 5 and, 6 or, 7 xor,
+     8 shl, 9 shr_u, 10 shr_s, 11 lt_u, 12 lt_s, 13 eq,
+     14 div_u, 15 div_s, 16 rem_u, 17 rem_s, 31 trap
+   Arithmetic wraps around at 64 bits, shifts use the amount modulo 64 and comparisons give 0 or 1.
+   Division by zero gives all ones and the remainder is the dividend, signed overflow gives the
+   dividend and zero remainder. Trap, like any operation without a gadget, cannot be proven, so a
+   program that reaches it has no proof. The interpreter stops there.
+
+   Memory instructions (generate_memop) make exactly one memory operation, bits 4..7 select the kind:
+     0 load   rd = mem[ra]
+     1 store  mem[ra] = rb
+     2 save   push the address after the following instruction to the control stack, "save; jmp f" is a call
+     3 jmp    jump to label imm
+     4 jnz    jump to label imm if rb is not zero
+     5 ret    pop the control stack and jump there
+
+   Memory is split in segments: data memory is below 1 << 32, the control stack is at CONTROL_BASE + pointer
+   and the code table mapping labels to code hashes is at CODE_BASE + label.
+*/
+
+pub const CONTROL_BASE : u64 = 1 << 32;
+pub const CODE_BASE : u64 = 1 << 33;
+
+const MEM_CLASS : u64 = 1 << 31;
+
+pub const OP_ADD : u64 = 0;
+pub const OP_MUL : u64 = 1;
+pub const OP_SUB : u64 = 2;
+pub const OP_NEG : u64 = 3;
+pub const OP_CONST : u64 = 4;
+pub const OP_AND : u64 = 5;
+pub const OP_OR : u64 = 6;
+pub const OP_XOR : u64 = 7;
+pub const OP_SHL : u64 = 8;
+pub const OP_SHR_U : u64 = 9;
+pub const OP_SHR_S : u64 = 10;
+pub const OP_LT_U : u64 = 11;
+pub const OP_LT_S : u64 = 12;
+pub const OP_EQ : u64 = 13;
+pub const OP_DIV_U : u64 = 14;
+pub const OP_DIV_S : u64 = 15;
+pub const OP_REM_U : u64 = 16;
+pub const OP_REM_S : u64 = 17;
+pub const OP_TRAP : u64 = 31;
+
+pub const MEM_LOAD : u64 = 0;
+pub const MEM_STORE : u64 = 1;
+pub const MEM_SAVE : u64 = 2;
+pub const MEM_JMP : u64 = 3;
+pub const MEM_JNZ : u64 = 4;
+pub const MEM_RET : u64 = 5;
+
+const ALU_NAMES : [&str; 18] = [
+    "add", "mul", "sub", "neg", "const", "and", "or", "xor",
+    "shl", "shr_u", "shr_s", "lt_u", "lt_s", "eq",
+    "div_u", "div_s", "rem_u", "rem_s",
+];
+
+pub fn encode_alu(op: u64, a: usize, b: usize, dest: usize, imm: u32) -> u64 {
+    (a as u64) | ((b as u64) << 2) | (op << 4) | ((1u64 << dest) << 9) | ((imm as u64) << 32)
+}
+
+pub fn encode_mem(kind: u64, a: usize, b: usize, dest_mask: u64, imm: u32) -> u64 {
+    MEM_CLASS | (a as u64) | ((b as u64) << 2) | (kind << 4) | (dest_mask << 9) | ((imm as u64) << 32)
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub code: Vec<u64>,
+    // code position of each label
+    pub labels: Vec<usize>,
+}
+
+fn parse_reg(s: &str, line: usize) -> Result<usize, String> {
+    match s {
+        "r0" => Ok(0),
+        "r1" => Ok(1),
+        "r2" => Ok(2),
+        "r3" => Ok(3),
+        _ => Err(format!("line {}: expected register, got {}", line, s)),
+    }
+}
+
+fn parse_imm(s: &str, line: usize) -> Result<u32, String> {
+    let res = if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else {
+        s.parse::<u32>()
+    };
+    res.map_err(|_| format!("line {}: bad immediate {}", line, s))
+}
+
+/* Text assembler, one instruction per line:
+     add r0, r1, r2     ALU operations take the destination first, neg takes one operand
+     const r0, 123
+     load r0, r1
+     store r1, r0
+     jmp label / jnz r0, label / save / ret / call label / trap
+   Labels are written as "name:", comments start with ";".
+*/
+pub fn assemble(src: &str) -> Result<Program, String> {
+    // first pass finds the labels
+    let mut names : Vec<String> = vec![];
+    let mut labels = vec![];
+    let mut pos = 0;
+    for line in src.lines() {
+        let line = line.split(';').next().unwrap().trim();
+        if line.len() == 0 {
+            continue;
+        }
+        if let Some(name) = line.strip_suffix(':') {
+            if names.iter().any(|n| n == name) {
+                return Err(format!("label {} defined twice", name));
+            }
+            names.push(name.to_string());
+            labels.push(pos);
+        } else if line.starts_with("call") {
+            pos += 2;
+        } else {
+            pos += 1;
+        }
+    }
+    let label = |s: &str, line: usize| -> Result<u32, String> {
+        match names.iter().position(|n| n == s) {
+            Some(idx) => Ok(idx as u32),
+            None => Err(format!("line {}: unknown label {}", line, s)),
+        }
+    };
+    let mut code = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let line_num = i + 1;
+        let line = line.split(';').next().unwrap().trim();
+        if line.len() == 0 || line.ends_with(':') {
+            continue;
+        }
+        let (name, rest) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], line[idx..].trim()),
+            None => (line, ""),
+        };
+        let args : Vec<&str> = if rest.len() == 0 { vec![] } else { rest.split(',').map(|a| a.trim()).collect() };
+        let expect = |n: usize| {
+            if args.len() != n {
+                return Err(format!("line {}: {} takes {} operands", line_num, name, n));
+            }
+            Ok(())
+        };
+        if let Some(op) = ALU_NAMES.iter().position(|n| *n == name) {
+            let op = op as u64;
+            if op == OP_CONST {
+                expect(2)?;
+                code.push(encode_alu(op, 0, 0, parse_reg(args[0], line_num)?, parse_imm(args[1], line_num)?));
+            } else if op == OP_NEG {
+                expect(2)?;
+                code.push(encode_alu(op, parse_reg(args[1], line_num)?, 0, parse_reg(args[0], line_num)?, 0));
+            } else {
+                expect(3)?;
+                code.push(encode_alu(op, parse_reg(args[1], line_num)?, parse_reg(args[2], line_num)?, parse_reg(args[0], line_num)?, 0));
+            }
+            continue;
+        }
+        match name {
+            "load" => {
+                expect(2)?;
+                code.push(encode_mem(MEM_LOAD, parse_reg(args[1], line_num)?, 0, 1 << parse_reg(args[0], line_num)?, 0));
+            }
+            "store" => {
+                expect(2)?;
+                code.push(encode_mem(MEM_STORE, parse_reg(args[0], line_num)?, parse_reg(args[1], line_num)?, 0, 0));
+            }
+            "save" => {
+                expect(0)?;
+                code.push(encode_mem(MEM_SAVE, 0, 0, 0, 0));
+            }
+            "jmp" => {
+                expect(1)?;
+                code.push(encode_mem(MEM_JMP, 0, 0, 0, label(args[0], line_num)?));
+            }
+            "jnz" => {
+                expect(2)?;
+                code.push(encode_mem(MEM_JNZ, 0, parse_reg(args[0], line_num)?, 0, label(args[1], line_num)?));
+            }
+            "ret" => {
+                expect(0)?;
+                code.push(encode_mem(MEM_RET, 0, 0, 0, 0));
+            }
+            "call" => {
+                expect(1)?;
+                code.push(encode_mem(MEM_SAVE, 0, 0, 0, 0));
+                code.push(encode_mem(MEM_JMP, 0, 0, 0, label(args[0], line_num)?));
+            }
+            "trap" => {
+                expect(0)?;
+                code.push(encode_alu(OP_TRAP, 0, 0, 0, 0));
+            }
+            _ => return Err(format!("line {}: unknown instruction {}", line_num, name)),
+        }
+    }
+    Ok(Program { code, labels })
+}
 
 #[derive(Debug, Clone)]
 pub struct VM {
     pub registers: Vec<u64>,
     pub params: Params,
     pub control_pointer: usize,
-    pub frame_pointer: usize,
     pub mem_counter: usize,
     // pub pc: Vec<CodeTree>,
     pub pc: Vec<u64>,
@@ -36,7 +250,6 @@ pub struct VM {
 pub struct VMVar {
     pub registers: Vec<FpVar<Fr>>,
     pub control_pointer: FpVar<Fr>,
-    pub frame_pointer: FpVar<Fr>,
     pub mem_counter: FpVar<Fr>,
     pub pc_hash: FpVar<Fr>,
 }
@@ -49,6 +262,24 @@ pub struct MemOpVar {
     pub is_set: Boolean<Fr>,
 }
 
+impl VM {
+    pub fn hash(&self) -> Fr {
+        let mut inputs : Vec<Fr> = self.registers.iter().map(|r| Fr::from(*r)).collect();
+        inputs.push(Fr::from(self.control_pointer as u64));
+        inputs.push(Fr::from(self.mem_counter as u64));
+        inputs.push(hash_code(&self.params, &self.pc));
+        poseidon(&self.params, inputs)
+    }
+}
+
+fn hash_vmvar(params: &Params, vm: &VMVar) -> FpVar<Fr> {
+    let mut inputs = vm.registers.clone();
+    inputs.push(vm.control_pointer.clone());
+    inputs.push(vm.mem_counter.clone());
+    inputs.push(vm.pc_hash.clone());
+    poseidon_gadget(params, inputs)
+}
+
 fn hash_memop(params: &Params, memop: MemOpVar) -> FpVar<Fr> {
     poseidon_gadget(params, vec![
         memop.counter,
@@ -58,7 +289,7 @@ fn hash_memop(params: &Params, memop: MemOpVar) -> FpVar<Fr> {
     ])
 }
 
-fn hash_code(params: &Params, code: &[u64]) -> Fr {
+pub fn hash_code(params: &Params, code: &[u64]) -> Fr {
     let mut res = Fr::from(0);
     for op in code.iter().rev() {
         // println!("hashing {:?}", op);
@@ -100,181 +331,862 @@ fn decode_var2(cs: &ConstraintSystemRef<Fr>, a: u128, n: usize) -> Vec<Boolean<F
     decode2(a, n).iter().map(|b| Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(b)).unwrap())).collect::<Vec<_>>()
 }
 
-fn decode_op(a: u64) -> (u64, usize, usize) {
-    (a%16, ((a/16)%4) as usize, ((a/(16*4))%4) as usize)
+// operation, register a, register b, destination mask, immediate
+fn decode_op(a: u64) -> (u64, usize, usize, u64, u64) {
+    ((a >> 4) % 32, (a % 4) as usize, ((a >> 2) % 4) as usize, (a >> 9) % 16, a >> 32)
 }
 
-fn compute(op: u64, a: u64, b: u64) -> u128 {
-    if op == 0 {
-        (a as u128) + (b as u128)
-    } else if op == 1 {
-        (a as u128) * (b as u128)
-    } else /* if op == 2 */ {
-        (1u128 << 64) + (a as u128) - (b as u128)
-    }
+fn is_mem(a: u64) -> bool {
+    a & MEM_CLASS != 0
+}
+
+fn has_gadget(op: u64) -> bool {
+    op < ALU_NAMES.len() as u64
+}
+
+// None for operations that don't exist, the interpreter traps on them
+fn compute(op: u64, a: u64, b: u64, imm: u64) -> Option<u64> {
+    let sa = a as i64;
+    let sb = b as i64;
+    let res = match op {
+        OP_ADD => a.wrapping_add(b),
+        OP_MUL => a.wrapping_mul(b),
+        OP_SUB => a.wrapping_sub(b),
+        OP_NEG => a.wrapping_neg(),
+        OP_CONST => imm,
+        OP_AND => a & b,
+        OP_OR => a | b,
+        OP_XOR => a ^ b,
+        OP_SHL => a << (b % 64),
+        OP_SHR_U => a >> (b % 64),
+        OP_SHR_S => (sa >> (b % 64)) as u64,
+        OP_LT_U => (a < b) as u64,
+        OP_LT_S => (sa < sb) as u64,
+        OP_EQ => (a == b) as u64,
+        OP_DIV_U => if b == 0 { u64::MAX } else { a / b },
+        OP_DIV_S => if b == 0 { u64::MAX } else { sa.wrapping_div(sb) as u64 },
+        OP_REM_U => if b == 0 { a } else { a % b },
+        OP_REM_S => if b == 0 { a } else { sa.wrapping_rem(sb) as u64 },
+        _ => return None,
+    };
+    Some(res)
+}
+
+// Witness values for generate_step
+struct AluWitness {
+    // 128 bit value checked against the operation, the low bits are the result for arithmetic
+    combined: u128,
+    quotient: u64,
+    remainder: u64,
+    // remainder is smaller than the divisor
+    range: u64,
+}
+
+fn alu_witness(op: u64, a: u64, b: u64, imm: u64) -> AluWitness {
+    let s = b % 64;
+    let combined = match op {
+        OP_ADD => (a as u128) + (b as u128),
+        OP_MUL => (a as u128) * (b as u128),
+        OP_SUB => (1u128 << 64) + (a as u128) - (b as u128),
+        OP_NEG => (1u128 << 64) - (a as u128),
+        OP_CONST => imm as u128,
+        OP_SHL => (a as u128) << s,
+        OP_SHR_U | OP_SHR_S => (a as u128) << (64 - s),
+        OP_LT_U => (1u128 << 64) + (a as u128) - (b as u128),
+        OP_LT_S => (1u128 << 64) + ((a ^ (1 << 63)) as u128) - ((b ^ (1 << 63)) as u128),
+        // no gadget, nothing to match
+        _ => compute(op, a, b, imm).unwrap_or(0) as u128,
+    };
+    let sa = a as i64;
+    let sb = b as i64;
+    let (quotient, remainder, range) = match op {
+        OP_DIV_U | OP_REM_U => {
+            if b == 0 { (u64::MAX, a, 0) } else { (a / b, a % b, b - a % b - 1) }
+        }
+        OP_DIV_S | OP_REM_S => {
+            if b == 0 {
+                (u64::MAX, a, 0)
+            } else if sa == i64::MIN && sb == -1 {
+                (a, 0, 0)
+            } else {
+                let r = sa % sb;
+                ((sa / sb) as u64, r as u64, sb.unsigned_abs() - r.unsigned_abs() - 1)
+            }
+        }
+        _ => (0, 0, 0),
+    };
+    AluWitness { combined, quotient, remainder, range }
+}
+
+fn bits_var(cs: &ConstraintSystemRef<Fr>, a: u64) -> (Vec<Boolean<Fr>>, FpVar<Fr>) {
+    let bits = decode_var(cs, a, 64);
+    let var = Boolean::le_bits_to_fp_var(&bits).unwrap();
+    (bits, var)
+}
+
+// Value of a 64 bit two's complement number
+fn signed_var(v: &FpVar<Fr>, sign: &Boolean<Fr>) -> FpVar<Fr> {
+    v.clone() - FpVar::from(sign.clone()) * FpVar::constant(Fr::from(1u128 << 64))
 }
 
 fn generate_step(cs: &ConstraintSystemRef<Fr>, before: VM, before_var: VMVar) -> Result<VMVar, SynthesisError> {
     // For PC, just make the hash
     let params = &before.params;
     let inst = before.pc[0];
-    let inst_bools = decode_var(cs, inst, 16);
+    let inst_bools = decode_var(cs, inst, 64);
     let next_pc_hash = hash_code(params, &before.pc[1..]);
     let next_hash = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(next_pc_hash))).unwrap());
     // Instruction comes from boolean wires...
     let inst_var = Boolean::le_bits_to_fp_var(&inst_bools).unwrap();
     let prev_hash = poseidon_gadget(params, vec![inst_var, next_hash.clone()]);
     before_var.pc_hash.enforce_equal(&prev_hash).unwrap();
+    inst_bools[31].enforce_equal(&Boolean::constant(false)).unwrap();
 
     // Select registers
     let a_var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[0..2], &before_var.registers).unwrap();
     let b_var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[2..4], &before_var.registers).unwrap();
+    let imm_var = Boolean::le_bits_to_fp_var(&inst_bools[32..64]).unwrap();
+
+    let op_var = Boolean::le_bits_to_fp_var(&inst_bools[4..9]).unwrap();
+    let is_op = |k: u64| op_var.is_eq(&FpVar::constant(Fr::from(k))).unwrap();
+    let two64 = FpVar::constant(Fr::from(1u128 << 64));
+    let one = FpVar::constant(Fr::from(1));
+    let zero = FpVar::constant(Fr::from(0));
+
+    let (op, r1, r2, _, imm) = decode_op(inst);
+    let (a, b) = (before.registers[r1], before.registers[r2]);
+    let w = alu_witness(op, a, b, imm);
+
+    // Operands are 64 bit
+    let (a_bits, a_check) = bits_var(cs, a);
+    let (b_bits, b_check) = bits_var(cs, b);
+    a_check.enforce_equal(&a_var).unwrap();
+    b_check.enforce_equal(&b_var).unwrap();
 
     let add_var = a_var.clone() + b_var.clone();
     let mul_var = a_var.clone() * b_var.clone();
-    let minus_var = (a_var.clone() - b_var.clone()) + FpVar::constant(Fr::from(1u128 << 64));
-    let neg_var = FpVar::constant(Fr::from(1u128 << 64)) - a_var.clone();
+    let minus_var = (a_var.clone() - b_var.clone()) + two64.clone();
+    let neg_var = two64.clone() - a_var.clone();
 
     // result will have 64 bits
     // or for multiplication there will be 128 bits
-    let (op, r1, r2) = decode_op(inst);
-    let result = compute(op, before.registers[r1], before.registers[r2]);
-    let result_bools = decode_var2(cs, result, 128);
+    let result_bools = decode_var2(cs, w.combined, 128);
     let result_var = Boolean::le_bits_to_fp_var(&result_bools[0..64]).unwrap();
     let over_var = Boolean::le_bits_to_fp_var(&result_bools[64..128]).unwrap();
-    let combined_var = result_var.clone() + FpVar::constant(Fr::from(1u128 << 64)) * over_var.clone();
+    let combined_var = result_var.clone() + two64.clone() * over_var.clone();
+
+    // Bitwise
+    let mut and_bits = vec![];
+    let mut or_bits = vec![];
+    let mut xor_bits = vec![];
+    for i in 0..64 {
+        and_bits.push(a_bits[i].and(&b_bits[i]).unwrap());
+        or_bits.push(a_bits[i].or(&b_bits[i]).unwrap());
+        xor_bits.push(a_bits[i].xor(&b_bits[i]).unwrap());
+    }
+    let and_var = Boolean::le_bits_to_fp_var(&and_bits).unwrap();
+    let or_var = Boolean::le_bits_to_fp_var(&or_bits).unwrap();
+    let xor_var = Boolean::le_bits_to_fp_var(&xor_bits).unwrap();
 
-    let ops = vec![add_var, mul_var, minus_var, neg_var];
-    let selected = FpVar::conditionally_select_power_of_two_vector(&inst_bools[4..6], &ops).unwrap();
+    // Shifts multiply with 2^s or 2^(64-s), the result is in the low or high half
+    let mut pow = one.clone();
+    for j in 0..6 {
+        let factor = b_bits[j].select(&FpVar::constant(Fr::from(1u64 << (1 << j))), &one).unwrap();
+        pow = pow * factor;
+    }
+    let s = b % 64;
+    let pow_rev = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(1u128 << (64 - s)))).unwrap());
+    (pow.clone() * pow_rev.clone()).enforce_equal(&two64).unwrap();
+    let shl_var = a_var.clone() * pow;
+    let shr_var = a_var.clone() * pow_rev.clone();
+    let a_sign = a_bits[63].clone();
+    let b_sign = b_bits[63].clone();
+    let shr_s_var = over_var.clone() + FpVar::from(a_sign.clone()) * (two64.clone() - pow_rev);
+
+    // Comparisons look at bit 64 of the difference, signed ones flip the sign bits first
+    let two63 = FpVar::constant(Fr::from(1u64 << 63));
+    let lt_u_check = minus_var.clone();
+    let a_flip = a_var.clone() + two63.clone() - FpVar::from(a_sign.clone()) * two64.clone();
+    let b_flip = b_var.clone() + two63.clone() - FpVar::from(b_sign.clone()) * two64.clone();
+    let lt_s_check = a_flip - b_flip + two64.clone();
+    let lt_var = one.clone() - FpVar::from(result_bools[64].clone());
+    let eq_var = FpVar::from(a_var.is_eq(&b_var).unwrap());
+
+    // Division, a = q*b + r with the remainder smaller than the divisor
+    let (q_bits, q_var) = bits_var(cs, w.quotient);
+    let (r_bits, r_var) = bits_var(cs, w.remainder);
+    let (_, range_var) = bits_var(cs, w.range);
+    let all_ones = FpVar::constant(Fr::from(u64::MAX));
+    let b_zero = b_var.is_zero().unwrap();
+    let is_div_u = is_op(OP_DIV_U).or(&is_op(OP_REM_U)).unwrap();
+    let is_div_s = is_op(OP_DIV_S).or(&is_op(OP_REM_S)).unwrap();
+
+    (q_var.clone() * b_var.clone() + r_var.clone()).conditional_enforce_equal(&a_var, &is_div_u).unwrap();
+    let range_u = b_var.clone() - r_var.clone() - one.clone();
+
+    let sa = signed_var(&a_var, &a_sign);
+    let sb = signed_var(&b_var, &b_sign);
+    let r_sign = r_bits[63].clone();
+    let sq = signed_var(&q_var, &q_bits[63]);
+    let sr = signed_var(&r_var, &r_sign);
+    let overflow = a_var.is_eq(&two63).unwrap().and(&b_var.is_eq(&all_ones).unwrap()).unwrap();
+    let div_s_normal = is_div_s.and(&b_zero.not()).unwrap().and(&overflow.not()).unwrap();
+    (sq * sb.clone() + sr.clone()).conditional_enforce_equal(&sa, &div_s_normal).unwrap();
+    // remainder has the sign of the dividend
+    let r_nonzero = r_var.is_zero().unwrap().not();
+    r_sign.conditional_enforce_equal(&a_sign, &div_s_normal.and(&r_nonzero).unwrap()).unwrap();
+    let abs_b = b_sign.select(&(zero.clone() - sb.clone()), &sb).unwrap();
+    let abs_r = r_sign.select(&(zero.clone() - sr.clone()), &sr).unwrap();
+    let range_s = abs_b - abs_r - one.clone();
+    let is_div_s_overflow = is_div_s.and(&overflow).unwrap();
+    q_var.conditional_enforce_equal(&a_var, &is_div_s_overflow).unwrap();
+    r_var.conditional_enforce_equal(&zero, &is_div_s_overflow).unwrap();
+
+    // Division by zero
+    let div_zero = is_div_u.or(&is_div_s).unwrap().and(&b_zero).unwrap();
+    q_var.conditional_enforce_equal(&all_ones, &div_zero).unwrap();
+    r_var.conditional_enforce_equal(&a_var, &div_zero).unwrap();
+
+    let range = is_div_u.and(&b_zero.not()).unwrap().select(
+        &range_u,
+        &div_s_normal.select(&range_s, &zero).unwrap(),
+    ).unwrap();
+    range_var.enforce_equal(&range).unwrap();
 
+    // Value that has to match the 128 bit result, operations without a gadget cannot be matched
+    let mut checks = vec![
+        add_var, mul_var, minus_var, neg_var, imm_var,
+        combined_var.clone(), combined_var.clone(), combined_var.clone(),
+        shl_var, shr_var.clone(), shr_var, lt_u_check, lt_s_check, combined_var.clone(),
+        combined_var.clone(), combined_var.clone(), combined_var.clone(), combined_var.clone(),
+    ];
+    checks.resize(32, FpVar::constant(-Fr::from(1)));
+    let selected = FpVar::conditionally_select_power_of_two_vector(&inst_bools[4..9], &checks).unwrap();
     combined_var.enforce_equal(&selected).unwrap();
 
+    // Value written to the registers
+    let mut results = vec![
+        result_var.clone(), result_var.clone(), result_var.clone(), result_var.clone(), result_var.clone(),
+        and_var, or_var, xor_var,
+        result_var.clone(), over_var, shr_s_var, lt_var.clone(), lt_var, eq_var,
+        q_var.clone(), q_var, r_var.clone(), r_var,
+    ];
+    results.resize(32, result_var.clone());
+    let result_var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[4..9], &results).unwrap();
+
     // Then need to select the result registers
-    let r1var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[6..7], &vec![before_var.registers[0].clone(), selected.clone()]).unwrap();
-    let r2var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[7..8], &vec![before_var.registers[1].clone(), selected.clone()]).unwrap();
-    let r3var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[8..9], &vec![before_var.registers[2].clone(), selected.clone()]).unwrap();
-    let r4var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[9..10], &vec![before_var.registers[3].clone(), selected.clone()]).unwrap();
+    let r1var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[9..10], &vec![before_var.registers[0].clone(), result_var.clone()]).unwrap();
+    let r2var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[10..11], &vec![before_var.registers[1].clone(), result_var.clone()]).unwrap();
+    let r3var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[11..12], &vec![before_var.registers[2].clone(), result_var.clone()]).unwrap();
+    let r4var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[12..13], &vec![before_var.registers[3].clone(), result_var.clone()]).unwrap();
 
     Ok(VMVar {
         registers: vec![r1var, r2var, r3var, r4var],
         control_pointer: before_var.control_pointer.clone(),
-        frame_pointer: before_var.frame_pointer.clone(),
         mem_counter: before_var.mem_counter.clone(),
         pc_hash: next_hash,
     })
 }
 
-fn generate_memop(cs: &ConstraintSystemRef<Fr>, before: VM, before_var: VMVar, control_stack: &Vec<Fr>) -> Result<(VMVar, MemOpVar), SynthesisError> {
+// Step of a memory or control instruction, the memory operation is checked later by check_memory
+fn generate_memop(cs: &ConstraintSystemRef<Fr>, before: VM, before_var: VMVar, memop: &MemOp) -> Result<(VMVar, MemOpVar), SynthesisError> {
     // For PC, just make the hash
     let params = &before.params;
     let inst = before.pc[0];
-    let inst_bools = decode_var(cs, inst, 32);
+    let inst_bools = decode_var(cs, inst, 64);
     let next_pc_hash = hash_code(params, &before.pc[1..]);
     let next_hash = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(next_pc_hash))).unwrap());
     // Instruction comes from boolean wires...
     let inst_var = Boolean::le_bits_to_fp_var(&inst_bools).unwrap();
     let prev_hash = poseidon_gadget(params, vec![inst_var, next_hash.clone()]);
     before_var.pc_hash.enforce_equal(&prev_hash).unwrap();
+    inst_bools[31].enforce_equal(&Boolean::constant(true)).unwrap();
+
+    // The instruction after this one, save has to skip it
+    let (next_inst, next2_pc_hash) = if before.pc.len() > 1 {
+        (before.pc[1], hash_code(params, &before.pc[2..]))
+    } else {
+        (0, Fr::from(0))
+    };
+    let next_inst_var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(next_inst))).unwrap());
+    let next2_hash = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(next2_pc_hash)).unwrap());
 
     // Select registers
     let a_var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[0..2], &before_var.registers).unwrap();
     let b_var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[2..4], &before_var.registers).unwrap();
+    let imm_var = Boolean::le_bits_to_fp_var(&inst_bools[32..64]).unwrap();
 
-    // Operations: save PC, jump, read memory, write memory
+    let kind_var = Boolean::le_bits_to_fp_var(&inst_bools[4..7]).unwrap();
+    let is_kind = |k: u64| kind_var.is_eq(&FpVar::constant(Fr::from(k))).unwrap();
+    let is_load = is_kind(MEM_LOAD);
+    let is_store = is_kind(MEM_STORE);
+    let is_save = is_kind(MEM_SAVE);
+    let is_jmp = is_kind(MEM_JMP);
+    let is_jnz = is_kind(MEM_JNZ);
+    let is_ret = is_kind(MEM_RET);
+    let mut found = FpVar::constant(Fr::from(0));
+    for b in vec![&is_load, &is_store, &is_save, &is_jmp, &is_jnz, &is_ret] {
+        found = found + FpVar::from(b.clone());
+    }
+    found.enforce_equal(&FpVar::constant(Fr::from(1))).unwrap();
 
     // All will inc mem counter
     let mem_counter = before_var.mem_counter.clone() + FpVar::constant(Fr::from(1));
+    let value = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(memop.value)).unwrap());
 
-    // Save PC
-    let save_control_pointer = before_var.control_pointer.clone() + FpVar::constant(Fr::from(1));
-    let save_address = save_control_pointer.clone() + FpVar::constant(Fr::from(1u64 << 32));
-    let save_value = next_hash.clone();
-    // let save_is_set = Boolean::constant(true);
-    // TODO: actually there should be somekind of branching point (given as immediate?)
-
-    // Jump
-    let jump_var = Boolean::le_bits_to_fp_var(&inst_bools[4..10]).unwrap();
-    let jump_control_pointer = before_var.control_pointer.clone() - jump_var.clone();
-    // TODO: compute correct location
-    let jump_pc = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(control_stack[0].clone())).unwrap());
-    let jump_address = jump_control_pointer.clone() + FpVar::constant(Fr::from(1u64 << 32));
-    let jump_value = jump_pc.clone();
-    // let jump_is_set = Boolean::constant(false);
-    // TODO: jump control (check from register b)
-
-    // Read
-    let read_address = a_var.clone();
-    // TODO: actual memory
-    let read_value = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(control_stack[0].clone())).unwrap());
-    // let read_is_set = Boolean::constant(false);
-
-    // Write
-    let write_address = a_var.clone();
-    // TODO: actual memory
-    let write_value = b_var;
-    // let write_is_set = Boolean::constant(true);
-
-    let selected = write_value.clone();
+    // Load and store use data memory
+    let is_data = is_load.or(&is_store).unwrap();
+    let a_low = Boolean::le_bits_to_fp_var(&decode_var(cs, before.registers[(inst % 4) as usize] % CONTROL_BASE, 32)).unwrap();
+    a_var.conditional_enforce_equal(&a_low, &is_data).unwrap();
+    value.conditional_enforce_equal(&b_var, &is_store).unwrap();
 
-    // Then need to select the result registers
-    let r1var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[10..11], &vec![before_var.registers[0].clone(), selected.clone()]).unwrap();
-    let r2var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[11..12], &vec![before_var.registers[1].clone(), selected.clone()]).unwrap();
-    let r3var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[12..13], &vec![before_var.registers[2].clone(), selected.clone()]).unwrap();
-    let r4var = FpVar::conditionally_select_power_of_two_vector(&inst_bools[13..14], &vec![before_var.registers[3].clone(), selected.clone()]).unwrap();
+    // Save pushes the code after the next instruction
+    let control_pointer = before_var.control_pointer.clone();
+    let save_pointer = control_pointer.clone() + FpVar::constant(Fr::from(1));
+    let next_check = poseidon_gadget(params, vec![next_inst_var, next2_hash.clone()]);
+    next_hash.conditional_enforce_equal(&next_check, &is_save).unwrap();
+    value.conditional_enforce_equal(&next2_hash, &is_save).unwrap();
+
+    // Return cannot go below the bottom of the control stack
+    control_pointer.is_zero().unwrap().conditional_enforce_equal(&Boolean::constant(false), &is_ret).unwrap();
+    let ret_pointer = control_pointer.clone() - FpVar::constant(Fr::from(1));
 
-    let control_pointer = FpVar::conditionally_select_power_of_two_vector(&inst_bools[14..16], &vec![before_var.control_pointer.clone(), save_control_pointer, jump_control_pointer.clone(), jump_control_pointer]).unwrap();
+    let b_nonzero = b_var.is_zero().unwrap().not();
+    let taken = is_jmp.or(&is_jnz.and(&b_nonzero).unwrap()).unwrap().or(&is_ret).unwrap();
+    let pc_hash = taken.select(&value, &next_hash).unwrap();
+
+    let control_pointer_after = is_save.select(
+        &save_pointer,
+        &is_ret.select(&ret_pointer, &control_pointer).unwrap(),
+    ).unwrap();
 
-    let pc_hash = FpVar::conditionally_select_power_of_two_vector(
-        &inst_bools[16..17],
-        &vec![next_hash, jump_pc],
+    let control_base = FpVar::constant(Fr::from(CONTROL_BASE));
+    let code_address = FpVar::constant(Fr::from(CODE_BASE)) + imm_var;
+    let address = is_data.select(
+        &a_var,
+        &is_save.select(
+            &(control_base.clone() + save_pointer),
+            &is_ret.select(&(control_base + control_pointer), &code_address).unwrap(),
+        ).unwrap(),
     ).unwrap();
 
+    // Only load writes registers
+    let mut registers = vec![];
+    for i in 0..4 {
+        let write = inst_bools[9+i].and(&is_load).unwrap();
+        registers.push(write.select(&value, &before_var.registers[i]).unwrap());
+    }
+
     let after = VMVar {
-        registers: vec![r1var, r2var, r3var, r4var],
-        control_pointer,
-        frame_pointer: before_var.frame_pointer.clone(),
+        registers,
+        control_pointer: control_pointer_after,
         mem_counter: mem_counter.clone(),
         pc_hash,
     };
 
-    let address = FpVar::conditionally_select_power_of_two_vector(
-        &inst_bools[18..20],
-        &vec![save_address, jump_address, read_address, write_address],
-    ).unwrap();
-
-    let value = FpVar::conditionally_select_power_of_two_vector(
-        &inst_bools[20..22],
-        &vec![save_value, jump_value, read_value, write_value],
-    ).unwrap();
-
     let memop = MemOpVar {
         counter: mem_counter,
         address,
         value,
-        is_set: inst_bools[17].clone(),
+        is_set: is_store.or(&is_save).unwrap(),
     };
 
     Ok((after, memop))
 }
 
+// Proves the steps starting from the given state, returns the state after and the memory operations
+pub fn generate_steps(cs: &ConstraintSystemRef<Fr>, steps: &[Step], start: VMVar) -> (VMVar, Vec<MemOpVar>) {
+    let mut state = start;
+    let mut ops = vec![];
+    for step in steps.iter() {
+        state = match step {
+            Step::Alu(vm) => generate_step(cs, vm.clone(), state).unwrap(),
+            Step::Mem(vm, op) => {
+                let (after, op_var) = generate_memop(cs, vm.clone(), state, op).unwrap();
+                ops.push(op_var);
+                after
+            }
+        };
+    }
+    (state, ops)
+}
+
+fn memop_var(cs: &ConstraintSystemRef<Fr>, op: &MemOp) -> MemOpVar {
+    MemOpVar {
+        counter: FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(op.counter as u64))).unwrap()),
+        address: FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(op.address))).unwrap()),
+        value: FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(op.value)).unwrap()),
+        is_set: Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(op.is_set)).unwrap()),
+    }
+}
+
+pub fn memop_vars(cs: &ConstraintSystemRef<Fr>, ops: &[MemOp]) -> Vec<MemOpVar> {
+    ops.iter().map(|op| memop_var(cs, op)).collect()
+}
+
+fn table_values(cs: &ConstraintSystemRef<Fr>, table: &[MemOp]) -> Vec<FpVar<Fr>> {
+    table.iter().map(|op| FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(op.value)).unwrap())).collect()
+}
+
+// Initial contents of the code table, written before the first step
+pub fn code_table(params: &Params, program: &Program) -> Vec<MemOp> {
+    program.labels.iter().enumerate().map(|(l, pos)| MemOp {
+        counter: 0,
+        address: CODE_BASE + l as u64,
+        value: hash_code(params, &program.code[*pos..]),
+        is_set: true,
+    }).collect()
+}
+
+// Code table of a circuit with room for labels entries, unused labels have hash zero
+pub fn padded_code_table(params: &Params, program: &Program, labels: usize) -> Vec<MemOp> {
+    let mut table = code_table(params, program);
+    while table.len() < labels {
+        table.push(MemOp { counter: 0, address: CODE_BASE + table.len() as u64, value: Fr::from(0), is_set: true });
+    }
+    table
+}
+
+// Commitment to the code hashes of the table, the verifier computes it from the program
+pub fn code_table_commitment(params: &Params, table: &[MemOp]) -> Fr {
+    let mut res = Fr::from(0);
+    for op in table.iter() {
+        res = poseidon(params, vec![op.value, res]);
+    }
+    res
+}
+
+pub fn sort_memops(ops: &[MemOp]) -> Vec<MemOp> {
+    let mut res = ops.to_vec();
+    res.sort_by_key(|op| (op.address, op.counter));
+    res
+}
+
+// Multiset equality with a random linear combination, the challenges are hashed from both lists
+fn enforce_permutation(params: &Params, a: &[MemOpVar], b: &[MemOpVar]) {
+    assert_eq!(a.len(), b.len());
+    let mut h = FpVar::constant(Fr::from(0));
+    for op in a.iter().chain(b.iter()) {
+        h = poseidon_gadget(params, vec![hash_memop(params, op.clone()), h]);
+    }
+    let alpha = poseidon_gadget(params, vec![h.clone(), FpVar::constant(Fr::from(1))]);
+    let gamma = poseidon_gadget(params, vec![h, FpVar::constant(Fr::from(2))]);
+    let compress = |op: &MemOpVar| {
+        gamma.clone() - (
+            op.counter.clone() +
+            alpha.clone() * (op.address.clone() + alpha.clone() * (op.value.clone() + alpha.clone() * FpVar::from(op.is_set.clone())))
+        )
+    };
+    let mut prod_a = FpVar::constant(Fr::from(1));
+    let mut prod_b = FpVar::constant(Fr::from(1));
+    for i in 0..a.len() {
+        prod_a = prod_a * compress(&a[i]);
+        prod_b = prod_b * compress(&b[i]);
+    }
+    prod_a.enforce_equal(&prod_b).unwrap();
+}
+
+// Code table entry for label l with the given code hash, only the hash is a witness
+fn table_var(l: usize, value: &FpVar<Fr>) -> MemOpVar {
+    MemOpVar {
+        counter: FpVar::constant(Fr::from(0)),
+        address: FpVar::constant(Fr::from(CODE_BASE + l as u64)),
+        value: value.clone(),
+        is_set: Boolean::constant(true),
+    }
+}
+
+/* Offline memory checking. The code table is written at counter 0 to CODE_BASE + label before the
+   operations in execution order. The operations sorted by address and counter have to be a permutation
+   of the table and the operations, and in the sorted order every read returns the previous value at the
+   same address, or zero if the address was not written before.
+*/
+pub fn check_memory(cs: &ConstraintSystemRef<Fr>, params: &Params, table: &[FpVar<Fr>], ops: &[MemOpVar], sorted: &[MemOp]) {
+    let mut all : Vec<MemOpVar> = table.iter().enumerate().map(|(l, v)| table_var(l, v)).collect();
+    all.extend(ops.iter().cloned());
+    let sorted_vars = memop_vars(cs, sorted);
+    enforce_permutation(params, &all, &sorted_vars);
+    let zero = FpVar::constant(Fr::from(0));
+    for i in 0..sorted.len() {
+        let op = &sorted_vars[i];
+        let is_read = op.is_set.not();
+        if i == 0 {
+            op.value.conditional_enforce_equal(&zero, &is_read).unwrap();
+            continue;
+        }
+        let prev = &sorted_vars[i-1];
+        let same = op.address.is_eq(&prev.address).unwrap();
+        let expected = same.select(&prev.value, &zero).unwrap();
+        op.value.conditional_enforce_equal(&expected, &is_read).unwrap();
+
+        // Counter increases for the same address, otherwise the address increases
+        let diff = same.select(
+            &(op.counter.clone() - prev.counter.clone()),
+            &(op.address.clone() - prev.address.clone()),
+        ).unwrap() - FpVar::constant(Fr::from(1));
+        let native_diff = if sorted[i].address == sorted[i-1].address {
+            (sorted[i].counter as u64).wrapping_sub(sorted[i-1].counter as u64)
+        } else {
+            sorted[i].address.wrapping_sub(sorted[i-1].address)
+        }.wrapping_sub(1);
+        let diff_bits = decode_var(cs, native_diff, 64);
+        Boolean::le_bits_to_fp_var(&diff_bits).unwrap().enforce_equal(&diff).unwrap();
+    }
+}
+
 fn vmvar(cs: &ConstraintSystemRef<Fr>, params: &Params, vm: VM) -> VMVar {
     let r1var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(vm.registers[0]))).unwrap());
     let r2var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(vm.registers[1]))).unwrap());
     let r3var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(vm.registers[2]))).unwrap());
     let r4var = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(vm.registers[3]))).unwrap());
     let control_pointer = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(vm.control_pointer as u64))).unwrap());
-    let frame_pointer = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(vm.mem_counter as u64))).unwrap());
     let mem_counter = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(Fr::from(vm.mem_counter as u64))).unwrap());
     let pc_hash = FpVar::Var(AllocatedFp::<Fr>::new_witness(cs.clone(), || Ok(hash_code(params, &vm.pc))).unwrap());
     VMVar {
         registers: vec![r1var, r2var, r3var, r4var],
         control_pointer,
-        frame_pointer,
         mem_counter,
         pc_hash,
     }
 }
 
+// Memory operation in the order of execution
+#[derive(Debug, Clone)]
+pub struct MemOp {
+    pub counter: usize,
+    pub address: u64,
+    pub value: Fr,
+    pub is_set: bool,
+}
+
+impl MemOp {
+    pub fn hash(&self, params: &Params) -> Fr {
+        poseidon(params, vec![
+            Fr::from(self.counter as u64),
+            Fr::from(self.address),
+            self.value,
+            Fr::from(self.is_set),
+        ])
+    }
+}
+
+// Commitment to the memory log, the code table comes first and then the operations in execution order
+pub fn memlog_commitment(params: &Params, ops: &[MemOp]) -> Fr {
+    let mut res = Fr::from(0);
+    for op in ops.iter() {
+        res = poseidon(params, vec![op.hash(params), res]);
+    }
+    res
+}
+
+// Witness for one step, the machine is the state before the step
+#[derive(Debug, Clone)]
+pub enum Step {
+    Alu(VM),
+    Mem(VM, MemOp),
+}
+
+// Native interpreter for the register machine
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    pub program: Program,
+    pub params: Params,
+    pub pc: usize,
+    pub registers: Vec<u64>,
+    pub memory: HashMap<u64, u64>,
+    pub control: Vec<usize>,
+    pub mem_counter: usize,
+    // stopped at an instruction that cannot be proven
+    pub trapped: bool,
+}
+
+impl Interpreter {
+    pub fn new(params: &Params, program: Program) -> Self {
+        Interpreter {
+            program,
+            params: params.clone(),
+            pc: 0,
+            registers: vec![0, 0, 0, 0],
+            memory: HashMap::new(),
+            control: vec![],
+            mem_counter: 0,
+            trapped: false,
+        }
+    }
+
+    pub fn vm(&self) -> VM {
+        VM {
+            registers: self.registers.clone(),
+            params: self.params.clone(),
+            control_pointer: self.control.len(),
+            mem_counter: self.mem_counter,
+            pc: self.program.code[self.pc..].to_vec(),
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.trapped || self.pc >= self.program.code.len()
+    }
+
+    fn code_hash(&self, pos: usize) -> Fr {
+        hash_code(&self.params, &self.program.code[pos..])
+    }
+
+    fn write(&mut self, mask: u64, v: u64) {
+        for i in 0..4 {
+            if (mask >> i) & 1 == 1 {
+                self.registers[i] = v;
+            }
+        }
+    }
+
+    // Operations without a gadget, data addresses outside data memory, jumps to labels that don't
+    // exist and returns with an empty control stack trap
+    fn traps(&self, inst: u64) -> bool {
+        let (op, a, _b, _dest, imm) = decode_op(inst);
+        if !is_mem(inst) {
+            return !has_gadget(op);
+        }
+        match op % 8 {
+            MEM_LOAD | MEM_STORE => self.registers[a] >= CONTROL_BASE,
+            MEM_SAVE => false,
+            MEM_JMP | MEM_JNZ => imm as usize >= self.program.labels.len(),
+            MEM_RET => self.control.len() == 0,
+            _ => true,
+        }
+    }
+
+    // None once the program has finished
+    pub fn step(&mut self) -> Option<Step> {
+        if self.finished() {
+            return None;
+        }
+        let vm = self.vm();
+        let inst = self.program.code[self.pc];
+        let (op, a, b, dest, imm) = decode_op(inst);
+        if self.traps(inst) {
+            self.trapped = true;
+            return Some(Step::Alu(vm));
+        }
+        if !is_mem(inst) {
+            match compute(op, self.registers[a], self.registers[b], imm) {
+                Some(res) => {
+                    self.write(dest, res);
+                    self.pc += 1;
+                }
+                None => self.trapped = true,
+            }
+            return Some(Step::Alu(vm));
+        }
+        self.pc += 1;
+        self.mem_counter += 1;
+        let counter = self.mem_counter;
+        let kind = op % 8;
+        let memop = match kind {
+            MEM_LOAD => {
+                let address = self.registers[a];
+                let v = *self.memory.get(&address).unwrap_or(&0);
+                self.write(dest, v);
+                MemOp { counter, address, value: Fr::from(v), is_set: false }
+            }
+            MEM_STORE => {
+                let address = self.registers[a];
+                let v = self.registers[b];
+                self.memory.insert(address, v);
+                MemOp { counter, address, value: Fr::from(v), is_set: true }
+            }
+            MEM_SAVE => {
+                let ret = self.pc + 1;
+                self.control.push(ret);
+                MemOp { counter, address: CONTROL_BASE + self.control.len() as u64, value: self.code_hash(ret), is_set: true }
+            }
+            MEM_JMP | MEM_JNZ => {
+                let target = self.program.labels[imm as usize];
+                if kind == MEM_JMP || self.registers[b] != 0 {
+                    self.pc = target;
+                }
+                MemOp { counter, address: CODE_BASE + imm, value: self.code_hash(target), is_set: false }
+            }
+            MEM_RET => {
+                let address = CONTROL_BASE + self.control.len() as u64;
+                let target = self.control.pop().unwrap();
+                self.pc = target;
+                MemOp { counter, address, value: self.code_hash(target), is_set: false }
+            }
+            _ => unreachable!("unknown memory instructions trap"),
+        };
+        Some(Step::Mem(vm, memop))
+    }
+
+    pub fn run(&mut self) -> Vec<Step> {
+        let mut steps = vec![];
+        while let Some(step) = self.step() {
+            steps.push(step);
+        }
+        steps
+    }
+}
+
+// Memory operation logged by slots that don't access memory, reads at this address return zero
+pub const PAD_ADDRESS : u64 = 1 << 34;
+
+fn pad_memop(slot: usize) -> MemOp {
+    MemOp { counter: slot, address: PAD_ADDRESS, value: Fr::from(0), is_set: false }
+}
+
+// Runs a program, public inputs are the machine hashes before and after, the memory log commitment and
+// the code table commitment. Without the last one the jump targets would be free.
+// The circuit has size step slots and a code table of labels entries whatever the program is, slots
+// after the end of the program are padding.
+#[derive(Debug, Clone)]
+pub struct RegisterVMCircuit {
+    pub params: Params,
+    pub init: Vec<MemOp>,
+    pub steps: Vec<Step>,
+    pub end: VM,
+    pub size: usize,
+}
+
+impl Step {
+    pub fn vm(&self) -> &VM {
+        match self {
+            Step::Alu(vm) => vm,
+            Step::Mem(vm, _) => vm,
+        }
+    }
+}
+
+impl RegisterVMCircuit {
+    pub fn new(params: &Params, program: Program, size: usize, labels: usize) -> Self {
+        assert!(program.labels.len() <= labels);
+        let init = padded_code_table(params, &program, labels);
+        let mut interp = Interpreter::new(params, program);
+        let steps = interp.run();
+        RegisterVMCircuit {
+            params: params.clone(),
+            init,
+            steps,
+            end: interp.vm(),
+            size,
+        }
+    }
+
+    fn memops(&self) -> Vec<MemOp> {
+        let mut res = self.init.clone();
+        for i in 0..self.size {
+            match self.steps.get(i) {
+                Some(Step::Mem(_, op)) => res.push(op.clone()),
+                _ => res.push(pad_memop(i)),
+            }
+        }
+        res
+    }
+
+    pub fn public_inputs(&self) -> Vec<Fr> {
+        vec![
+            self.steps[0].vm().hash(),
+            self.end.hash(),
+            memlog_commitment(&self.params, &self.memops()),
+            code_table_commitment(&self.params, &self.init),
+        ]
+    }
+}
+
+fn select_vmvar(cond: &Boolean<Fr>, a: &VMVar, b: &VMVar) -> VMVar {
+    let sel = |x: &FpVar<Fr>, y: &FpVar<Fr>| cond.select(x, y).unwrap();
+    VMVar {
+        registers: a.registers.iter().zip(b.registers.iter()).map(|(x, y)| sel(x, y)).collect(),
+        control_pointer: sel(&a.control_pointer, &b.control_pointer),
+        mem_counter: sel(&a.mem_counter, &b.mem_counter),
+        pc_hash: sel(&a.pc_hash, &b.pc_hash),
+    }
+}
+
+fn dummy_vm(params: &Params, inst: u64) -> VM {
+    VM {
+        registers: vec![0, 0, 0, 0],
+        params: params.clone(),
+        control_pointer: 0,
+        mem_counter: 0,
+        pc: vec![inst],
+    }
+}
+
+// Both gadgets are used in every slot. The one that doesn't match the step, and both in padding slots,
+// run on a dummy machine and their results are dropped. Each gadget checks the class of the instruction
+// of the machine it gets, so the step can't be executed by the wrong one.
+fn generate_slot(cs: &ConstraintSystemRef<Fr>, params: &Params, slot: usize, step: Option<&Step>, state: VMVar) -> Result<(VMVar, MemOpVar), SynthesisError> {
+    let active = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(step.is_some()))?);
+    let is_mem = Boolean::from(AllocatedBool::<Fr>::new_witness(cs.clone(), || Ok(matches!(step, Some(Step::Mem(_, _)))))?);
+    // padding only comes after the program has finished
+    state.pc_hash.conditional_enforce_equal(&FpVar::constant(Fr::from(0)), &active.not())?;
+
+    let alu_vm = match step {
+        Some(Step::Alu(vm)) => vm.clone(),
+        _ => dummy_vm(params, encode_alu(OP_CONST, 0, 0, 0, 0)),
+    };
+    let (mem_vm, mem_op) = match step {
+        Some(Step::Mem(vm, op)) => (vm.clone(), op.clone()),
+        _ => (dummy_vm(params, encode_mem(MEM_JMP, 0, 0, 0, 0)), MemOp { counter: 1, address: CODE_BASE, value: Fr::from(0), is_set: false }),
+    };
+    let is_alu = active.and(&is_mem.not())?;
+    let is_mem = active.and(&is_mem)?;
+    let alu_in = select_vmvar(&is_alu, &state, &vmvar(cs, params, alu_vm.clone()));
+    let mem_in = select_vmvar(&is_mem, &state, &vmvar(cs, params, mem_vm.clone()));
+    let alu_out = generate_step(cs, alu_vm, alu_in)?;
+    let (mem_out, op) = generate_memop(cs, mem_vm, mem_in, &mem_op)?;
+
+    let after = select_vmvar(&is_mem, &mem_out, &select_vmvar(&is_alu, &alu_out, &state));
+    let pad = pad_memop(slot);
+    let memop = MemOpVar {
+        counter: is_mem.select(&op.counter, &FpVar::constant(Fr::from(pad.counter as u64)))?,
+        address: is_mem.select(&op.address, &FpVar::constant(Fr::from(pad.address)))?,
+        value: is_mem.select(&op.value, &FpVar::constant(pad.value))?,
+        is_set: is_mem.and(&op.is_set)?,
+    };
+    Ok((after, memop))
+}
+
+impl ConstraintSynthesizer<Fr> for RegisterVMCircuit {
+    fn generate_constraints(
+        self,
+        cs: ConstraintSystemRef<Fr>,
+    ) -> Result<(), SynthesisError> {
+        let _metrics = crate::metrics::synthesis::<Self, _>(&cs);
+        if self.steps.len() > self.size {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+        let params = &self.params;
+        let first = self.steps.first().ok_or(SynthesisError::Unsatisfiable)?;
+        let start = vmvar(&cs, params, first.vm().clone());
+        let mut state = start.clone();
+        let table = table_values(&cs, &self.init);
+        let mut ops : Vec<MemOpVar> = table.iter().enumerate().map(|(l, v)| table_var(l, v)).collect();
+        for i in 0..self.size {
+            let (after, op) = generate_slot(&cs, params, i, self.steps.get(i), state)?;
+            state = after;
+            ops.push(op);
+        }
+        check_memory(&cs, params, &table, &ops[table.len()..], &sort_memops(&self.memops()));
+
+        let mut log = FpVar::constant(Fr::from(0));
+        for op in ops.iter() {
+            log = poseidon_gadget(params, vec![hash_memop(params, op.clone()), log]);
+        }
+        let mut code = FpVar::constant(Fr::from(0));
+        for v in table.iter() {
+            code = poseidon_gadget(params, vec![v.clone(), code]);
+        }
+        let start_hash = hash_vmvar(params, &start);
+        let end_hash = hash_vmvar(params, &state);
+        for v in vec![start_hash, end_hash, log, code] {
+            let input = FpVar::Var(AllocatedFp::<Fr>::new_input(cs.clone(), || v.value()).unwrap());
+            input.enforce_equal(&v)?;
+        }
+        Ok(())
+    }
+}
+
 pub fn test() {
     let cs_sys = ConstraintSystem::<Fr>::new();
     let cs = ConstraintSystemRef::new(cs_sys);
@@ -282,7 +1194,6 @@ pub fn test() {
     let vm = VM {
         params: params.clone(),
         mem_counter: 0,
-        frame_pointer: 0,
         control_pointer: 0,
         pc: vec![0,0,0,0,0],
         registers: vec![0,0,0,0],
@@ -290,5 +1201,366 @@ pub fn test() {
     let vm_var = vmvar(&cs, &params, vm.clone());
     generate_step(&cs, vm, vm_var).unwrap();
     // println!("gadget {}", res.value().unwrap());
-    println!("constraints {}", cs.num_constraints());
+    tracing::debug!(constraints = cs.num_constraints(), "vm step");
+}
+
+#[test]
+fn test_interpreter() {
+    let params = generate_params();
+    let program = assemble("
+        const r0, 10
+        const r1, 0
+        const r3, 1
+    loop:
+        add r1, r1, r0
+        sub r0, r0, r3   ; count down
+        jnz r0, loop
+        call store_result
+        jmp end
+    store_result:
+        const r2, 100
+        store r2, r1
+        ret
+    end:
+    ").unwrap();
+    assert_eq!(program.labels, vec![3, 9, 12]);
+    let mut interp = Interpreter::new(&params, program);
+    let steps = interp.run();
+    assert_eq!(interp.memory[&100], 55);
+    assert_eq!(interp.registers, vec![0, 55, 100, 1]);
+    assert_eq!(interp.control.len(), 0);
+    let memops : Vec<MemOp> = steps.iter().filter_map(|s| match s {
+        Step::Mem(_, op) => Some(op.clone()),
+        Step::Alu(_) => None,
+    }).collect();
+    // ten jnz, save, jmp, store, ret and the final jmp
+    assert_eq!(memops.len(), 15);
+    for (i, op) in memops.iter().enumerate() {
+        assert_eq!(op.counter, i + 1);
+    }
+    // return goes to the instruction after the call
+    assert_eq!(memops[10].address, CONTROL_BASE + 1);
+    assert_eq!(memops[13].address, CONTROL_BASE + 1);
+    assert_eq!(memops[13].value, memops[10].value);
+    assert_eq!(memops[13].value, hash_code(&params, &interp.program.code[8..]));
+}
+
+#[test]
+fn test_compute() {
+    let min = 1u64 << 63;
+    assert_eq!(compute(OP_DIV_U, 7, 0, 0), Some(u64::MAX));
+    assert_eq!(compute(OP_REM_U, 7, 0, 0), Some(7));
+    assert_eq!(compute(OP_DIV_S, min, u64::MAX, 0), Some(min));
+    assert_eq!(compute(OP_REM_S, min, u64::MAX, 0), Some(0));
+    assert_eq!(compute(OP_DIV_S, (-7i64) as u64, 2, 0), Some((-3i64) as u64));
+    assert_eq!(compute(OP_REM_S, (-7i64) as u64, 2, 0), Some((-1i64) as u64));
+    assert_eq!(compute(OP_SHR_S, min, 65, 0), Some(3u64 << 62));
+    assert_eq!(compute(OP_LT_S, min, 0, 0), Some(1));
+    assert_eq!(compute(OP_LT_U, min, 0, 0), Some(0));
+    assert_eq!(compute(OP_SUB, 1, 2, 0), Some(u64::MAX));
+    assert_eq!(compute(OP_NEG, 0, 0, 0), Some(0));
+    // the checked value gives the result in the low or high half
+    let w = alu_witness(OP_SHR_U, 0xf0, 4, 0);
+    assert_eq!((w.combined >> 64) as u64, 0xf);
+    let w = alu_witness(OP_LT_S, (-1i64) as u64, 1, 0);
+    assert_eq!((w.combined >> 64) & 1, 0);
+}
+
+// Each ALU step of the program satisfies generate_step and leads to the next state
+#[cfg(test)]
+fn check_alu_program(params: &Params, program: Program) -> bool {
+    let mut interp = Interpreter::new(params, program);
+    let steps = interp.run();
+    let mut states : Vec<VM> = steps.iter().map(|s| match s {
+        Step::Alu(vm) => vm.clone(),
+        Step::Mem(_, _) => panic!("expected ALU step"),
+    }).collect();
+    states.push(interp.vm());
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    for i in 0..states.len()-1 {
+        let before_var = vmvar(&cs, params, states[i].clone());
+        let after_var = vmvar(&cs, params, states[i+1].clone());
+        let res = generate_step(&cs, states[i].clone(), before_var).unwrap();
+        for j in 0..4 {
+            res.registers[j].enforce_equal(&after_var.registers[j]).unwrap();
+        }
+        res.pc_hash.enforce_equal(&after_var.pc_hash).unwrap();
+    }
+    cs.is_satisfied().unwrap()
+}
+
+#[test]
+fn test_alu_steps() {
+    let params = generate_params();
+    let program = assemble("
+        const r0, 0xffffffff
+        mul r1, r0, r0
+        add r2, r1, r1
+        sub r3, r0, r2
+        neg r0, r3
+    ").unwrap();
+    assert!(check_alu_program(&params, program));
+
+    // operation without a gadget is rejected
+    let program = Program { code: vec![encode_alu(20, 1, 2, 0, 0)], labels: vec![] };
+    assert!(!check_alu_program(&params, program));
+
+    // the interpreter stops at a trap, and the trap step cannot be proven
+    let program = assemble("
+        const r0, 1
+        trap
+        const r0, 2
+    ").unwrap();
+    let mut interp = Interpreter::new(&params, program.clone());
+    assert_eq!(interp.run().len(), 2);
+    assert!(interp.trapped);
+    assert_eq!(interp.registers[0], 1);
+    assert!(!check_alu_program(&params, program));
+}
+
+#[test]
+fn test_alu_operations() {
+    let params = generate_params();
+    // r0 = -7, r1 = 2, r2 = 0, r3 = i64::MIN
+    let setup = "
+        const r1, 7
+        neg r0, r1
+        const r1, 2
+        const r2, 0
+        const r3, 1
+        const r2, 63
+        shl r3, r3, r2
+        const r2, 0
+    ";
+    let cases = vec![
+        "and r2, r0, r1", "or r2, r0, r1", "xor r2, r0, r3",
+        "shl r2, r0, r1", "shr_u r2, r0, r1", "shr_s r2, r0, r1", "shr_s r2, r0, r2", "shr_u r2, r3, r3",
+        "lt_u r2, r0, r1", "lt_s r2, r0, r1", "lt_s r2, r1, r0", "lt_s r2, r3, r0", "eq r2, r0, r0", "eq r2, r0, r1",
+        "div_u r2, r0, r1", "rem_u r2, r0, r1", "div_s r2, r0, r1", "rem_s r2, r0, r1",
+        "div_s r2, r1, r0", "rem_s r2, r1, r0",
+        "div_u r2, r0, r2", "rem_s r2, r0, r2", "div_s r2, r3, r3",
+    ];
+    for case in cases {
+        assert!(check_alu_program(&params, assemble(&format!("{}\n{}", setup, case)).unwrap()), "{}", case);
+    }
+    // i64::MIN / -1
+    let program = assemble(&format!("{}\nconst r2, 0\nsub r0, r2, r1\nconst r1, 1\nadd r0, r0, r1\ndiv_s r2, r3, r0\nrem_s r1, r3, r0", setup)).unwrap();
+    assert!(check_alu_program(&params, program));
+
+    // wrong quotient
+    let mut program = assemble(&format!("{}\ndiv_u r2, r0, r1", setup)).unwrap();
+    let last = program.code.len() - 1;
+    program.code[last] = encode_alu(OP_DIV_S, 0, 1, 2, 0);
+    let mut interp = Interpreter::new(&params, program.clone());
+    let mut steps = interp.run();
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    let vm = match steps.pop().unwrap() {
+        Step::Alu(vm) => vm,
+        Step::Mem(_, _) => panic!("expected ALU step"),
+    };
+    let vm_var = vmvar(&cs, &params, vm.clone());
+    let res = generate_step(&cs, vm, vm_var).unwrap();
+    res.registers[2].enforce_equal(&FpVar::constant(Fr::from(compute(OP_DIV_U, (-7i64) as u64, 2, 0).unwrap()))).unwrap();
+    assert!(!cs.is_satisfied().unwrap());
+}
+
+#[test]
+fn test_memory_steps() {
+    let params = generate_params();
+    let program = assemble("
+        const r0, 3
+        const r1, 40
+    loop:
+        load r2, r1
+        add r2, r2, r0
+        store r1, r2
+        const r3, 1
+        sub r0, r0, r3
+        jnz r0, loop
+        call get
+        jmp end
+    get:
+        load r3, r1
+        ret
+    end:
+    ").unwrap();
+    let init = code_table(&params, &program);
+    let mut interp = Interpreter::new(&params, program);
+    let steps = interp.run();
+    assert_eq!(interp.registers[3], 6);
+
+    let prove = |init: &[MemOp], steps: &[Step]| {
+        let cs_sys = ConstraintSystem::<Fr>::new();
+        let cs = ConstraintSystemRef::new(cs_sys);
+        let first = match &steps[0] {
+            Step::Alu(vm) => vm.clone(),
+            Step::Mem(vm, _) => vm.clone(),
+        };
+        let start = vmvar(&cs, &params, first);
+        let (last, exec_ops) = generate_steps(&cs, steps, start);
+        let end = vmvar(&cs, &params, interp.vm());
+        for i in 0..4 {
+            last.registers[i].enforce_equal(&end.registers[i]).unwrap();
+        }
+        last.pc_hash.enforce_equal(&end.pc_hash).unwrap();
+        last.control_pointer.enforce_equal(&end.control_pointer).unwrap();
+        last.mem_counter.enforce_equal(&end.mem_counter).unwrap();
+
+        let mut native_ops = init.to_vec();
+        for step in steps.iter() {
+            if let Step::Mem(_, op) = step {
+                native_ops.push(op.clone());
+            }
+        }
+        check_memory(&cs, &params, &table_values(&cs, init), &exec_ops, &sort_memops(&native_ops));
+        cs.is_satisfied().unwrap()
+    };
+    assert!(prove(&init, &steps));
+
+    // load returning a value that was not stored
+    let mut bad = steps.clone();
+    for step in bad.iter_mut() {
+        if let Step::Mem(_, op) = step {
+            if op.address == 40 && !op.is_set && op.counter > 1 {
+                op.value = Fr::from(100);
+                break;
+            }
+        }
+    }
+    assert!(!prove(&init, &bad));
+
+    // the table is written at fixed addresses, an entry can't be moved to data memory
+    let mut moved = init.clone();
+    moved[0].address = 40;
+    assert!(!prove(&moved, &steps));
+}
+
+#[test]
+fn test_memory_traps() {
+    let params = generate_params();
+    // the machine stays at the instruction that traps
+    let check = |program: Program, steps: usize| {
+        let mut interp = Interpreter::new(&params, program);
+        assert_eq!(interp.run().len(), steps);
+        assert!(interp.trapped);
+        assert_eq!(interp.pc, steps - 1);
+        assert_eq!(interp.mem_counter, 0);
+    };
+    // load from the control stack
+    check(assemble("
+        const r0, 1
+        shl r0, r0, r0
+        const r1, 31
+        shl r0, r0, r1
+        load r2, r0
+    ").unwrap(), 5);
+    check(assemble("ret").unwrap(), 1);
+    check(Program { code: vec![encode_mem(MEM_JMP, 0, 0, 0, 5)], labels: vec![] }, 1);
+    check(Program { code: vec![encode_mem(7, 0, 0, 0, 0)], labels: vec![] }, 1);
+}
+
+#[test]
+fn test_assemble_errors() {
+    assert!(assemble("add r0, r1").is_err());
+    assert!(assemble("const r4, 1").is_err());
+    assert!(assemble("const r0, x").is_err());
+    assert!(assemble("jmp nowhere").is_err());
+    assert!(assemble("a:\na:").is_err());
+    assert!(assemble("nop").is_err());
+    let params = generate_params();
+    let mut interp = Interpreter::new(&params, assemble("const r0, 1").unwrap());
+    assert!(interp.step().is_some());
+    assert!(interp.step().is_none());
+}
+
+#[test]
+fn test_register_vm_circuit() {
+    let params = generate_params();
+    let program = assemble("
+        const r0, 5
+        const r1, 1
+        const r2, 1
+    loop:
+        mul r1, r1, r0
+        sub r0, r0, r2
+        jnz r0, loop
+        call save
+        jmp end
+    save:
+        store r2, r1
+        ret
+    end:
+    ").unwrap();
+    let circuit = RegisterVMCircuit::new(&params, program, 32, 4);
+    assert_eq!(circuit.end.registers[1], 120);
+    let inputs = circuit.public_inputs();
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    circuit.clone().generate_constraints(cs.clone()).unwrap();
+    assert!(cs.is_satisfied().unwrap());
+    let assignment = cs.borrow().unwrap().instance_assignment.clone();
+    assert_eq!(assignment[1..].to_vec(), inputs);
+
+    let num_constraints = cs.num_constraints();
+
+    // another program has the same shape
+    let other = RegisterVMCircuit::new(&params, assemble("
+        const r0, 2
+        const r1, 1
+    loop:
+        sub r0, r0, r1
+        store r0, r0
+        jnz r0, loop
+    ").unwrap(), 32, 4);
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    other.generate_constraints(cs.clone()).unwrap();
+    assert_eq!(cs.num_constraints(), num_constraints);
+
+    // stopping before the end of the program
+    let mut bad = circuit.clone();
+    bad.steps.pop();
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    bad.generate_constraints(cs.clone()).unwrap();
+    assert!(!cs.is_satisfied().unwrap());
+
+    // a forged code table entry sends the jump to another label, the circuit is satisfied but the
+    // table does not match the one of the program
+    let source = "
+        const r0, 1
+        jmp a
+    b:
+        const r0, 2
+        jmp end
+    a:
+        const r0, 3
+    end:
+    ";
+    let honest = RegisterVMCircuit::new(&params, assemble(source).unwrap(), 16, 4);
+    assert_eq!(honest.end.registers[0], 3);
+    let mut program = assemble(source).unwrap();
+    program.labels[1] = program.labels[0];
+    let forged = RegisterVMCircuit::new(&params, program, 16, 4);
+    assert_eq!(forged.end.registers[0], 2);
+    let expected = code_table_commitment(&params, &padded_code_table(&params, &assemble(source).unwrap(), 4));
+    assert_eq!(honest.public_inputs()[3], expected);
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    let cs = ConstraintSystemRef::new(cs_sys);
+    forged.clone().generate_constraints(cs.clone()).unwrap();
+    let assignment = cs.borrow().unwrap().instance_assignment.clone();
+    assert_eq!(assignment[1], honest.public_inputs()[0]);
+    assert_ne!(assignment[4], expected);
+
+    // empty trace and a trace longer than the circuit
+    let mut empty = circuit.clone();
+    empty.steps.clear();
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    assert!(matches!(empty.generate_constraints(ConstraintSystemRef::new(cs_sys)), Err(SynthesisError::Unsatisfiable)));
+    let mut long = circuit.clone();
+    long.size = 8;
+    let cs_sys = ConstraintSystem::<Fr>::new();
+    assert!(matches!(long.generate_constraints(ConstraintSystemRef::new(cs_sys)), Err(SynthesisError::Unsatisfiable)));
 }